@@ -400,6 +400,50 @@ fn bench_free<A: AllocLike<Item = BenchItem> + 'static>(nthreads: usize, per_thr
     a.kill()
 }
 
+/// Compare freeing objects allocated by other threads one at a time with
+/// `DynamicAllocator::free_batch`.
+fn bench_free_batch(nthreads: usize, per_thread: usize) {
+    for &batched in &[false, true] {
+        let a = DynamicAllocator::new();
+        let b = Arc::new(Barrier::new(nthreads + 1));
+        let mut threads = Vec::new();
+        for _ in 0..nthreads {
+            let mut alloc = a.clone();
+            let barrier = b.clone();
+            threads.push(thread::spawn(move || {
+                // Objects are freed by a different handle than the one that allocated them.
+                let mut producer = alloc.clone();
+                let mut ptrs = Vec::with_capacity(per_thread);
+                for i in 0..per_thread {
+                    unsafe {
+                        let ptr = producer.alloc(mem::size_of::<BenchItem>());
+                        write_volatile(ptr as *mut usize, i);
+                        ptrs.push(ptr);
+                    }
+                }
+                barrier.wait();
+                time_block_once!(unsafe {
+                    if batched {
+                        alloc.free_batch(&ptrs);
+                    } else {
+                        for &ptr in &ptrs {
+                            alloc.free(ptr);
+                        }
+                    }
+                })
+            }));
+        }
+        b.wait();
+        let mut total = 0;
+        for i in threads {
+            total += i.join().unwrap();
+        }
+        println!("{}: {} Mops/s",
+                 if batched { "free_batch" } else { "free" },
+                 ((nthreads * nthreads * per_thread * 1_000) as f64) / (total as f64));
+    }
+}
+
 macro_rules! run_bench_inner {
     ($bench:tt, $nthreads:expr, $iters:expr) => {
         let iters = $iters;
@@ -441,4 +485,9 @@ fn main() {
     run_bench!(both "free (thread-local)", bench_free, nthreads, ITERS);
     run_bench!(both "alloc & free (thread-local)", bench_alloc_free, nthreads, ITERS);
     run_bench!(threads "free (producer-consumer)", bench_prod_cons, nthreads, ITERS);
+
+    println!("\nremote free vs. free_batch - single-threaded");
+    bench_free_batch(1, ITERS);
+    println!("\nremote free vs. free_batch - {} threads", nthreads);
+    bench_free_batch(nthreads, ITERS);
 }
@@ -14,10 +14,25 @@
 //!
 //! # Using this Allocator from Rust
 //!
-//! We currently rely on some global allocator (bsalloc) to be running to service normal heap
-//! allocations. As a result, this allocator cannot be used as a global allocator via the
-//! `#[global_allocator]` attribute. Currently the only way around this is to use the `System`
-//! allocator along with `libelfc` from the `elfc` crate loaded with `LD_PRELOAD`.
+//! By default, we rely on some global allocator (bsalloc) to be running to service normal heap
+//! allocations. With the `use_default_allocator` feature enabled, bsalloc is not linked in, and
+//! `global::ElfGlobal` can be used as the global allocator via the `#[global_allocator]`
+//! attribute:
+//!
+//! ```rust,ignore
+//! #[global_allocator]
+//! static GLOBAL: ElfGlobal = ElfGlobal;
+//! ```
+//!
+//! See the `ElfGlobal` documentation for how the allocator bootstraps its own metadata in this
+//! configuration. The alternative is to use the `System` allocator along with `libelfc` from the
+//! `elfc` crate loaded with `LD_PRELOAD`.
+//!
+//! To use `DynamicAllocator` as a library in a program with some other global allocator, enable
+//! the `library` feature. This does not link in bsalloc, and allocates the allocator's internal
+//! metadata from the system allocator rather than with `mmap`. The `heap_metadata`,
+//! `system_metadata` and `extern_metadata` features select the metadata allocator on their own;
+//! see `utils::meta_alloc` for the details.
 //!
 //! It is also possible to use this allocator using a `Clone`-based API. As alluded to elsewhere,
 //! the allocator is thread-safe and any handle on the allocator can be used to free a pointer from
@@ -41,15 +56,37 @@
 //! instead want something more specialized, such as the `LocalAllocator` and `MagazineAllocator`
 //! object-specific allocators.
 
-use std::ptr;
+use alloc::allocator::{CannotReallocInPlace, Layout};
+use std::cmp;
+#[cfg(feature = "fault_injection")]
+use std::cell::Cell;
+use std::cell::RefCell;
+#[cfg(feature = "leak_check")]
+use std::collections::HashMap;
+use std::ptr::{self, NonNull};
 use std::mem;
+use std::sync::Arc;
+#[cfg(feature = "leak_check")]
+use std::sync::Mutex;
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
-// One of MagazineCache and LocalCache is unused, depending on whether the 'local_cache' feature is
-// enabled.
+// Only one of MagazineCache, LocalCache and PerCpuCache is used, depending on whether the
+// 'local_cache' or 'percpu_cache' feature is enabled.
 #[allow(unused_imports)]
-use super::slag::{compute_metadata, CoarseAllocator, Creek, DirtyFn, LocalCache, MagazineCache,
-                  MemoryBlock, Metadata, PageAlloc, RevocablePipe, Slag};
-use super::utils::{mmap, Lazy, TypedArray};
+use super::slag::{compute_metadata, CoarseAllocator, Creek, DirtyFn, FreshPage, Frontend,
+                  LocalCache, MagazineCache, MemoryBlock, Metadata, PageAlloc, PerCpuCache,
+                  RevocablePipe, Slag, DEFAULT_MAGAZINE_BYTES};
+use super::utils::{meta_alloc, mmap, Lazy, TypedArray};
+#[cfg(any(feature = "dump_stats_on_drop", feature = "quarantine"))]
+use super::utils::OwnedArray;
+#[cfg(feature = "quarantine")]
+use super::utils::PAGE_SIZE;
+use super::mmap_alloc::MapAlloc;
+use super::num_cpus;
+use super::object_alloc::Exhausted;
 
 #[cfg(feature = "nightly")]
 use std::intrinsics::likely;
@@ -90,17 +127,41 @@ pub mod global {
     //!
     //! The fix for this is to use the thread-local attribute to create a thread-local boolean that
     //! indicates if the current thread's value has been initialized. If this value is false, a
-    //! slower fallback algorithm is used.
+    //! slower fallback algorithm is used: allocations are served from a static bump region (see
+    //! the `bootstrap` module), falling back to `mmap` once the region is exhausted.
+    //!
+    //! Without the `nightly` feature, the `#[thread_local]` attribute is unavailable, and the
+    //! boolean is kept in a `thread_local!` `Cell` instead. This is slightly slower, but it is
+    //! still per-thread, and it does not allocate on targets with native TLS.
+    //!
+    //! ## Single-threaded mode
+    //!
+    //! The background thread makes the global allocator nondeterministic: memory released by a
+    //! thread may be reclaimed at any later point. Programs that never spawn threads (and tests)
+    //! can opt out of it with `set_single_threaded`, in which case all work is done synchronously
+    //! on the calling thread and the background thread is never started.
+    //!
+    //! ## Configuration
+    //!
+    //! Apart from `set_single_threaded`, the global allocator cannot be configured. In
+    //! particular, its large allocations are always readable, writable, and executable:
+    //! `DynamicAllocatorBuilder::large_permissions` has no global counterpart.
     #[allow(unused_imports)]
-    use super::{CoarseAllocator, Creek, DirtyFn, ElfMalloc, MemoryBlock, ObjectAlloc, PageAlloc,
-                TieredSizeClasses, TypedArray};
+    use super::{CoarseAllocator, Creek, DirtyFn, ElfMalloc, FreshPage, MemoryBlock, ObjectAlloc,
+                PageAlloc, TieredSizeClasses, TypedArray};
     #[cfg(feature = "nightly")]
     use super::likely;
-    use std::ptr;
-    use std::cell::UnsafeCell;
-    use std::mem;
+    use super::{bootstrap, Exhausted};
+    use super::super::utils::SpinLock;
+    use super::super::libc;
+    use alloc::allocator::{Alloc, AllocErr, Layout};
+    use std::cmp;
+    use std::ptr::{self, NonNull};
     #[allow(unused_imports)]
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::cell::{Cell, UnsafeCell};
+    use std::mem::{self, ManuallyDrop};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                            ATOMIC_USIZE_INIT};
     use std::sync::mpsc::{channel, Sender};
     use std::sync::Mutex;
     use std::thread;
@@ -112,28 +173,62 @@ pub mod global {
     //
     // type PA = PageAlloc<Block, BackgroundDirty>;
 
-    unsafe fn dirty_slag(mem: *mut u8) {
-        trace!("dirtying {:?}", mem);
-        let usable_size = 32 << 10;
-        let base_page = 4096;
-        let mut cur_addr = mem.offset(base_page);
-        while cur_addr < mem.offset(usable_size) {
-            cur_addr = cur_addr.offset(base_page);
-            (*(cur_addr as *mut AtomicUsize)).compare_and_swap(0, 1, Ordering::Relaxed);
+    /// Dirty each of the operating system's pages in `page`, and then publish it.
+    ///
+    /// The page is fresh, and thus filled with zeros, so writing zeros dirties it without changing
+    /// its contents.
+    fn dirty_slag(page: FreshPage) {
+        trace!("dirtying {:?}", page.as_ptr());
+        let base_page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut offset = 0;
+        while offset < page.size() {
+            unsafe { ptr::write_volatile(page.as_ptr().offset(offset as isize), 0) };
+            offset += base_page;
         }
+        page.publish();
     }
 
+    /// A `DirtyFn` that hands fresh pages to the background thread, which dirties them before
+    /// making them available for allocation.
     #[derive(Clone)]
-    struct BackgroundDirty;
+    pub struct BackgroundDirty;
     impl DirtyFn for BackgroundDirty {
-        fn dirty(_mem: *mut u8) {
-            #[cfg(feature = "nightly")]
-            {
-                let _ = LOCAL_DESTRUCTOR_CHAN.try_with(|h| h.send(Husk::Slag(_mem)));
+        fn dirty(page: FreshPage) {
+            if is_single_threaded() {
+                dirty_slag(page);
+                return;
             }
+            send(Husk::Slag(page));
         }
     }
 
+    /// Whether the global allocator is in single-threaded mode; see `set_single_threaded`.
+    static SINGLE_THREADED: AtomicBool = ATOMIC_BOOL_INIT;
+
+    /// Do all of the global allocator's work synchronously on the calling thread.
+    ///
+    /// Normally, some work is handed to a background thread: the memory cached by a thread is
+    /// reclaimed there once the thread exits, as are frees that happen after a thread's allocator
+    /// has been torn down. In single-threaded mode, that work happens immediately on the thread
+    /// that triggers it, and the background thread is never started. This makes the global
+    /// allocator deterministic, which is useful for tests and single-threaded embedded programs.
+    ///
+    /// # Safety
+    ///
+    /// The background thread exists because a thread's allocator cannot always be safely torn
+    /// down from within that thread's TLS destructors. Single-threaded mode must only be enabled
+    /// before any threads other than the calling one have used the global allocator, and no
+    /// threads that use it may be spawned (or exit) while it is enabled. Disabling it again is
+    /// always safe.
+    pub unsafe fn set_single_threaded(enabled: bool) {
+        SINGLE_THREADED.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Whether the global allocator is in single-threaded mode; see `set_single_threaded`.
+    pub fn is_single_threaded() -> bool {
+        SINGLE_THREADED.load(Ordering::Relaxed)
+    }
+
     #[cfg(all(feature = "nightly", target_thread_local))]
     #[thread_local]
     /// A thread-local value used to guard against recursive calls to allocation functions during
@@ -144,12 +239,11 @@ pub mod global {
     #[thread_local]
     /// A "cached" pointer to the thread-local allocator. This is set after initialization and
     /// set to null out prior to destruction.
-    static mut PTR: *mut ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>> = ptr::null_mut();
+    static mut PTR: *mut GlobalAllocator = ptr::null_mut();
 
     #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
     #[inline(always)]
-    /// Signal that initialization has begun. Note the use of a fall-back/stable method uses an
-    /// atomic integer. This is painfully slow.
+    /// Signal that initialization has begun on the current thread.
     fn init_begin() {
         #[cfg(feature = "nightly")]
         #[cfg(target_thread_local)]
@@ -159,12 +253,12 @@ pub mod global {
         #[cfg(feature = "nightly")]
         #[cfg(not(target_thread_local))]
         {
-            INITIALIZING.fetch_add(1, Ordering::Relaxed);
+            INITIALIZING.with(|i| i.set(true));
         }
 
         #[cfg(not(feature = "nightly"))]
         {
-            INITIALIZING.fetch_add(1, Ordering::Relaxed);
+            INITIALIZING.with(|i| i.set(true));
         }
     }
 
@@ -180,18 +274,18 @@ pub mod global {
         #[cfg(feature = "nightly")]
         #[cfg(not(target_thread_local))]
         {
-            INITIALIZING.fetch_sub(1, Ordering::Relaxed);
+            INITIALIZING.with(|i| i.set(false));
         }
 
         #[cfg(not(feature = "nightly"))]
         {
-            INITIALIZING.fetch_sub(1, Ordering::Relaxed);
+            INITIALIZING.with(|i| i.set(false));
         }
     }
 
     #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
     #[inline(always)]
-    /// Check if we are in a recursive call to an allocation function.
+    /// Check if we are in a recursive call to an allocation function on the current thread.
     fn is_initializing() -> bool {
         #[cfg(feature = "nightly")]
         #[cfg(target_thread_local)]
@@ -199,23 +293,37 @@ pub mod global {
         #[cfg(feature = "nightly")]
         #[cfg(not(target_thread_local))]
         {
-            INITIALIZING.load(Ordering::Relaxed) > 0
+            INITIALIZING.with(Cell::get)
         }
 
         #[cfg(not(feature = "nightly"))]
         {
-            INITIALIZING.load(Ordering::Relaxed) > 0
+            INITIALIZING.with(Cell::get)
         }
     }
 
 
-    #[derive(Clone)]
     /// A wrapper like `DynamicAllocator` in the parent module.
     ///
     /// The reason we have a wrapper is for this module's custom `Drop` implementation, mentioned
     /// in the module documentation.
     struct GlobalAllocator {
         inner: ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>,
+        /// The handle's id in `HANDLES`, or 0 if it is not registered.
+        id: usize,
+        /// Set by `trim` on other threads to ask the owning thread to flush its caches.
+        trim_requested: AtomicBool,
+    }
+
+    impl Clone for GlobalAllocator {
+        fn clone(&self) -> GlobalAllocator {
+            // The clone is a different handle, so it is not registered.
+            GlobalAllocator {
+                inner: self.inner.clone(),
+                id: 0,
+                trim_requested: AtomicBool::new(false),
+            }
+        }
     }
 
     unsafe impl Send for GlobalAllocator {}
@@ -225,8 +333,115 @@ pub mod global {
     unsafe impl Sync for GlobalAllocator {}
     impl GlobalAllocator {
         fn new() -> GlobalAllocator {
-            GlobalAllocator { inner: ElfMalloc::new() }
+            #[cfg(feature = "dump_stats_on_drop")]
+            unsafe {
+                libc::atexit(dump_stats_at_exit);
+            }
+            GlobalAllocator {
+                inner: ElfMalloc::new(),
+                id: 0,
+                trim_requested: AtomicBool::new(false),
+            }
+        }
+
+        /// Flush this handle's caches if `trim` has asked for it since the last check.
+        #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+        #[inline(always)]
+        unsafe fn check_trim(&mut self) {
+            if self.trim_requested.load(Ordering::Relaxed) {
+                self.trim_requested.store(false, Ordering::Relaxed);
+                self.inner.flush_caches();
+            }
+        }
+
+        unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+            self.check_trim();
+            self.inner.alloc(size)
+        }
+
+        unsafe fn free(&mut self, item: *mut u8) {
+            self.check_trim();
+            self.inner.free(item)
+        }
+
+        /// Add this handle to `HANDLES`, if it isn't already.
+        ///
+        /// The handle must not be moved until it is dropped.
+        unsafe fn register(&mut self) {
+            if self.id != 0 {
+                return;
+            }
+            // Growing the registry allocates. This may be the first access to this thread's
+            // handle (e.g., from `free`), so the allocation must not recurse into it.
+            let initializing = is_initializing();
+            init_begin();
+            // Ids start at 1, since 0 means unregistered.
+            self.id = NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed) + 1;
+            HANDLES
+                .lock()
+                .unwrap()
+                .push((self.id, self as *mut GlobalAllocator as usize));
+            if !initializing {
+                init_end();
+            }
+        }
+
+        /// Remove this handle from `HANDLES`, if it was registered.
+        fn deregister(&mut self) {
+            if self.id == 0 {
+                return;
+            }
+            let mut handles = HANDLES.lock().unwrap();
+            // Record this handle's counts while it is still locked in the registry, so that
+            // `dump_stats_at_exit` counts them exactly once.
+            #[cfg(feature = "dump_stats_on_drop")]
+            self.inner.record_stats();
+            if let Some(i) = handles.iter().position(|&(id, _)| id == self.id) {
+                handles.swap_remove(i);
+            }
+            self.id = 0;
+        }
+    }
+
+    /// Get the calling thread's handle from `LOCAL_ELF_HEAP`, registering it on first use.
+    #[cfg_attr(feature = "cargo-clippy", allow(mut_from_ref))]
+    unsafe fn local(h: &UnsafeCell<GlobalAllocator>) -> &mut GlobalAllocator {
+        let heap = &mut *h.get();
+        heap.register();
+        heap
+    }
+
+    /// Call `f` on each live thread-local handle.
+    ///
+    /// Each thread's handle is registered the first time the thread uses the global allocator,
+    /// and deregistered when the thread exits. This is the hook for operations that need to reach
+    /// all of the allocator's handles, such as aggregating statistics or trimming caches.
+    ///
+    /// # Safety
+    ///
+    /// Handles are owned by their threads, which may be using them concurrently: `f` must only
+    /// perform operations that are safe to run from another thread. The registry is locked while
+    /// `f` runs, so threads that start or stop using the allocator in the meantime will block; `f`
+    /// must not do either itself.
+    unsafe fn for_each_handle<F: FnMut(&GlobalAllocator)>(mut f: F) {
+        let handles = HANDLES.lock().unwrap();
+        for &(_, handle) in handles.iter() {
+            f(&*(handle as *const GlobalAllocator));
+        }
+    }
+
+    /// Log the allocation histogram of all threads (see `dump_histogram`) when the process exits.
+    ///
+    /// Threads that have exited have already added their counts to the global allocator's
+    /// `Totals`; the counts of live threads are added here.
+    #[cfg(feature = "dump_stats_on_drop")]
+    extern "C" fn dump_stats_at_exit() {
+        init_begin();
+        unsafe {
+            for_each_handle(|h| h.inner.record_stats());
         }
+        super::dump_histogram(&ELF_HEAP.inner.totals);
+        init_end();
     }
 
     /// The type for messages sent to the background thread. These can either be arrays of size
@@ -237,67 +452,117 @@ pub mod global {
         Obj(T),
         #[allow(dead_code)]
         Ptr(*mut u8),
-        #[allow(dead_code)]
-        Slag(*mut u8),
+        Slag(FreshPage),
+        /// Release the background thread's cached memory (see `trim`), and then set the flag to
+        /// signal that this is done.
+        Trim(*const AtomicBool),
     }
 
     unsafe impl<T> Send for Husk<T> {}
 
+    /// A thread's own clone of the background thread's `Sender` (see `LOCAL_DESTRUCTOR_CHAN`).
+    type LocalChan = ManuallyDrop<UnsafeCell<Option<Sender<Husk<ObjectAlloc<PA>>>>>>;
+
+    /// Send `msg` to the background thread.
+    ///
+    /// This uses the calling thread's own `Sender`, so that threads sending concurrently do not
+    /// contend with one another: only a thread's first message takes the `DESTRUCTOR_CHAN` lock,
+    /// to clone the global `Sender`. With the `nightly` feature, if the thread's `Sender` cannot
+    /// be accessed (on targets where all thread-locals are torn down), the global `Sender` is
+    /// cloned with the lock held, and `msg` is sent through the clone once the lock is released.
+    fn send(msg: Husk<ObjectAlloc<PA>>) {
+        #[cfg(feature = "nightly")]
+        {
+            let mut msg = Some(msg);
+            let _ = LOCAL_DESTRUCTOR_CHAN
+                .try_with(|chan| unsafe { send_local(chan, msg.take().unwrap()) });
+            if let Some(msg) = msg {
+                let _ = clone_chan().send(msg);
+            }
+        }
+        #[cfg(not(feature = "nightly"))]
+        LOCAL_DESTRUCTOR_CHAN.with(|chan| unsafe { send_local(chan, msg) });
+    }
+
+    /// Send `msg` through `chan`, a thread's own `Sender`, cloning it first if necessary.
+    ///
+    /// `chan` must belong to the calling thread.
+    unsafe fn send_local(chan: &LocalChan, msg: Husk<ObjectAlloc<PA>>) {
+        let chan = &mut *chan.get();
+        if chan.is_none() {
+            *chan = Some(clone_chan());
+        }
+        let _ = chan.as_ref().unwrap().send(msg);
+    }
+
+    /// Clone the global `Sender` from `DESTRUCTOR_CHAN`.
+    fn clone_chan() -> Sender<Husk<ObjectAlloc<PA>>> {
+        // Cloning may allocate, and the calling thread's allocator may be in use or torn down, so
+        // the allocation must not recurse into it.
+        let initializing = is_initializing();
+        init_begin();
+        let chan = DESTRUCTOR_CHAN.with(|chan| chan.clone());
+        if !initializing {
+            init_end();
+        }
+        chan
+    }
+
+    /// Drop each element of `tarray` and free the array.
+    unsafe fn destroy_array<T>(tarray: TypedArray<T>) {
+        for p in tarray.iter() {
+            ptr::drop_in_place(p);
+        }
+        tarray.destroy();
+    }
+
     impl Drop for GlobalAllocator {
         fn drop(&mut self) {
-            #[cfg(not(feature = "nightly"))]
-            {
-                let chan = DESTRUCTOR_CHAN.lock().unwrap().clone();
+            self.deregister();
+            #[cfg(feature = "quarantine")]
+            unsafe {
+                self.inner.release_quarantine();
+            }
+            if is_single_threaded() {
+                #[cfg(all(feature = "nightly", target_thread_local))]
                 unsafe {
-                    let _ =
-                        chan.send(Husk::Array(ptr::read(&self.inner.allocs.small_objs.classes)));
-                    let _ =
-                        chan.send(Husk::Array(ptr::read(&self.inner.allocs.medium_objs.classes)));
-                    let sc = Husk::Obj(self.inner.allocs.word_objs.take().unwrap());
-                    let _ = chan.send(sc);
+                    PTR = ptr::null_mut();
                 }
-            }
-            #[cfg(feature = "nightly")]
-            {
-                #[cfg(target_thread_local)]
-                #[thread_local]
-                {
-                    unsafe {
-                        PTR = ptr::null_mut();
-                    }
+                unsafe {
+                    destroy_array(ptr::read(&self.inner.allocs.small_objs.classes));
+                    destroy_array(ptr::read(&self.inner.allocs.medium_objs.classes));
                 }
-                LOCAL_DESTRUCTOR_CHAN.try_with(|chan| unsafe {
-                        let _ = chan.send(Husk::Array(ptr::read(&self.inner
-                            .allocs
-                            .small_objs
-                            .classes)));
-                        let _ = chan.send(Husk::Array(ptr::read(&self.inner
-                            .allocs
-                            .medium_objs
-                            .classes)));
-                        let sc = Husk::Obj(self.inner.allocs.word_objs.take().unwrap());
-                        let _ = chan.send(sc);
-                    })
-                    .unwrap_or_else(|_| unsafe {
-                        let chan = DESTRUCTOR_CHAN.lock().unwrap().clone();
-                        let _ = chan.send(Husk::Array(ptr::read(&self.inner
-                            .allocs
-                            .small_objs
-                            .classes)));
-                        let _ = chan.send(Husk::Array(ptr::read(&self.inner
-                            .allocs
-                            .medium_objs
-                            .classes)));
-                        let sc = Husk::Obj(self.inner.allocs.word_objs.take().unwrap());
-                        let _ = chan.send(sc);
-                    })
+                mem::drop(self.inner.allocs.word_objs.take());
+                return;
+            }
+            #[cfg(all(feature = "nightly", target_thread_local))]
+            unsafe {
+                PTR = ptr::null_mut();
+            }
+            unsafe {
+                let small_objs = ptr::read(&self.inner.allocs.small_objs.classes);
+                let medium_objs = ptr::read(&self.inner.allocs.medium_objs.classes);
+                send(Husk::Array(small_objs));
+                send(Husk::Array(medium_objs));
             }
+            let word_objs = self.inner.allocs.word_objs.take().unwrap();
+            send(Husk::Obj(word_objs));
         }
     }
 
+    /// The number of handles that have been registered, used to assign their ids.
+    static NEXT_HANDLE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
     lazy_static! {
         static ref ELF_HEAP: GlobalAllocator = GlobalAllocator::new();
-        static ref DESTRUCTOR_CHAN: Mutex<Sender<Husk<ObjectAlloc<PA>>>> = {
+        /// The registry of live thread-local handles, as pairs of an id and the handle's address
+        /// (see `for_each_handle`).
+        static ref HANDLES: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+        /// The sending side of the background thread's channel.
+        ///
+        /// Threads normally send with their own clones of it (see `send`), so the lock is only
+        /// taken on cold paths, and never held while sending.
+        static ref DESTRUCTOR_CHAN: SpinLock<Sender<Husk<ObjectAlloc<PA>>>> = {
             // Background thread code: block on a channel waiting for memory reclamation messages
             // (Husks).
             let (sender, receiver) = channel();
@@ -307,38 +572,66 @@ pub mod global {
                     if let Ok(msg) = receiver.recv() {
                         let msg: Husk<_> = msg;
                         match msg {
-                            Husk::Array(tarray) => {
-                                for p in tarray.iter() {
-                                    ptr::drop_in_place(p);
-                                }
-                                tarray.destroy();
-                            },
+                            Husk::Array(tarray) => destroy_array(tarray),
                             Husk::Ptr(p) => local_alloc.inner.free(p),
                             Husk::Slag(s) => dirty_slag(s),
                             Husk::Obj(t) => mem::drop(t),
+                            Husk::Trim(done) => {
+                                local_alloc.inner.flush_caches();
+                                local_alloc.inner.pages.trim();
+                                (*done).store(true, Ordering::Release);
+                            }
                         }
                         continue
                     }
                     return;
                 }
             });
-            Mutex::new(sender)
+            SpinLock::new(sender)
         };
     }
 
-    #[allow(dead_code)]
-    lazy_static!{
-        // only used on stable nightly or targets where thread-local is not supported
-        static ref INITIALIZING: AtomicUsize = AtomicUsize::new(0);
-    }
-
     thread_local! {
-        static LOCAL_DESTRUCTOR_CHAN: Sender<Husk<ObjectAlloc<PA>>> =
-            DESTRUCTOR_CHAN.lock().unwrap().clone();
+        /// The recursion guard used when the `#[thread_local]` attribute is unavailable (without
+        /// the `nightly` feature, or on targets without native TLS support).
+        ///
+        /// Unlike the other thread-locals here, accessing it must never allocate, since it is
+        /// checked on every allocation. `Cell<bool>` needs no destructor, so on targets with
+        /// native TLS, no destructor is registered (which would call `calloc`) when it is first
+        /// accessed. On targets where `thread_local!` is emulated with pthread keys, the first
+        /// access still allocates.
+        #[allow(dead_code)]
+        static INITIALIZING: Cell<bool> = Cell::new(false);
+
+        /// This thread's own clone of the background thread's `Sender`, made the first time the
+        /// thread sends a message (see `send`).
+        ///
+        /// Like `INITIALIZING`, it has no destructor, so it can be accessed even without the
+        /// `nightly` feature's `try_with` while the thread's other thread-locals (including
+        /// `LOCAL_ELF_HEAP`, which sends its caches to the background thread) are being torn
+        /// down. The clone is never dropped; this only keeps the channel's sender count up, which
+        /// the global `Sender` does anyway.
+        static LOCAL_DESTRUCTOR_CHAN: LocalChan = ManuallyDrop::new(UnsafeCell::new(None));
         static LOCAL_ELF_HEAP: UnsafeCell<GlobalAllocator> = UnsafeCell::new(ELF_HEAP.clone());
+
+        /// The number of bytes allocated and freed by this thread (see `thread_stats`).
+        #[cfg(feature = "stats")]
+        static THREAD_STATS: Cell<(u64, u64)> = Cell::new((0, 0));
     }
 
     pub unsafe fn alloc(size: usize) -> *mut u8 {
+        #[cfg(feature = "stats")]
+        {
+            if !is_initializing() {
+                let res = alloc_uncounted(size);
+                count(res, false);
+                return res;
+            }
+        }
+        alloc_uncounted(size)
+    }
+
+    unsafe fn alloc_uncounted(size: usize) -> *mut u8 {
         #[cfg(feature = "nightly")]
         #[cfg(target_thread_local)]
         #[thread_local]
@@ -348,7 +641,7 @@ pub mod global {
             }
         }
         if is_initializing() {
-            return super::large_alloc::alloc(size);
+            return bootstrap_alloc(size);
         }
         init_begin();
         let res = alloc_inner(size);
@@ -356,12 +649,38 @@ pub mod global {
         res
     }
 
+    /// Allocate memory for a recursive call to `alloc`.
+    unsafe fn bootstrap_alloc(size: usize) -> *mut u8 {
+        let res = bootstrap::alloc(size);
+        if res.is_null() {
+            super::large_alloc::alloc(size)
+        } else {
+            res
+        }
+    }
+
+    /// Allocate `size` bytes, returning `Err(Exhausted)` on failure.
+    ///
+    /// `alloc` is equivalent, but returns null on failure.
+    pub unsafe fn try_alloc(size: usize) -> Result<NonNull<u8>, Exhausted> {
+        NonNull::new(alloc(size)).ok_or(Exhausted)
+    }
+
+    /// Allocate `size` bytes, returning `None` on failure.
+    ///
+    /// Unlike `alloc`, this is safe to call: it only hands out memory, and the non-null result
+    /// must still be passed to `free` (which is unsafe) to release it.
+    pub fn alloc_nonnull(size: usize) -> Option<NonNull<u8>> {
+        unsafe { NonNull::new(alloc(size)) }
+    }
+
     unsafe fn alloc_inner(size: usize) -> *mut u8 {
         #[cfg(feature = "nightly")]
         {
             LOCAL_ELF_HEAP.try_with(|h| {
-                    let res = (*h.get()).inner.alloc(size);
-                    PTR = &mut (*h.get()).inner as *mut _;
+                    let heap = local(h);
+                    let res = heap.alloc(size);
+                    PTR = heap as *mut _;
                     res
                 })
                 .unwrap_or_else(|_| super::large_alloc::alloc(size))
@@ -369,23 +688,170 @@ pub mod global {
 
         #[cfg(not(feature = "nightly"))]
         {
-            LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.alloc(size))
+            LOCAL_ELF_HEAP.with(|h| local(h).alloc(size))
         }
     }
 
     unsafe fn realloc_inner(item: *mut u8, size: usize) -> *mut u8 {
-        LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.realloc(item, size))
+        LOCAL_ELF_HEAP.with(|h| local(h).inner.realloc(item, size))
     }
 
     pub unsafe fn realloc(item: *mut u8, new_size: usize) -> *mut u8 {
         assert!(!is_initializing(), "realloc can't be called recursively");
+        // The old size has to be read before `item` is freed, but it is only counted once the
+        // realloc has succeeded: on failure, `item` is still live.
+        #[cfg(feature = "stats")]
+        let old_size = counted_size(item);
         init_begin();
         let res = realloc_inner(item, new_size);
         init_end();
+        #[cfg(feature = "stats")]
+        {
+            if !res.is_null() || new_size == 0 {
+                count_bytes(old_size, true);
+            }
+            count(res, false);
+        }
+        res
+    }
+
+    // Return the usable size of `item`, or zero for null pointers and bootstrap allocations,
+    // which are not counted.
+    #[cfg(feature = "stats")]
+    unsafe fn counted_size(item: *mut u8) -> u64 {
+        if item.is_null() || bootstrap::contains(item) {
+            return 0;
+        }
+        ELF_HEAP.inner.usable_size(item) as u64
+    }
+
+    // Add the usable size of `item` to the calling thread's count of bytes freed (if `freed`) or
+    // allocated. Null pointers and bootstrap allocations are not counted.
+    #[cfg(feature = "stats")]
+    unsafe fn count(item: *mut u8, freed: bool) {
+        count_bytes(counted_size(item), freed)
+    }
+
+    // Add `size` to the calling thread's count of bytes freed (if `freed`) or allocated.
+    #[cfg(feature = "stats")]
+    unsafe fn count_bytes(size: u64, freed: bool) {
+        if size == 0 {
+            return;
+        }
+        // Accessing THREAD_STATS for the first time may allocate on some targets.
+        init_begin();
+        let update = |stats: &Cell<(u64, u64)>| {
+            let (allocated, deallocated) = stats.get();
+            stats.set(if freed {
+                (allocated, deallocated + size)
+            } else {
+                (allocated + size, deallocated)
+            });
+        };
+        #[cfg(feature = "nightly")]
+        let _ = THREAD_STATS.try_with(update);
+        #[cfg(not(feature = "nightly"))]
+        THREAD_STATS.with(update);
+        init_end();
+    }
+
+    /// Return the number of bytes allocated and freed by the calling thread.
+    ///
+    /// The result is a pair of the total bytes allocated and the total bytes freed over the
+    /// lifetime of the thread, counted by usable size (which may exceed the requested size).
+    /// Memory allocated on one thread and freed on another is counted as allocated by the first
+    /// and freed by the second. Recursive allocations made while the allocator is being
+    /// initialized are not counted.
+    #[cfg(feature = "stats")]
+    pub fn thread_stats() -> (u64, u64) {
+        init_begin();
+        #[cfg(feature = "nightly")]
+        let res = THREAD_STATS.try_with(|s| s.get()).unwrap_or((0, 0));
+        #[cfg(not(feature = "nightly"))]
+        let res = THREAD_STATS.with(|s| s.get());
+        init_end();
+        res
+    }
+
+    /// Write the allocation counts of the calling thread's size classes to `out`.
+    ///
+    /// See `DynamicAllocator::class_histogram_into`; as with that method, counts are per-thread.
+    /// This does not allocate or take any locks (other than when initializing the calling thread's
+    /// allocator), so it can be called from any context, including signal handlers, in a program
+    /// that uses this allocator as its `malloc`.
+    #[cfg(feature = "stats")]
+    pub fn class_histogram_into(out: &mut [(usize, u64)]) -> usize {
+        init_begin();
+        let res = unsafe {
+            LOCAL_ELF_HEAP.with(|h| super::histogram_into(&local(h).inner.allocs, out))
+        };
+        init_end();
+        res
+    }
+
+    /// Release cached memory back to the operating system.
+    ///
+    /// This is a best-effort hint, analogous to glibc's `malloc_trim`. Objects cached by the
+    /// calling thread and by the background thread (including those of threads that have exited)
+    /// are returned to their `Slag`s, and all empty `Slag`s cached by the allocator are
+    /// uncommitted before `trim` returns.
+    ///
+    /// Every other live thread is asked to flush its caches as well (see `for_each_handle`). It
+    /// does so the next time it allocates or frees, since a thread's caches can only be accessed
+    /// by that thread; the `Slag`s this empties are uncommitted by the next call to `trim`, or
+    /// earlier if the allocator's usual policy does so.
+    pub fn trim() {
+        init_begin();
+        unsafe {
+            let flush = |h: &UnsafeCell<GlobalAllocator>| {
+                let heap = local(h);
+                heap.inner.flush_caches();
+                heap.id
+            };
+            #[cfg(feature = "nightly")]
+            let own_id = LOCAL_ELF_HEAP.try_with(flush).unwrap_or(0);
+            #[cfg(not(feature = "nightly"))]
+            let own_id = LOCAL_ELF_HEAP.with(flush);
+            for_each_handle(|h| if h.id != own_id {
+                h.trim_requested.store(true, Ordering::Relaxed);
+            });
+        }
+        ELF_HEAP.inner.pages.clone().trim();
+        if !is_single_threaded() {
+            // Messages are handled in order, so by the time this one is, the background thread
+            // has also cleaned up after every thread that exited before the call to `trim`.
+            let done = AtomicBool::new(false);
+            send(Husk::Trim(&done));
+            while !done.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+        }
+        init_end();
+    }
+
+    /// The size of the pages that the global allocator carves into `Slag`s.
+    ///
+    /// This is read from the global allocator's backing memory, which is initialized on first
+    /// use, so it is cheap to call repeatedly. It is not the operating system's page size: each
+    /// of these pages holds objects of a single size class.
+    pub fn page_size() -> usize {
+        init_begin();
+        let res = ELF_HEAP.inner.pages.backing_memory().page_size();
+        init_end();
         res
     }
 
     pub unsafe fn free(item: *mut u8) {
+        if bootstrap::contains(item) {
+            // Bootstrap allocations are never reused.
+            return;
+        }
+        #[cfg(feature = "stats")]
+        {
+            if !is_initializing() {
+                count(item, true);
+            }
+        }
         #[cfg(feature = "nightly")]
         {
             #[cfg(target_thread_local)]
@@ -395,18 +861,262 @@ pub mod global {
                     return (*PTR).free(item);
                 }
             }
-            LOCAL_ELF_HEAP.try_with(|h| (*h.get()).inner.free(item))
+            LOCAL_ELF_HEAP.try_with(|h| local(h).free(item))
                 .unwrap_or_else(|_| if !ELF_HEAP.inner.pages.backing_memory().contains(item) {
                     super::large_alloc::free(item);
+                } else if is_single_threaded() {
+                    // This handle is torn down synchronously when it is dropped.
+                    ELF_HEAP.clone().inner.free(item);
                 } else {
-                    let chan = DESTRUCTOR_CHAN.lock().unwrap().clone();
-                    let _ = chan.send(Husk::Ptr(item));
+                    send(Husk::Ptr(item));
                 })
         }
         #[cfg(not(feature = "nightly"))]
         {
-            LOCAL_ELF_HEAP.with(|h| (*h.get()).inner.free(item))
+            LOCAL_ELF_HEAP.with(|h| local(h).free(item))
+        }
+    }
+
+    /// The largest alignment that `ElfGlobal` serves from size classes; larger alignments are
+    /// mapped directly.
+    const MAX_ALIGN: usize = 4096;
+
+    /// A handle on the global allocator that can be used with the `#[global_allocator]`
+    /// attribute.
+    ///
+    /// `ElfGlobal` is a thin wrapper around the functions in this module. The allocator's metadata
+    /// is managed with `mmap` rather than the heap (the `heap_metadata` feature, which would
+    /// allocate it from the heap, is rejected in this configuration), but setting up a thread's
+    /// allocator does use the heap (for TLS, channels, and the like). With `ElfGlobal` installed,
+    /// these allocations are recursive calls into the allocator; they are detected by the same
+    /// mechanism used for `malloc` (see the module documentation) and are served from a small
+    /// static bump region, falling back to `mmap` if it runs out. Frees of memory in the bump
+    /// region are ignored.
+    ///
+    /// This requires the `use_default_allocator` feature; otherwise, `bsalloc` is linked in as
+    /// the global allocator and the two will conflict.
+    ///
+    /// Allocations with an alignment larger than a word, and of up to a page, are rounded up to a
+    /// power of two, which this allocator aligns to its size. Allocations with larger alignments
+    /// are mapped directly, like large allocations.
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct ElfGlobal;
+
+    impl ElfGlobal {
+        /// The size to allocate in order to satisfy `l`.
+        ///
+        /// Sizes too large to be rounded up saturate, so that allocating them fails.
+        fn size_for(l: &Layout) -> usize {
+            if l.align() <= mem::size_of::<usize>() {
+                l.size()
+            } else {
+                cmp::max(l.size(), l.align())
+                    .checked_next_power_of_two()
+                    .unwrap_or(usize::max_value())
+            }
+        }
+    }
+
+    unsafe impl<'a> Alloc for &'a ElfGlobal {
+        unsafe fn alloc(&mut self, l: Layout) -> Result<*mut u8, AllocErr> {
+            let size = ElfGlobal::size_for(&l);
+            let res = if l.align() > MAX_ALIGN {
+                let res = super::large_alloc::alloc_aligned(l.size(), l.align());
+                #[cfg(feature = "stats")]
+                {
+                    if !is_initializing() {
+                        count(res, false);
+                    }
+                }
+                res
+            } else if l.align() > bootstrap::ALIGN && is_initializing() {
+                // The bootstrap region cannot provide this alignment.
+                super::large_alloc::alloc(size)
+            } else {
+                alloc(size)
+            };
+            if res.is_null() {
+                Err(AllocErr::Exhausted { request: l })
+            } else {
+                Ok(res)
+            }
+        }
+
+        unsafe fn dealloc(&mut self, item: *mut u8, _l: Layout) {
+            free(item)
+        }
+
+        unsafe fn realloc(&mut self,
+                          item: *mut u8,
+                          old_l: Layout,
+                          new_l: Layout)
+                          -> Result<*mut u8, AllocErr> {
+            // realloc preserves the alignment that the object was allocated with.
+            if old_l.align() == new_l.align() && !is_initializing() && !bootstrap::contains(item) {
+                let res = realloc(item, new_l.size());
+                return if res.is_null() {
+                    Err(AllocErr::Exhausted { request: new_l })
+                } else {
+                    Ok(res)
+                };
+            }
+            let new_memory = self.alloc(new_l.clone())?;
+            ptr::copy_nonoverlapping(item, new_memory, cmp::min(old_l.size(), new_l.size()));
+            self.dealloc(item, old_l);
+            Ok(new_memory)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn recursive_alloc() {
+            // Simulate a recursive call to alloc made while setting up this thread's TLS (as
+            // happens when pthread's TSD code calls calloc): it must be served from the bootstrap
+            // region rather than recursing into the thread's allocator.
+            thread::spawn(|| unsafe {
+                    init_begin();
+                    let item = alloc(64);
+                    assert!(bootstrap::contains(item));
+                    // The guard is per-thread: other threads allocate normally in the meantime.
+                    thread::spawn(|| {
+                            assert!(!is_initializing());
+                            let item = alloc(64);
+                            assert!(!item.is_null() && !bootstrap::contains(item));
+                            free(item);
+                        })
+                        .join()
+                        .unwrap();
+                    assert!(is_initializing());
+                    init_end();
+                    assert!(!is_initializing());
+                    free(item);
+                    let item = alloc(64);
+                    assert!(!bootstrap::contains(item));
+                    free(item);
+                })
+                .join()
+                .unwrap();
+        }
+
+        #[test]
+        fn send_without_lock() {
+            // Once a thread has its own Sender, it must be able to hand work to the background
+            // thread while another thread holds the DESTRUCTOR_CHAN lock.
+            use std::sync::mpsc::channel;
+            use std::time::Duration;
+            let (ready, started) = channel();
+            let (go, wait) = channel();
+            let (done, finished) = channel();
+            let t = thread::spawn(move || unsafe {
+                send(Husk::Ptr(alloc(64)));
+                ready.send(()).unwrap();
+                wait.recv().unwrap();
+                send(Husk::Ptr(alloc(64)));
+                done.send(()).unwrap();
+            });
+            started.recv().unwrap();
+            DESTRUCTOR_CHAN.with(|_| {
+                go.send(()).unwrap();
+                finished
+                    .recv_timeout(Duration::from_secs(60))
+                    .expect("sending blocked on the DESTRUCTOR_CHAN lock");
+            });
+            t.join().unwrap();
+        }
+
+        /// The ids of the registered handles.
+        fn handle_ids() -> Vec<usize> {
+            let mut ids = Vec::new();
+            unsafe { for_each_handle(|h| ids.push(h.id)) };
+            ids
+        }
+
+        #[test]
+        fn handle_registry() {
+            use std::sync::{Arc, Barrier};
+            const THREADS: usize = 4;
+            // Other tests may be running concurrently, so only look for these threads' handles.
+            let allocated = Arc::new(Barrier::new(THREADS + 1));
+            let checked = Arc::new(Barrier::new(THREADS + 1));
+            let (ids_send, ids_recv) = channel();
+            let threads: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    let allocated = allocated.clone();
+                    let checked = checked.clone();
+                    let ids_send = ids_send.clone();
+                    thread::spawn(move || unsafe {
+                        let item = alloc(64);
+                        free(item);
+                        let id = LOCAL_ELF_HEAP.with(|h| (*h.get()).id);
+                        ids_send.send(id).unwrap();
+                        allocated.wait();
+                        checked.wait();
+                    })
+                })
+                .collect();
+            allocated.wait();
+            let ids: Vec<usize> = ids_recv.iter().take(THREADS).collect();
+            let live = handle_ids();
+            for id in &ids {
+                assert!(*id != 0);
+                assert_eq!(live.iter().filter(|&l| l == id).count(), 1, "{} in {:?}", id, live);
+            }
+            checked.wait();
+            for t in threads {
+                t.join().unwrap();
+            }
+            // Handles are deregistered when their threads exit.
+            let live = handle_ids();
+            for id in &ids {
+                assert!(!live.contains(id), "{} in {:?}", id, live);
+            }
+        }
+    }
+}
+
+/// A bump allocator for memory requested while the global allocator is initializing.
+///
+/// Setting up a thread's allocator (and the background thread) allocates: lazy statics, channels,
+/// and thread-local storage all use the heap. When this crate is the process's `malloc` or Rust
+/// global allocator, those allocations call back into the allocator before it can service them.
+/// They are instead carved out of a static region that lives in the binary's `.bss` section, so
+/// satisfying them requires no allocator metadata at all. Memory in this region is never reused;
+/// freeing it is a no-op.
+mod bootstrap {
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    const REGION_SIZE: usize = 1 << 20;
+    /// The alignment of all bootstrap allocations.
+    pub const ALIGN: usize = 16;
+
+    static mut REGION: [u8; REGION_SIZE] = [0; REGION_SIZE];
+    static CURSOR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// Allocate `size` bytes from the region, returning null if the region is exhausted.
+    pub unsafe fn alloc(size: usize) -> *mut u8 {
+        let size = (size + ALIGN - 1) & !(ALIGN - 1);
+        let base = REGION.as_ptr() as usize;
+        let start = (base + ALIGN - 1) & !(ALIGN - 1);
+        let offset = CURSOR.fetch_add(size, Ordering::Relaxed);
+        // This could in principle overflow if called an astronomical number of times after the
+        // region is exhausted; in practice, the region is only used during initialization.
+        if start + offset + size > base + REGION_SIZE {
+            return ptr::null_mut();
         }
+        (start + offset) as *mut u8
+    }
+
+    /// Check if `item` was allocated from the region.
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    pub fn contains(item: *mut u8) -> bool {
+        let base = unsafe { REGION.as_ptr() as usize };
+        let item = item as usize;
+        item >= base && item < base + REGION_SIZE
     }
 }
 
@@ -419,14 +1129,25 @@ trait AllocMap<T>
 
     /// Create and initialize the map.
     fn init<F: FnMut(Self::Key) -> T>(start: Self::Key, n_classes: usize, f: F) -> Self {
-        Self::init_conserve(start, n_classes, f).1
+        Self::try_init(start, n_classes, f).expect("mmap should not fail")
+    }
+
+    /// Create and initialize the map, returning `Err(Exhausted)` if the memory for the map could
+    /// not be mapped.
+    fn try_init<F: FnMut(Self::Key) -> T>(start: Self::Key,
+                                          n_classes: usize,
+                                          f: F)
+                                          -> Result<Self, Exhausted> {
+        Self::try_init_conserve(start, n_classes, f).map(|(_, map)| map)
     }
 
     /// Create and initialize the map, handing back ownership of the constructor.
-    fn init_conserve<F: FnMut(Self::Key) -> T>(start: Self::Key,
-                                               n_classes: usize,
-                                               f: F)
-                                               -> (F, Self);
+    ///
+    /// On failure, nothing is leaked: any classes that were already constructed are dropped.
+    fn try_init_conserve<F: FnMut(Self::Key) -> T>(start: Self::Key,
+                                                   n_classes: usize,
+                                                   f: F)
+                                                   -> Result<(F, Self), Exhausted>;
 
     /// Get an unchecked raw pointer to the class corresponding to `k`.
     unsafe fn get_raw(&self, k: Self::Key) -> *mut T;
@@ -455,37 +1176,68 @@ trait AllocMap<T>
     /// This method is most useful when the `Key` type is a numeric type representing a "size
     /// class".
     fn max_key(&self) -> Self::Key;
+
+    /// Get the allocation counter for the class corresponding to `k`.
+    ///
+    /// Counters are updated on the allocation path, which may itself be servicing a recursive
+    /// call to `malloc` (see the `global` module). They must therefore be plain atomics stored in
+    /// memory that is mapped up front (e.g. in a `TypedArray`): updating or reading them must
+    /// never take a lock or allocate.
+    #[cfg(feature = "stats")]
+    unsafe fn counter(&self, k: Self::Key) -> &AtomicUsize;
+
+    /// Iterate over the object size and allocation counter of each class.
+    #[cfg(feature = "stats")]
+    fn foreach_counter<F: FnMut(usize, &AtomicUsize)>(&self, f: F);
 }
 
 /// Size classes from the `scalloc` and `tcmalloc` allocators.
 ///
 /// This includes two runs of size classes: the first (smaller) size classes are multiples of 16.
-/// The larger classes are powers of two.
+/// The larger classes are powers of two. In addition, requests of up to the "word size" (the
+/// `start` key passed to `init`, normally `size_of::<usize>()`) get a class of their own.
 struct TieredSizeClasses<T> {
+    word_size: usize,
     word_objs: Option<T>,
+    #[cfg(feature = "stats")]
+    word_count: AtomicUsize,
     small_objs: Multiples<T>,
     medium_objs: PowersOfTwo<T>,
 }
 
 impl<T> AllocMap<T> for TieredSizeClasses<T> {
     type Key = usize;
-    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, f: F) -> (F, Self) {
+    fn try_init_conserve<F: FnMut(usize) -> T>(start: usize,
+                                               n_classes: usize,
+                                               f: F)
+                                               -> Result<(F, Self), Exhausted> {
         let n_small_classes = n_classes / 2;
         let n_medium_classes = n_classes - n_small_classes;
-        let (f2, small_classes) = Multiples::init_conserve(start, n_small_classes, f);
+        let (f2, small_classes) = Multiples::try_init_conserve(start + 1, n_small_classes, f)?;
         let (mut f3, medium_classes) =
-            PowersOfTwo::init_conserve(small_classes.max_key() + 1, n_medium_classes, f2);
-        let word_objs = f3(8);
-        (f3,
-         TieredSizeClasses {
-             word_objs: Some(word_objs),
-             small_objs: small_classes,
-             medium_objs: medium_classes,
-         })
+            match PowersOfTwo::try_init_conserve(small_classes.max_key() + 1,
+                                                 n_medium_classes,
+                                                 f2) {
+                Ok(res) => res,
+                Err(e) => {
+                    unsafe { small_classes.destroy() };
+                    return Err(e);
+                }
+            };
+        let word_objs = f3(start);
+        Ok((f3,
+            TieredSizeClasses {
+                word_size: start,
+                word_objs: Some(word_objs),
+                #[cfg(feature = "stats")]
+                word_count: AtomicUsize::new(0),
+                small_objs: small_classes,
+                medium_objs: medium_classes,
+            }))
     }
 
     unsafe fn get_raw(&self, n: usize) -> *mut T {
-        if n <= 8 {
+        if n <= self.word_size {
             self.word_objs.as_ref().unwrap() as *const _ as *mut T
         } else if n <= self.small_objs.max_key() {
             self.small_objs.get_raw(n)
@@ -503,6 +1255,26 @@ impl<T> AllocMap<T> for TieredSizeClasses<T> {
         self.small_objs.foreach(&f);
         self.medium_objs.foreach(f);
     }
+
+    #[cfg(feature = "stats")]
+    unsafe fn counter(&self, n: usize) -> &AtomicUsize {
+        if n <= self.word_size {
+            &self.word_count
+        } else if n <= self.small_objs.max_key() {
+            self.small_objs.counter(n)
+        } else {
+            self.medium_objs.counter(n)
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    fn foreach_counter<F: FnMut(usize, &AtomicUsize)>(&self, mut f: F) {
+        if self.word_objs.is_some() {
+            f(self.word_size, &self.word_count);
+        }
+        self.small_objs.foreach_counter(&mut f);
+        self.medium_objs.foreach_counter(f);
+    }
 }
 
 // Once this can be a type parameter, it should be.
@@ -513,9 +1285,26 @@ struct Multiples<T> {
     starting_size: usize,
     max_size: usize,
     classes: TypedArray<T>,
+    #[cfg(feature = "stats")]
+    counts: TypedArray<AtomicUsize>,
 }
 
-/// Round up to the closest multiple of 16 greater than or equal to `n`.
+/// Clamp the size class index `ix` into an array of `len` classes.
+///
+/// Class indices are computed from object sizes, which on the free path are read out of a
+/// `Slag`'s metadata. Clamping keeps a corrupted size from indexing past the end of a
+/// `TypedArray` in release builds; `cmp::min` compiles to a conditional move, so this does not add
+/// a branch to the hot path. With the `debug_checks` feature, an out-of-range index panics
+/// instead.
+#[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+#[inline(always)]
+fn clamp_index(ix: usize, len: usize) -> usize {
+    #[cfg(feature = "debug_checks")]
+    assert!(ix < len, "size class index {} out of range (len={})", ix, len);
+    cmp::min(ix, len - 1)
+}
+
+/// Round up to the closest multiple of 16 greater than or equal to `n`.
 #[inline]
 fn round_up(n: usize) -> usize {
     (n + (MULTIPLE - 1)) & !(MULTIPLE - 1)
@@ -523,13 +1312,27 @@ fn round_up(n: usize) -> usize {
 
 impl<T> AllocMap<T> for Multiples<T> {
     type Key = usize;
-    fn init_conserve<F: FnMut(usize) -> T>(start: usize, n_classes: usize, mut f: F) -> (F, Self) {
+    fn try_init_conserve<F: FnMut(usize) -> T>(start: usize,
+                                               n_classes: usize,
+                                               mut f: F)
+                                               -> Result<(F, Self), Exhausted> {
         debug_assert!(n_classes >= 1);
         let starting_size = round_up(start);
+        let classes = TypedArray::try_new(n_classes).ok_or(Exhausted)?;
+        #[cfg(feature = "stats")]
+        let counts = match TypedArray::try_new(n_classes) {
+            Some(counts) => counts,
+            None => {
+                unsafe { classes.destroy() };
+                return Err(Exhausted);
+            }
+        };
         let res = Multiples {
             starting_size: starting_size,
             max_size: n_classes * MULTIPLE + starting_size - MULTIPLE,
-            classes: TypedArray::new(n_classes),
+            classes: classes,
+            #[cfg(feature = "stats")]
+            counts: counts,
         };
         let mut cur_size = res.starting_size;
         for p in res.classes.iter() {
@@ -539,16 +1342,13 @@ impl<T> AllocMap<T> for Multiples<T> {
             cur_size += MULTIPLE;
         }
         debug_assert_eq!(res.max_size, cur_size - MULTIPLE);
-        (f, res)
+        Ok((f, res))
     }
 
     #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
     #[inline(always)]
     unsafe fn get_raw(&self, n: usize) -> *mut T {
-        let class = round_up(n);
-        debug_assert!(class <= self.max_size);
-        self.classes
-            .get((round_up(n) - self.starting_size) / MULTIPLE)
+        self.classes.get(self.class_index(n))
     }
 
     #[inline]
@@ -561,6 +1361,41 @@ impl<T> AllocMap<T> for Multiples<T> {
             f(class)
         }
     }
+
+    #[cfg(feature = "stats")]
+    unsafe fn counter(&self, n: usize) -> &AtomicUsize {
+        &*self.counts.get(self.class_index(n))
+    }
+
+    #[cfg(feature = "stats")]
+    fn foreach_counter<F: FnMut(usize, &AtomicUsize)>(&self, mut f: F) {
+        for (i, count) in self.counts.iter().enumerate() {
+            f(self.starting_size + i * MULTIPLE, unsafe { &*count })
+        }
+    }
+}
+
+impl<T> Multiples<T> {
+    /// Drop every size class, and free the memory used for the map.
+    unsafe fn destroy(&self) {
+        self.foreach(|x| ptr::drop_in_place(x));
+        self.classes.destroy();
+        #[cfg(feature = "stats")]
+        self.counts.destroy();
+    }
+
+    /// The number of size classes, as implied by the smallest and largest object sizes.
+    fn n_classes(&self) -> usize {
+        (self.max_size - self.starting_size) / MULTIPLE + 1
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    fn class_index(&self, n: usize) -> usize {
+        let class = round_up(n);
+        clamp_index(class.saturating_sub(self.starting_size) / MULTIPLE,
+                    self.classes.len())
+    }
 }
 
 /// Size classes that are just the powers of two.
@@ -571,36 +1406,74 @@ struct PowersOfTwo<T> {
     starting_size: usize,
     max_size: usize,
     classes: TypedArray<T>,
+    #[cfg(feature = "stats")]
+    counts: TypedArray<AtomicUsize>,
 }
 
 
 impl Drop for DynamicAllocator {
     fn drop(&mut self) {
-        self.0.allocs.foreach(|x| unsafe { ptr::drop_in_place(x) });
-        unsafe {
-            self.0.allocs.medium_objs.classes.destroy();
-            self.0.allocs.small_objs.classes.destroy();
-        }
+        // This is not checked, since it also runs while unwinding from a failed check.
+        #[cfg(feature = "quarantine")]
+        dispatch!(self.0, elf => unsafe { elf.release_quarantine() });
+        #[cfg(feature = "dump_stats_on_drop")]
+        dispatch!(ref self.0, elf => elf.record_stats());
+        dispatch!(self.0, elf => unsafe { elf.destroy_classes() })
     }
 }
 
 impl<T> PowersOfTwo<T> {
-    fn new(start_from: usize, n_classes: usize) -> PowersOfTwo<T> {
-        PowersOfTwo {
-            starting_size: start_from.next_power_of_two(),
-            max_size: 0, // currently uninitialized
-            classes: TypedArray::new(n_classes),
-        }
+    /// Create an uninitialized map, or `None` if the memory for it could not be mapped.
+    fn try_new(start_from: usize, n_classes: usize) -> Option<PowersOfTwo<T>> {
+        let classes = TypedArray::try_new(n_classes)?;
+        #[cfg(feature = "stats")]
+        let counts = match TypedArray::try_new(n_classes) {
+            Some(counts) => counts,
+            None => {
+                unsafe { classes.destroy() };
+                return None;
+            }
+        };
+        Some(PowersOfTwo {
+                 starting_size: start_from.next_power_of_two(),
+                 max_size: 0, // currently uninitialized
+                 classes: classes,
+                 #[cfg(feature = "stats")]
+                 counts: counts,
+             })
+    }
+
+    /// Drop every size class, and free the memory used for the map.
+    unsafe fn destroy(&self) {
+        self.foreach(|x| ptr::drop_in_place(x));
+        self.classes.destroy();
+        #[cfg(feature = "stats")]
+        self.counts.destroy();
+    }
+
+    /// The number of size classes, as implied by the smallest and largest object sizes.
+    fn n_classes(&self) -> usize {
+        (self.max_size.trailing_zeros() - self.starting_size.trailing_zeros()) as usize + 1
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
+    #[inline(always)]
+    fn class_index(&self, k: usize) -> usize {
+        // sizes below the smallest class are rounded up to it
+        let k = cmp::max(k, self.starting_size);
+        let log = (k.next_power_of_two().trailing_zeros() -
+                   self.starting_size.trailing_zeros()) as usize;
+        clamp_index(log, self.classes.len())
     }
 }
 
 impl<T> AllocMap<T> for PowersOfTwo<T> {
     type Key = usize;
-    fn init_conserve<F: FnMut(Self::Key) -> T>(start: usize,
-                                               n_classes: usize,
-                                               mut f: F)
-                                               -> (F, Self) {
-        let mut res = Self::new(start, n_classes);
+    fn try_init_conserve<F: FnMut(Self::Key) -> T>(start: usize,
+                                                   n_classes: usize,
+                                                   mut f: F)
+                                                   -> Result<(F, Self), Exhausted> {
+        let mut res = Self::try_new(start, n_classes).ok_or(Exhausted)?;
         let mut cur_size = res.starting_size;
         unsafe {
             for item in res.classes.iter() {
@@ -609,20 +1482,13 @@ impl<T> AllocMap<T> for PowersOfTwo<T> {
             }
         }
         res.max_size = cur_size / 2;
-        (f, res)
+        Ok((f, res))
     }
 
     #[cfg_attr(feature = "cargo-clippy", allow(inline_always))]
     #[inline(always)]
     unsafe fn get_raw(&self, k: usize) -> *mut T {
-        debug_assert!(k <= self.max_size);
-        let log = (k.next_power_of_two().trailing_zeros() -
-                   self.starting_size.trailing_zeros()) as usize;
-        debug_assert!(log < self.classes.len(),
-                      "log={} len={}",
-                      log,
-                      self.classes.len());
-        self.classes.get(log)
+        self.classes.get(self.class_index(k))
     }
 
     #[inline]
@@ -635,250 +1501,2114 @@ impl<T> AllocMap<T> for PowersOfTwo<T> {
             f(class)
         }
     }
+
+    #[cfg(feature = "stats")]
+    unsafe fn counter(&self, k: usize) -> &AtomicUsize {
+        &*self.counts.get(self.class_index(k))
+    }
+
+    #[cfg(feature = "stats")]
+    fn foreach_counter<F: FnMut(usize, &AtomicUsize)>(&self, mut f: F) {
+        for (i, count) in self.counts.iter().enumerate() {
+            f(self.starting_size << i, unsafe { &*count })
+        }
+    }
 }
 /// A Dynamic memory allocator, instantiated with sane defaults for various `ElfMalloc` type
 /// parameters.
+///
+/// The second field is only present if idle page reclamation is enabled. It keeps the background
+/// sweeping thread alive: once all clones of the allocator are dropped, the thread exits.
 #[derive(Clone)]
-pub struct DynamicAllocator(ElfMalloc<PageAlloc<Creek>,
-                                      TieredSizeClasses<ObjectAlloc<PageAlloc<Creek>>>>);
+pub struct DynamicAllocator(SizeClasses, Option<Arc<()>>);
 
 unsafe impl Send for DynamicAllocator {}
 
-impl DynamicAllocator {
-    pub fn new() -> Self {
-        DynamicAllocator(ElfMalloc::new())
-    }
-    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
-        self.0.alloc(size)
-    }
-    pub unsafe fn free(&mut self, item: *mut u8) {
-        self.0.free(item)
-    }
+/// The size-class strategy used by a `DynamicAllocator`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Size classes from the `scalloc` and `tcmalloc` allocators: multiples of 16 for smaller
+    /// sizes, and powers of two for larger ones. This is the default.
+    Tiered,
+    /// Only powers of two. This uses fewer size classes (and thus less metadata), at the cost of
+    /// more internal fragmentation.
+    PowersOfTwo,
 }
 
-// we default to using the `MagazineCache` here, as it performs better in general. There are some
-// settings in which the `LocalCache` frontend is superior. Hence, we feature-gate this.
-#[cfg(not(feature = "local_cache"))]
-type ObjectAlloc<CA> = Lazy<MagazineCache<CA>>;
-#[cfg(feature = "local_cache")]
-type ObjectAlloc<CA> = Lazy<LocalCache<CA>>;
-
-/// A Dynamic memory allocator, parmetrized on a particular `ObjectAlloc`, `CourseAllocator` and
-/// `AllocMap`.
-///
-/// `ElfMalloc` encapsulates the logic of constructing and selecting object classes, as well as
-/// delgating to the `large_alloc` module for large allocations. Most of the logic occurs in its
-/// type parameters.
-struct ElfMalloc<CA: CoarseAllocator, AM: AllocMap<ObjectAlloc<CA>>> {
-    /// A global cache of pages, shared by all size classes in `allocs`.
-    pages: CA,
-    /// An `AllocMap` of size classes of individual fixed-size object allocator.
-    allocs: AM,
-    /// The maximum size of a "non-large" object. Objects larger than `max_size` are allocated
-    /// directly with mmap.
-    max_size: usize,
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::Tiered
+    }
+}
 
-    start_from: usize,
-    n_classes: usize,
+/// The policy a `DynamicAllocator` uses to dirty pages before they are first used.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DirtyPolicy {
+    /// Pages are dirtied on first touch by the thread allocating from them. This is the default.
+    None,
+    /// Fresh pages are handed to a background thread that dirties them before they are made
+    /// available for allocation. This moves page-fault latency off of the allocating thread, which
+    /// can smooth out tail latency, but it costs a cross-thread message per new page and some
+    /// background CPU time, so it generally lowers throughput.
+    Background,
 }
 
-impl Default for DynamicAllocator {
+impl Default for DirtyPolicy {
     fn default() -> Self {
-        Self::new()
+        DirtyPolicy::None
     }
 }
 
-impl<M: MemoryBlock, D: DirtyFn> ElfMalloc<PageAlloc<M, D>,
-                                           TieredSizeClasses<ObjectAlloc<PageAlloc<M, D>>>> {
-    fn new() -> Self {
-        let pa = PageAlloc::new(1 << 21, 1 << 20);
-        Self::new_internal(128 << 10, 0.6, pa, 8, 25)
-    }
+/// The permissions of memory returned by a `DynamicAllocator` for large allocations.
+///
+/// The default is readable, writable, and executable, which is how all of the allocator's memory
+/// is mapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
 }
 
-impl<M: MemoryBlock, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>> Clone
-    for ElfMalloc<PageAlloc<M, D>, AM> {
-    fn clone(&self) -> Self {
-        let new_map = AM::init(self.start_from, self.n_classes, |size: usize| unsafe {
-            self.allocs.get(size).clone()
-        });
-        ElfMalloc {
-            pages: self.pages.clone(),
-            allocs: new_map,
-            max_size: self.max_size,
-            start_from: self.start_from,
-            n_classes: self.n_classes,
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            read: true,
+            write: true,
+            exec: true,
         }
     }
 }
 
-impl<M: MemoryBlock, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>>
-    ElfMalloc<PageAlloc<M, D>, AM> {
-    fn new_internal(usable_size: usize,
-                    cutoff_factor: f64,
-                    pa: PageAlloc<M, D>,
-                    start_from: usize,
-                    n_classes: usize)
-                    -> Self {
-        use self::mmap::map;
-        let mut meta_pointer = map(mem::size_of::<Metadata>() * n_classes) as *mut Metadata;
-        let am = AM::init(start_from, n_classes, |size: usize| {
-            let u_size = if size < usable_size / 4 {
-                usable_size
-            } else {
-                1 << 50
-            };
-            let m_ptr = meta_pointer;
-            unsafe {
-                meta_pointer = meta_pointer.offset(1);
-                ptr::write(m_ptr,
-                           compute_metadata(size,
-                                            pa.backing_memory().page_size(),
-                                            0,
-                                            cutoff_factor,
-                                            u_size));
-            }
+/// Tuning parameters for the per-size-class caches.
+#[derive(Copy, Clone, Debug)]
+struct CacheParams {
+    /// The number of pipes in each size class's `RevocablePipe` of available `Slag`s.
+    pipe_depth: usize,
+    /// The number of bytes of objects held by each thread's magazine for each size class.
+    magazine_bytes: usize,
+    /// The number of bytes of each `Slag` to use for a size class's objects, given their size.
+    slab_sizing: fn(usize) -> usize,
+}
 
-            // TODO(ezrosent); new_size(8) is a good default, but a better one would take
-            // num_cpus::get() into account when picking this size, as in principle this will run
-            // into scaling limits at some point.
-            let params = (m_ptr, 1 << 20, pa.clone(), RevocablePipe::new_size(8));
-            ObjectAlloc::new(params)
-        });
-        let max_size = am.max_key();
-        ElfMalloc {
-            pages: pa.clone(),
-            allocs: am,
-            max_size: max_size,
-            start_from: start_from,
-            n_classes: n_classes,
+impl Default for CacheParams {
+    fn default() -> Self {
+        CacheParams {
+            // The pipe is shared by every thread using a size class, so contention grows with the
+            // number of threads. 8 pipes are plenty for small machines.
+            pipe_depth: cmp::max(8, num_cpus::get()),
+            magazine_bytes: default_magazine_bytes(num_cpus::get()),
+            slab_sizing: default_slab_sizing,
         }
     }
+}
 
-    unsafe fn alloc(&mut self, bytes: usize) -> *mut u8 {
-        if likely(bytes < self.max_size) {
-            self.allocs.get_mut(bytes).alloc()
-        } else {
-            large_alloc::alloc(bytes)
-        }
+/// The default number of bytes of objects held by each magazine on a machine with `n_cpus` CPUs.
+///
+/// More CPUs mean more threads freeing objects to each other's `Slag`s, so larger magazines are
+/// used to batch those frees: `DEFAULT_MAGAZINE_BYTES` for every 8 CPUs (rounding the multiplier
+/// up to a power of two), and at most 8 times that.
+fn default_magazine_bytes(n_cpus: usize) -> usize {
+    let scale = cmp::max(1, n_cpus / 8).next_power_of_two();
+    DEFAULT_MAGAZINE_BYTES * cmp::min(scale, 8)
+}
+
+/// The default number of bytes of each `Slag` used for objects of size `object_size`.
+///
+/// Size classes of objects smaller than 32KB use the first 128KB of each `Slag`, leaving the rest
+/// untouched (and so uncommitted); larger size classes use the whole `Slag`. See
+/// `DynamicAllocatorBuilder::slab_sizing`.
+pub fn default_slab_sizing(object_size: usize) -> usize {
+    const SMALL_USABLE_SIZE: usize = 128 << 10;
+    if object_size < SMALL_USABLE_SIZE / 4 {
+        SMALL_USABLE_SIZE
+    } else {
+        // This is capped at the size of a `Slag`.
+        usize::max_value()
     }
+}
 
-    unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
-        if item.is_null() {
-            return self.alloc(new_size);
+type PA = PageAlloc<Creek>;
+type BackgroundPA = PageAlloc<Creek, global::BackgroundDirty>;
+
+/// An `ElfMalloc` for each supported `Strategy` and `DirtyPolicy`.
+#[derive(Clone)]
+enum SizeClasses {
+    Tiered(ElfMalloc<PA, TieredSizeClasses<ObjectAlloc<PA>>>),
+    PowersOfTwo(ElfMalloc<PA, PowersOfTwo<ObjectAlloc<PA>>>),
+    TieredBackground(ElfMalloc<BackgroundPA, TieredSizeClasses<ObjectAlloc<BackgroundPA>>>),
+    PowersOfTwoBackground(ElfMalloc<BackgroundPA, PowersOfTwo<ObjectAlloc<BackgroundPA>>>),
+}
+
+/// Evaluate `$body` with `$elf` bound to the `ElfMalloc` inside of a `SizeClasses`.
+macro_rules! dispatch {
+    (ref $classes:expr, $elf:ident => $body:expr) => {
+        match $classes {
+            SizeClasses::Tiered(ref $elf) => $body,
+            SizeClasses::PowersOfTwo(ref $elf) => $body,
+            SizeClasses::TieredBackground(ref $elf) => $body,
+            SizeClasses::PowersOfTwoBackground(ref $elf) => $body,
         }
-        if new_size == 0 {
-            self.free(item);
-            return ptr::null_mut();
+    };
+    ($classes:expr, $elf:ident => $body:expr) => {
+        match $classes {
+            SizeClasses::Tiered(ref mut $elf) => $body,
+            SizeClasses::PowersOfTwo(ref mut $elf) => $body,
+            SizeClasses::TieredBackground(ref mut $elf) => $body,
+            SizeClasses::PowersOfTwoBackground(ref mut $elf) => $body,
         }
-        if likely(self.pages.backing_memory().contains(item)) {
-            let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
-            let meta = slag.get_metadata();
-            // TODO(ezrosent): support shrinking
-            if meta.object_size >= new_size {
-                return item;
+    };
+}
+
+/// The number of tiered and power-of-two size classes needed to serve everything below
+/// `large_threshold` (see `SizeClasses::new`).
+fn class_counts(word_size: usize, large_threshold: Option<usize>) -> (usize, usize) {
+    // Add classes until the largest class reaches the threshold.
+    let n_classes = |default: usize, max_key: &Fn(usize) -> usize| {
+        let mut n = default;
+        if let Some(threshold) = large_threshold {
+            while max_key(n) < cmp::min(threshold, MAX_CLASS_SIZE) {
+                n += 1;
             }
-            let new_memory = self.alloc(new_size);
-            ptr::copy_nonoverlapping(item, new_memory, meta.object_size);
-            self.free(item);
-            new_memory
-        } else {
-            let (size, _) = large_alloc::get_commitment(item);
-            if size >= new_size {
-                return item;
+        }
+        n
+    };
+    // By default, 8 bytes through 1MB, the same range covered by the tiered size classes.
+    (n_classes(25, &|n| tiered_max_key(n, word_size)), n_classes(18, &|n| 8 << (n - 1)))
+}
+
+/// The size of the pages carved out of a `SizeClasses`' backing memory: `page_size` (or
+/// `DEFAULT_SLAB_PAGE_SIZE`), raised to twice the largest size class if necessary.
+fn class_page_size(strategy: Strategy,
+                   word_size: usize,
+                   large_threshold: Option<usize>,
+                   page_size: Option<usize>)
+                   -> usize {
+    let (n_tiered, n_powers) = class_counts(word_size, large_threshold);
+    cmp::max(page_size.unwrap_or(DEFAULT_SLAB_PAGE_SIZE),
+             match strategy {
+                 Strategy::Tiered => slab_page_size(tiered_max_key(n_tiered, word_size)),
+                 Strategy::PowersOfTwo => slab_page_size(8 << (n_powers - 1)),
+             })
+}
+
+/// The largest size class that a `DynamicAllocator` can be configured with.
+const MAX_CLASS_SIZE: usize = 1 << 22;
+
+/// The page size of a `PageAlloc` serving size classes up to `max_class` bytes.
+///
+/// Pages are 2MB by default, but must be large enough to hold a few of the largest objects.
+fn slab_page_size(max_class: usize) -> usize {
+    cmp::max(DEFAULT_SLAB_PAGE_SIZE, 2 * max_class)
+}
+
+/// The default size of the pages that size classes carve into `Slag`s.
+const DEFAULT_SLAB_PAGE_SIZE: usize = 1 << 21;
+
+/// Where the pages for a `DynamicAllocator`'s size classes come from, if not a fresh mapping.
+enum Backing {
+    /// A pre-reserved region (see `Creek::from_region`).
+    Region(*mut u8, usize),
+    /// A region of the given size allocated from a `MapAlloc` (see `Creek::from_map_alloc`).
+    MapAlloc(Arc<MapAlloc>, usize),
+}
+
+impl SizeClasses {
+    /// Create size classes for `strategy`.
+    ///
+    /// If `large_threshold` is given, allocations of at least `large_threshold` bytes are
+    /// served by `large_alloc`, and enough size classes are created to serve everything smaller
+    /// (up to a largest class of `MAX_CLASS_SIZE`).
+    ///
+    /// If `backing` is given, pages are carved out of that memory rather than out of a fresh
+    /// mapping. If `backing` is a `MapAlloc` from which the region cannot be allocated (for
+    /// example, because it uses huge pages and not enough are reserved), a fresh mapping of
+    /// normal pages is used instead. If `page_size` is given, the pages carved out of `backing`
+    /// are at least that large (the default is `DEFAULT_SLAB_PAGE_SIZE`); it has no effect on a
+    /// fresh mapping.
+    unsafe fn new(strategy: Strategy,
+                  word_size: usize,
+                  large_threshold: Option<usize>,
+                  dirty: DirtyPolicy,
+                  cache: CacheParams,
+                  backing: Option<Backing>,
+                  page_size: Option<usize>)
+                  -> Self {
+        let (n_tiered, n_powers) = class_counts(word_size, large_threshold);
+        let page_size = class_page_size(strategy, word_size, large_threshold, page_size);
+        let block = backing.and_then(|backing| match backing {
+            Backing::Region(base, size) => Some(Creek::from_region(page_size, base, size)),
+            Backing::MapAlloc(alloc, size) => {
+                let block = Creek::from_map_alloc(page_size, alloc, size);
+                if block.is_none() {
+                    warn!("unable to allocate a heap of {} bytes from the page source; falling \
+                           back to a default mapping",
+                          size);
+                }
+                block
             }
-            let new_memory = self.alloc(new_size);
-            ptr::copy_nonoverlapping(item, new_memory, size);
-            new_memory
+        });
+        let mut res = match (strategy, dirty) {
+            (Strategy::Tiered, DirtyPolicy::None) => {
+                SizeClasses::Tiered(ElfMalloc::with_n_classes(n_tiered, word_size, cache, block))
+            }
+            (Strategy::Tiered, DirtyPolicy::Background) => {
+                SizeClasses::TieredBackground(ElfMalloc::with_n_classes(n_tiered,
+                                                                        word_size,
+                                                                        cache,
+                                                                        block))
+            }
+            (Strategy::PowersOfTwo, DirtyPolicy::None) => {
+                SizeClasses::PowersOfTwo(ElfMalloc::powers_of_two(n_powers, cache, block))
+            }
+            (Strategy::PowersOfTwo, DirtyPolicy::Background) => {
+                SizeClasses::PowersOfTwoBackground(ElfMalloc::powers_of_two(n_powers,
+                                                                            cache,
+                                                                            block))
+            }
+        };
+        if let Some(threshold) = large_threshold {
+            dispatch!(res, elf => elf.max_size = cmp::min(elf.max_size, threshold));
         }
+        res
     }
+}
 
-    unsafe fn free(&mut self, item: *mut u8) {
-        if likely(self.pages.backing_memory().contains(item)) {
-            let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
-            self.allocs
-                .get_mut(slag.get_metadata().object_size)
-                .free(item)
-        } else {
-            large_alloc::free(item)
-        }
+impl SizeClasses {
+    fn try_clone(&self) -> Result<Self, Exhausted> {
+        Ok(match *self {
+               SizeClasses::Tiered(ref elf) => SizeClasses::Tiered(elf.try_clone()?),
+               SizeClasses::PowersOfTwo(ref elf) => SizeClasses::PowersOfTwo(elf.try_clone()?),
+               SizeClasses::TieredBackground(ref elf) => {
+                   SizeClasses::TieredBackground(elf.try_clone()?)
+               }
+               SizeClasses::PowersOfTwoBackground(ref elf) => {
+                   SizeClasses::PowersOfTwoBackground(elf.try_clone()?)
+               }
+           })
     }
 }
 
-mod large_alloc {
-    //! This module governs "large" allocations that are beyond the size of the largest size class
-    //! of a dynamic allocator.
-    //!
-    //! Large allocations are implemented by mapping a region of memory of the indicated size, with
-    //! an additional page of padding to store the size information.
-    #[cfg(test)]
-    use std::collections::HashMap;
-    #[cfg(test)]
-    use std::cell::RefCell;
+/// The largest size class in a `TieredSizeClasses` with `n_classes` classes, starting from
+/// `word_size`.
+///
+/// This mirrors the computation in `TieredSizeClasses::try_init_conserve`.
+fn tiered_max_key(n_classes: usize, word_size: usize) -> usize {
+    let n_small = n_classes / 2;
+    let small_max = round_up(word_size + 1) + (n_small - 1) * MULTIPLE;
+    (small_max + 1).next_power_of_two() << (n_classes - n_small - 1)
+}
 
-    // For debugging, we keep around a thread-local map of pointers to lengths. This helps us
-    // scrutinize if various header data is getting propagated correctly.
-    #[cfg(test)]
-    thread_local! {
-        pub static SEEN_PTRS: RefCell<HashMap<*mut u8, usize>> = RefCell::new(HashMap::new());
+impl DynamicAllocator {
+    pub fn new() -> Self {
+        Self::with_size_classes(Strategy::default())
     }
-    use super::mmap::{map, unmap};
-    // TODO(ezrosent): sysconf
-    const PAGE_SIZE: isize = 4096;
 
-    pub unsafe fn alloc(size: usize) -> *mut u8 {
-        let mem = map(size + PAGE_SIZE as usize);
-        *(mem as *mut usize) = size + PAGE_SIZE as usize;
-        let res = mem.offset(PAGE_SIZE);
+    /// Create a new `DynamicAllocator` using the size classes given by `strategy`.
+    pub fn with_size_classes(strategy: Strategy) -> Self {
+        let classes = unsafe {
+            SizeClasses::new(strategy,
+                             mem::size_of::<usize>(),
+                             None,
+                             DirtyPolicy::default(),
+                             CacheParams::default(),
+                             None,
+                             None)
+        };
+        DynamicAllocator(classes, None)
+    }
 
-        // begin extra debugging information
-        debug_assert!(!mem.is_null());
-        let upage = PAGE_SIZE as usize;
-        debug_assert_eq!(mem as usize % upage, 0);
-        debug_assert_eq!(res as usize % upage, 0);
-        #[cfg(test)]
-        SEEN_PTRS.with(|hs| hs.borrow_mut().insert(mem, size + PAGE_SIZE as usize));
-        // end extra debugging information
+    /// Create a new `DynamicAllocator` whose size classes get their memory from the `size` bytes
+    /// starting at `base`.
+    ///
+    /// By default, size classes carve their pages out of a single large mapping of up to several
+    /// terabytes of address space. This instead confines them to a fixed, pre-reserved region,
+    /// such as a static buffer or a specific mapping. Once the region is exhausted, allocations
+    /// that need a fresh page fail, returning null from `alloc` and `Exhausted` from `try_alloc`.
+    ///
+    /// Pages are 2MB and aligned to their size, and one page of the region is used for
+    /// bookkeeping, so only part of the region may be usable: see `Creek::from_region` for
+    /// details. Each size class needs a page the first time it is used; if none is left, that
+    /// allocation fails as well. Large allocations, as well as the allocator's metadata, are still
+    /// mapped separately.
+    ///
+    /// # Safety
+    ///
+    /// The region must be readable, writable, and filled with zeros, and it must outlive the
+    /// allocator and all of its clones. No other code may use the region in the meantime.
+    pub unsafe fn with_backing(base: *mut u8, size: usize) -> Self {
+        let classes = SizeClasses::new(Strategy::default(),
+                                       mem::size_of::<usize>(),
+                                       None,
+                                       DirtyPolicy::default(),
+                                       CacheParams::default(),
+                                       Some(Backing::Region(base, size)),
+                                       None);
+        DynamicAllocator(classes, None)
+    }
+    /// Create a new handle on the allocator, like `clone`, but without panicking on failure.
+    ///
+    /// Each handle has its own table of size classes, which is mapped when the handle is created;
+    /// `clone` panics if that fails. `try_clone` returns `Err(Exhausted)` instead, which lets a
+    /// program that is under memory pressure decline to start a new thread rather than crash.
+    /// Size classes are initialized lazily, so this does not use any of the allocator's pages.
+    pub fn try_clone(&self) -> Result<Self, Exhausted> {
+        Ok(DynamicAllocator(self.0.try_clone()?, self.1.clone()))
+    }
 
-        res
+    /// Allocate `size` bytes, returning null on failure.
+    ///
+    /// This is a thin wrapper around `try_alloc` for callers that expect `malloc`-like semantics.
+    pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
+        self.try_alloc(size).map(NonNull::as_ptr).unwrap_or(ptr::null_mut())
     }
 
-    pub unsafe fn free(item: *mut u8) {
-        let base_ptr = item.offset(-PAGE_SIZE);
+    /// Allocate `size` bytes.
+    ///
+    /// Returns `Err(Exhausted)` if the underlying memory could not be obtained.
+    pub unsafe fn try_alloc(&mut self, size: usize) -> Result<NonNull<u8>, Exhausted> {
+        NonNull::new(dispatch!(self.0, elf => elf.alloc(size))).ok_or(Exhausted)
+    }
 
-        // begin extra debugging information:
-        #[cfg(debug_assertions)]
-        {
-            use std::ptr;
-            ptr::write_volatile(item, 10);
-        }
-        let upage = PAGE_SIZE as usize;
-        debug_assert_eq!(item as usize % upage, 0);
-        debug_assert_eq!(base_ptr as usize % upage, 0);
-        #[cfg(test)]
-        {
-            SEEN_PTRS.with(|hm| {
-                let mut hmap = hm.borrow_mut();
-                {
-                    if let Some(len) = hmap.get(&base_ptr) {
-                        let size = *(base_ptr as *mut usize);
-                        assert_eq!(*len, size);
-                    }
-                }
-                hmap.remove(&base_ptr);
-            });
-        }
-        // end extra debugging information
+    /// Allocate `size` bytes, returning `None` on failure.
+    ///
+    /// Unlike `alloc`, this is safe to call: it only hands out memory, and the non-null result
+    /// must still be passed to `free` (which is unsafe) to release it. Returning an `Option`
+    /// lets callers propagate failure with `?`.
+    pub fn alloc_nonnull(&mut self, size: usize) -> Option<NonNull<u8>> {
+        unsafe { self.try_alloc(size).ok() }
+    }
 
-        let size = *(base_ptr as *mut usize);
-        unmap(base_ptr, size);
+    /// Allocate `size` bytes aligned to `align`, returning null on failure.
+    ///
+    /// Alignments of up to a page are satisfied by size classes, by rounding `size` up to a power
+    /// of two. Larger alignments (such as a 2MB-aligned buffer meant for huge pages) are served by
+    /// mapping the object directly, with enough slack to align it. Either way, the object can be
+    /// freed with `free` and keeps its alignment across `realloc`.
+    ///
+    /// # Panics
+    ///
+    /// `alloc_aligned` panics if `align` is not a power of two.
+    pub unsafe fn alloc_aligned(&mut self, size: usize, align: usize) -> *mut u8 {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        dispatch!(self.0, elf => elf.alloc_aligned(size, align))
+    }
+    pub unsafe fn free(&mut self, item: *mut u8) {
+        dispatch!(self.0, elf => elf.free(item))
     }
 
-    pub unsafe fn get_commitment(item: *mut u8) -> (usize, *mut u8) {
-        let base_ptr = item.offset(-PAGE_SIZE) as *mut usize;
-        (*base_ptr, base_ptr as *mut u8)
+    /// Free each of `items`, like calling `free` on each of them in turn.
+    ///
+    /// Consecutive objects in the same size class are freed together: they are pushed onto the
+    /// calling handle's cache in one go, and any that do not fit are returned to their `Slag`s
+    /// with one atomic operation per word of a `Slag`'s bit-set, rather than one per object. This
+    /// makes freeing many objects at once (such as the elements of a collection being dropped)
+    /// considerably cheaper, particularly if they were allocated by other threads; it is most
+    /// effective when `items` are grouped by size. Large allocations are freed individually.
+    pub unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        dispatch!(self.0, elf => elf.free_batch(items))
     }
-}
+
+    /// Resize `item` to `new_size` bytes, returning null on failure.
+    ///
+    /// This follows `realloc(3)`: the contents of `item` are preserved up to the smaller of the
+    /// old and new sizes, and the object may be moved, in which case `item` is freed. If `item` is
+    /// null, this is equivalent to `alloc(new_size)`; if `new_size` is zero, `item` is freed and
+    /// null is returned. On failure, `item` is left untouched.
+    pub unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
+        dispatch!(self.0, elf => elf.realloc(item, new_size))
+    }
+
+    /// Shrink `item`, which was allocated with `layout`, to `new_size` bytes without moving it.
+    ///
+    /// This is analogous to `Alloc::shrink_in_place`. An object in a size class always stays in
+    /// its block, since the smaller size still fits. For large allocations, the pages past the
+    /// end of the new size are unmapped. `Err` is returned if the object cannot stay where it is
+    /// (if `new_size` is zero or larger than `layout.size()`), in which case it is left untouched
+    /// and the caller should fall back to `realloc`.
+    pub unsafe fn shrink_in_place(&mut self,
+                                  item: *mut u8,
+                                  layout: Layout,
+                                  new_size: usize)
+                                  -> Result<(), CannotReallocInPlace> {
+        if new_size == 0 || new_size > layout.size() {
+            return Err(CannotReallocInPlace);
+        }
+        dispatch!(self.0, elf => elf.shrink_in_place(item, new_size));
+        Ok(())
+    }
+
+    /// Allocate `size` bytes, also returning the number of bytes that are actually usable.
+    ///
+    /// Requests are rounded up to the object size of a size class (or, for large allocations, to
+    /// the end of a page). All of the returned number of bytes, which is at least `size`, may be
+    /// used. This is analogous to `Alloc::alloc_excess`. If the allocation fails, the pointer is
+    /// null and the size is zero.
+    pub unsafe fn alloc_excess(&mut self, size: usize) -> (*mut u8, usize) {
+        dispatch!(self.0, elf => elf.alloc_excess(size))
+    }
+
+    /// Return the number of usable bytes in the object `item`, which must have been allocated by
+    /// this allocator (or one of its clones) and not yet freed.
+    pub unsafe fn usable_size(&self, item: *mut u8) -> usize {
+        dispatch!(ref self.0, elf => elf.usable_size(item))
+    }
+
+    /// Free `item`, first checking that it could have been returned by `alloc`.
+    ///
+    /// Passing `free` a pointer into the middle of an object (for example, into a buffer that
+    /// has since been `realloc`-ed) silently corrupts the allocator's state. `free_checked`
+    /// instead returns an error, leaving the allocator untouched, if `item` does not point to the
+    /// start of an object in a size class, or if it does not look like the start of a large
+    /// allocation: large allocations are page-aligned, and preceded by a header describing the
+    /// page-aligned mapping that contains them.
+    ///
+    /// These checks are best-effort: the header of a large allocation is read from the memory
+    /// preceding `item`, so a page-aligned pointer into a large allocation whose contents happen
+    /// to look like a valid header will not be detected. It also cannot detect double frees.
+    #[cfg(feature = "checked_free")]
+    pub unsafe fn free_checked(&mut self, item: *mut u8) -> Result<(), InvalidPointer> {
+        dispatch!(self.0, elf => elf.free_checked(item))
+    }
+
+    /// Uncommit pages that have been unused since the previous call to `sweep_idle`.
+    ///
+    /// This is called periodically in the background if the allocator was built with
+    /// `DynamicAllocatorBuilder::decommit_idle`, but it may also be called directly. See
+    /// `PageAlloc::sweep_idle` for details.
+    pub fn sweep_idle(&mut self) {
+        dispatch!(self.0, elf => elf.pages.sweep_idle())
+    }
+
+    /// Release all cached slab memory back to the operating system.
+    ///
+    /// Objects cached by this handle are freed back to their `Slag`s, and all empty `Slag`s are
+    /// uncommitted. Unlike dropping the allocator, this leaves it usable. It is intended to be
+    /// called after a phase of work whose objects have all been freed. Objects cached by other
+    /// clones of this allocator are not released.
+    pub fn purge(&mut self) {
+        dispatch!(self.0, elf => {
+            unsafe { elf.flush_caches() };
+            elf.pages.trim()
+        })
+    }
+
+    /// Move objects out of sparsely-used slabs, returning the slabs that this empties to the page
+    /// allocator.
+    ///
+    /// Over the life of a program, a size class can end up with many slabs that each hold only a
+    /// few live objects, none of which can be returned to the page allocator. For each size
+    /// class, `compact` moves the objects of the emptiest such slabs into the fullest ones,
+    /// copying each object to its new address and freeing the old one. Objects cached by this
+    /// handle are freed to their slabs first, and with the `quarantine` feature, quarantined
+    /// objects are released. The returned `Compaction` lists every object that was moved, and
+    /// how many slabs were freed.
+    ///
+    /// # Safety
+    ///
+    /// Moved objects are not valid at their old addresses, so the caller must not hold any
+    /// references into this allocator's objects across the call, and must replace every pointer
+    /// to a moved object with its new address before using it again. Objects are copied
+    /// bytewise, so they must not contain pointers to themselves.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are other handles on the allocator (clones of this `DynamicAllocator`).
+    /// Their cached free objects would look live, and be moved.
+    pub unsafe fn compact(&mut self) -> Compaction {
+        dispatch!(self.0, elf => {
+            assert!(Arc::strong_count(&elf.meta) == 1,
+                    "compact requires that there be no other handles on the allocator");
+            #[cfg(feature = "quarantine")]
+            elf.release_quarantine();
+            let mut res = Compaction::default();
+            elf.compact_classes(&mut res);
+            #[cfg(feature = "leak_check")]
+            elf.relocate(&res.moved);
+            res
+        })
+    }
+
+    /// Return whether `ptr` points into memory managed by this allocator's size classes.
+    ///
+    /// This is true of any pointer into the backing memory region shared by this allocator and
+    /// its clones, whether or not the object it points to is currently allocated. Large
+    /// allocations are mapped individually, outside of that region, so `owns` returns false for
+    /// them; it should be read as "slab-managed" rather than "allocated by this allocator".
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        dispatch!(ref self.0, elf => elf.pages.backing_memory().contains(ptr as *mut u8))
+    }
+
+    /// Return the number of allocations made from each size class.
+    ///
+    /// Each entry is a pair of a size class's object size and the number of objects allocated
+    /// from it, in increasing order of object size. Counters are kept per handle: a clone of a
+    /// `DynamicAllocator` starts counting from zero, and allocations made through other handles
+    /// are not included. Large allocations, which do not belong to a size class, are not counted.
+    #[cfg(feature = "stats")]
+    pub fn class_histogram(&self) -> Vec<(usize, u64)> {
+        let mut hist = Vec::new();
+        {
+            let mut push = |size: usize, count: &AtomicUsize| {
+                hist.push((size, count.load(Ordering::Relaxed) as u64))
+            };
+            dispatch!(ref self.0, elf => elf.allocs.foreach_counter(&mut push));
+        }
+        hist
+    }
+
+    /// Like `class_histogram`, but without allocating.
+    ///
+    /// The entries for the first `out.len()` size classes are written to `out`, and the total
+    /// number of size classes is returned.
+    #[cfg(feature = "stats")]
+    pub fn class_histogram_into(&self, out: &mut [(usize, u64)]) -> usize {
+        dispatch!(ref self.0, elf => histogram_into(&elf.allocs, out))
+    }
+
+    /// Return the allocations made by this allocator or any of its clones that are still live.
+    ///
+    /// Each entry is a pair of an object's address and the size it was requested with, in
+    /// increasing order of address; each is also logged at the `warn` level. Calling this at a
+    /// point where everything should have been freed (such as the end of a test) reports leaked
+    /// objects. Live allocations are recorded in a table on the process's global heap, so this is
+    /// only meaningful when this crate is not itself the global allocator.
+    #[cfg(feature = "leak_check")]
+    pub fn report_leaks(&self) -> Vec<(*mut u8, usize)> {
+        let mut leaks: Vec<_> = dispatch!(ref self.0, elf => {
+            elf.live.lock().unwrap().iter().map(|(&ptr, &size)| (ptr as *mut u8, size)).collect()
+        });
+        leaks.sort();
+        for &(ptr, size) in &leaks {
+            warn!("leaked {} bytes at {:?}", size, ptr);
+        }
+        leaks
+    }
+}
+
+/// The error returned by `DynamicAllocator::free_checked` for a pointer that could not have been
+/// returned by `alloc`.
+#[cfg(feature = "checked_free")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InvalidPointer(pub *mut u8);
+
+/// The result of `DynamicAllocator::compact`.
+#[derive(Debug, Default)]
+pub struct Compaction {
+    /// Each object that was moved, as a pair of its old and new addresses.
+    pub moved: Vec<(*mut u8, *mut u8)>,
+    /// The number of slabs that were emptied and returned to the page allocator.
+    pub freed_slabs: usize,
+}
+
+/// Write the size and allocation count of each class in `allocs` to `out`, returning the number
+/// of classes. This never allocates, so it is safe to call from within the global allocator.
+#[cfg(feature = "stats")]
+fn histogram_into<T, AM: AllocMap<T>>(allocs: &AM, out: &mut [(usize, u64)]) -> usize {
+    let mut n = 0;
+    allocs.foreach_counter(|size, count| {
+        if let Some(entry) = out.get_mut(n) {
+            *entry = (size, count.load(Ordering::Relaxed) as u64);
+        }
+        n += 1;
+    });
+    n
+}
+
+/// The allocation counts of an allocator and all of its clones.
+///
+/// Each handle adds its own counts to these when it is dropped (see `ElfMalloc::record_stats`),
+/// and the histogram is logged (see `dump_histogram`) when the last of them is dropped.
+#[cfg(feature = "dump_stats_on_drop")]
+struct Totals {
+    /// The object size and allocation count of each size class.
+    classes: OwnedArray<(usize, AtomicUsize)>,
+    /// The number of large allocations.
+    large: AtomicUsize,
+}
+
+// The counts are atomic, and the sizes are only written when the `Totals` are created.
+#[cfg(feature = "dump_stats_on_drop")]
+unsafe impl Send for Totals {}
+#[cfg(feature = "dump_stats_on_drop")]
+unsafe impl Sync for Totals {}
+
+#[cfg(feature = "dump_stats_on_drop")]
+impl Totals {
+    /// Create zeroed counts for the size classes of `allocs`.
+    fn new<T, AM: AllocMap<T>>(allocs: &AM) -> Totals {
+        let mut n = 0;
+        allocs.foreach_counter(|_, _| n += 1);
+        let classes = OwnedArray::new(n);
+        let mut i = 0;
+        allocs.foreach_counter(|size, _| {
+            unsafe { ptr::write(classes.get(i), (size, AtomicUsize::new(0))) };
+            i += 1;
+        });
+        Totals {
+            classes: classes,
+            large: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add the counts of the size classes in `allocs`, and `large` large allocations.
+    fn add<T, AM: AllocMap<T>>(&self, allocs: &AM, large: usize) {
+        allocs.foreach_counter(|size, count| {
+            let class = self.classes.iter().map(|c| unsafe { &*c }).find(|c| c.0 == size);
+            if let Some(&(_, ref total)) = class {
+                total.fetch_add(count.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+        });
+        self.large.fetch_add(large, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "dump_stats_on_drop")]
+impl Drop for Totals {
+    fn drop(&mut self) {
+        dump_histogram(self);
+    }
+}
+
+/// Log a histogram of the allocations made from each size class in `totals`, followed by the
+/// large allocations made outside of them, at the `info` level. Size classes that were never
+/// allocated from are omitted.
+#[cfg(feature = "dump_stats_on_drop")]
+fn dump_histogram(totals: &Totals) {
+    use std::fmt::Write;
+    let mut table = String::new();
+    let large = totals.large.load(Ordering::Relaxed);
+    let mut total = large as u64;
+    for class in totals.classes.iter() {
+        let &(size, ref count) = unsafe { &*class };
+        let count = count.load(Ordering::Relaxed) as u64;
+        if count > 0 {
+            let _ = writeln!(table, "{:>12} {:>12}", size, count);
+            total += count;
+        }
+    }
+    let _ = write!(table, "{:>12} {:>12}", "large", large);
+    info!("allocation histogram ({} allocations):\n{:>12} {:>12}\n{}",
+          total,
+          "size",
+          "count",
+          table);
+}
+
+/// A builder for `DynamicAllocator`s.
+///
+/// ```rust,ignore
+/// // Return pages to the OS once they have been unused for at least a second.
+/// let da = DynamicAllocatorBuilder::default()
+///     .decommit_idle(Duration::from_secs(1))
+///     .build();
+/// ```
+#[derive(Clone, Default)]
+pub struct DynamicAllocatorBuilder {
+    decommit_idle: Option<Duration>,
+    size_classes: Strategy,
+    large_threshold: Option<usize>,
+    word_size: Option<usize>,
+    dirty_policy: DirtyPolicy,
+    pipe_depth: Option<usize>,
+    batch_size: Option<usize>,
+    slab_sizing: Option<fn(usize) -> usize>,
+    large_perms: Permissions,
+    #[cfg(target_os = "linux")]
+    dont_fork: bool,
+    page_source: Option<(Arc<MapAlloc>, usize)>,
+    creek_size: Option<(usize, usize)>,
+}
+
+impl DynamicAllocatorBuilder {
+    /// Use the size classes given by `strategy`. The default is `Strategy::Tiered`.
+    pub fn size_classes(&mut self, strategy: Strategy) -> &mut Self {
+        self.size_classes = strategy;
+        self
+    }
+
+    /// Give requests of up to `bytes` bytes a size class of their own.
+    ///
+    /// With `Strategy::Tiered`, the smallest size class holds objects of exactly `bytes` bytes,
+    /// and the next one holds the smallest multiple of 16 greater than `bytes`. This is meant for
+    /// the pointer-sized objects that are common in many programs. The default is
+    /// `size_of::<usize>()`, which is 8 on 64-bit targets and 4 on 32-bit ones. This has no effect
+    /// with `Strategy::PowersOfTwo`.
+    ///
+    /// # Panics
+    ///
+    /// `word_size` panics if `bytes` is not a power of two.
+    pub fn word_size(&mut self, bytes: usize) -> &mut Self {
+        assert!(bytes.is_power_of_two(), "word size must be a power of two");
+        self.word_size = Some(bytes);
+        self
+    }
+
+    /// Dirty pages according to `policy`. The default is `DirtyPolicy::None`.
+    ///
+    /// See the `DirtyPolicy` documentation for the tradeoffs involved.
+    pub fn dirty_policy(&mut self, policy: DirtyPolicy) -> &mut Self {
+        self.dirty_policy = policy;
+        self
+    }
+
+    /// Use `depth` pipes for each size class's pool of available `Slag`s.
+    ///
+    /// When a thread frees enough objects to a `Slag` owned by another thread, the `Slag` is
+    /// pushed onto a pool shared by all threads using that size class. The pool is split into
+    /// `depth` pipes (rounded up to a power of two) so that threads pushing and popping
+    /// concurrently rarely contend on the same one. Deeper pools reduce contention on machines
+    /// with many cores, but each pipe costs a little memory per size class and makes it take
+    /// longer to find an available `Slag` when few are left. The default is the number of CPUs,
+    /// and at least 8.
+    pub fn pipe_depth(&mut self, depth: usize) -> &mut Self {
+        assert!(depth > 0, "pipe depth must be positive");
+        self.pipe_depth = Some(depth);
+        self
+    }
+
+    /// Cache up to `bytes` bytes of freed objects per thread and size class.
+    ///
+    /// Each thread keeps a magazine of recently freed objects for each size class, which serves
+    /// subsequent allocations without synchronization. Frees of objects owned by other threads
+    /// are batched together when a magazine fills up, so larger magazines mean fewer atomic
+    /// operations on remote frees. The cost is memory: objects in a magazine cannot be used by
+    /// other threads. The default is 512KB for every 8 CPUs, between 512KB and 4MB. Magazines
+    /// always hold at least one object. This has no effect with the `local_cache` feature. With
+    /// the `percpu_cache` feature, magazines are per-CPU rather than per-thread.
+    pub fn batch_size(&mut self, bytes: usize) -> &mut Self {
+        self.batch_size = Some(bytes);
+        self
+    }
+
+    /// Use `sizing` to choose how much of each `Slag` a size class uses.
+    ///
+    /// Each size class carves its objects out of `Slag`s, pages of a fixed size (2MB, unless the
+    /// largest size class requires larger ones). `sizing` is called once per size class with the
+    /// class's object size, and returns the number of bytes at the start of each of the class's
+    /// `Slag`s to use for its header and objects; the rest of the `Slag` is never touched, so its
+    /// memory is never committed. Values larger than a `Slag` are capped at its size, and every
+    /// `Slag` holds at least one object.
+    ///
+    /// Packing more objects into each `Slag` reduces the number of `Slag`s a size class cycles
+    /// through, but a `Slag` can only be reused once enough of its objects are freed, so it also
+    /// lets a few long-lived objects pin more memory. The default is `default_slab_sizing`.
+    pub fn slab_sizing(&mut self, sizing: fn(usize) -> usize) -> &mut Self {
+        self.slab_sizing = Some(sizing);
+        self
+    }
+
+    /// Serve allocations of at least `bytes` bytes directly with `mmap`.
+    ///
+    /// By default, allocations larger than the largest size class (1MB) are "large": each one is
+    /// mapped separately, with a page of overhead for its header. Lowering the threshold sends
+    /// more allocations down that path. Raising it adds size classes so that more allocations are
+    /// served from slabs instead; the largest size class is 4MB, so thresholds above that have
+    /// the same effect as a threshold of 4MB.
+    pub fn large_threshold(&mut self, bytes: usize) -> &mut Self {
+        self.large_threshold = Some(bytes);
+        self
+    }
+
+    /// Give large allocations the permissions `perms`.
+    ///
+    /// Only large allocations (see `large_threshold`) are affected: each is mapped separately, so
+    /// its pages can be protected individually, whereas objects in size classes share pages. For
+    /// example, a JIT can allocate large code buffers as read/execute. Memory without write
+    /// permission cannot be written by the allocator either, so `realloc`-ing such an object to a
+    /// larger size leaves the newly available bytes zeroed, and it must be readable to be copied.
+    /// The default is `Permissions::default()`.
+    ///
+    /// This only applies to allocators built with this builder. The global allocator (see the
+    /// `global` module) cannot be configured, so its large allocations always have the default
+    /// permissions; a JIT that needs other permissions must use its own `DynamicAllocator`.
+    pub fn large_permissions(&mut self, perms: Permissions) -> &mut Self {
+        self.large_perms = perms;
+        self
+    }
+
+    /// Uncommit pages that have been idle for at least `interval`.
+    ///
+    /// Fully-empty `Slag`s are cached by the allocator so that they can be reused cheaply. With
+    /// this option, a background thread sweeps the cache every `interval`, uncommitting pages that
+    /// have not been reused since the previous sweep. This returns physical memory to the OS while
+    /// keeping the address space reserved. The default is to never uncommit idle pages.
+    pub fn decommit_idle(&mut self, interval: Duration) -> &mut Self {
+        self.decommit_idle = Some(interval);
+        self
+    }
+
+    /// Do not let child processes inherit the allocator's memory across `fork`.
+    ///
+    /// The backing memory region for all size classes is marked with `MADV_DONTFORK`, so a child
+    /// created with `fork` does not receive a copy of it, and any access to it from the child
+    /// faults. This is intended for programs whose children reinitialize their own allocator
+    /// rather than reusing the parent's. Large allocations are mapped separately and are still
+    /// inherited.
+    #[cfg(target_os = "linux")]
+    pub fn dont_fork(&mut self) -> &mut Self {
+        self.dont_fork = true;
+        self
+    }
+
+    /// Get the pages for size classes from `alloc`.
+    ///
+    /// By default, pages are carved out of a mapping made with a default-configured `MapAlloc`.
+    /// This instead allocates a region of `heap_size` bytes from `alloc` when the allocator is
+    /// built, so that the whole heap gets `alloc`'s configuration: huge pages, for example.
+    /// `alloc` must allocate readable and writable memory. The region is returned to `alloc` once
+    /// the allocator and all of its clones have been dropped; since `build` can be called more
+    /// than once, `alloc` is shared by every allocator built from this configuration.
+    ///
+    /// Most of the region is typically never touched, so `heap_size` can be much larger than the
+    /// memory that will actually be used, as long as `alloc` does not reserve physical memory up
+    /// front (as it does for huge pages on Linux). Large allocations are still mapped separately.
+    ///
+    /// If the region cannot be allocated when the allocator is built (for example, because
+    /// `alloc` uses huge pages and the host has too few of them reserved), `build` logs a warning
+    /// and falls back to the default mapping of normal pages rather than failing.
+    pub fn page_source(&mut self, alloc: MapAlloc, heap_size: usize) -> &mut Self {
+        self.page_source = Some((Arc::new(alloc), heap_size));
+        self
+    }
+
+    /// Carve the pages for size classes out of a region of `total` bytes, in pages of `chunk`
+    /// bytes.
+    ///
+    /// By default, size classes share a single mapping of up to several terabytes of address
+    /// space, carved into 2MB pages. This caps the memory that size classes can use at `total`
+    /// bytes, which is useful in embedded or memory-constrained settings. `chunk` is raised to
+    /// 2MB, or to twice the largest size class if that is larger. One page of the region is used
+    /// for bookkeeping and up to one more is lost to aligning the region (see
+    /// `Creek::from_region`), so `total` must be at least twice the resulting page size.
+    ///
+    /// Once the region is exhausted, allocations that would need a fresh page spill over to the
+    /// large-allocation path: each is mapped separately, as allocations larger than the largest
+    /// size class are. They are slower and use at least a page each, but they can be freed and
+    /// `realloc`-ed like any other object. If `page_source` is also set, its `MapAlloc` and heap
+    /// size are used for the region instead of `total`.
+    ///
+    /// # Panics
+    ///
+    /// `creek_size` panics if `total` or `chunk` is not a power of two, if `chunk` is larger
+    /// than `total`, or if `total` is less than twice `chunk` or 4MB. Since other settings (such
+    /// as `large_threshold`) can raise the page size further, `build` panics if `total` is less
+    /// than twice the page size that is actually used.
+    pub fn creek_size(&mut self, total: usize, chunk: usize) -> &mut Self {
+        assert!(total.is_power_of_two(), "creek size must be a power of two");
+        assert!(chunk.is_power_of_two(), "chunk size must be a power of two");
+        assert!(chunk <= total, "chunk size must not exceed creek size");
+        let page_size = cmp::max(chunk, DEFAULT_SLAB_PAGE_SIZE);
+        assert!(total >= 2 * page_size,
+                "creek size of {} bytes must be at least twice the page size of {} bytes",
+                total,
+                page_size);
+        self.creek_size = Some((total, chunk));
+        self
+    }
+
+    /// Build a `DynamicAllocator` from the current configuration.
+    pub fn build(&self) -> DynamicAllocator {
+        let mut cache = CacheParams::default();
+        if let Some(depth) = self.pipe_depth {
+            cache.pipe_depth = depth;
+        }
+        if let Some(bytes) = self.batch_size {
+            cache.magazine_bytes = bytes;
+        }
+        if let Some(sizing) = self.slab_sizing {
+            cache.slab_sizing = sizing;
+        }
+        let word_size = self.word_size.unwrap_or(mem::size_of::<usize>());
+        if let (None, Some((total, chunk))) = (self.page_source.as_ref(), self.creek_size) {
+            let page_size =
+                class_page_size(self.size_classes, word_size, self.large_threshold, Some(chunk));
+            assert!(total >= 2 * page_size,
+                    "creek size of {} bytes must be at least twice the page size of {} bytes",
+                    total,
+                    page_size);
+        }
+        let backing = match (self.page_source.clone(), self.creek_size) {
+            (Some((alloc, size)), _) => Some(Backing::MapAlloc(alloc, size)),
+            (None, Some((total, _))) => {
+                Some(Backing::MapAlloc(Arc::new(MapAlloc::default()), total))
+            }
+            (None, None) => None,
+        };
+        let classes = unsafe {
+            SizeClasses::new(self.size_classes,
+                             word_size,
+                             self.large_threshold,
+                             self.dirty_policy,
+                             cache,
+                             backing,
+                             self.creek_size.map(|(_, chunk)| chunk))
+        };
+        let mut da = DynamicAllocator(classes, None);
+        if self.creek_size.is_some() {
+            dispatch!(da.0, elf => elf.spill = true);
+        }
+        if self.large_perms != Permissions::default() {
+            let perms = self.large_perms;
+            dispatch!(da.0, elf => elf.large_perms = Some(perms));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if self.dont_fork {
+                dispatch!(da.0, elf => elf.pages.backing_memory().dont_fork());
+            }
+        }
+        if let Some(interval) = self.decommit_idle {
+            da.1 = Some(dispatch!(da.0, elf => spawn_idle_sweeper(elf.pages.clone(), interval)));
+        }
+        da
+    }
+}
+
+/// Spawn a thread calling `sweep_idle` on `pages` every `interval`.
+///
+/// The thread exits once the returned token (and all of its clones) have been dropped.
+fn spawn_idle_sweeper<D>(mut pages: PageAlloc<Creek, D>, interval: Duration) -> Arc<()>
+    where D: DirtyFn + Send + 'static
+{
+    let token = Arc::new(());
+    let weak = Arc::downgrade(&token);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if weak.upgrade().is_none() {
+            return;
+        }
+        pages.sweep_idle();
+    });
+    token
+}
+
+// we default to using the `MagazineCache` here, as it performs better in general. There are some
+// settings in which the `LocalCache` frontend is superior. Hence, we feature-gate this. The
+// `PerCpuCache` frontend uses less memory when there are many more threads than CPUs.
+#[cfg(not(any(feature = "local_cache", feature = "percpu_cache")))]
+type Cache<CA> = MagazineCache<CA>;
+#[cfg(all(feature = "local_cache", not(feature = "percpu_cache")))]
+type Cache<CA> = LocalCache<CA>;
+#[cfg(feature = "percpu_cache")]
+type Cache<CA> = PerCpuCache<CA>;
+type ObjectAlloc<CA> = Lazy<Cache<CA>>;
+
+/// A Dynamic memory allocator, parmetrized on a particular `ObjectAlloc`, `CourseAllocator` and
+/// `AllocMap`.
+///
+/// `ElfMalloc` encapsulates the logic of constructing and selecting object classes, as well as
+/// delgating to the `large_alloc` module for large allocations. Most of the logic occurs in its
+/// type parameters.
+struct ElfMalloc<CA: CoarseAllocator, AM: AllocMap<ObjectAlloc<CA>>> {
+    /// A global cache of pages, shared by all size classes in `allocs`.
+    pages: CA,
+    /// An `AllocMap` of size classes of individual fixed-size object allocator.
+    allocs: AM,
+    /// The maximum size of a "non-large" object. Objects larger than `max_size` are allocated
+    /// directly with mmap.
+    max_size: usize,
+    /// The permissions to give large allocations, if they differ from the default.
+    large_perms: Option<Permissions>,
+    /// Whether allocations that a size class cannot serve because the backing memory is
+    /// exhausted are made large instead (see `DynamicAllocatorBuilder::creek_size`).
+    spill: bool,
+    /// The size of every live allocation made by this allocator or one of its clones, keyed by
+    /// address.
+    #[cfg(feature = "leak_check")]
+    live: LiveTable,
+    /// Objects freed through this handle that have not yet been returned to their size classes.
+    #[cfg(feature = "quarantine")]
+    quarantine: Quarantine,
+    /// The number of large allocations made through this handle.
+    #[cfg(feature = "stats")]
+    large_allocs: AtomicUsize,
+    /// The allocation counts of this allocator and its clones, logged once the last of them is
+    /// dropped.
+    #[cfg(feature = "dump_stats_on_drop")]
+    totals: Arc<Totals>,
+    /// The mapping holding the `Metadata` of every size class in `allocs`. It is shared with
+    /// clones, whose size classes use the same metadata.
+    meta: Arc<MetadataMap>,
+
+    start_from: usize,
+    n_classes: usize,
+}
+
+/// The base address and size of the mapping holding an allocator's size class `Metadata`.
+///
+/// It is unmapped when dropped; `ElfMalloc` keeps it in an `Arc` so that this happens once the
+/// allocator and all of its clones have been dropped.
+struct MetadataMap(*mut Metadata, usize);
+
+// The metadata is only written when the allocator is created; after that, it is read-only.
+unsafe impl Send for MetadataMap {}
+unsafe impl Sync for MetadataMap {}
+
+impl Drop for MetadataMap {
+    fn drop(&mut self) {
+        unsafe { meta_alloc::dealloc(self.0 as *mut u8, self.1) }
+    }
+}
+
+/// A table of live allocations, shared between an `ElfMalloc` and its clones.
+///
+/// The table lives on the process's global heap (`bsalloc`, or the system allocator with the
+/// `use_default_allocator` feature), never in the allocator whose objects it tracks.
+#[cfg(feature = "leak_check")]
+type LiveTable = Arc<Mutex<HashMap<usize, usize>>>;
+
+/// The byte written over objects when they are freed, with the `quarantine` feature.
+///
+/// With that feature, objects in size classes are overwritten with this byte when they are freed,
+/// and held in a quarantine (per handle and size class) before being returned to their size class,
+/// so that they are not immediately reused. Reads of freed memory return the poison, freeing an
+/// object that is still quarantined panics, and every allocation checks that the objects
+/// quarantined for its size class are intact, panicking if one has been written to since it was
+/// freed. Large allocations are unmapped when freed, so they are not quarantined.
+///
+/// This is meant for debugging and hardening, and it is expensive: each free writes the whole
+/// object, each allocation reads up to 64KB of quarantined objects, and each handle keeps up to
+/// 64KB (and at least one object) per size class out of use. Quarantined objects are released when
+/// their handle is dropped.
+#[cfg(feature = "quarantine")]
+pub const POISON_BYTE: u8 = 0xDE;
+
+/// The number of bytes of freed objects that each handle quarantines per size class, with the
+/// `quarantine` feature. Each size class quarantines at least one object.
+#[cfg(feature = "quarantine")]
+const QUARANTINE_BYTES: usize = 64 << 10;
+
+/// Check that `item`, a quarantined object of `size` bytes, is still filled with `POISON_BYTE`.
+///
+/// # Panics
+///
+/// Panics if the object has been written to since it was freed.
+#[cfg(feature = "quarantine")]
+unsafe fn check_poison(item: *mut u8, size: usize) {
+    for i in 0..size {
+        if *item.offset(i as isize) != POISON_BYTE {
+            panic!("object {:?} of size {} was written to after being freed (at offset {})",
+                   item,
+                   size,
+                   i);
+        }
+    }
+}
+
+/// The objects quarantined by one handle, with the `quarantine` feature.
+///
+/// Unlike `LiveTable`, this never touches the global heap: it is a table of fixed-capacity ring
+/// buffers, one per object size, all mapped with `meta_alloc`. When elfmalloc is the global
+/// allocator, growing a heap-allocated queue from within `free` would re-enter the same handle,
+/// and the nested `free` would modify the queue being grown.
+#[cfg(feature = "quarantine")]
+struct Quarantine {
+    /// One ring per object size that has had an object quarantined; unused entries are zeroed.
+    rings: OwnedArray<QuarantineRing>,
+    /// The number of rings in use.
+    used: usize,
+}
+
+/// The addresses of the quarantined objects of one size, with the oldest at `head`.
+///
+/// An all-zero `QuarantineRing` is unused, which is how the rings of a fresh `Quarantine` start
+/// out.
+#[cfg(feature = "quarantine")]
+struct QuarantineRing {
+    size: usize,
+    items: *mut usize,
+    cap: usize,
+    head: usize,
+    len: usize,
+}
+
+#[cfg(feature = "quarantine")]
+impl QuarantineRing {
+    /// The bytes mapped for the ring's `items`.
+    fn mapped(&self) -> usize {
+        let bytes = self.cap * mem::size_of::<usize>();
+        (bytes + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+    }
+
+    unsafe fn get(&self, i: usize) -> usize {
+        *self.items.offset(((self.head + i) % self.cap) as isize)
+    }
+
+    unsafe fn contains(&self, item: usize) -> bool {
+        (0..self.len).any(|i| self.get(i) == item)
+    }
+
+    /// Add `item` as the newest object, returning the oldest one if the ring was full.
+    unsafe fn push(&mut self, item: usize) -> Option<usize> {
+        let slot = self.items.offset(((self.head + self.len) % self.cap) as isize);
+        if self.len < self.cap {
+            *slot = item;
+            self.len += 1;
+            None
+        } else {
+            // The ring is full, so the slot after the newest object holds the oldest.
+            let old = *slot;
+            *slot = item;
+            self.head = (self.head + 1) % self.cap;
+            Some(old)
+        }
+    }
+}
+
+#[cfg(feature = "quarantine")]
+impl Quarantine {
+    /// Create a quarantine with room for `n_sizes` object sizes.
+    fn new(n_sizes: usize) -> Quarantine {
+        Quarantine {
+            rings: OwnedArray::new(n_sizes),
+            used: 0,
+        }
+    }
+
+    /// The ring for objects of `size` bytes, if any have been quarantined.
+    fn get(&self, size: usize) -> Option<&QuarantineRing> {
+        (0..self.used)
+            .map(|i| unsafe { &*self.rings.get(i) })
+            .find(|ring| ring.size == size)
+    }
+
+    /// The ring for objects of `size` bytes, which is mapped if this is the first of them.
+    ///
+    /// Returns `None` if the ring could not be mapped.
+    fn get_or_map(&mut self, size: usize) -> Option<&mut QuarantineRing> {
+        let found = (0..self.used).find(|&i| unsafe { (*self.rings.get(i)).size } == size);
+        let i = match found {
+            Some(i) => i,
+            None => {
+                assert!(self.used < self.rings.len(),
+                        "more object sizes than the quarantine has room for");
+                let ring = unsafe { &mut *self.rings.get(self.used) };
+                ring.size = size;
+                ring.cap = cmp::max(1, QUARANTINE_BYTES / size);
+                ring.items = meta_alloc::alloc(ring.mapped())? as *mut usize;
+                self.used += 1;
+                self.used - 1
+            }
+        };
+        Some(unsafe { &mut *self.rings.get(i) })
+    }
+
+    /// Call `f` on the size and address of every quarantined object, oldest first per size, and
+    /// empty the quarantine (keeping the rings mapped).
+    unsafe fn drain<F: FnMut(usize, usize)>(&mut self, mut f: F) {
+        for i in 0..self.used {
+            let ring = &mut *self.rings.get(i);
+            for j in 0..ring.len {
+                f(ring.size, ring.get(j));
+            }
+            ring.head = 0;
+            ring.len = 0;
+        }
+    }
+}
+
+#[cfg(feature = "quarantine")]
+impl Drop for Quarantine {
+    fn drop(&mut self) {
+        for i in 0..self.used {
+            unsafe {
+                let ring = &*self.rings.get(i);
+                meta_alloc::dealloc(ring.items as *mut u8, ring.mapped());
+            }
+        }
+    }
+}
+
+impl Default for DynamicAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a `PageAlloc` for pages of size `page_size`, getting its pages from `block` if given.
+fn page_alloc<M: MemoryBlock, D: DirtyFn>(page_size: usize, block: Option<M>) -> PageAlloc<M, D> {
+    match block {
+        Some(block) => {
+            // The block's pages may be larger than required (see `SizeClasses::new`).
+            debug_assert!(block.page_size() >= page_size);
+            PageAlloc::with_block(block, 1 << 20)
+        }
+        None => PageAlloc::new(page_size, 1 << 20),
+    }
+}
+
+impl<M: MemoryBlock, D: DirtyFn> ElfMalloc<PageAlloc<M, D>,
+                                           TieredSizeClasses<ObjectAlloc<PageAlloc<M, D>>>> {
+    fn new() -> Self {
+        Self::with_n_classes(25, mem::size_of::<usize>(), CacheParams::default(), None)
+    }
+
+    fn with_n_classes(n_classes: usize,
+                      word_size: usize,
+                      cache: CacheParams,
+                      block: Option<M>)
+                      -> Self {
+        let pa = page_alloc(slab_page_size(tiered_max_key(n_classes, word_size)), block);
+        Self::new_internal(0.6, pa, word_size, n_classes, cache)
+    }
+
+    /// Destroy the size classes, freeing the memory used for their metadata.
+    unsafe fn destroy_classes(&mut self) {
+        debug_assert_eq!(self.allocs.small_objs.classes.len(),
+                         self.allocs.small_objs.n_classes());
+        debug_assert_eq!(self.allocs.medium_objs.classes.len(),
+                         self.allocs.medium_objs.n_classes());
+        self.allocs.medium_objs.destroy();
+        self.allocs.small_objs.destroy();
+    }
+
+    /// Free all objects cached by this handle's size classes back to their `Slag`s.
+    ///
+    /// Size classes that have not been used yet are left uninitialized.
+    unsafe fn flush_caches(&mut self) {
+        self.allocs.foreach(|x| if let Some(cache) = (*x).get_mut_if_init() {
+            cache.flush()
+        });
+        if let Some(cache) = self.allocs.word_objs.as_mut().and_then(|x| x.get_mut_if_init()) {
+            cache.flush()
+        }
+    }
+
+    /// Compact each size class that has been used, adding the results to `res`.
+    unsafe fn compact_classes(&mut self, res: &mut Compaction) {
+        let res = RefCell::new(res);
+        self.allocs.foreach(|x| if let Some(cache) = (*x).get_mut_if_init() {
+            let mut res = res.borrow_mut();
+            let freed = cache.compact(&mut res.moved);
+            res.freed_slabs += freed;
+        });
+        let res = res.into_inner();
+        if let Some(cache) = self.allocs.word_objs.as_mut().and_then(|x| x.get_mut_if_init()) {
+            let freed = cache.compact(&mut res.moved);
+            res.freed_slabs += freed;
+        }
+    }
+}
+
+impl<M: MemoryBlock, D: DirtyFn> ElfMalloc<PageAlloc<M, D>,
+                                           PowersOfTwo<ObjectAlloc<PageAlloc<M, D>>>> {
+    fn powers_of_two(n_classes: usize, cache: CacheParams, block: Option<M>) -> Self {
+        let pa = page_alloc(slab_page_size(8 << (n_classes - 1)), block);
+        Self::new_internal(0.6, pa, 8, n_classes, cache)
+    }
+
+    /// Destroy the size classes, freeing the memory used for their metadata.
+    unsafe fn destroy_classes(&mut self) {
+        debug_assert_eq!(self.allocs.classes.len(), self.allocs.n_classes());
+        self.allocs.destroy();
+    }
+
+    /// Free all objects cached by this handle's size classes back to their `Slag`s.
+    ///
+    /// Size classes that have not been used yet are left uninitialized.
+    unsafe fn flush_caches(&mut self) {
+        self.allocs.foreach(|x| if let Some(cache) = (*x).get_mut_if_init() {
+            cache.flush()
+        });
+    }
+
+    /// Compact each size class that has been used, adding the results to `res`.
+    unsafe fn compact_classes(&mut self, res: &mut Compaction) {
+        let res = RefCell::new(res);
+        self.allocs.foreach(|x| if let Some(cache) = (*x).get_mut_if_init() {
+            let mut res = res.borrow_mut();
+            let freed = cache.compact(&mut res.moved);
+            res.freed_slabs += freed;
+        });
+    }
+}
+
+impl<M: MemoryBlock, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>> Clone
+    for ElfMalloc<PageAlloc<M, D>, AM> {
+    fn clone(&self) -> Self {
+        self.try_clone().expect("mmap should not fail")
+    }
+}
+
+impl<M: MemoryBlock, D: DirtyFn, AM: AllocMap<ObjectAlloc<PageAlloc<M, D>>, Key = usize>>
+    ElfMalloc<PageAlloc<M, D>, AM> {
+    /// Clone the allocator, returning `Err(Exhausted)` if the new size classes' metadata could
+    /// not be mapped.
+    fn try_clone(&self) -> Result<Self, Exhausted> {
+        let new_map = AM::try_init(self.start_from, self.n_classes, |size: usize| unsafe {
+            self.allocs.get(size).clone()
+        })?;
+        Ok(ElfMalloc {
+               pages: self.pages.clone(),
+               allocs: new_map,
+               max_size: self.max_size,
+               large_perms: self.large_perms,
+               spill: self.spill,
+               #[cfg(feature = "leak_check")]
+               live: self.live.clone(),
+               #[cfg(feature = "quarantine")]
+               quarantine: Quarantine::new(self.n_classes + 1),
+               #[cfg(feature = "stats")]
+               large_allocs: AtomicUsize::new(0),
+               #[cfg(feature = "dump_stats_on_drop")]
+               totals: self.totals.clone(),
+               meta: self.meta.clone(),
+               start_from: self.start_from,
+               n_classes: self.n_classes,
+           })
+    }
+
+    fn new_internal(cutoff_factor: f64,
+                    pa: PageAlloc<M, D>,
+                    start_from: usize,
+                    n_classes: usize,
+                    cache: CacheParams)
+                    -> Self {
+        let meta_size = mem::size_of::<Metadata>() * n_classes;
+        let meta = MetadataMap(meta_alloc::alloc(meta_size).expect("metadata allocation failed") as
+                               *mut Metadata,
+                               meta_size);
+        let mut meta_pointer = meta.0;
+        let am = AM::init(start_from, n_classes, |size: usize| {
+            // Each `Slag` holds at least one object anyway.
+            let u_size = cmp::max((cache.slab_sizing)(size), 1);
+            let m_ptr = meta_pointer;
+            unsafe {
+                meta_pointer = meta_pointer.offset(1);
+                ptr::write(m_ptr,
+                           compute_metadata(size,
+                                            pa.backing_memory().page_size(),
+                                            0,
+                                            cutoff_factor,
+                                            u_size));
+            }
+
+            let params = (m_ptr,
+                          1 << 20,
+                          pa.clone(),
+                          RevocablePipe::new_size(cache.pipe_depth),
+                          cache.magazine_bytes);
+            ObjectAlloc::new(<Cache<PageAlloc<M, D>> as Frontend>::params(params))
+        });
+        let max_size = am.max_key();
+        #[cfg(feature = "dump_stats_on_drop")]
+        let totals = Arc::new(Totals::new(&am));
+        ElfMalloc {
+            pages: pa.clone(),
+            allocs: am,
+            max_size: max_size,
+            large_perms: None,
+            spill: false,
+            #[cfg(feature = "leak_check")]
+            live: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "quarantine")]
+            quarantine: Quarantine::new(n_classes + 1),
+            #[cfg(feature = "stats")]
+            large_allocs: AtomicUsize::new(0),
+            #[cfg(feature = "dump_stats_on_drop")]
+            totals: totals,
+            meta: Arc::new(meta),
+            start_from: start_from,
+            n_classes: n_classes,
+        }
+    }
+
+    unsafe fn alloc(&mut self, bytes: usize) -> *mut u8 {
+        let item = self.alloc_unprotected(bytes);
+        let item = self.protect(item);
+        #[cfg(feature = "leak_check")]
+        self.track(item, bytes);
+        item
+    }
+
+    /// Allocate `bytes` bytes aligned to `align`, which must be a power of two.
+    unsafe fn alloc_aligned(&mut self, bytes: usize, align: usize) -> *mut u8 {
+        let item = self.alloc_aligned_unprotected(bytes, align);
+        let item = self.protect(item);
+        #[cfg(feature = "leak_check")]
+        self.track(item, bytes);
+        item
+    }
+
+    /// Record `item`, if it is not null, as a live allocation of `bytes` bytes.
+    #[cfg(feature = "leak_check")]
+    fn track(&self, item: *mut u8, bytes: usize) {
+        if !item.is_null() {
+            self.live.lock().unwrap().insert(item as usize, bytes);
+        }
+    }
+
+    /// Remove `item` from the table of live allocations.
+    #[cfg(feature = "leak_check")]
+    fn untrack(&self, item: *mut u8) {
+        self.live.lock().unwrap().remove(&(item as usize));
+    }
+
+    /// Update the table of live allocations for objects that `compact` moved.
+    #[cfg(feature = "leak_check")]
+    fn relocate(&self, moved: &[(*mut u8, *mut u8)]) {
+        let mut live = self.live.lock().unwrap();
+        for &(old, new) in moved {
+            if let Some(size) = live.remove(&(old as usize)) {
+                live.insert(new as usize, size);
+            }
+        }
+    }
+
+    /// Add the allocation counts of this handle to those shared with its clones (see `Totals`).
+    ///
+    /// This only reads atomic counters, so it is safe to call on another thread's handle.
+    #[cfg(feature = "dump_stats_on_drop")]
+    fn record_stats(&self) {
+        self.totals.add(&self.allocs, self.large_allocs.load(Ordering::Relaxed));
+    }
+
+    /// Allocate `bytes` bytes, leaving large allocations writable regardless of `large_perms`.
+    unsafe fn alloc_unprotected(&mut self, bytes: usize) -> *mut u8 {
+        if likely(bytes < self.max_size) {
+            #[cfg(feature = "fault_injection")]
+            {
+                if inject_failure() {
+                    return ptr::null_mut();
+                }
+            }
+            // Even an uninitialized size class fails cleanly (rather than panicking) when no fresh
+            // page is left to initialize it with, so trying it first is enough to decide whether
+            // to spill.
+            let item = self.allocs.get_mut(bytes).alloc();
+            if item.is_null() {
+                if self.spill {
+                    return self.alloc_large(bytes);
+                }
+                warn!("allocation of {} bytes failed: size class backing memory exhausted",
+                      bytes);
+            }
+            #[cfg(feature = "stats")]
+            {
+                if !item.is_null() {
+                    self.allocs.counter(bytes).fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            #[cfg(feature = "quarantine")]
+            {
+                if !item.is_null() {
+                    let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
+                    self.check_quarantine(slag.get_metadata().object_size);
+                }
+            }
+            item
+        } else {
+            self.alloc_large(bytes)
+        }
+    }
+
+    /// Allocate `bytes` bytes with `large_alloc`.
+    unsafe fn alloc_large(&mut self, bytes: usize) -> *mut u8 {
+        #[cfg(feature = "stats")]
+        {
+            self.large_allocs.fetch_add(1, Ordering::Relaxed);
+        }
+        large_alloc::alloc(bytes)
+    }
+
+    /// Allocate `bytes` bytes aligned to `align`, leaving large allocations writable regardless of
+    /// `large_perms`.
+    ///
+    /// Objects in a size class whose size is a power of two are aligned to that size (up to a
+    /// page), so alignments of up to a page are satisfied by rounding `bytes` up to a power of two
+    /// that is at least `align`. Larger alignments are left to `large_alloc`.
+    unsafe fn alloc_aligned_unprotected(&mut self, bytes: usize, align: usize) -> *mut u8 {
+        if align <= mem::size_of::<usize>() {
+            self.alloc_unprotected(bytes)
+        } else if align <= large_alloc::PAGE_SIZE as usize {
+            // Sizes too large to be rounded up saturate, so that allocating them fails.
+            let size = cmp::max(bytes, align)
+                .checked_next_power_of_two()
+                .unwrap_or(usize::max_value());
+            self.alloc_unprotected(size)
+        } else {
+            #[cfg(feature = "stats")]
+            {
+                self.large_allocs.fetch_add(1, Ordering::Relaxed);
+            }
+            large_alloc::alloc_aligned(bytes, align)
+        }
+    }
+
+    /// Get the alignment that the object `item` is guaranteed to have.
+    ///
+    /// `realloc` gives the new object at least this alignment, so that memory allocated with a
+    /// larger alignment than usual (for example, by `ElfGlobal`) keeps it. Objects in a
+    /// power-of-two size class are aligned to their size, capped at a page; objects in other
+    /// size classes are only word-aligned. Large allocations record their alignment.
+    unsafe fn alignment(&self, item: *mut u8) -> usize {
+        if likely(self.pages.backing_memory().contains(item)) {
+            let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
+            let size = slag.get_metadata().object_size;
+            if size.is_power_of_two() {
+                cmp::min(size, large_alloc::PAGE_SIZE as usize)
+            } else {
+                mem::size_of::<usize>()
+            }
+        } else {
+            large_alloc::alignment(item)
+        }
+    }
+
+    /// Apply `large_perms` to `item` if it is a large allocation, returning `item`, or null (after
+    /// freeing `item`) if its permissions could not be changed.
+    unsafe fn protect(&mut self, item: *mut u8) -> *mut u8 {
+        match self.large_perms {
+            Some(perms) if !item.is_null() && !self.pages.backing_memory().contains(item) => {
+                if large_alloc::protect(item, perms) {
+                    item
+                } else {
+                    large_alloc::free(item);
+                    ptr::null_mut()
+                }
+            }
+            _ => item,
+        }
+    }
+
+    /// Allocate `bytes` bytes, also returning the number of bytes that are actually usable.
+    unsafe fn alloc_excess(&mut self, bytes: usize) -> (*mut u8, usize) {
+        let item = self.alloc(bytes);
+        if item.is_null() {
+            (item, 0)
+        } else {
+            (item, self.usable_size(item))
+        }
+    }
+
+    /// Get the number of usable bytes in the object `item`.
+    unsafe fn usable_size(&self, item: *mut u8) -> usize {
+        if likely(self.pages.backing_memory().contains(item)) {
+            let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
+            slag.get_metadata().object_size
+        } else {
+            large_alloc::get_commitment(item).0
+        }
+    }
+
+    unsafe fn realloc(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
+        let new_item = self.realloc_untracked(item, new_size);
+        #[cfg(feature = "leak_check")]
+        {
+            // On failure, `item` is still live with its old size.
+            if !new_item.is_null() {
+                self.untrack(item);
+                self.track(new_item, new_size);
+            }
+        }
+        new_item
+    }
+
+    /// Shrink `item` to `new_size` bytes in place, unmapping the pages of large allocations that
+    /// are no longer needed.
+    unsafe fn shrink_in_place(&mut self, item: *mut u8, new_size: usize) {
+        if !self.pages.backing_memory().contains(item) {
+            large_alloc::shrink(item, new_size);
+        }
+        #[cfg(feature = "leak_check")]
+        self.track(item, new_size);
+    }
+
+    /// The body of `realloc`, which leaves recording the new object as live to its caller.
+    unsafe fn realloc_untracked(&mut self, item: *mut u8, new_size: usize) -> *mut u8 {
+        if item.is_null() {
+            return self.alloc(new_size);
+        }
+        if new_size == 0 {
+            self.free(item);
+            return ptr::null_mut();
+        }
+        if likely(self.pages.backing_memory().contains(item)) {
+            let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
+            let meta = slag.get_metadata();
+            // TODO(ezrosent): support shrinking
+            if meta.object_size >= new_size {
+                return item;
+            }
+            let new_memory = self.alloc_aligned_unprotected(new_size, self.alignment(item));
+            if new_memory.is_null() {
+                return new_memory;
+            }
+            ptr::copy_nonoverlapping(item, new_memory, meta.object_size);
+            self.free(item);
+            self.protect(new_memory)
+        } else {
+            let (size, _) = large_alloc::get_commitment(item);
+            if size >= new_size {
+                return item;
+            }
+            let new_memory = large_alloc::realloc(item, new_size);
+            if !new_memory.is_null() {
+                // The mapping may have been copied rather than moved, losing its permissions.
+                return self.protect(new_memory);
+            }
+            let new_memory = self.alloc_aligned_unprotected(new_size, self.alignment(item));
+            if new_memory.is_null() {
+                return new_memory;
+            }
+            ptr::copy_nonoverlapping(item, new_memory, size);
+            self.free(item);
+            self.protect(new_memory)
+        }
+    }
+
+    unsafe fn free(&mut self, item: *mut u8) {
+        #[cfg(feature = "leak_check")]
+        self.untrack(item);
+        if likely(self.pages.backing_memory().contains(item)) {
+            let slag = &*Slag::find(item, self.pages.backing_memory().page_size());
+            #[cfg(feature = "quarantine")]
+            {
+                return self.quarantine_free(item, slag.get_metadata().object_size);
+            }
+            #[cfg(not(feature = "quarantine"))]
+            {
+                self.allocs
+                    .get_mut(slag.get_metadata().object_size)
+                    .free(item)
+            }
+        } else {
+            if self.large_perms.is_some() {
+                // large_alloc::free may write to the object in debug builds.
+                large_alloc::protect(item, Permissions::default());
+            }
+            large_alloc::free(item)
+        }
+    }
+
+    unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        #[cfg(feature = "quarantine")]
+        {
+            // Each object has to be poisoned and quarantined individually.
+            for &item in items {
+                self.free(item);
+            }
+        }
+        #[cfg(not(feature = "quarantine"))]
+        {
+            let page_size = self.pages.backing_memory().page_size();
+            let mut rest = items;
+            while let Some(&first) = rest.first() {
+                if !self.pages.backing_memory().contains(first) {
+                    self.free(first);
+                    rest = &rest[1..];
+                    continue;
+                }
+                // Free the run of objects in the same size class as `first` together.
+                let size = (*Slag::find(first, page_size)).get_metadata().object_size;
+                let run = {
+                    let block = self.pages.backing_memory();
+                    rest.iter()
+                        .position(|&item| {
+                                      !block.contains(item) ||
+                                      (*Slag::find(item, page_size)).get_metadata().object_size !=
+                                      size
+                                  })
+                        .unwrap_or(rest.len())
+                };
+                let (batch, tail) = rest.split_at(run);
+                #[cfg(feature = "leak_check")]
+                {
+                    for &item in batch {
+                        self.untrack(item);
+                    }
+                }
+                self.allocs.get_mut(size).free_batch(batch);
+                rest = tail;
+            }
+        }
+    }
+
+    /// Poison `item`, an object of `size` bytes, and add it to its size class's quarantine.
+    ///
+    /// If the quarantine is full, its oldest object is checked and returned to the size class.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `item` is already quarantined (a double free), or if the object released from
+    /// the quarantine has been written to since it was freed.
+    #[cfg(feature = "quarantine")]
+    unsafe fn quarantine_free(&mut self, item: *mut u8, size: usize) {
+        let old = match self.quarantine.get_or_map(size) {
+            Some(ring) => {
+                if ring.contains(item as usize) {
+                    panic!("double free of object {:?} of size {}", item, size);
+                }
+                ptr::write_bytes(item, POISON_BYTE, size);
+                ring.push(item as usize)
+            }
+            None => {
+                // There is no memory to quarantine the object in, so free it right away.
+                warn!("could not map a quarantine for objects of size {}", size);
+                Some(item as usize)
+            }
+        };
+        if let Some(old) = old {
+            let old = old as *mut u8;
+            if old != item {
+                check_poison(old, size);
+            }
+            self.allocs.get_mut(size).free(old);
+        }
+    }
+
+    /// Check that every object quarantined for the size class of `size`-byte objects is still
+    /// poisoned.
+    #[cfg(feature = "quarantine")]
+    unsafe fn check_quarantine(&self, size: usize) {
+        if let Some(ring) = self.quarantine.get(size) {
+            for i in 0..ring.len {
+                check_poison(ring.get(i) as *mut u8, size);
+            }
+        }
+    }
+
+    /// Return every quarantined object to its size class, without checking it.
+    #[cfg(feature = "quarantine")]
+    unsafe fn release_quarantine(&mut self) {
+        let allocs = &mut self.allocs;
+        self.quarantine.drain(|size, item| allocs.get_mut(size).free(item as *mut u8));
+    }
+
+    #[cfg(feature = "checked_free")]
+    unsafe fn free_checked(&mut self, item: *mut u8) -> Result<(), InvalidPointer> {
+        // The backing memory is mapped in its entirety, so any `Slag` within it can be read.
+        let valid = if self.pages.backing_memory().contains(item) {
+            (*Slag::find(item, self.pages.backing_memory().page_size())).is_object_start(item)
+        } else {
+            large_alloc::is_valid(item)
+        };
+        if !valid {
+            return Err(InvalidPointer(item));
+        }
+        self.free(item);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fault_injection")]
+thread_local! {
+    /// The number of allocations on this thread until one is made to fail, or 0 if none will be.
+    static FAILURE_COUNTDOWN: Cell<usize> = Cell::new(0);
+}
+
+/// Make an upcoming allocation on the current thread fail.
+///
+/// After `set_alloc_failure_countdown(n)`, the `n`th allocation made by an allocator in this crate
+/// on the calling thread fails as if memory were exhausted: `alloc` returns null, and `try_alloc`
+/// returns `Err(Exhausted)`. Later allocations succeed as usual. `n = 1` fails the next
+/// allocation, and `n = 0` disarms a pending failure. Both size class and large allocations count,
+/// including those made by `realloc` when an object has to move.
+///
+/// This is meant for testing how code recovers from allocation failure, and is only available
+/// with the `fault_injection` feature. It uses thread-local storage, which may itself allocate on
+/// first use, so it should not be used when this crate is the process's global allocator.
+#[cfg(feature = "fault_injection")]
+pub fn set_alloc_failure_countdown(n: usize) {
+    FAILURE_COUNTDOWN.with(|c| c.set(n));
+}
+
+/// Count an allocation against the failure countdown, returning whether it should fail.
+#[cfg(feature = "fault_injection")]
+fn inject_failure() -> bool {
+    FAILURE_COUNTDOWN.with(|c| match c.get() {
+                               0 => false,
+                               n => {
+                                   c.set(n - 1);
+                                   n == 1
+                               }
+                           })
+}
+
+mod large_alloc {
+    //! This module governs "large" allocations that are beyond the size of the largest size class
+    //! of a dynamic allocator.
+    //!
+    //! Large allocations are implemented by mapping a region of memory of the indicated size, with
+    //! additional padding in front of the object to store a `Header`. The `Header` is always
+    //! stored immediately before the pointer handed back to the caller, and records the base and
+    //! length of the entire mapping. This allows `free` to recover the mapping no matter how far
+    //! the object was offset from the start of the mapping in order to satisfy its alignment.
+    #[cfg(test)]
+    use std::collections::HashMap;
+    #[cfg(test)]
+    use std::cell::RefCell;
+
+    // For debugging, we keep around a thread-local map of pointers to lengths. This helps us
+    // scrutinize if various header data is getting propagated correctly.
+    #[cfg(test)]
+    thread_local! {
+        pub static SEEN_PTRS: RefCell<HashMap<*mut u8, usize>> = RefCell::new(HashMap::new());
+    }
+    use super::mmap::{fallible_map, remap, unmap};
+    use super::Permissions;
+    use super::super::libc;
+    use std::cmp;
+    use std::ptr;
+    // TODO(ezrosent): sysconf
+    pub const PAGE_SIZE: isize = 4096;
+    /// The largest mapping that will be requested. Offsets into an object must fit in an `isize`,
+    /// so no object can be larger than this anyway.
+    const MAX_LEN: usize = ::std::isize::MAX as usize;
+
+    /// The information needed to unmap a large allocation.
+    #[repr(C)]
+    struct Header {
+        /// The base of the mapping, as returned by `map`.
+        base: *mut u8,
+        /// The length of the entire mapping (in bytes).
+        len: usize,
+        /// The alignment that the object was allocated with (at least a page).
+        align: usize,
+    }
+
+    /// Get the `Header` corresponding to the object `item`.
+    #[inline]
+    unsafe fn header(item: *mut u8) -> *mut Header {
+        (item as *mut Header).offset(-1)
+    }
+
+    /// Map a new region for an object of size `size`, returning null if the map fails.
+    pub unsafe fn alloc(size: usize) -> *mut u8 {
+        alloc_aligned(size, PAGE_SIZE as usize)
+    }
+
+    /// Map a new region for an object of size `size` aligned to `align`, returning null if the
+    /// map fails.
+    ///
+    /// `align` must be a power of two. Objects are always at least page-aligned.
+    pub unsafe fn alloc_aligned(size: usize, align: usize) -> *mut u8 {
+        debug_assert!(align.is_power_of_two());
+        #[cfg(feature = "fault_injection")]
+        {
+            if super::inject_failure() {
+                return ptr::null_mut();
+            }
+        }
+        let align = cmp::max(align, PAGE_SIZE as usize);
+        // The mapping is page-aligned, so rounding up past its base leaves at least a page for
+        // the header, and at most `align` bytes of padding. Sizes so large that the mapping's
+        // length would overflow (and so be too short for the object) fail instead.
+        let len = match size.checked_add(align) {
+            Some(len) if len <= MAX_LEN => len,
+            _ => {
+                warn!("large allocation of {} bytes (align {}) failed: size too large",
+                      size,
+                      align);
+                return ptr::null_mut();
+            }
+        };
+        let mem = match fallible_map(len) {
+            Some(mem) => mem,
+            None => {
+                warn!("large allocation of {} bytes (align {}) failed: could not map {} bytes",
+                      size,
+                      align,
+                      len);
+                return ptr::null_mut();
+            }
+        };
+        let res = ((mem as usize + align) & !(align - 1)) as *mut u8;
+        ptr::write(header(res),
+                   Header {
+                       base: mem,
+                       len: len,
+                       align: align,
+                   });
+
+        // begin extra debugging information
+        debug_assert!(!mem.is_null());
+        debug_assert!(header(res) as usize >= mem as usize);
+        let upage = PAGE_SIZE as usize;
+        debug_assert_eq!(mem as usize % upage, 0);
+        debug_assert_eq!(res as usize % align, 0);
+        debug_assert!(res as usize + size <= mem as usize + len);
+        #[cfg(test)]
+        SEEN_PTRS.with(|hs| hs.borrow_mut().insert(mem, len));
+        // end extra debugging information
+
+        res
+    }
+
+    pub unsafe fn free(item: *mut u8) {
+        let Header { base: base_ptr, len: size, .. } = ptr::read(header(item));
+
+        // begin extra debugging information:
+        #[cfg(debug_assertions)]
+        {
+            ptr::write_volatile(item, 10);
+        }
+        debug_assert_eq!(base_ptr as usize % PAGE_SIZE as usize, 0);
+        #[cfg(test)]
+        {
+            SEEN_PTRS.with(|hm| {
+                let mut hmap = hm.borrow_mut();
+                {
+                    if let Some(len) = hmap.get(&base_ptr) {
+                        assert_eq!(*len, size);
+                    }
+                }
+                hmap.remove(&base_ptr);
+            });
+        }
+        // end extra debugging information
+
+        unmap(base_ptr, size);
+    }
+
+    /// Grow or shrink the mapping containing `item` so that it has room for `new_size` bytes,
+    /// returning the (possibly moved) object, or null if the mapping could not be resized.
+    ///
+    /// On Linux, this uses `mremap`, which can often extend the mapping in place and otherwise
+    /// moves it without copying. Elsewhere, the mapping is copied. Either way, the object keeps
+    /// its offset within the mapping, and the `Header` moves along with it.
+    ///
+    /// The new mapping is only guaranteed to be page-aligned, so an object aligned to more than a
+    /// page may be misaligned once its mapping moves. Such mappings are resized with `align`
+    /// bytes of slack, and if the object is misaligned, it is copied to the aligned address within
+    /// the slack. Either way, the object keeps the alignment it was allocated with.
+    pub unsafe fn realloc(item: *mut u8, new_size: usize) -> *mut u8 {
+        let Header { base, len, align } = ptr::read(header(item));
+        let page = PAGE_SIZE as usize;
+        let round_up = |n: usize| (n + page - 1) & !(page - 1);
+        let offset = item as usize - base as usize;
+        let old_len = round_up(len);
+        // The header takes up at least the first page, so with `align - page` more bytes there
+        // is room for the object at the first aligned address past the header.
+        let slack = align - page;
+        let new_len = match offset.checked_add(new_size) {
+            Some(new_len) if new_len <= MAX_LEN - page - slack => round_up(new_len) + slack,
+            _ => return ptr::null_mut(),
+        };
+        let new_base = match remap(base, old_len, new_len) {
+            Some(new_base) => new_base,
+            None => return ptr::null_mut(),
+        };
+        let mut res = new_base.offset(offset as isize);
+        if res as usize % align != 0 {
+            let aligned = ((new_base as usize + align) & !(align - 1)) as *mut u8;
+            ptr::copy(res, aligned, cmp::min(old_len - offset, new_size));
+            res = aligned;
+        }
+        ptr::write(header(res),
+                   Header {
+                       base: new_base,
+                       len: new_len,
+                       align: align,
+                   });
+
+        // begin extra debugging information
+        debug_assert_eq!(new_base as usize % page, 0);
+        debug_assert_eq!(res as usize % align, 0);
+        debug_assert!(res as usize + new_size <= new_base as usize + new_len);
+        #[cfg(test)]
+        SEEN_PTRS.with(|hs| {
+            let mut hmap = hs.borrow_mut();
+            hmap.remove(&base);
+            hmap.insert(new_base, new_len);
+        });
+        // end extra debugging information
+
+        res
+    }
+
+    /// Shrink the mapping containing `item` so that it only has room for `new_size` bytes.
+    ///
+    /// The pages past the end of the page containing the object's new last byte are unmapped.
+    /// The object does not move, and `new_size` must be no larger than its usable size.
+    pub unsafe fn shrink(item: *mut u8, new_size: usize) {
+        let Header { base, len, align } = ptr::read(header(item));
+        let page = PAGE_SIZE as usize;
+        let round_up = |n: usize| (n + page - 1) & !(page - 1);
+        let old_len = round_up(len);
+        let new_len = round_up(item as usize - base as usize + new_size);
+        debug_assert!(new_len <= old_len);
+        if new_len >= old_len {
+            return;
+        }
+        unmap(base.offset(new_len as isize), old_len - new_len);
+        ptr::write(header(item),
+                   Header {
+                       base: base,
+                       len: new_len,
+                       align: align,
+                   });
+        #[cfg(test)]
+        SEEN_PTRS.with(|hs| hs.borrow_mut().insert(base, new_len));
+    }
+
+    /// Set the permissions of the pages holding the object `item` to `perms`, returning whether
+    /// this succeeded.
+    ///
+    /// Objects are mapped readable, writable, and executable. The `Header`, which lives in the
+    /// page before `item`, keeps those permissions so that the object can still be freed.
+    pub unsafe fn protect(item: *mut u8, perms: Permissions) -> bool {
+        let Header { base, len, .. } = ptr::read(header(item));
+        let mut prot = libc::PROT_NONE;
+        if perms.read {
+            prot |= libc::PROT_READ;
+        }
+        if perms.write {
+            prot |= libc::PROT_WRITE;
+        }
+        if perms.exec {
+            prot |= libc::PROT_EXEC;
+        }
+        let end = base as usize + len;
+        libc::mprotect(item as *mut libc::c_void, end - item as usize, prot) == 0
+    }
+
+    /// Check that `item` plausibly points to the start of a large allocation.
+    ///
+    /// Objects are always page-aligned, and their `Header` must describe a page-aligned mapping
+    /// that contains them. Large allocations are not tracked, so the page holding the `Header` is
+    /// checked to be mapped before it is read.
+    #[cfg(feature = "checked_free")]
+    pub unsafe fn is_valid(item: *mut u8) -> bool {
+        let page = PAGE_SIZE as usize;
+        if item.is_null() || item as usize % page != 0 || !is_mapped(item.offset(-PAGE_SIZE)) {
+            return false;
+        }
+        let Header { base, len, .. } = ptr::read(header(item));
+        let (base, item) = (base as usize, item as usize);
+        base % page == 0 && base < item && item - base < len
+    }
+
+    /// Return whether the page starting at `page` is mapped.
+    #[cfg(feature = "checked_free")]
+    unsafe fn is_mapped(page: *mut u8) -> bool {
+        // mincore fails with ENOMEM if any part of the range is unmapped.
+        let mut vec = 0u8;
+        libc::mincore(page as *mut libc::c_void,
+                      PAGE_SIZE as usize,
+                      &mut vec as *mut u8 as *mut _) == 0
+    }
+
+    /// Get the alignment that `item` was allocated with.
+    ///
+    /// This is the `align` passed to `alloc_aligned`, rounded up to a page; `realloc` preserves
+    /// it.
+    pub unsafe fn alignment(item: *mut u8) -> usize {
+        (*header(item)).align
+    }
+
+    /// Get the number of usable bytes starting at `item`, along with the base of its mapping.
+    ///
+    /// The mapping extends to the end of the page containing its last byte, so all of that page
+    /// is usable.
+    pub unsafe fn get_commitment(item: *mut u8) -> (usize, *mut u8) {
+        let hdr = &*header(item);
+        let page = PAGE_SIZE as usize;
+        let end = (hdr.base as usize + hdr.len + page - 1) & !(page - 1);
+        (end - item as usize, hdr.base)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -887,26 +3617,1236 @@ mod tests {
     use std::ptr::{write_bytes, write_volatile};
 
     #[test]
-    fn general_alloc_basic_global_single_threaded() {
+    fn general_alloc_basic_global_single_threaded() {
+        let _ = env_logger::init();
+        for size in ((1 << 13) - 8)..((1 << 13) + 1) {
+            unsafe {
+                let item = global::alloc(size * 8);
+                write_volatile(item, 10);
+                global::free(item);
+            }
+        }
+    }
+
+    #[test]
+    fn general_alloc_basic_clone_single_threaded() {
+        let _ = env_logger::init();
+        let da_c = DynamicAllocator::new();
+        let mut da = da_c.clone();
+        for size in ((1 << 13) - 8)..((1 << 13) + 1) {
+            unsafe {
+                let item = da.alloc(size * 8);
+                write_volatile(item, 10);
+                da.free(item);
+            }
+        }
+    }
+
+    #[test]
+    fn alloc_nonnull() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        for (i, size) in [8, 24, 1 << 10, 1 << 20].iter().enumerate() {
+            let item = da.alloc_nonnull(*size).expect("allocation should succeed");
+            unsafe {
+                fill(item.as_ptr(), i, *size);
+                check_fill(item.as_ptr(), i, *size);
+                da.free(item.as_ptr());
+            }
+        }
+        let item = global::alloc_nonnull(64).expect("allocation should succeed");
+        unsafe {
+            fill(item.as_ptr(), 0, 64);
+            check_fill(item.as_ptr(), 0, 64);
+            global::free(item.as_ptr());
+        }
+    }
+
+    #[test]
+    fn try_alloc_basic() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        for size in &[8, 24, 1 << 10, 1 << 20] {
+            unsafe {
+                let item = da.try_alloc(*size).expect("allocation should succeed");
+                write_bytes(item.as_ptr(), 0xFF, *size);
+                da.free(item.as_ptr());
+            }
+        }
+        // An allocation that cannot possibly be mapped should fail gracefully. On 64-bit Linux,
+        // overly large maps fail with ENOMEM rather than panicking.
+        #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+        unsafe {
+            assert_eq!(da.try_alloc(1 << 62), Err(Exhausted));
+        }
+    }
+
+    #[test]
+    fn magazine_bytes_scale_with_cpus() {
+        assert_eq!(default_magazine_bytes(1), 512 << 10);
+        assert_eq!(default_magazine_bytes(8), 512 << 10);
+        assert_eq!(default_magazine_bytes(16), 1 << 20);
+        assert_eq!(default_magazine_bytes(24), 2 << 20);
+        assert_eq!(default_magazine_bytes(64), 4 << 20);
+        assert_eq!(default_magazine_bytes(1024), 4 << 20);
+    }
+
+    #[test]
+    fn alloc_excess_usable_size() {
+        let _ = env_logger::init();
+        for &strategy in &[Strategy::Tiered, Strategy::PowersOfTwo] {
+            let mut da = DynamicAllocator::with_size_classes(strategy);
+            for &size in &[1, 8, 9, 24, 100, 1000, 4097, 1 << 20, (1 << 20) + 1, 3 << 20] {
+                unsafe {
+                    let (item, usable) = da.alloc_excess(size);
+                    assert!(!item.is_null());
+                    assert!(usable >= size, "size={} usable={}", size, usable);
+                    assert_eq!(da.usable_size(item), usable);
+                    // all of the excess must be writable
+                    write_bytes(item, 0xFF, usable);
+                    da.free(item);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn class_histogram_counts() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let items: Vec<_> = (0..3).map(|_| da.alloc(24)).chain(Some(da.alloc(1000))).collect();
+            for item in items {
+                da.free(item);
+            }
+        }
+        let hist = da.class_histogram();
+        assert!(hist.contains(&(32, 3)), "{:?}", hist);
+        assert!(hist.contains(&(1024, 1)), "{:?}", hist);
+        assert_eq!(hist.iter().map(|&(_, c)| c).sum::<u64>(), 4);
+        // a fresh clone starts from zero
+        assert!(da.clone().class_histogram().iter().all(|&(_, c)| c == 0));
+
+        let mut buf = [(0, 0); 4];
+        assert_eq!(da.class_histogram_into(&mut buf), hist.len());
+        assert_eq!(&buf[..], &hist[..4]);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn class_histogram_during_churn() {
+        // Reading the statistics while allocating must not deadlock or recurse into the
+        // allocator.
+        let _ = env_logger::init();
+        const N_THREADS: usize = 4;
+        const N_ITERS: usize = 1 << 16;
+        let threads: Vec<_> = (0..N_THREADS)
+            .map(|t| {
+                thread::spawn(move || unsafe {
+                    let mut buf = [(0, 0); 64];
+                    let mut last_total = 0;
+                    let mut rng = XorShift(t as u64 + 1);
+                    for i in 0..N_ITERS {
+                        let size = random_size(&mut rng);
+                        let item = global::alloc(size);
+                        write_bytes(item, 0xFF, size);
+                        global::free(item);
+                        if i % 64 == 0 {
+                            let n = global::class_histogram_into(&mut buf);
+                            assert!(n <= buf.len());
+                            let total = buf[..n].iter().map(|&(_, c)| c).sum::<u64>();
+                            assert!(total >= last_total);
+                            last_total = total;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("threads should exit successfully");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn thread_stats() {
+        let _ = env_logger::init();
+        const N_THREADS: usize = 4;
+        const SIZE: usize = 1024;
+        let threads: Vec<_> = (0..N_THREADS)
+            .map(|t| {
+                thread::spawn(move || unsafe {
+                    // Each thread allocates a different amount; the counters must only reflect
+                    // this thread's allocations.
+                    let n = (t + 1) * 100;
+                    let (alloc0, free0) = global::thread_stats();
+                    let items: Vec<_> = (0..n).map(|_| global::alloc(SIZE)).collect();
+                    let (alloc1, free1) = global::thread_stats();
+                    let allocated = alloc1 - alloc0;
+                    assert!(allocated >= (n * SIZE) as u64, "{} < {}", allocated, n * SIZE);
+                    assert!(allocated <= (2 * n * SIZE) as u64,
+                            "{} > {}",
+                            allocated,
+                            2 * n * SIZE);
+                    assert_eq!(free1, free0);
+                    for item in items {
+                        global::free(item);
+                    }
+                    let (alloc2, free2) = global::thread_stats();
+                    assert_eq!(alloc2, alloc1);
+                    assert_eq!(free2 - free1, allocated);
+
+                    // A failed realloc leaves the object live, so nothing is counted.
+                    let item = global::alloc(SIZE);
+                    let (alloc3, free3) = global::thread_stats();
+                    assert!(global::realloc(item, usize::max_value()).is_null());
+                    assert_eq!(global::thread_stats(), (alloc3, free3));
+                    global::free(item);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("threads should exit successfully");
+        }
+    }
+
+    #[test]
+    fn small_creek_spills_to_large() {
+        let _ = env_logger::init();
+        const TOTAL: usize = 64 << 20;
+        const SIZE: usize = 64 << 10;
+        let mut da = DynamicAllocatorBuilder::default().creek_size(TOTAL, 4 << 20).build();
+        unsafe {
+            // Allocate twice as much as fits in the region: the rest is served by large_alloc.
+            let items: Vec<_> = (0..2 * TOTAL / SIZE)
+                .map(|i| {
+                         let item = da.alloc(SIZE);
+                         assert!(!item.is_null());
+                         fill(item, i, SIZE);
+                         item
+                     })
+                .collect();
+            let spilled = items.iter().filter(|&&item| !da.owns(item)).count();
+            assert!(spilled >= TOTAL / SIZE, "only {} allocations spilled", spilled);
+            // A size class that is first used once the region is exhausted spills as well.
+            let small = da.alloc(24);
+            assert!(!small.is_null() && !da.owns(small));
+            fill(small, 0, 24);
+            for (i, &item) in items.iter().enumerate() {
+                check_fill(item, i, SIZE);
+                da.free(item);
+            }
+            check_fill(small, 0, 24);
+            da.free(small);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "chunk size must not exceed creek size")]
+    fn creek_size_chunk_too_large() {
+        DynamicAllocatorBuilder::default().creek_size(1 << 21, 1 << 22);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be at least twice the page size of 4194304 bytes")]
+    fn creek_size_one_chunk() {
+        DynamicAllocatorBuilder::default().creek_size(4 << 20, 4 << 20);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be at least twice the page size of 2097152 bytes")]
+    fn creek_size_below_default_page_size() {
+        // The chunk is raised to the 2MB default page size.
+        DynamicAllocatorBuilder::default().creek_size(1 << 20, 1 << 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be at least twice the page size of 8388608 bytes")]
+    fn creek_size_raised_by_large_threshold() {
+        // The largest size class of 4MB needs 8MB pages.
+        DynamicAllocatorBuilder::default()
+            .creek_size(8 << 20, 1 << 16)
+            .large_threshold(MAX_CLASS_SIZE)
+            .build();
+    }
+
+    #[test]
+    fn creek_size_minimum() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocatorBuilder::default().creek_size(8 << 20, 4 << 20).build();
+        unsafe {
+            let item = da.alloc(64);
+            assert!(!item.is_null());
+            fill(item, 0, 64);
+            check_fill(item, 0, 64);
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn bounded_backing_region() {
+        let _ = env_logger::init();
+        const REGION_SIZE: usize = 32 << 20;
+        const SIZE: usize = 64 << 10;
+        let region = mmap::map(REGION_SIZE);
+        let region_end = region as usize + REGION_SIZE;
+        {
+            let mut da = unsafe { DynamicAllocator::with_backing(region, REGION_SIZE) };
+            let mut items = Vec::new();
+            unsafe {
+                while let Ok(item) = da.try_alloc(SIZE) {
+                    let item = item.as_ptr();
+                    assert!(item > region && item as usize + SIZE <= region_end);
+                    write_bytes(item, 0xFF, SIZE);
+                    items.push(item);
+                    assert!(items.len() * SIZE <= REGION_SIZE, "allocated past the region");
+                }
+                assert!(!items.is_empty());
+                // failure is not sticky: freed objects can be allocated again.
+                da.free(items.pop().unwrap());
+                items.push(da.try_alloc(SIZE).expect("reallocating a freed object").as_ptr());
+                for item in items {
+                    da.free(item);
+                }
+            }
+        }
+        unsafe { mmap::unmap(region, REGION_SIZE) };
+    }
+
+    #[test]
+    fn remote_free_deep_pipe() {
+        // Objects allocated on one thread and freed on another end up in the pool of available
+        // Slags; make sure that works with a deeper pool and small magazines.
+        let _ = env_logger::init();
+        use std::sync::mpsc::channel;
+        const N_PAIRS: usize = 4;
+        const N_ITEMS: usize = 1 << 16;
+        let alloc = DynamicAllocatorBuilder::default()
+            .pipe_depth(64)
+            .batch_size(4096)
+            .build();
+        let mut threads = Vec::new();
+        for t in 0..N_PAIRS {
+            let (send, recv) = channel::<(usize, usize)>();
+            let mut producer = alloc.clone();
+            let mut consumer = alloc.clone();
+            threads.push(thread::spawn(move || unsafe {
+                let mut rng = XorShift(t as u64 + 1);
+                for _ in 0..N_ITEMS {
+                    let size = random_size(&mut rng);
+                    let item = producer.alloc(size);
+                    write_bytes(item, (size % 256) as u8, size);
+                    send.send((item as usize, size)).unwrap();
+                }
+            }));
+            threads.push(thread::spawn(move || unsafe {
+                for (item, size) in recv {
+                    let item = item as *mut u8;
+                    assert_eq!(*item, (size % 256) as u8);
+                    assert_eq!(*item.offset(size as isize - 1), (size % 256) as u8);
+                    consumer.free(item);
+                }
+            }));
+        }
+        for t in threads {
+            t.join().expect("threads should exit successfully");
+        }
+    }
+
+    #[test]
+    fn try_clone_exhausted_region() {
+        // Cloning a handle does not need any pages, so it succeeds even once the backing region
+        // is exhausted. The new handle can then use pages freed through the original.
+        let _ = env_logger::init();
+        const REGION_SIZE: usize = 16 << 20;
+        const SIZE: usize = 64 << 10;
+        let region = mmap::map(REGION_SIZE);
+        {
+            let mut da = unsafe { DynamicAllocator::with_backing(region, REGION_SIZE) };
+            let mut items = Vec::new();
+            unsafe {
+                while let Ok(item) = da.try_alloc(SIZE) {
+                    items.push(item.as_ptr());
+                }
+                let mut clone = da.try_clone().expect("cloning should not need backing memory");
+                assert!(clone.owns(items[0]));
+                for item in items.drain(..) {
+                    da.free(item);
+                }
+                let item = clone.try_alloc(SIZE).expect("freed pages should be reusable");
+                clone.free(item.as_ptr());
+            }
+        }
+        unsafe { mmap::unmap(region, REGION_SIZE) };
+    }
+
+    #[test]
+    fn uninitialized_class_exhausted_region() {
+        // A size class that is first used once the backing region is exhausted fails cleanly
+        // rather than panicking.
+        let _ = env_logger::init();
+        const REGION_SIZE: usize = 16 << 20;
+        const SIZE: usize = 64 << 10;
+        let region = mmap::map(REGION_SIZE);
+        {
+            let mut da = unsafe { DynamicAllocator::with_backing(region, REGION_SIZE) };
+            let mut items = Vec::new();
+            unsafe {
+                while let Ok(item) = da.try_alloc(SIZE) {
+                    items.push(item.as_ptr());
+                }
+                assert!(da.try_alloc(1024).is_err());
+                assert!(da.alloc(1024).is_null());
+                for item in items {
+                    da.free(item);
+                }
+            }
+        }
+        unsafe { mmap::unmap(region, REGION_SIZE) };
+    }
+
+    #[test]
+    fn cross_thread_free_after_drop() {
+        // Objects allocated through one handle must be freeable through another handle on another
+        // thread, even after the allocating handle is dropped. Run several rounds within a bounded
+        // region: if any objects (or the Slags holding them) leaked, later rounds would exhaust
+        // it.
+        let _ = env_logger::init();
+        use std::sync::mpsc::channel;
+        const REGION_SIZE: usize = 256 << 20;
+        const N_ROUNDS: usize = 16;
+        const N_ITEMS: usize = 1 << 12;
+        let region = mmap::map(REGION_SIZE);
+        {
+            let alloc = unsafe { DynamicAllocator::with_backing(region, REGION_SIZE) };
+            for round in 0..N_ROUNDS {
+                let (send, recv) = channel::<(usize, usize)>();
+                let mut producer = alloc.clone();
+                let mut consumer = alloc.clone();
+                let a = thread::spawn(move || unsafe {
+                    let mut rng = XorShift(round as u64 + 1);
+                    for _ in 0..N_ITEMS {
+                        let size = 1 + rng.below(8 << 10);
+                        let item = producer
+                            .try_alloc(size)
+                            .expect("backing region should not be exhausted")
+                            .as_ptr();
+                        write_bytes(item, (size % 256) as u8, size);
+                        send.send((item as usize, size)).unwrap();
+                    }
+                    drop(producer);
+                });
+                let b = thread::spawn(move || unsafe {
+                    // Wait for the producer to finish (and drop its handle) before freeing.
+                    let items: Vec<_> = recv.iter().collect();
+                    assert_eq!(items.len(), N_ITEMS);
+                    let mut ptrs: Vec<_> = items.iter().map(|&(item, _)| item).collect();
+                    ptrs.sort();
+                    ptrs.dedup();
+                    assert_eq!(ptrs.len(), N_ITEMS, "live objects should not overlap");
+                    for (item, size) in items {
+                        let item = item as *mut u8;
+                        assert_eq!(*item, (size % 256) as u8);
+                        assert_eq!(*item.offset(size as isize - 1), (size % 256) as u8);
+                        consumer.free(item);
+                    }
+                });
+                a.join().expect("producer should exit successfully");
+                b.join().expect("consumer should exit successfully");
+            }
+        }
+        unsafe { mmap::unmap(region, REGION_SIZE) };
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn large_alloc_permissions() {
+        let _ = env_logger::init();
+        // Find the permissions of the mapping containing `ptr` in /proc/self/maps.
+        fn mapping_perms(ptr: *mut u8) -> String {
+            use std::fs::File;
+            use std::io::Read;
+            let mut maps = String::new();
+            File::open("/proc/self/maps")
+                .and_then(|mut f| f.read_to_string(&mut maps))
+                .expect("reading /proc/self/maps should succeed");
+            for line in maps.lines() {
+                let mut fields = line.split_whitespace();
+                let range = fields.next().unwrap();
+                let mut bounds = range.split('-').map(|x| usize::from_str_radix(x, 16).unwrap());
+                let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+                if start <= ptr as usize && (ptr as usize) < end {
+                    return fields.next().unwrap().to_string();
+                }
+            }
+            panic!("{:?} is not mapped", ptr)
+        }
+        let mut da = DynamicAllocatorBuilder::default()
+            .large_permissions(Permissions {
+                                   read: true,
+                                   write: false,
+                                   exec: true,
+                               })
+            .build();
+        unsafe {
+            let item = da.alloc(4 << 20);
+            assert!(!item.is_null());
+            assert_eq!(mapping_perms(item), "r-xp");
+            assert_eq!(*item.offset((4 << 20) - 1), 0);
+            // small objects are unaffected.
+            let small = da.alloc(64);
+            write_bytes(small, 1, 64);
+            assert_eq!(mapping_perms(small), "rwxp");
+            da.free(small);
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn owns_small_not_large() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        let mut other = DynamicAllocator::new();
+        unsafe {
+            let small = da.alloc(64);
+            let large = da.alloc(4 << 20);
+            let others = other.alloc(64);
+            assert!(da.owns(small));
+            assert!(da.clone().owns(small));
+            assert!(!da.owns(large));
+            assert!(!da.owns(others));
+            assert!(other.owns(others));
+            assert!(!da.owns(::std::ptr::null()));
+            da.free(small);
+            da.free(large);
+            other.free(others);
+        }
+    }
+
+    /// A xorshift PRNG, so that randomized tests are reproducible without external crates.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// The fill pattern for the `i`th byte of an object with tag `tag`.
+    fn fill_byte(tag: usize, i: usize) -> u8 {
+        (tag.wrapping_mul(31).wrapping_add(i) % 251) as u8
+    }
+
+    unsafe fn fill(ptr: *mut u8, tag: usize, size: usize) {
+        for i in 0..size {
+            *ptr.offset(i as isize) = fill_byte(tag, i);
+        }
+    }
+
+    unsafe fn check_fill(ptr: *mut u8, tag: usize, size: usize) {
+        for i in 0..size {
+            assert_eq!(*ptr.offset(i as isize),
+                       fill_byte(tag, i),
+                       "corrupted byte {} of object {:?} (tag {})",
+                       i,
+                       ptr,
+                       tag);
+        }
+    }
+
+    fn random_size(rng: &mut XorShift) -> usize {
+        match rng.below(100) {
+            0 => 1 + rng.below(2 << 20),
+            1...10 => 1 + rng.below(64 << 10),
+            _ => 1 + rng.below(2048),
+        }
+    }
+
+    #[test]
+    fn randomized_alloc_realloc_free() {
+        let _ = env_logger::init();
+        for &strategy in &[Strategy::Tiered, Strategy::PowersOfTwo] {
+            let mut da = DynamicAllocator::with_size_classes(strategy);
+            // seeded deterministically so that failures are reproducible
+            let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+            // (ptr, size, tag) for each live object
+            let mut live: Vec<(*mut u8, usize, usize)> = Vec::new();
+            for step in 0..20_000 {
+                unsafe {
+                    match rng.below(3) {
+                        0 if !live.is_empty() => {
+                            let (ptr, size, tag) = live.swap_remove(rng.below(live.len()));
+                            check_fill(ptr, tag, size);
+                            da.free(ptr);
+                        }
+                        1 if !live.is_empty() => {
+                            let i = rng.below(live.len());
+                            let (ptr, size, tag) = live[i];
+                            let new_size = random_size(&mut rng);
+                            let new_ptr = dispatch!(da.0, elf => elf.realloc(ptr, new_size));
+                            assert!(!new_ptr.is_null());
+                            check_fill(new_ptr, tag, ::std::cmp::min(size, new_size));
+                            fill(new_ptr, step, new_size);
+                            live[i] = (new_ptr, new_size, step);
+                        }
+                        _ => {
+                            let size = random_size(&mut rng);
+                            let ptr = da.alloc(size);
+                            assert!(!ptr.is_null());
+                            for &(other, other_size, _) in &live {
+                                let (a, b) = (ptr as usize, other as usize);
+                                assert!(a + size <= b || b + other_size <= a,
+                                        "{:?} (size {}) overlaps live object {:?} (size {})",
+                                        ptr,
+                                        size,
+                                        other,
+                                        other_size);
+                            }
+                            fill(ptr, step, size);
+                            live.push((ptr, size, step));
+                        }
+                    }
+                }
+            }
+            for (ptr, size, tag) in live {
+                unsafe {
+                    check_fill(ptr, tag, size);
+                    da.free(ptr);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_realloc_preserves_contents() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        let size = 4 << 20;
+        unsafe {
+            let item = da.alloc(size);
+            fill(item, 1, size);
+            let new_size = 16 << 20;
+            let new_item = dispatch!(da.0, elf => elf.realloc(item, new_size));
+            assert!(!new_item.is_null());
+            check_fill(new_item, 1, size);
+            assert!(large_alloc::get_commitment(new_item).0 >= new_size);
+            write_bytes(new_item, 0xFF, new_size);
+            da.free(new_item);
+        }
+    }
+
+    #[test]
+    fn dynamic_allocator_realloc() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            // Grow an object within the size classes, then into a large allocation, and then
+            // grow that large allocation in turn.
+            let mut item = da.alloc(16);
+            assert!(!item.is_null());
+            fill(item, 1, 16);
+            let mut old_size = 16;
+            for &size in &[100, 4000, 1 << 20, 8 << 20] {
+                item = da.realloc(item, size);
+                assert!(!item.is_null());
+                check_fill(item, 1, old_size);
+                fill(item, 1, size);
+                old_size = size;
+            }
+            assert!(!da.owns(item));
+
+            // A zero size frees the object, and a null pointer allocates a new one.
+            assert!(da.realloc(item, 0).is_null());
+            let item = da.realloc(ptr::null_mut(), 64);
+            assert!(!item.is_null());
+            assert!(da.owns(item));
+            fill(item, 2, 64);
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn compact() {
+        use std::collections::{HashMap, HashSet};
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            // Fill several slabs, and then free all but every 16th object, leaving every slab
+            // sparsely used.
+            const N: usize = 4096;
+            const SIZE: usize = 1024;
+            let mut kept = Vec::new();
+            for i in 0..N {
+                let item = da.alloc(SIZE);
+                assert!(!item.is_null());
+                fill(item, i, SIZE);
+                if i % 16 == 0 {
+                    kept.push((item, i));
+                } else {
+                    da.free(item);
+                }
+            }
+
+            let res = da.compact();
+            assert!(res.freed_slabs > 0, "{:?}", res);
+            assert!(!res.moved.is_empty());
+            let moved: HashMap<_, _> = res.moved.iter().cloned().collect();
+            assert_eq!(moved.len(), res.moved.len());
+            for entry in &mut kept {
+                if let Some(&new) = moved.get(&entry.0) {
+                    entry.0 = new;
+                }
+                check_fill(entry.0, entry.1, SIZE);
+            }
+            let addrs: HashSet<_> = kept.iter().map(|&(item, _)| item).collect();
+            assert_eq!(addrs.len(), kept.len());
+
+            // the allocator is still usable
+            let item = da.alloc(SIZE);
+            assert!(!addrs.contains(&item));
+            da.free(item);
+            for (item, _) in kept {
+                da.free(item);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "no other handles")]
+    fn compact_with_clone_panics() {
+        let mut da = DynamicAllocator::new();
+        let _other = da.clone();
+        unsafe {
+            da.compact();
+        }
+    }
+
+    #[test]
+    fn free_batch() {
+        let _ = env_logger::init();
+        use std::thread;
+        // Allocate objects from several size classes, in runs and interleaved, along with the odd
+        // large allocation.
+        unsafe fn alloc_all(da: &mut DynamicAllocator, n: usize) -> Vec<(*mut u8, usize)> {
+            let sizes = [16, 16, 16, 100, 100, 16, 2000, 100, 16];
+            (0..n)
+                .map(|i| {
+                         let size = if i % 1000 == 999 {
+                             2 << 20
+                         } else {
+                             sizes[i % sizes.len()]
+                         };
+                         let item = da.alloc(size);
+                         assert!(!item.is_null());
+                         fill(item, i, size);
+                         (item, size)
+                     })
+                .collect()
+        }
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            // More objects than fit in a magazine, so that some are freed in bulk.
+            let n = 20_000;
+            let items = alloc_all(&mut da, n);
+            for (i, &(item, size)) in items.iter().enumerate() {
+                check_fill(item, i, size);
+            }
+            let ptrs: Vec<_> = items.iter().map(|&(item, _)| item).collect();
+            da.free_batch(&ptrs);
+
+            // Objects allocated by another handle, so that all frees are remote.
+            let mut other = da.clone();
+            let items = thread::spawn(move || unsafe {
+                                          alloc_all(&mut other, n)
+                                              .into_iter()
+                                              .map(|(item, size)| (item as usize, size))
+                                              .collect::<Vec<_>>()
+                                      })
+                    .join()
+                    .unwrap();
+            let ptrs: Vec<_> = items.iter().map(|&(item, _)| item as *mut u8).collect();
+            da.free_batch(&ptrs);
+            da.free_batch(&[]);
+
+            // The freed memory can be reused without objects overlapping.
+            let items = alloc_all(&mut da, n);
+            for (i, &(item, size)) in items.iter().enumerate() {
+                check_fill(item, i, size);
+            }
+            for (item, _) in items {
+                da.free(item);
+            }
+        }
+    }
+
+    #[cfg(feature = "quarantine")]
+    #[test]
+    fn quarantine_poisons_and_delays_reuse() {
         let _ = env_logger::init();
-        for size in ((1 << 13) - 8)..((1 << 13) + 1) {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let item = da.alloc(64);
+            let size = da.usable_size(item);
+            fill(item, 1, size);
+            da.free(item);
+            for i in 0..size {
+                assert_eq!(*item.offset(i as isize), POISON_BYTE);
+            }
+            // The object stays quarantined until enough others of its class have been freed.
+            let n = QUARANTINE_BYTES / size;
+            let items: Vec<_> = (0..n).map(|_| da.alloc(64)).collect();
+            assert!(!items.contains(&item));
+            for item in items {
+                da.free(item);
+            }
+        }
+    }
+
+    #[cfg(feature = "quarantine")]
+    #[test]
+    fn quarantine_ring_evicts_oldest() {
+        // The quarantine does not allocate from the heap: each object size gets a fixed-capacity
+        // ring, which hands back its oldest object once it is full.
+        let mut q = Quarantine::new(2);
+        let size = QUARANTINE_BYTES / 4;
+        unsafe {
+            let ring = q.get_or_map(size).unwrap();
+            assert_eq!(ring.cap, 4);
+            for item in 1..5 {
+                assert_eq!(ring.push(item), None);
+            }
+            assert!(ring.contains(1));
+            for item in 5..11 {
+                assert_eq!(ring.push(item), Some(item - 4));
+            }
+            assert!(!ring.contains(6) && ring.contains(7) && ring.contains(10));
+        }
+        assert!(q.get(size).is_some() && q.get(2 * size).is_none());
+        let mut drained = Vec::new();
+        unsafe { q.drain(|s, item| drained.push((s, item))) };
+        assert_eq!(drained, vec![(size, 7), (size, 8), (size, 9), (size, 10)]);
+        assert_eq!(q.get(size).unwrap().len, 0);
+    }
+
+    #[cfg(feature = "quarantine")]
+    #[test]
+    #[should_panic(expected = "written to after being freed")]
+    fn quarantine_write_after_free() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let item = da.alloc(64);
+            da.free(item);
+            *item.offset(10) = 0;
+            // Allocating from the same size class checks its quarantine.
+            da.alloc(64);
+        }
+    }
+
+    #[cfg(feature = "quarantine")]
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn quarantine_double_free() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let item = da.alloc(64);
+            da.free(item);
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn metadata_unmapped_on_drop() {
+        // Clones share their size class metadata, so cloning must not map more of it, and it is
+        // only unmapped once the last clone is dropped.
+        let _ = env_logger::init();
+        // Return whether all of the pages in the given range are mapped; mincore fails with
+        // ENOMEM if any part of the range is unmapped.
+        #[cfg(target_os = "linux")]
+        fn is_mapped(ptr: *mut u8, len: usize) -> bool {
+            use super::super::libc;
+            let mut vec = vec![0u8; (len + 4095) / 4096];
+            unsafe { libc::mincore(ptr as *mut libc::c_void, len, vec.as_mut_ptr()) == 0 }
+        }
+        let da = DynamicAllocator::new();
+        let meta = dispatch!(ref da.0, elf => Arc::downgrade(&elf.meta));
+        #[cfg(target_os = "linux")]
+        let (meta_ptr, meta_len) = meta.upgrade().map(|m| (m.0 as *mut u8, m.1)).unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(is_mapped(meta_ptr, meta_len));
+        for _ in 0..1024 {
+            let mut clone = da.clone();
             unsafe {
-                let item = global::alloc(size * 8);
-                write_volatile(item, 10);
-                global::free(item);
+                let item = clone.alloc(16);
+                clone.free(item);
             }
+            let shared = meta.upgrade().unwrap();
+            assert!(dispatch!(ref clone.0, elf => Arc::ptr_eq(&elf.meta, &shared)));
         }
+        assert_eq!(meta.upgrade().map(|m| Arc::strong_count(&m)), Some(2));
+        // Dropping the clones left the metadata mapped for the original.
+        #[cfg(target_os = "linux")]
+        assert!(is_mapped(meta_ptr, meta_len));
+        mem::drop(da);
+        assert!(meta.upgrade().is_none());
+        #[cfg(target_os = "linux")]
+        assert!(!is_mapped(meta_ptr, meta_len));
     }
 
+    #[cfg(feature = "leak_check")]
     #[test]
-    fn general_alloc_basic_clone_single_threaded() {
+    fn report_leaks() {
         let _ = env_logger::init();
-        let da_c = DynamicAllocator::new();
-        let mut da = da_c.clone();
-        for size in ((1 << 13) - 8)..((1 << 13) + 1) {
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let small = da.alloc(16);
+            let large = da.alloc(4 << 20);
+            let leaked = da.alloc(32);
+            let moved = da.alloc(8);
+            let moved = dispatch!(da.0, elf => elf.realloc(moved, 1 << 10));
+            da.free(small);
+            da.free(large);
+            da.free(moved);
+            assert_eq!(da.report_leaks(), vec![(leaked, 32)]);
+            // Clones share the table.
+            let mut clone = da.clone();
+            clone.free(leaked);
+            assert!(da.report_leaks().is_empty());
+        }
+    }
+
+    #[cfg(feature = "fault_injection")]
+    #[test]
+    fn fault_injection() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        // Check both a size class and a large allocation.
+        for &size in &[16, 4 << 20] {
+            unsafe {
+                set_alloc_failure_countdown(2);
+                let first = da.try_alloc(size).unwrap();
+                assert!(da.try_alloc(size).is_err());
+                let third = da.try_alloc(size).unwrap();
+                da.free(first.as_ptr());
+                da.free(third.as_ptr());
+            }
+        }
+        // A countdown of zero disarms the injector.
+        set_alloc_failure_countdown(1);
+        set_alloc_failure_countdown(0);
+        unsafe {
+            let item = da.alloc(16);
+            assert!(!item.is_null());
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn word_size() {
+        let _ = env_logger::init();
+        // Returns the usable size of a fresh allocation of 'size' bytes.
+        unsafe fn usable(da: &mut DynamicAllocator, size: usize) -> usize {
+            let item = da.alloc(size);
+            assert!(!item.is_null());
+            let usable = da.usable_size(item);
+            assert!(usable >= size);
+            write_bytes(item, 0xFF, usable);
+            da.free(item);
+            usable
+        }
+
+        // Requests of up to the word size share a class, and the multiples of 16 start right
+        // after it.
+        let mut da = DynamicAllocatorBuilder::default().word_size(16).build();
+        unsafe {
+            let word = usable(&mut da, 16);
+            assert_eq!(usable(&mut da, 1), word);
+            assert_eq!(usable(&mut da, 9), word);
+            assert!(usable(&mut da, 17) < usable(&mut da, 33));
+            assert!(word < usable(&mut da, 17));
+        }
+        assert_eq!(tiered_max_key(25, 16), 1 << 20);
+
+        let mut da = DynamicAllocatorBuilder::default().word_size(4).build();
+        unsafe {
+            let word = usable(&mut da, 4);
+            assert_eq!(usable(&mut da, 1), word);
+            assert!(word < usable(&mut da, 5));
+        }
+    }
+
+    #[test]
+    fn slab_sizing() {
+        let _ = env_logger::init();
+        // Use just enough of each `Slag` for a handful of objects.
+        fn few_objects(object_size: usize) -> usize {
+            4 * object_size
+        }
+        fn whole_slag(_: usize) -> usize {
+            usize::max_value()
+        }
+        let slag_of = |da: &DynamicAllocator, item: *mut u8| {
+            let page_size = dispatch!(ref da.0, elf => elf.pages.backing_memory().page_size());
+            item as usize & !(page_size - 1)
+        };
+        let sizings = [(few_objects as fn(usize) -> usize, true),
+                       (whole_slag, false),
+                       (default_slab_sizing, false)];
+        for &(sizing, few) in &sizings {
+            let mut da = DynamicAllocatorBuilder::default().slab_sizing(sizing).build();
+            unsafe {
+                for &size in &[8, 64, 1000, 16 << 10, 100 << 10] {
+                    let items: Vec<_> = (0..16).map(|_| da.alloc(size)).collect();
+                    for (i, &item) in items.iter().enumerate() {
+                        assert!(!item.is_null());
+                        fill(item, i, size);
+                    }
+                    // With room for only a few objects per `Slag`, 16 objects span several.
+                    let mut slags: Vec<_> = items.iter().map(|&item| slag_of(&da, item)).collect();
+                    slags.sort();
+                    slags.dedup();
+                    if few {
+                        assert!(slags.len() > 1, "size {}: {:?}", size, slags);
+                    } else if size <= 1000 {
+                        assert_eq!(slags.len(), 1, "size {}: {:?}", size, slags);
+                    }
+                    for (i, &item) in items.iter().enumerate() {
+                        check_fill(item, i, size);
+                        da.free(item);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_threshold() {
+        let _ = env_logger::init();
+        assert_eq!(tiered_max_key(25, 8), 1 << 20);
+        for &strategy in &[Strategy::Tiered, Strategy::PowersOfTwo] {
+            for &threshold in &[4096, 1 << 21] {
+                let mut da = DynamicAllocatorBuilder::default()
+                    .size_classes(strategy)
+                    .large_threshold(threshold)
+                    .build();
+                unsafe {
+                    let below = da.alloc(threshold - 1);
+                    let at = da.alloc(threshold);
+                    assert!(da.owns(below), "{:?} {}", strategy, threshold);
+                    assert!(!da.owns(at), "{:?} {}", strategy, threshold);
+                    write_bytes(below, 0xFF, threshold - 1);
+                    write_bytes(at, 0xFF, threshold);
+                    da.free(below);
+                    da.free(at);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "debug_checks"))]
+    fn class_index_past_max_key() {
+        let multiples = Multiples::init(16, 4, |size| size);
+        let max = multiples.max_key();
+        assert_eq!(multiples.class_index(max), 3);
+        assert_eq!(multiples.class_index(max + 1), 3);
+        assert_eq!(multiples.class_index(max * 4), 3);
+        unsafe { assert_eq!(*multiples.get(max + 1), max) };
+
+        let powers = PowersOfTwo::init(16, 4, |size| size);
+        let max = powers.max_key();
+        assert_eq!(powers.class_index(max), 3);
+        assert_eq!(powers.class_index(max + 1), 3);
+        assert_eq!(powers.class_index(max * 4), 3);
+        unsafe { assert_eq!(*powers.get(max + 1), max) };
+
+        unsafe {
+            multiples.classes.destroy();
+            powers.classes.destroy();
+            #[cfg(feature = "stats")]
+            {
+                multiples.counts.destroy();
+                powers.counts.destroy();
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "debug_checks")]
+    #[should_panic]
+    fn class_index_past_max_key_panics() {
+        let multiples = Multiples::init(16, 4, |size| size);
+        let max = multiples.max_key();
+        multiples.class_index(max + 1);
+    }
+
+    #[test]
+    #[cfg(feature = "checked_free")]
+    fn free_checked_interior_pointers() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let small = da.alloc(64);
+            assert_eq!(da.free_checked(small.offset(8)),
+                       Err(InvalidPointer(small.offset(8))));
+            assert_eq!(da.free_checked(small), Ok(()));
+
+            const LARGE: usize = 8 << 20;
+            let large = da.alloc(LARGE);
+            write_bytes(large, 0, LARGE);
+            // neither an unaligned nor a page-aligned interior pointer has a valid header
+            for offset in &[8, 4096, 1 << 20] {
+                let interior = large.offset(*offset);
+                assert_eq!(da.free_checked(interior), Err(InvalidPointer(interior)));
+            }
+            // pointers just past the end of an unmapped region, or into a part of the backing
+            // memory that has never been used, are rejected without being read
+            let unmapped = mmap::map(2 << 20);
+            mmap::unmap(unmapped, 1 << 20);
+            let after_hole = unmapped.offset(1 << 20);
+            assert_eq!(da.free_checked(after_hole), Err(InvalidPointer(after_hole)));
+            mmap::unmap(after_hole, 1 << 20);
+            assert_eq!(da.free_checked(after_hole), Err(InvalidPointer(after_hole)));
+            let small = da.alloc(64);
+            let unused = small.offset(1 << 36);
+            assert_eq!(da.free_checked(unused), Err(InvalidPointer(unused)));
+            da.free(small);
+            assert_eq!(da.free_checked(large), Ok(()));
+        }
+    }
+
+    #[test]
+    fn foreach_visits_each_class_once() {
+        use std::cell::Cell;
+        for &n in &[1, 2, 3, 17, 64] {
+            // Each class records its object size, so visiting a class past the end of the array
+            // (which is zero-filled) or visiting a class twice would be detected.
+            let visited = Cell::new(0);
+            let multiples = Multiples::init(16, n, |size| size);
+            multiples.foreach(|x| unsafe {
+                assert_eq!(*x, 16 + visited.get() * MULTIPLE);
+                visited.set(visited.get() + 1);
+            });
+            assert_eq!(visited.get(), n);
+            assert_eq!(multiples.n_classes(), n);
+
+            visited.set(0);
+            let powers = PowersOfTwo::init(16, n, |size| size);
+            powers.foreach(|x| unsafe {
+                assert_eq!(*x, 16 << visited.get());
+                visited.set(visited.get() + 1);
+            });
+            assert_eq!(visited.get(), n);
+            assert_eq!(powers.n_classes(), n);
+
+            unsafe {
+                multiples.classes.destroy();
+                powers.classes.destroy();
+                #[cfg(feature = "stats")]
+                {
+                    multiples.counts.destroy();
+                    powers.counts.destroy();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drop_unusual_class_counts() {
+        let _ = env_logger::init();
+        fn exercise(mut da: DynamicAllocator) {
+            unsafe {
+                for size in &[1, 8, 100, 4096, 1 << 20] {
+                    let item = da.alloc(*size);
+                    write_bytes(item, 0xFF, *size);
+                    da.free(item);
+                }
+            }
+        }
+        for &n in &[2, 3, 30] {
+            let elf = ElfMalloc::with_n_classes(n, 8, CacheParams::default(), None);
+            exercise(DynamicAllocator(SizeClasses::Tiered(elf), None));
+        }
+        for &n in &[1, 2, 20] {
+            let elf = ElfMalloc::powers_of_two(n, CacheParams::default(), None);
+            exercise(DynamicAllocator(SizeClasses::PowersOfTwo(elf), None));
+        }
+    }
+
+    #[test]
+    fn background_dirty() {
+        let _ = env_logger::init();
+        for &strategy in &[Strategy::Tiered, Strategy::PowersOfTwo] {
+            let mut da = DynamicAllocatorBuilder::default()
+                .size_classes(strategy)
+                .dirty_policy(DirtyPolicy::Background)
+                .build();
+            let mut items = Vec::new();
+            unsafe {
+                // Fill some objects with zeros and others with a pattern. Dirtying a page must
+                // not change either, even if the page has already been handed out.
+                for (i, size) in (1..1000).map(|i| i * 64).enumerate() {
+                    let item = da.alloc(size);
+                    if i % 2 == 0 {
+                        write_bytes(item, 0, size);
+                    } else {
+                        fill(item, i, size);
+                    }
+                    items.push((item, size));
+                }
+                // Messages to the background thread are handled in order, so once `trim` returns,
+                // the pages sent to it by the allocations above have been dirtied.
+                global::trim();
+                for (i, &(item, size)) in items.iter().enumerate() {
+                    if i % 2 == 0 {
+                        assert!((0..size).all(|j| *item.offset(j as isize) == 0),
+                                "object {} was modified",
+                                i);
+                    } else {
+                        check_fill(item, i, size);
+                    }
+                }
+                for (item, _) in items {
+                    da.free(item);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn powers_of_two_all_sizes() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::with_size_classes(Strategy::PowersOfTwo);
+        let mut items = Vec::new();
+        for shift in 0..22 {
+            let base = 1 << shift;
+            for size in &[base, base + 1, base + base / 2] {
+                unsafe {
+                    let item = da.alloc(*size);
+                    write_bytes(item, items.len() as u8, *size);
+                    items.push((item, *size));
+                }
+            }
+        }
+        // check that no allocations overlapped
+        for (i, &(item, size)) in items.iter().enumerate() {
+            for j in 0..size {
+                unsafe {
+                    assert_eq!(*item.offset(j as isize), i as u8);
+                }
+            }
+        }
+        for (item, _) in items {
             unsafe {
-                let item = da.alloc(size * 8);
-                write_volatile(item, 10);
                 da.free(item);
             }
         }
@@ -1002,6 +4942,335 @@ mod tests {
         }
     }
 
+    #[cfg(target_os = "linux")]
+    fn resident_pages() -> usize {
+        use std::fs::File;
+        use std::io::Read;
+        let mut statm = String::new();
+        File::open("/proc/self/statm")
+            .and_then(|mut f| f.read_to_string(&mut statm))
+            .expect("reading /proc/self/statm should succeed");
+        statm.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn decommit_idle_sweep_releases_memory() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 1 << 16;
+        const SIZE: usize = 1 << 10;
+        let mut da = DynamicAllocatorBuilder::default()
+            .decommit_idle(Duration::from_secs(3600))
+            .build();
+        unsafe {
+            let ptrs: Vec<*mut u8> = (0..N_ITEMS).map(|_| da.alloc(SIZE)).collect();
+            for p in &ptrs {
+                write_bytes(*p, 0xFF, SIZE);
+            }
+            for p in ptrs {
+                da.free(p);
+            }
+        }
+        let before = resident_pages();
+        // the first sweep marks pages as idle, the second uncommits them
+        da.sweep_idle();
+        da.sweep_idle();
+        let after = resident_pages();
+        // require at least half of the working set to have been released
+        let working_set_pages = N_ITEMS * SIZE / 4096;
+        assert!(after + working_set_pages / 2 < before,
+                "before={} after={}",
+                before,
+                after);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn purge_releases_memory() {
+        let _ = env_logger::init();
+        const N_ITEMS: usize = 1 << 16;
+        const SIZE: usize = 1 << 10;
+        for &strategy in &[Strategy::Tiered, Strategy::PowersOfTwo] {
+            let mut da = DynamicAllocatorBuilder::default().size_classes(strategy).build();
+            unsafe {
+                let ptrs: Vec<*mut u8> = (0..N_ITEMS).map(|_| da.alloc(SIZE)).collect();
+                for p in &ptrs {
+                    write_bytes(*p, 0xFF, SIZE);
+                }
+                for p in ptrs {
+                    da.free(p);
+                }
+            }
+            let before = resident_pages();
+            da.purge();
+            let after = resident_pages();
+            // require at least half of the working set to have been released
+            let working_set_pages = N_ITEMS * SIZE / 4096;
+            assert!(after + working_set_pages / 2 < before,
+                    "before={} after={}",
+                    before,
+                    after);
+
+            // the allocator remains usable after a purge
+            unsafe {
+                let ptrs: Vec<*mut u8> = (0..N_ITEMS).map(|_| da.alloc(SIZE)).collect();
+                for p in &ptrs {
+                    write_bytes(*p, 0xFF, SIZE);
+                }
+                for p in ptrs {
+                    da.free(p);
+                }
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn global_trim_releases_memory() {
+        let _ = env_logger::init();
+        use std::thread;
+
+        const N_THREADS: usize = 4;
+        const N_ITEMS: usize = 1 << 14;
+        const SIZE: usize = 1 << 10;
+        let mut threads = Vec::with_capacity(N_THREADS);
+        for _ in 0..N_THREADS {
+            threads.push(thread::spawn(|| unsafe {
+                let ptrs: Vec<*mut u8> = (0..N_ITEMS).map(|_| global::alloc(SIZE)).collect();
+                for p in &ptrs {
+                    write_bytes(*p, 0xFF, SIZE);
+                }
+                for p in ptrs {
+                    global::free(p);
+                }
+            }));
+        }
+        for t in threads {
+            t.join().expect("threads should exit successfully");
+        }
+
+        // `trim` waits for the background thread to clean up after the exited threads
+        let before = resident_pages();
+        global::trim();
+        let after = resident_pages();
+        // require at least a quarter of the working set to have been released
+        let working_set_pages = N_THREADS * N_ITEMS * SIZE / 4096;
+        assert!(after + working_set_pages / 4 < before,
+                "before={} after={}",
+                before,
+                after);
+    }
+
+    #[test]
+    fn global_page_size() {
+        let _ = env_logger::init();
+        let page_size = global::page_size();
+        assert_eq!(page_size, slab_page_size(tiered_max_key(25, mem::size_of::<usize>())));
+        assert_eq!(page_size, global::page_size());
+        unsafe {
+            // the Slag holding a small object is found by rounding down to the page size
+            let item = global::alloc(8);
+            let slag = &*Slag::find(item, page_size);
+            assert!(slag.get_metadata().object_size >= 8);
+            assert!(slag.get_metadata().object_size < 64);
+            global::free(item);
+        }
+    }
+
+    #[test]
+    fn global_realloc_preserves_alignment() {
+        use alloc::allocator::{Alloc, Layout};
+        let _ = env_logger::init();
+        unsafe {
+            // global::realloc keeps the alignment of memory allocated by ElfGlobal, both within
+            // the size classes and when the object becomes large.
+            let layout = Layout::from_size_align(48, 64).unwrap();
+            let mut item = (&global::ElfGlobal).alloc(layout).unwrap();
+            assert_eq!(item as usize % 64, 0);
+            fill(item, 1, 48);
+            let mut old_size = 48;
+            for &size in &[100, 1000, 5000, 100 << 10, 2 << 20] {
+                item = global::realloc(item, size);
+                assert!(!item.is_null());
+                assert_eq!(item as usize % 64, 0, "size {}: {:?}", size, item);
+                check_fill(item, 1, old_size);
+                fill(item, 1, size);
+                old_size = size;
+            }
+            global::free(item);
+
+            // Through the Alloc API, with the same alignment on both sides.
+            let old = Layout::from_size_align(64, 64).unwrap();
+            let new = Layout::from_size_align(300, 64).unwrap();
+            let item = (&global::ElfGlobal).alloc(old.clone()).unwrap();
+            let item = (&global::ElfGlobal).realloc(item, old, new.clone()).unwrap();
+            assert_eq!(item as usize % 64, 0);
+            (&global::ElfGlobal).dealloc(item, new);
+        }
+    }
+
+    #[test]
+    fn realloc_preserves_large_alignment() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            // Whether or not the mapping can be resized in place, the new object must keep the
+            // alignment that the original was allocated with.
+            let item = large_alloc::alloc_aligned(2 << 20, 1 << 21);
+            fill(item, 2, 2 << 20);
+            let item = dispatch!(da.0, elf => elf.realloc(item, 8 << 20));
+            assert!(!item.is_null());
+            assert_eq!(item as usize % (1 << 21), 0);
+            check_fill(item, 2, 2 << 20);
+            da.free(item);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn realloc_moved_large_alignment() {
+        use super::super::libc;
+        let _ = env_logger::init();
+        const ALIGN: usize = 16 << 20;
+        const SIZE: usize = 1 << 20;
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            let item = da.alloc_aligned(SIZE, ALIGN);
+            fill(item, 4, SIZE);
+            // Map the page just past the object's mapping, so that mremap can't grow it in place.
+            // If the kernel puts this mapping elsewhere, that page is already taken.
+            let (usable, _) = large_alloc::get_commitment(item);
+            let end = item.offset(usable as isize);
+            let blocker = libc::mmap(end as *mut libc::c_void,
+                                     4096,
+                                     libc::PROT_NONE,
+                                     libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                                     -1,
+                                     0);
+            assert_ne!(blocker, libc::MAP_FAILED);
+            if blocker != end as *mut libc::c_void {
+                libc::munmap(blocker, 4096);
+            }
+            let new = dispatch!(da.0, elf => elf.realloc(item, 64 << 20));
+            assert!(!new.is_null());
+            assert_ne!(new, item);
+            assert_eq!(new as usize % ALIGN, 0);
+            check_fill(new, 4, SIZE);
+            fill(new, 5, 64 << 20);
+            da.free(new);
+            if blocker == end as *mut libc::c_void {
+                libc::munmap(blocker, 4096);
+            }
+        }
+    }
+
+    #[test]
+    fn large_alloc_overflow() {
+        // Sizes whose mapping length would overflow must fail cleanly rather than wrapping
+        // around to a small mapping.
+        let _ = env_logger::init();
+        let huge = [usize::max_value(), usize::max_value() - 4096, isize::max_value() as usize];
+        unsafe {
+            for &size in &huge {
+                assert!(large_alloc::alloc(size).is_null());
+                assert!(large_alloc::alloc_aligned(size, 1 << 21).is_null());
+            }
+            let item = large_alloc::alloc(1 << 20);
+            for &size in &huge {
+                assert!(large_alloc::realloc(item, size).is_null());
+            }
+            // the object is left untouched
+            write_bytes(item, 0xFF, 1 << 20);
+            large_alloc::free(item);
+
+            let mut da = DynamicAllocator::new();
+            assert!(da.alloc(usize::max_value()).is_null());
+        }
+    }
+
+    #[test]
+    fn large_alloc_aligned() {
+        let _ = env_logger::init();
+        for align in &[1, 8, 4096, 1 << 14, 1 << 16, 1 << 21] {
+            for size in &[1, 4096, (1 << 20) + 17] {
+                unsafe {
+                    let item = large_alloc::alloc_aligned(*size, *align);
+                    assert!(!item.is_null());
+                    assert_eq!(item as usize % align, 0);
+                    write_bytes(item, 0xFF, *size);
+                    let (usable, base) = large_alloc::get_commitment(item);
+                    assert!(usable >= *size);
+                    assert!(base <= item);
+                    large_alloc::free(item);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_in_place() {
+        let _ = env_logger::init();
+        let mut da = DynamicAllocator::new();
+        unsafe {
+            // Within a size class, the object stays where it is.
+            let layout = Layout::from_size_align(1000, 8).unwrap();
+            let item = da.alloc(1000);
+            fill(item, 1, 1000);
+            let usable = da.usable_size(item);
+            assert!(da.shrink_in_place(item, layout.clone(), 990).is_ok());
+            assert_eq!(da.usable_size(item), usable);
+            check_fill(item, 1, 990);
+            assert!(da.shrink_in_place(item, layout.clone(), 0).is_err());
+            assert!(da.shrink_in_place(item, layout, 1001).is_err());
+            da.free(item);
+
+            // Large allocations release their trailing pages.
+            const SIZE: usize = 8 << 20;
+            let layout = Layout::from_size_align(SIZE, 8).unwrap();
+            let item = da.alloc(SIZE);
+            fill(item, 2, SIZE);
+            assert!(da.usable_size(item) >= SIZE);
+            assert!(da.shrink_in_place(item, layout, 2 << 20).is_ok());
+            let usable = da.usable_size(item);
+            assert!(usable >= 2 << 20 && usable < (2 << 20) + 4096, "usable size {}", usable);
+            check_fill(item, 2, 2 << 20);
+            let (_, base) = large_alloc::get_commitment(item);
+            let end = item as usize + usable;
+            assert!(large_alloc::SEEN_PTRS
+                        .with(|hs| hs.borrow().get(&base) == Some(&(end - base as usize))));
+            da.free(item);
+        }
+    }
+
+    #[test]
+    fn over_aligned_large_alloc() {
+        use alloc::allocator::{Alloc, Layout};
+        let _ = env_logger::init();
+        const SIZE: usize = 4 << 20;
+        let mut da = DynamicAllocator::new();
+        for &align in &[4096, 2 << 20, 16 << 20] {
+            unsafe {
+                let item = da.alloc_aligned(SIZE, align);
+                assert!(!item.is_null());
+                assert_eq!(item as usize % align, 0);
+                fill(item, 3, SIZE);
+                check_fill(item, 3, SIZE);
+                // As documented on `alloc_aligned`, growing the object keeps its alignment.
+                let item = da.realloc(item, 2 * SIZE);
+                assert!(!item.is_null());
+                assert_eq!(item as usize % align, 0);
+                check_fill(item, 3, SIZE);
+                da.free(item);
+
+                let layout = Layout::from_size_align(SIZE, align).unwrap();
+                let item = (&global::ElfGlobal).alloc(layout.clone()).unwrap();
+                assert_eq!(item as usize % align, 0);
+                write_bytes(item, 0xFF, SIZE);
+                (&global::ElfGlobal).dealloc(item, layout);
+            }
+        }
+    }
+
     #[test]
     fn all_sizes_one_thread() {
         let _ = env_logger::init();
@@ -16,13 +16,19 @@ extern crate bagpipe;
 
 // Linking in `bsalloc` causes it to be used as the global heap allocator. That is important when
 // using this as a basis for a `malloc` library, but it becomes a hindrance when using this crate
-// as a specialized allocator library.
+// as a specialized allocator library. Which allocator services elfmalloc's own metadata is chosen
+// separately (see `utils::meta_alloc`); the `library` feature configures both for use alongside
+// another global allocator.
 #[cfg(not(feature = "use_default_allocator"))]
 extern crate bsalloc;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate mmap_alloc;
+extern crate num_cpus;
+extern crate object_alloc;
 
 mod utils;
 #[macro_use]
@@ -56,13 +56,21 @@
 //! cached for use by other object sizes.
 //!
 //! [1]: https://arxiv.org/abs/1503.09006
+use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem;
 use std::sync::Arc;
-use std::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
 use super::bagpipe::bag::{Revocable, WeakBag};
 use super::bagpipe::BagPipe;
 use super::bagpipe::queue::{FAAQueueLowLevel, RevocableFAAQueue};
-use super::utils::{mmap, LazyInitializable, OwnedArray};
+use super::alloc::allocator::{Alloc, Layout};
+use super::mmap_alloc::MapAlloc;
+use super::utils::{meta_alloc, mmap, LazyInitializable, OwnedArray};
+use super::num_cpus;
+#[cfg(target_os = "linux")]
+use super::libc;
 use std::marker::PhantomData;
 use std::ptr;
 use std::cmp;
@@ -96,7 +104,7 @@ pub trait MemoryBlock
     fn page_size(&self) -> usize;
     /// Is `it` a pointer to somewhere in the block of memory.
     fn contains(&self, it: *mut u8) -> bool;
-    /// Return `npages` fresh pages from the `Creek`.
+    /// Return `npages` fresh pages from the `Creek`, or null if the block is exhausted.
     ///
     /// Currently, there is code in this module (see the `Coalescer`) that relies on fresh pages
     /// returned from `carve` to be filled with zeros.
@@ -111,7 +119,8 @@ pub trait CoarseAllocator
     /// The concrete type representing backing memory for the allocator.
     type Block: MemoryBlock;
 
-    /// The start of a new block of memory of size `backing_memory().page_size()`.
+    /// The start of a new block of memory of size `backing_memory().page_size()`, or null if the
+    /// backing memory is exhausted.
     ///
     /// Furthermore, all memory returned by `alloc` must satisfy
     /// `c.backing_memory().contains(c.alloc())`*.
@@ -578,6 +587,19 @@ impl AllocIter {
         }
     }
 
+    /// Create an `AllocIter` that yields nothing, for a `SlagAllocator` without a `Slag`.
+    fn empty() -> AllocIter {
+        AllocIter {
+            cur_word: 0,
+            next_word: ptr::null_mut(),
+            refcnt: ptr::null(),
+            object_base: ptr::null_mut(),
+            object_size: 0,
+            remaining_words: 0,
+            cur_word_index: 0,
+        }
+    }
+
     /// Acquire a new word from the bit-set.
     ///
     /// Pre-condition: remaining_words > 0.
@@ -742,6 +764,21 @@ impl Slag {
         ((item as usize) & !(alignment - 1)) as *mut Self
     }
 
+    /// Check whether `item` points to the start of one of the objects in this `Slag`.
+    ///
+    /// This is false for pages that have never been used as a `Slag`, which have no metadata.
+    #[cfg(feature = "checked_free")]
+    pub fn is_object_start(&self, item: *mut u8) -> bool {
+        let m = match unsafe { self.meta.load(Ordering::Relaxed).as_ref() } {
+            Some(m) => m,
+            None => return false,
+        };
+        let first = self.as_raw() as usize + m.objects_offset as usize;
+        let item = item as usize;
+        item >= first && (item - first) % m.object_size == 0 &&
+        (item - first) / m.object_size < m.n_objects
+    }
+
     #[inline]
     fn get_word(raw_self: *mut Slag, item: *mut u8, m: &Metadata) -> (isize, usize) {
         let it_num = item as usize;
@@ -797,6 +834,18 @@ impl Slag {
         Transition::Null
     }
 
+    /// Get the `i`th object of the `Slag`, along with the bit-set word holding its bit and the
+    /// mask for that bit. The bit is set if the object is available.
+    unsafe fn object_bit(&self, i: usize, m: &Metadata) -> (*mut u8, &Word, usize) {
+        let item = (self.as_raw() as *mut u8).offset((m.objects_offset as usize +
+                                                        i * m.object_size) as
+                                                       isize);
+        let (word, word_ix) = Self::get_word(self.as_raw(), item, m);
+        let word = &*((self.as_raw() as *mut u8).offset(m.bitset_offset) as *mut Word)
+                         .offset(word);
+        (item, word, 1 << word_ix)
+    }
+
     /// Initialize an `AllocIter` for allocating out of the `Slag`.
     fn refresh(&self, meta: &Metadata) -> AllocIter {
         // offset calls are valid because size_of(u8) is 1
@@ -901,31 +950,65 @@ pub struct MagazineCache<CA: CoarseAllocator> {
     coalescer: Coalescer,
 }
 
+/// The default number of bytes of objects that a `MagazineCache` holds.
+pub const DEFAULT_MAGAZINE_BYTES: usize = 512 << 10;
+
+/// The parameters for a size class's cache: its metadata, eager decommit threshold, page
+/// allocator, available `Slag`s and magazine size in bytes.
+pub type FrontendParams<CA> = (*mut Metadata, usize, CA, RevocablePipe<Slag>, usize);
+
+/// A cache in front of a size class's `SlagAllocator`s.
+///
+/// Frontends are lazily initialized from their `Params`, which are cloned for every handle on the
+/// allocator. `params` converts the common `FrontendParams` into a frontend's `Params` once per
+/// size class, which lets frontends share state between handles.
+pub trait Frontend: LazyInitializable {
+    type Pages: CoarseAllocator;
+    fn params(p: FrontendParams<Self::Pages>) -> Self::Params;
+}
+
 impl<CA: CoarseAllocator> LazyInitializable for MagazineCache<CA> {
-    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>);
-    fn init(&(meta, decommit, ref page_alloc, ref avail): &Self::Params) -> Self {
+    type Params = FrontendParams<CA>;
+    fn init(&(meta, decommit, ref page_alloc, ref avail, bytes): &Self::Params) -> Self {
         let salloc = SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone());
-        Self::new(salloc)
+        Self::with_bytes(salloc, bytes)
+    }
+}
+
+impl<CA: CoarseAllocator> Frontend for MagazineCache<CA> {
+    type Pages = CA;
+    fn params(p: FrontendParams<CA>) -> FrontendParams<CA> {
+        p
     }
 }
 
 impl<CA: CoarseAllocator> LazyInitializable for LocalCache<CA> {
-    type Params = (*mut Metadata, usize, CA, RevocablePipe<Slag>);
-    fn init(&(meta, decommit, ref page_alloc, ref avail): &Self::Params) -> Self {
+    /// `LocalCache` has no magazine, so the magazine size is ignored.
+    type Params = FrontendParams<CA>;
+    fn init(&(meta, decommit, ref page_alloc, ref avail, _): &Self::Params) -> Self {
         let salloc = SlagAllocator::partial_new(meta, decommit, page_alloc.clone(), avail.clone());
         Self::new(salloc)
     }
 }
 
+impl<CA: CoarseAllocator> Frontend for LocalCache<CA> {
+    type Pages = CA;
+    fn params(p: FrontendParams<CA>) -> FrontendParams<CA> {
+        p
+    }
+}
+
 impl<CA: CoarseAllocator> Drop for MagazineCache<CA> {
     fn drop(&mut self) {
         unsafe {
             let meta = &*self.alloc.m;
-            let mask = self.iter.cur_word;
-            let word = self.iter.next_word.offset(-1);
             let slag = self.alloc.slag;
             // bulk-free the current AllocIter word. Then free all elements in the magazine.
-            self.alloc.bulk_free(mask, word, slag, meta);
+            if !slag.is_null() {
+                let mask = self.iter.cur_word;
+                let word = self.iter.next_word.offset(-1);
+                self.alloc.bulk_free(mask, word, slag, meta);
+            }
             for i in 0..self.s.top {
                 let item = *self.s.data.get(i);
                 self.alloc.free(item)
@@ -944,7 +1027,8 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
     pub fn new_sized(mut alloc: SlagAllocator<CA>, magazine_size: usize) -> Self {
         assert!(magazine_size > 0);
         let s = PtrStack::new(magazine_size);
-        let iter = unsafe { alloc.refresh() };
+        // If there was no page left for a first `Slag`, the first allocation will try again.
+        let iter = unsafe { alloc.refresh() }.unwrap_or_else(AllocIter::empty);
         let buckets = Coalescer::new(magazine_size * 2);
         MagazineCache {
             stack_size: magazine_size,
@@ -956,23 +1040,31 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
     }
 
     pub fn new(alloc: SlagAllocator<CA>) -> Self {
+        Self::with_bytes(alloc, DEFAULT_MAGAZINE_BYTES)
+    }
+
+    /// Create a `MagazineCache` whose magazine holds up to `bytes` bytes worth of objects (and at
+    /// least one object).
+    pub fn with_bytes(alloc: SlagAllocator<CA>, bytes: usize) -> Self {
         use std::cmp;
-        unsafe {
-            const DEFAULT_MAGAZINE_BYTES: usize = 512 << 10;
-            let sz = DEFAULT_MAGAZINE_BYTES / (*alloc.m).object_size;
-            Self::new_sized(alloc, cmp::max(1, sz))
-        }
+        let sz = bytes / unsafe { (*alloc.m).object_size };
+        Self::new_sized(alloc, cmp::max(1, sz))
     }
 
     /// Allocate memory from the current owned `Slag`.
     ///
     /// This amounts to getting memory from the current alloc iterator. If the iterator is
-    /// exhausted, a new `Slag` is acquired.
+    /// exhausted, a new `Slag` is acquired. Returns null if the backing memory is exhausted.
     unsafe fn slag_alloc(&mut self) -> *mut u8 {
         for _ in 0..2 {
             match self.iter.next() {
                 Some(ptr) => return ptr,
-                None => self.iter = self.alloc.refresh(),
+                None => {
+                    match self.alloc.refresh() {
+                        Some(iter) => self.iter = iter,
+                        None => return ptr::null_mut(),
+                    }
+                }
             }
         }
         panic!("New slag is empty {:?} {:?}",
@@ -1000,6 +1092,45 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
         self.s.push(item);
     }
 
+    /// Free all objects cached in the magazine back to their `Slag`s.
+    pub unsafe fn flush(&mut self) {
+        for i in 0..self.s.top {
+            let item = *self.s.data.get(i);
+            self.alloc.free(item)
+        }
+        self.s.top = 0;
+    }
+
+    /// Flush the magazine, and then compact the size class (see `SlagAllocator::compact`).
+    pub unsafe fn compact(&mut self, moved: &mut Vec<(*mut u8, *mut u8)>) -> usize {
+        self.flush();
+        self.alloc.compact(moved)
+    }
+
+    /// Free each of `items`, all of which must belong to this size class.
+    ///
+    /// Objects are pushed onto the magazine while there is room for them. The rest are freed back
+    /// to their `Slag`s in bulk, as when the magazine overflows: frees to the same bit-set word
+    /// are composed into a single mask, so they take one atomic operation rather than one each.
+    pub unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        let room = cmp::min(self.stack_size - self.s.top, items.len());
+        let (cached, rest) = items.split_at(room);
+        for &item in cached {
+            self.s.push(item);
+        }
+        let meta = &*self.alloc.m;
+        // The coalescer has room for twice as many cells as the magazine has objects, and each
+        // object takes at most one cell.
+        for chunk in rest.chunks(self.stack_size) {
+            for &item in chunk {
+                if !self.coalescer.insert(item, meta) {
+                    self.alloc.free(item)
+                }
+            }
+            self.free_coalesced(meta);
+        }
+    }
+
     /// Perform the bulk-level frees for the `Coalescer`.
     unsafe fn return_memory(&mut self) {
         debug_assert_eq!(self.s.top as usize, self.stack_size);
@@ -1014,6 +1145,11 @@ impl<CA: CoarseAllocator> MagazineCache<CA> {
             }
         }
         self.s.top = new_top;
+        self.free_coalesced(meta);
+    }
+
+    /// Free the objects accumulated in the `Coalescer`, leaving it empty.
+    unsafe fn free_coalesced(&mut self, meta: &Metadata) {
         for cell_ptr in 0..self.coalescer.1.top {
             let cell = &mut **(self.coalescer.1.data.get(cell_ptr) as *mut *mut RemoteFreeCell);
             // Slag::find will technically work if you hand it any pointer within the slag
@@ -1045,10 +1181,12 @@ impl<CA: CoarseAllocator> Drop for LocalCache<CA> {
     fn drop(&mut self) {
         unsafe {
             let meta = &*self.alloc.m;
-            let mask = self.iter.cur_word;
-            let word = self.iter.next_word.offset(-1);
             let slag = self.alloc.slag;
-            self.alloc.bulk_free(mask, word, slag, meta);
+            if !slag.is_null() {
+                let mask = self.iter.cur_word;
+                let word = self.iter.next_word.offset(-1);
+                self.alloc.bulk_free(mask, word, slag, meta);
+            }
             for i in 0..self.vals.top {
                 let item = *self.vals.data.get(i);
                 self.alloc.free(item)
@@ -1066,7 +1204,7 @@ impl<CA: CoarseAllocator> LocalCache<CA> {
     fn new(mut alloc: SlagAllocator<CA>) -> Self {
         unsafe {
             let stack = PtrStack::new((*alloc.m).n_objects);
-            let iter = alloc.refresh();
+            let iter = alloc.refresh().unwrap_or_else(AllocIter::empty);
             LocalCache {
                 alloc: alloc,
                 vals: stack,
@@ -1083,30 +1221,257 @@ impl<CA: CoarseAllocator> LocalCache<CA> {
         }
     }
 
+    /// Free each of `items`, all of which must belong to this size class.
+    ///
+    /// `LocalCache` only caches objects from its current `Slag`, and frees the rest eagerly, so
+    /// this is equivalent to freeing each object in turn.
+    pub unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        for &item in items {
+            self.free(item);
+        }
+    }
+
+    /// Free all cached objects back to the current `Slag`.
+    pub unsafe fn flush(&mut self) {
+        for i in 0..self.vals.top {
+            let item = *self.vals.data.get(i);
+            self.alloc.free(item)
+        }
+        self.vals.top = 0;
+    }
+
+    /// Flush the cached objects, and then compact the size class (see
+    /// `SlagAllocator::compact`).
+    pub unsafe fn compact(&mut self, moved: &mut Vec<(*mut u8, *mut u8)>) -> usize {
+        self.flush();
+        self.alloc.compact(moved)
+    }
+
     pub unsafe fn alloc(&mut self) -> *mut u8 {
         self.vals
             .pop()
             .or_else(|| self.iter.next())
-            .unwrap_or_else(|| {
-                let next_iter = self.alloc.refresh();
-                self.iter = next_iter;
-                self.iter.next().expect("New iterator should have values")
+            .unwrap_or_else(|| match self.alloc.refresh() {
+                Some(next_iter) => {
+                    self.iter = next_iter;
+                    self.iter.next().expect("New iterator should have values")
+                }
+                None => ptr::null_mut(),
+            })
+    }
+}
+
+/// A cache shared by all threads running on the same CPU.
+///
+/// `MagazineCache` and `LocalCache` are per-thread, so their memory overhead grows with the number
+/// of threads. A `PerCpuCache` instead shards a size class into one `MagazineCache` per CPU, and
+/// all clones of a `PerCpuCache` share the same shards. Each operation locks the shard for the
+/// CPU that the calling thread is running on; if that shard is busy (because another thread was
+/// preempted while holding it, or the thread migrated), the next free shard is used instead, so
+/// threads only ever wait if every shard is busy. Shards are initialized the first time they are
+/// used.
+///
+/// The CPU is found with `sched_getcpu` on Linux. On other platforms, threads are spread across
+/// the shards based on the address of their stack.
+pub struct PerCpuCache<CA: CoarseAllocator> {
+    shards: Arc<CpuShards<CA>>,
+}
+
+/// The shards of a `PerCpuCache`, shared by all of its clones.
+pub struct CpuShards<CA: CoarseAllocator> {
+    params: FrontendParams<CA>,
+    shards: Box<[CpuShard<CA>]>,
+}
+
+struct CpuShard<CA: CoarseAllocator> {
+    locked: AtomicBool,
+    cache: UnsafeCell<Option<MagazineCache<CA>>>,
+}
+
+// The caches in each shard are only accessed with the shard's lock held.
+unsafe impl<CA: CoarseAllocator> Send for CpuShards<CA> {}
+unsafe impl<CA: CoarseAllocator> Sync for CpuShards<CA> {}
+
+impl<CA: CoarseAllocator> CpuShards<CA> {
+    fn new(params: FrontendParams<CA>, n_shards: usize) -> CpuShards<CA> {
+        let shards = (0..cmp::max(1, n_shards))
+            .map(|_| {
+                CpuShard {
+                    locked: AtomicBool::new(false),
+                    cache: UnsafeCell::new(None),
+                }
             })
+            .collect::<Vec<_>>();
+        CpuShards {
+            params: params,
+            shards: shards.into_boxed_slice(),
+        }
+    }
+
+    /// Lock a shard, preferring the one for the current CPU.
+    fn lock(&self) -> &CpuShard<CA> {
+        let n = self.shards.len();
+        let start = current_cpu() % n;
+        loop {
+            for i in 0..n {
+                let shard = &self.shards[(start + i) % n];
+                if !shard.locked.load(Ordering::Relaxed) &&
+                   !shard.locked.swap(true, Ordering::Acquire) {
+                    return shard;
+                }
+            }
+            // Every shard is busy, most likely because their owners were preempted.
+            thread::yield_now();
+        }
+    }
+
+    /// Run `f` on the cache of some shard, initializing it if necessary.
+    unsafe fn with_cache<R, F: FnOnce(&mut MagazineCache<CA>) -> R>(&self, f: F) -> R {
+        let shard = self.lock();
+        let cache = &mut *shard.cache.get();
+        if cache.is_none() {
+            *cache = Some(MagazineCache::init(&self.params));
+        }
+        let res = f(cache.as_mut().unwrap());
+        shard.locked.store(false, Ordering::Release);
+        res
+    }
+}
+
+impl<CA: CoarseAllocator> Clone for PerCpuCache<CA> {
+    fn clone(&self) -> Self {
+        PerCpuCache { shards: self.shards.clone() }
+    }
+}
+
+impl<CA: CoarseAllocator> LazyInitializable for PerCpuCache<CA> {
+    type Params = Arc<CpuShards<CA>>;
+    fn init(shards: &Self::Params) -> Self {
+        PerCpuCache { shards: shards.clone() }
+    }
+}
+
+impl<CA: CoarseAllocator> Frontend for PerCpuCache<CA> {
+    type Pages = CA;
+    fn params(p: FrontendParams<CA>) -> Arc<CpuShards<CA>> {
+        Arc::new(CpuShards::new(p, num_cpus::get()))
+    }
+}
+
+impl<CA: CoarseAllocator> PerCpuCache<CA> {
+    /// Create a `PerCpuCache` with one shard per CPU, the first of which uses `alloc`.
+    pub fn new(alloc: SlagAllocator<CA>) -> Self {
+        let params = (alloc.m,
+                      alloc.eager_decommit_threshold,
+                      alloc.pages.clone(),
+                      alloc.available.clone(),
+                      DEFAULT_MAGAZINE_BYTES);
+        let shards = CpuShards::new(params, num_cpus::get());
+        unsafe { *shards.shards[0].cache.get() = Some(MagazineCache::new(alloc)) };
+        PerCpuCache { shards: Arc::new(shards) }
+    }
+
+    /// The number of shards; one per CPU.
+    pub fn n_shards(&self) -> usize {
+        self.shards.shards.len()
+    }
+
+    pub unsafe fn alloc(&mut self) -> *mut u8 {
+        self.shards.with_cache(|cache| cache.alloc())
+    }
+
+    pub unsafe fn free(&mut self, item: *mut u8) {
+        self.shards.with_cache(|cache| cache.free(item))
+    }
+
+    /// Free each of `items`, all of which must belong to this size class, with the current CPU's
+    /// shard locked only once.
+    pub unsafe fn free_batch(&mut self, items: &[*mut u8]) {
+        self.shards.with_cache(|cache| cache.free_batch(items))
     }
+
+    /// Free all objects cached in every shard back to their `Slag`s.
+    pub unsafe fn flush(&mut self) {
+        for shard in self.shards.shards.iter() {
+            while shard.locked.swap(true, Ordering::Acquire) {
+                thread::yield_now();
+            }
+            if let Some(ref mut cache) = *shard.cache.get() {
+                cache.flush();
+            }
+            shard.locked.store(false, Ordering::Release);
+        }
+    }
+
+    /// Flush every shard, and then compact the size class (see `SlagAllocator::compact`).
+    ///
+    /// The shards share their available `Slag`s, so the size class only needs to be compacted
+    /// once. Each shard's current `Slag` is left alone.
+    pub unsafe fn compact(&mut self, moved: &mut Vec<(*mut u8, *mut u8)>) -> usize {
+        self.flush();
+        self.shards.with_cache(|cache| cache.alloc.compact(moved))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn current_cpu() -> usize {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 { stack_hash() } else { cpu as usize }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_cpu() -> usize {
+    stack_hash()
 }
 
-/// Base address and size of a memory map.
+/// Hash the address of the current stack frame.
 ///
-/// This could also just be a `*mut [u8]`, but having two fields is more explicit. We need a new
-/// type because the `Drop` implementation calls `unmap`.
+/// Threads have disjoint stacks, which are typically at least a few pages apart, so this is a
+/// cheap way to spread threads out.
+#[inline(never)]
+fn stack_hash() -> usize {
+    let x = 0u8;
+    let page = &x as *const u8 as usize >> 12;
+    page ^ (page >> 9) ^ (page >> 18)
+}
+
+/// Base address and size of a memory map, and who is responsible for unmapping it.
+///
+/// This could also just be a `*mut [u8]`, but having separate fields is more explicit. We need a
+/// new type because the `Drop` implementation calls `unmap` on owned maps.
 #[derive(Debug)]
-struct MapAddr(*mut u8, usize);
+struct MapAddr(*mut u8, usize, Owner);
+
+/// How the memory described by a `MapAddr` is released.
+enum Owner {
+    /// The memory is not owned (see `Creek::from_region`).
+    Borrowed,
+    /// The memory was mapped with `mmap::map`.
+    Mmap,
+    /// The memory was allocated from a `MapAlloc` (see `Creek::from_map_alloc`).
+    MapAlloc(Arc<MapAlloc>),
+}
+
+impl fmt::Debug for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+                        Owner::Borrowed => "Borrowed",
+                        Owner::Mmap => "Mmap",
+                        Owner::MapAlloc(_) => "MapAlloc",
+                    })
+    }
+}
 
 impl Drop for MapAddr {
     fn drop(&mut self) {
         use self::mmap::unmap;
-        unsafe {
-            unmap(self.0, self.1);
+        match self.2 {
+            Owner::Borrowed => {}
+            Owner::Mmap => unsafe { unmap(self.0, self.1) },
+            Owner::MapAlloc(ref alloc) => unsafe {
+                let layout = Layout::from_size_align(self.1, 1).unwrap();
+                <&MapAlloc as Alloc>::dealloc(&mut &**alloc, self.0, layout)
+            },
         }
     }
 }
@@ -1163,8 +1528,10 @@ impl MemoryBlock for Creek {
                 .as_ref()
                 .unwrap()
                 .fetch_add(npages, Ordering::Relaxed);
-            assert!((new_bump + npages) * self.page_size < self.map_info.1,
-                    "address space allocation exceeded");
+            // The bump pointer is left past the end, so all subsequent calls fail as well.
+            if self.base as usize + (new_bump + npages) * self.page_size > self.end() {
+                return ptr::null_mut();
+            }
             self.base.offset((new_bump * self.page_size) as isize)
         }
     }
@@ -1172,8 +1539,7 @@ impl MemoryBlock for Creek {
     fn contains(&self, it: *mut u8) -> bool {
         check_bump!(self);
         let it_num = it as usize;
-        let base_num = self.base as usize;
-        it_num >= base_num && it_num < base_num + self.map_info.1
+        it_num >= self.base as usize && it_num < self.end()
     }
 
     /// Create a new `Creek` with pages of size `page_size` total heap size of `heap_size`,
@@ -1194,12 +1560,57 @@ impl MemoryBlock for Creek {
             }
             panic!("unable to map heap")
         };
-        // lots of stuff breaks if this isn't true
-        assert!(page_size.is_power_of_two());
-        assert!(page_size > mem::size_of::<usize>());
         // first, let's grab some memory;
         let (orig_base, heap_size) = get_heap();
         info!("created heap of size {}", heap_size);
+        Creek::from_map(page_size, orig_base, heap_size, Owner::Mmap)
+    }
+}
+
+impl Creek {
+    /// Create a new `Creek` with pages of size `page_size` that carves pages out of the `size`
+    /// bytes starting at `base`.
+    ///
+    /// This allows an allocator to operate within a fixed, pre-reserved region of memory (e.g. a
+    /// static buffer or a specific mapping) rather than the large mapping created by `new`. Once
+    /// the region is exhausted, `carve` returns null. The region is not unmapped when the `Creek`
+    /// is dropped.
+    ///
+    /// The first page-aligned page of the region stores the `Creek`'s own state, so a region must
+    /// be at least three pages long to hold a single usable page: one page may be lost to
+    /// alignment, one stores the state, and the rest are handed out by `carve`.
+    ///
+    /// # Safety
+    ///
+    /// The region must be readable, writable, and filled with zeros, and it must outlive the
+    /// `Creek` and all of its clones. No other code may use the region in the meantime.
+    pub unsafe fn from_region(page_size: usize, base: *mut u8, size: usize) -> Creek {
+        assert!(!base.is_null(), "region must not be null");
+        Creek::from_map(page_size, base, size, Owner::Borrowed)
+    }
+
+    /// Create a new `Creek` with pages of size `page_size` that carves pages out of a region of
+    /// `heap_size` bytes allocated from `alloc`, or `None` if the region could not be allocated.
+    ///
+    /// This makes all of `MapAlloc`'s configuration (such as huge pages or memory permissions)
+    /// available for the `Creek`'s pages. The region is returned to `alloc` once the `Creek` and
+    /// all of its clones have been dropped. As with `new`, most of the region is typically never
+    /// touched, so `heap_size` can be much larger than the memory that will actually be used.
+    ///
+    /// `alloc` must be configured to allocate readable and writable memory.
+    pub fn from_map_alloc(page_size: usize,
+                          alloc: Arc<MapAlloc>,
+                          heap_size: usize)
+                          -> Option<Creek> {
+        let layout = Layout::from_size_align(heap_size, 1).ok()?;
+        let base = unsafe { <&MapAlloc as Alloc>::alloc(&mut &*alloc, layout).ok()? };
+        Some(Creek::from_map(page_size, base, heap_size, Owner::MapAlloc(alloc)))
+    }
+
+    fn from_map(page_size: usize, orig_base: *mut u8, heap_size: usize, owner: Owner) -> Creek {
+        // lots of stuff breaks if this isn't true
+        assert!(page_size.is_power_of_two());
+        assert!(page_size > mem::size_of::<usize>());
         let orig_addr = orig_base as usize;
         let (slush_addr, real_addr) = {
             // allocate some `slush` space at the beginning of the creek. This gives us space to
@@ -1220,13 +1631,30 @@ impl MemoryBlock for Creek {
             };
             (base as *mut u8, (base + page_size) as *mut u8)
         };
+        assert!(real_addr as usize <= orig_addr + heap_size,
+                "region of {} bytes is too small for pages of size {}",
+                heap_size,
+                page_size);
         Creek {
             page_size: page_size,
-            map_info: Arc::new(MapAddr(orig_base, heap_size)),
+            map_info: Arc::new(MapAddr(orig_base, heap_size, owner)),
             base: real_addr,
             bump: AtomicPtr::new(slush_addr as *mut AtomicUsize),
         }
     }
+
+    /// The address one past the end of the underlying region.
+    fn end(&self) -> usize {
+        self.map_info.0 as usize + self.map_info.1
+    }
+
+    /// Mark the entire `Creek` so that it is not inherited by child processes across `fork`.
+    ///
+    /// Accessing the `Creek`'s memory in a child process will fault.
+    #[cfg(target_os = "linux")]
+    pub fn dont_fork(&self) {
+        mmap::dont_fork(self.map_info.0, self.map_info.1)
+    }
 }
 
 impl Clone for Creek {
@@ -1242,16 +1670,51 @@ impl Clone for Creek {
     }
 }
 
-/// A `DirtyFn` is a callback that is called upon allocating a clean page from a `PageAlloc`. It
-/// generally does nothing, but its presence in `PageAlloc` allows us to inject other callbacks for
-/// debugging or performance analysis.
+/// A `DirtyFn` is a callback that is called on fresh pages from a `PageAlloc`'s backing memory
+/// before they are made available for allocation. It generally just publishes the page, but its
+/// presence in `PageAlloc` allows us to inject other callbacks for debugging or performance
+/// analysis.
 pub trait DirtyFn: Clone {
-    fn dirty(mem: *mut u8);
+    /// Prepare `page`, and then publish it (see `FreshPage::publish`).
+    fn dirty(page: FreshPage);
 }
 
 impl DirtyFn for () {
     #[inline(always)]
-    fn dirty(_mem: *mut u8) {}
+    fn dirty(page: FreshPage) {
+        page.publish()
+    }
+}
+
+/// A fresh page from a `PageAlloc`'s backing memory that has not yet been made available for
+/// allocation.
+///
+/// Until `publish` is called, nothing else accesses the page, so it may be written to freely
+/// (e.g., to dirty it), as long as it is left filled with zeros. It may be sent to another thread
+/// to be published there.
+pub struct FreshPage {
+    ptr: *mut u8,
+    size: usize,
+    clean: SlagPipe<u8>,
+}
+
+unsafe impl Send for FreshPage {}
+
+impl FreshPage {
+    /// The start of the page.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// The size of the page.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Make the page available for allocation from the `PageAlloc` that it came from.
+    pub fn publish(mut self) {
+        self.clean.push_mut(self.ptr)
+    }
 }
 
 /// An allocator for large, fixed-sized objects.
@@ -1263,6 +1726,9 @@ impl DirtyFn for () {
 /// The use of `BagPipe` data-structures allows the `PageAlloc` to scale to many concurrent
 /// allocating and freeing threads.
 ///
+/// Dirty pages that go unused for a while can be uncommitted with `sweep_idle`. Such pages are
+/// kept in separate caches so that pages which are still committed are preferred by `alloc`.
+///
 /// TODO: implement a threshold for eager uncommit in the `SlagAllocator` and propagate that to
 /// `CoarseAllocator`
 #[derive(Clone)]
@@ -1274,28 +1740,99 @@ pub struct PageAlloc<C: MemoryBlock, D = ()>
     // bagpipes of byte slices of size creek.page_size
     clean: SlagPipe<u8>,
     dirty: SlagPipe<u8>,
+    // dirty pages that were present during the last call to sweep_idle
+    idle: SlagPipe<u8>,
+    // idle pages that have since been uncommitted
+    decommitted: SlagPipe<u8>,
     _marker: PhantomData<D>,
 }
 
 impl<C: MemoryBlock, D: DirtyFn> PageAlloc<C, D> {
     /// Create a new `PageAlloc`.
     pub fn new(page_size: usize, target_overhead: usize) -> Self {
+        Self::with_block(C::new(page_size), target_overhead)
+    }
+
+    /// Create a new `PageAlloc` that gets its pages from `creek`.
+    pub fn with_block(creek: C, target_overhead: usize) -> Self {
         let mut res = PageAlloc {
             target_overhead: target_overhead,
-            creek: C::new(page_size),
+            creek: creek,
             clean: SlagPipe::new_size(2),
             dirty: SlagPipe::new_size(8),
+            idle: SlagPipe::new_size(2),
+            decommitted: SlagPipe::new_size(2),
             _marker: PhantomData,
         };
         res.refresh_pages();
         res
     }
 
-    /// Get more clean pages from the backing memory.
+    /// Get more clean pages from the backing memory, stopping early if it is exhausted.
+    ///
+    /// The pages are passed to `D::dirty`, which adds them to `clean`.
     fn refresh_pages(&mut self) {
-        let creek = &self.creek;
-        let iter = (0..4).map(|_| creek.carve(1));
-        self.clean.bulk_add(iter);
+        for _ in 0..4 {
+            let ptr = self.creek.carve(1);
+            if ptr.is_null() {
+                return;
+            }
+            D::dirty(FreshPage {
+                         ptr: ptr,
+                         size: self.creek.page_size(),
+                         clean: self.clean.clone(),
+                     });
+        }
+    }
+
+    /// Uncommit all but the first minor page of `ptr`.
+    ///
+    /// The first page is left alone because it holds the `Slag` header, which is consulted when
+    /// the page is handed out again.
+    unsafe fn uncommit_page(&self, ptr: *mut u8) {
+        const MINOR_PAGE_SIZE: isize = 4096;
+        use self::mmap::uncommit;
+        let uncommit_len = cmp::max(0,
+                                    self.creek.page_size() as isize -
+                                    MINOR_PAGE_SIZE) as usize;
+        if uncommit_len != 0 {
+            uncommit(ptr.offset(MINOR_PAGE_SIZE), uncommit_len);
+        }
+    }
+
+    /// Uncommit all cached dirty pages.
+    ///
+    /// This keeps the address space of the pages, so they can still be handed out by `alloc`.
+    pub fn trim(&mut self) {
+        // The first sweep moves all dirty pages to the idle cache; the second uncommits them.
+        self.sweep_idle();
+        self.sweep_idle();
+    }
+
+    /// Uncommit dirty pages that have not been used since the previous call to `sweep_idle`.
+    ///
+    /// Each call moves the current dirty pages to an "idle" cache. Pages still in the idle cache
+    /// at the next call have not been reused in the interim; they are uncommitted and moved to a
+    /// cache of decommitted pages. Decommitted pages keep their address space, and are handed out
+    /// by `alloc` once all committed pages are exhausted.
+    pub fn sweep_idle(&mut self) {
+        unsafe {
+            for _ in 0..self.idle.size_guess() {
+                if let Some(ptr) = self.idle.pop_mut() {
+                    self.uncommit_page(ptr);
+                    self.decommitted.push_mut(ptr);
+                } else {
+                    break;
+                }
+            }
+        }
+        for _ in 0..self.dirty.size_guess() {
+            if let Some(ptr) = self.dirty.pop_mut() {
+                self.idle.push_mut(ptr);
+            } else {
+                break;
+            }
+        }
     }
 }
 
@@ -1311,33 +1848,33 @@ impl<C: MemoryBlock, D: DirtyFn> CoarseAllocator for PageAlloc<C, D> {
             trace_event!(grabbed_dirty);
             return ptr;
         }
-        loop {
-            if let Ok(ptr) = self.clean.try_pop_mut() {
-                trace_event!(grabbed_clean);
-                D::dirty(ptr);
-                return ptr;
-            }
+        if let Ok(ptr) = self.idle.try_pop_mut() {
+            trace_event!(grabbed_dirty);
+            return ptr;
+        }
+        if let Ok(ptr) = self.decommitted.try_pop_mut() {
+            trace_event!(grabbed_dirty);
+            return ptr;
+        }
+        if let Ok(ptr) = self.clean.try_pop_mut() {
+            trace_event!(grabbed_clean);
+            return ptr;
+        }
+        // Pages passed to `D::dirty` may not be published right away, so take a fresh page
+        // directly rather than waiting for them.
+        let ptr = self.creek.carve(1);
+        if !ptr.is_null() {
+            trace_event!(grabbed_clean);
             self.refresh_pages();
         }
+        ptr
     }
 
     unsafe fn free(&mut self, ptr: *mut u8, decommit: bool) {
-        const MINOR_PAGE_SIZE: isize = 4096;
-        use self::mmap::uncommit;
-        use std::cmp;
         if decommit || self.dirty.size_guess() >= self.target_overhead as isize {
-            let uncommit_len = cmp::max(0,
-                                        self.backing_memory().page_size() as isize -
-                                        MINOR_PAGE_SIZE) as usize;
-            if uncommit_len == 0 {
-                self.dirty.push_mut(ptr);
-            } else {
-                uncommit(ptr.offset(MINOR_PAGE_SIZE), uncommit_len);
-                self.dirty.push_mut(ptr);
-            }
-        } else {
-            self.dirty.push_mut(ptr);
+            self.uncommit_page(ptr);
         }
+        self.dirty.push_mut(ptr);
     }
 }
 
@@ -1460,6 +1997,15 @@ impl<T> AllocBuilder<T> {
                                           self.eager_decommit_threshold,
                                           self.max_objects)
     }
+
+    /// Build a `PerCpuAllocator<T>` from the current configuration.
+    pub fn build_percpu(&self) -> PerCpuAllocator<T> {
+        PerCpuAllocator::new_standalone(self.cutoff_factor,
+                                        self.page_size,
+                                        self.target_overhead,
+                                        self.eager_decommit_threshold,
+                                        self.max_objects)
+    }
 }
 
 macro_rules! typed_wrapper {
@@ -1498,6 +2044,7 @@ macro_rules! typed_wrapper {
 
 typed_wrapper!(LocalAllocator, LocalCache);
 typed_wrapper!(MagazineAllocator, MagazineCache);
+typed_wrapper!(PerCpuAllocator, PerCpuCache);
 
 /// Allocator state wrapping a `Slag`.
 ///
@@ -1505,7 +2052,7 @@ typed_wrapper!(MagazineAllocator, MagazineCache);
 /// transitions of different `Slag`s and also acquires new `Slag`s for iteration over the bitset.
 pub struct SlagAllocator<CA: CoarseAllocator> {
     m: *mut Metadata,
-    /// The current (local) `Slag`.
+    /// The current (local) `Slag`, or null if there was no page left to create one.
     slag: *mut Slag,
     /// Global pages, potentially not initialized to match `m`
     pages: CA,
@@ -1515,10 +2062,23 @@ pub struct SlagAllocator<CA: CoarseAllocator> {
     eager_decommit_threshold: usize,
 }
 
+/// Get a page from `pa` and initialize it as a `Slag` for `meta`, or return null if the backing
+/// memory is exhausted.
+unsafe fn first_slag<CA: CoarseAllocator>(pa: &mut CA, meta: &Metadata) -> *mut Slag {
+    let slag = pa.alloc() as *mut Slag;
+    if !slag.is_null() {
+        Slag::init(slag, meta);
+    }
+    slag
+}
+
 impl<CA: CoarseAllocator> Drop for SlagAllocator<CA> {
     fn drop(&mut self) {
         unsafe {
             let slag = self.slag;
+            if slag.is_null() {
+                return;
+            }
             let meta = &*self.m;
             let (claimed, was) = (*slag).rc.unclaim();
             if claimed {
@@ -1546,10 +2106,13 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
                        mut pa: CA,
                        avail: RevocablePipe<Slag>)
                        -> Self {
-        let first_slag = unsafe { pa.alloc() } as *mut Slag;
-        unsafe {
-            Slag::init(first_slag, meta.as_ref().unwrap());
-        };
+        debug!("initializing size class for {}-byte objects",
+               unsafe { (*meta).object_size });
+        let first_slag = unsafe { first_slag(&mut pa, &*meta) };
+        if first_slag.is_null() {
+            warn!("no page left to initialize size class for {}-byte objects",
+                  unsafe { (*meta).object_size });
+        }
         SlagAllocator {
             m: meta,
             slag: first_slag,
@@ -1567,15 +2130,17 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
                -> Self {
         // This is a bit wasteful as one metadata object consumes will wind up consuming a page. In
         // the dynamic allocator these are packed more tightly.
-        let meta = Box::into_raw(Box::new(compute_metadata(object_size,
-                                                           pa.backing_memory().page_size(),
-                                                           index,
-                                                           cutoff_factor,
-                                                           max_objects)));
-        let first_slag = unsafe { pa.alloc() } as *mut Slag;
+        let meta = meta_alloc::alloc(mem::size_of::<Metadata>())
+            .expect("metadata allocation failed") as *mut Metadata;
         unsafe {
-            Slag::init(first_slag, meta.as_ref().unwrap());
-        };
+            ptr::write(meta,
+                       compute_metadata(object_size,
+                                        pa.backing_memory().page_size(),
+                                        index,
+                                        cutoff_factor,
+                                        max_objects));
+        }
+        let first_slag = unsafe { first_slag(&mut pa, &*meta) };
         SlagAllocator {
             m: meta,
             slag: first_slag,
@@ -1586,9 +2151,29 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
     }
 
     /// Re-initialize a non-empty `AllocIter`; potentially getting a new `Slag` to do so.
-    unsafe fn refresh(&mut self) -> AllocIter {
-        let s_ref = &*self.slag;
+    ///
+    /// Returns `None` if a new `Slag` is needed but the backing memory is exhausted. In that case
+    /// the current `Slag` is kept. If there was no page left for a first `Slag` when this
+    /// `SlagAllocator` was created, one is acquired now.
+    unsafe fn refresh(&mut self) -> Option<AllocIter> {
         let meta = &*self.m;
+        if self.slag.is_null() {
+            self.slag = self.next_slag(meta)?;
+            let s_ref = &*self.slag;
+            let claimed = s_ref.rc.claim();
+            debug_assert!(claimed, "claiming first slag after refresh");
+            return Some(s_ref.refresh(meta));
+        }
+        let s_ref = &*self.slag;
+        // Frees only ever increase the reference count, so if the current slag is over the cutoff
+        // now it will still be once it is unclaimed. Otherwise we will probably need a new slag;
+        // get it while we still hold the current one, so that we can keep it if there is none.
+        let (_, now) = s_ref.rc.load();
+        let spare = if now < meta.cutoff_objects {
+            Some(self.next_slag(meta)?)
+        } else {
+            None
+        };
         let (_claimed, was) = s_ref.rc.unclaim();
         // We used to have this debug_assert
         //
@@ -1621,30 +2206,41 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
             let _claimed = s_ref.rc.claim();
             debug_assert!(_claimed,
                           "claiming slag either during initialization or due to being over cutoff");
-            s_ref.refresh(meta)
+            if let Some(slab) = spare {
+                // enough objects were freed in the meantime; give the spare slag back.
+                self.transition_available(slab);
+            }
+            Some(s_ref.refresh(meta))
         } else {
             // we need a new slag!
-            // first we try and get a slag from the available slagpipe. If it is empty, then we get
-            // a fresh page from PageAlloc and initialize it with the current object class's
-            // metadata.
-            let next_slab = match self.available.try_pop_mut() {
-                Ok(slab) => {
-                    trace_event!(grabbed_available);
-                    slab
-                }
-                Err(_) => {
-                    let new_raw = self.pages.alloc() as *mut Slag;
-                    if (*new_raw).meta.load(Ordering::Relaxed) != self.m {
-                        Slag::init(new_raw, meta);
-                    }
-                    new_raw
-                }
-            };
-            self.slag = next_slab;
+            self.slag = spare.expect("reference count decreased while claimed");
             let s_ref = self.slag.as_mut().expect("s_ref_2"); // let s_ref = &*self.slag;
             let claimed = s_ref.rc.claim();
             debug_assert!(claimed, "claiming new slag after refresh");
-            s_ref.refresh(meta)
+            Some(s_ref.refresh(meta))
+        }
+    }
+
+    /// Get a `Slag` to replace the current one, or `None` if the backing memory is exhausted.
+    ///
+    /// First we try and get a slag from the available slagpipe. If it is empty, then we get a
+    /// fresh page from `PageAlloc` and initialize it with the current object class's metadata.
+    unsafe fn next_slag(&mut self, meta: &Metadata) -> Option<*mut Slag> {
+        match self.available.try_pop_mut() {
+            Ok(slab) => {
+                trace_event!(grabbed_available);
+                Some(slab)
+            }
+            Err(_) => {
+                let new_raw = self.pages.alloc() as *mut Slag;
+                if new_raw.is_null() {
+                    return None;
+                }
+                if (*new_raw).meta.load(Ordering::Relaxed) != self.m {
+                    Slag::init(new_raw, meta);
+                }
+                Some(new_raw)
+            }
         }
     }
 
@@ -1714,15 +2310,83 @@ impl<CA: CoarseAllocator> SlagAllocator<CA> {
             it_slag == self.slag
         }
     }
+
+    /// Move objects out of sparsely-used `Slag`s and into fuller ones, freeing the `Slag`s that
+    /// this empties back to the page allocator.
+    ///
+    /// The `Slag`s considered are the available ones: those with enough free objects to be
+    /// handed out again. Objects are moved from the emptiest of them into the fullest, so that as
+    /// many as possible are emptied. The current `Slag` is left alone. Each move is appended to
+    /// `moved` as a pair of the object's old and new addresses, and the number of `Slag`s freed
+    /// is returned.
+    ///
+    /// This is only safe if nothing else is using this size class, and if no cache holds any of
+    /// its free objects: objects that are not available in their `Slag`'s bit-set are treated as
+    /// live, and moved.
+    pub unsafe fn compact(&mut self, moved: &mut Vec<(*mut u8, *mut u8)>) -> usize {
+        let meta = &*self.m;
+        let mut slags = Vec::new();
+        while let Ok(slag) = self.available.try_pop_mut() {
+            slags.push(slag);
+        }
+        // Sort by the number of live objects, so that sources are taken from the front and
+        // destinations from the back.
+        slags.sort_by_key(|&slag| meta.n_objects - (*slag).rc.load().1);
+        let mut freed = 0;
+        let (mut src, mut dst) = (0, slags.len());
+        // The next object of the destination to check for availability.
+        let mut dst_obj = 0;
+        'sources: while src + 1 < dst {
+            let s_ref = &*slags[src];
+            for i in 0..meta.n_objects {
+                let (item, word, mask) = s_ref.object_bit(i, meta);
+                if word.load(Ordering::Relaxed) & mask != 0 {
+                    continue;
+                }
+                // find an available object in the fullest remaining destination
+                let new = loop {
+                    if dst_obj == meta.n_objects {
+                        dst -= 1;
+                        dst_obj = 0;
+                        if src + 1 == dst {
+                            break 'sources;
+                        }
+                    }
+                    let (new, new_word, new_mask) = (*slags[dst - 1]).object_bit(dst_obj, meta);
+                    dst_obj += 1;
+                    if new_word.load(Ordering::Relaxed) & new_mask != 0 {
+                        new_word.fetch_and(!new_mask, Ordering::Relaxed);
+                        (*slags[dst - 1]).rc.dec_n(1);
+                        break new;
+                    }
+                };
+                ptr::copy_nonoverlapping(item, new, meta.object_size);
+                s_ref.rc.inc_n(1);
+                word.fetch_or(mask, Ordering::Relaxed);
+                moved.push((item, new));
+            }
+            src += 1;
+        }
+        for slag in slags {
+            let (_, avail) = (*slag).rc.load();
+            if avail == meta.n_objects {
+                let decommit = meta.usable_size >= self.eager_decommit_threshold;
+                self.pages.free(slag as *mut u8, decommit);
+                freed += 1;
+            } else if avail >= meta.cutoff_objects {
+                self.transition_available(slag);
+            }
+            // Otherwise, the slag becomes available again once enough of its objects are freed,
+            // as if it had just been filled.
+        }
+        freed
+    }
 }
 
 impl<CA: CoarseAllocator> Clone for SlagAllocator<CA> {
     fn clone(&self) -> Self {
         let mut new_page_handle = self.pages.clone();
-        let first_slag = unsafe { new_page_handle.alloc() as *mut Slag };
-        unsafe {
-            Slag::init(first_slag, self.m.as_ref().unwrap());
-        };
+        let first_slag = unsafe { first_slag(&mut new_page_handle, &*self.m) };
         SlagAllocator {
             m: self.m,
             slag: first_slag,
@@ -1882,4 +2546,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn percpu_many_threads() {
+        // Many more threads than CPUs share the same shards. Each thread frees half of its
+        // objects and hands the other half to its neighbor, so shards see plenty of objects that
+        // were allocated from other shards.
+        let _ = env_logger::init();
+        use std::sync::mpsc::channel;
+        const N_ITEMS: usize = 4096 * 4;
+        const N_THREADS: usize = 64;
+        let oa = AllocBuilder::<[usize; 4]>::default()
+            .page_size(4096)
+            .build_percpu();
+        assert_eq!(oa.0.n_shards(), ::num_cpus::get());
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..N_THREADS).map(|_| channel()).unzip();
+        let mut threads = Vec::new();
+        for (t, recv) in receivers.into_iter().enumerate() {
+            let mut my_alloc = oa.clone();
+            let send = senders[(t + 1) % N_THREADS].clone();
+            threads.push(thread::spawn(move || {
+                let mut h = HashSet::new();
+                for i in 0..N_ITEMS {
+                    unsafe {
+                        let item = my_alloc.alloc();
+                        assert!(h.insert(item as usize), "object allocated twice");
+                        write_volatile(item, [t, i, t, i]);
+                    }
+                }
+                for (n, item) in h.into_iter().enumerate() {
+                    unsafe {
+                        let item = item as *mut [usize; 4];
+                        assert_eq!((*item)[0], t);
+                        assert_eq!((*item)[0], (*item)[2]);
+                        if n % 2 == 0 {
+                            my_alloc.free(item);
+                        } else {
+                            send.send(item as usize).unwrap();
+                        }
+                    }
+                }
+                drop(send);
+                for item in recv {
+                    unsafe {
+                        let item = item as *mut [usize; 4];
+                        assert_eq!((*item)[1], (*item)[3]);
+                        my_alloc.free(item);
+                    }
+                }
+            }));
+        }
+        drop(senders);
+        for t in threads {
+            t.join().expect("threads should exit successfully");
+        }
+    }
 }
@@ -6,8 +6,10 @@
 
 //! Some basic utilities used throughout the allocator code.
 use std::cmp;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub mod mmap {
     extern crate mmap_alloc;
@@ -43,6 +45,161 @@ pub mod mmap {
             .build()
             .uncommit(p, Layout::from_size_align(len, 1).unwrap())
     }
+
+    /// Resize the mapping at `p` from `old_len` to `new_len` bytes (both multiples of the page
+    /// size), moving it if necessary. Returns `None` if the new mapping could not be created, in
+    /// which case the old mapping is left untouched.
+    pub unsafe fn remap(p: *mut u8, old_len: usize, new_len: usize) -> Option<*mut u8> {
+        MapAllocBuilder::default()
+            .exec()
+            .build()
+            .realloc_obj(p, old_len, new_len)
+            .ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn dont_fork(p: *mut u8, len: usize) {
+        MapAllocBuilder::default().build().mark_dont_fork(p, len)
+    }
+}
+
+/// The allocator that services elfmalloc's own metadata.
+///
+/// This covers the allocator's internal bookkeeping: `TypedArray`s (such as the size class
+/// tables), the `Metadata` computed for each size class by `compute_metadata`, and the like. It
+/// does not cover the pages that objects are carved out of, nor the few allocations that go
+/// through standard library types (`Arc`s, channels, thread-local storage), which always use the
+/// global heap.
+///
+/// Which allocator is used is chosen at build time with a Cargo feature:
+///
+/// - By default, metadata is mapped directly with `mmap`, which works regardless of the global
+///   allocator.
+/// - With `heap_metadata`, it comes from the global heap, which is `bsalloc`. This cannot be
+///   combined with the `use_default_allocator` feature: `global::ElfGlobal` may then be the
+///   global allocator, and allocating metadata would recurse into elfmalloc.
+/// - With `system_metadata`, it comes from the system allocator (`malloc`). This is meant for
+///   using elfmalloc as a library alongside another global allocator (see the `library`
+///   feature). It cannot be combined with `elfc` (which enables the `replaces_malloc` feature),
+///   since `elfc` replaces `malloc` itself.
+/// - With `extern_metadata`, it comes from functions that the program must define (see below).
+///   This takes precedence over the other features.
+///
+/// ```rust,ignore
+/// // Allocate `size` bytes aligned to `align`, returning null on failure.
+/// #[no_mangle]
+/// pub unsafe extern "C" fn elfmalloc_metadata_alloc(size: usize, align: usize) -> *mut u8;
+/// // Free memory returned by `elfmalloc_metadata_alloc` with the same size and alignment.
+/// #[no_mangle]
+/// pub unsafe extern "C" fn elfmalloc_metadata_dealloc(ptr: *mut u8, size: usize, align: usize);
+/// ```
+///
+/// All memory returned by `alloc` is page-aligned and zero-filled, like a fresh mapping.
+pub mod meta_alloc {
+    #[cfg(all(feature = "heap_metadata",
+              feature = "use_default_allocator",
+              not(any(feature = "extern_metadata", feature = "system_metadata"))))]
+    compile_error!("the heap_metadata feature requires bsalloc, so it cannot be combined with \
+                    use_default_allocator (ElfGlobal would allocate its own metadata)");
+    #[cfg(all(feature = "system_metadata",
+              feature = "replaces_malloc",
+              not(feature = "extern_metadata")))]
+    compile_error!("the system_metadata feature cannot be used with elfc, which replaces the \
+                    system allocator (elfmalloc would allocate its own metadata)");
+
+    #[cfg(any(feature = "extern_metadata",
+              feature = "system_metadata",
+              feature = "heap_metadata"))]
+    use super::PAGE_SIZE;
+    #[cfg(all(any(feature = "system_metadata", feature = "heap_metadata"),
+              not(feature = "extern_metadata")))]
+    use super::super::alloc::allocator::{Alloc, Layout};
+    #[cfg(not(any(feature = "extern_metadata",
+                  feature = "system_metadata",
+                  feature = "heap_metadata")))]
+    use super::mmap;
+    #[cfg(all(feature = "heap_metadata",
+              not(any(feature = "extern_metadata", feature = "system_metadata"))))]
+    use super::super::alloc::heap::Heap;
+    #[cfg(all(feature = "system_metadata", not(feature = "extern_metadata")))]
+    use std::heap::System;
+    #[cfg(feature = "extern_metadata")]
+    use std::ptr;
+
+    #[cfg(feature = "extern_metadata")]
+    extern "C" {
+        /// Allocate `size` bytes aligned to `align`, returning null on failure.
+        fn elfmalloc_metadata_alloc(size: usize, align: usize) -> *mut u8;
+        /// Free memory returned by `elfmalloc_metadata_alloc` with the same size and alignment.
+        fn elfmalloc_metadata_dealloc(ptr: *mut u8, size: usize, align: usize);
+    }
+
+    /// Allocate `size` bytes of metadata, returning `None` on failure.
+    #[cfg(feature = "extern_metadata")]
+    pub fn alloc(size: usize) -> Option<*mut u8> {
+        unsafe {
+            let ptr = elfmalloc_metadata_alloc(size, PAGE_SIZE);
+            if ptr.is_null() {
+                return None;
+            }
+            ptr::write_bytes(ptr, 0, size);
+            Some(ptr)
+        }
+    }
+
+    /// Free `size` bytes of metadata at `ptr`, which must have been returned by `alloc(size)`.
+    #[cfg(feature = "extern_metadata")]
+    pub unsafe fn dealloc(ptr: *mut u8, size: usize) {
+        elfmalloc_metadata_dealloc(ptr, size, PAGE_SIZE)
+    }
+
+    /// Allocate `size` bytes of metadata, returning `None` on failure.
+    #[cfg(all(feature = "system_metadata", not(feature = "extern_metadata")))]
+    pub fn alloc(size: usize) -> Option<*mut u8> {
+        unsafe { System.alloc_zeroed(layout(size)).ok() }
+    }
+
+    /// Free `size` bytes of metadata at `ptr`, which must have been returned by `alloc(size)`.
+    #[cfg(all(feature = "system_metadata", not(feature = "extern_metadata")))]
+    pub unsafe fn dealloc(ptr: *mut u8, size: usize) {
+        System.dealloc(ptr, layout(size))
+    }
+
+    /// Allocate `size` bytes of metadata, returning `None` on failure.
+    #[cfg(all(feature = "heap_metadata",
+              not(any(feature = "extern_metadata", feature = "system_metadata"))))]
+    pub fn alloc(size: usize) -> Option<*mut u8> {
+        unsafe { Heap.alloc_zeroed(layout(size)).ok() }
+    }
+
+    /// Free `size` bytes of metadata at `ptr`, which must have been returned by `alloc(size)`.
+    #[cfg(all(feature = "heap_metadata",
+              not(any(feature = "extern_metadata", feature = "system_metadata"))))]
+    pub unsafe fn dealloc(ptr: *mut u8, size: usize) {
+        Heap.dealloc(ptr, layout(size))
+    }
+
+    /// Allocate `size` bytes of metadata, returning `None` on failure.
+    #[cfg(not(any(feature = "extern_metadata",
+                  feature = "system_metadata",
+                  feature = "heap_metadata")))]
+    pub fn alloc(size: usize) -> Option<*mut u8> {
+        mmap::fallible_map(size)
+    }
+
+    /// Free `size` bytes of metadata at `ptr`, which must have been returned by `alloc(size)`.
+    #[cfg(not(any(feature = "extern_metadata",
+                  feature = "system_metadata",
+                  feature = "heap_metadata")))]
+    pub unsafe fn dealloc(ptr: *mut u8, size: usize) {
+        mmap::unmap(ptr, size)
+    }
+
+    #[cfg(all(any(feature = "system_metadata", feature = "heap_metadata"),
+              not(feature = "extern_metadata")))]
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, PAGE_SIZE).unwrap()
+    }
 }
 
 // we use the unlikely intrinsic if it is available.
@@ -96,6 +253,11 @@ impl<T: LazyInitializable> Lazy<T> {
             val: UnsafeCell::new(None),
         }
     }
+
+    /// Get a mutable reference to the underlying object, but only if it has been initialized.
+    pub fn get_mut_if_init(&mut self) -> Option<&mut T> {
+        unsafe { (*self.val.get()).as_mut() }
+    }
 }
 
 impl<T: LazyInitializable> Deref for Lazy<T> {
@@ -124,6 +286,40 @@ impl<T: LazyInitializable> DerefMut for Lazy<T> {
     }
 }
 
+/// A minimal spin lock protecting a `T`.
+///
+/// Unlike `std::sync::Mutex`, `SpinLock` only relies on atomics, so it does not depend on `std`
+/// and never allocates. It is meant for short critical sections on cold paths; waiters spin
+/// rather than block, and the lock is not poisoned if a holder panics.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    val: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub fn new(val: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            val: UnsafeCell::new(val),
+        }
+    }
+
+    /// Call `f` on the protected value with the lock held.
+    ///
+    /// The lock is not reentrant: `f` must not call `with` on the same lock.
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            while self.locked.load(Ordering::Relaxed) {}
+        }
+        let res = f(unsafe { &mut *self.val.get() });
+        self.locked.store(false, Ordering::Release);
+        res
+    }
+}
+
 
 /// A low-level dynamic collection of `T` values.
 ///
@@ -145,20 +341,28 @@ pub const PAGE_SIZE: usize = 4096;
 
 impl<T> TypedArray<T> {
     pub fn new(size: usize) -> TypedArray<T> {
-        use std::mem::size_of;
-        let bytes = size_of::<T>() * size;
+        Self::try_new(size).expect("mmap should not fail")
+    }
+
+    /// Like `new`, but returns `None` if the memory for the array could not be mapped.
+    pub fn try_new(size: usize) -> Option<TypedArray<T>> {
+        let bytes = mem::size_of::<T>() * size;
         let rem = bytes % PAGE_SIZE;
         let n_pages = bytes / PAGE_SIZE + cmp::min(1, rem);
         let region_size = n_pages * PAGE_SIZE;
-        let mem = mmap::map(region_size);
-        TypedArray {
-            data: mem as *mut T,
-            len: size,
-            mapped: region_size,
-        }
+        let mem = meta_alloc::alloc(region_size)?;
+        Some(TypedArray {
+                 data: mem as *mut T,
+                 len: size,
+                 mapped: region_size,
+             })
     }
 
     pub fn iter(&self) -> TypedArrayIter<T> {
+        debug_assert!(self.len * mem::size_of::<T>() <= self.mapped,
+                      "TypedArray of length {} does not fit in {} mapped bytes",
+                      self.len,
+                      self.mapped);
         TypedArrayIter {
             inner: self,
             cur: 0,
@@ -176,7 +380,7 @@ impl<T> TypedArray<T> {
     }
 
     pub unsafe fn destroy(&self) {
-        mmap::unmap(self.data as *mut u8, self.mapped);
+        meta_alloc::dealloc(self.data as *mut u8, self.mapped);
     }
 }
 
@@ -0,0 +1,73 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// This is a separate test binary so that the logger installed below is the only one.
+extern crate elfmalloc;
+extern crate env_logger;
+#[macro_use]
+extern crate lazy_static;
+extern crate log;
+
+use elfmalloc::general::DynamicAllocator;
+use env_logger::LogBuilder;
+use log::{LogLevel, LogLevelFilter, LogRecord};
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref RECORDS: Mutex<Vec<(LogLevel, String)>> = Mutex::new(Vec::new());
+}
+
+/// Take the messages logged by elfmalloc so far.
+fn take_records() -> Vec<(LogLevel, String)> {
+    let mut records = RECORDS.lock().unwrap();
+    records.drain(..).collect()
+}
+
+#[test]
+fn alloc_fail_log() {
+    LogBuilder::new()
+        .filter(Some("elfmalloc"), LogLevelFilter::Debug)
+        .format(|record: &LogRecord| {
+                    let msg = format!("{}", record.args());
+                    RECORDS.lock().unwrap().push((record.level(), msg.clone()));
+                    msg
+                })
+        .init()
+        .unwrap();
+
+    let mut da = DynamicAllocator::new();
+    // Creating the allocator logs about its heap; only the allocations below are checked.
+    take_records();
+    unsafe {
+        // Successful allocations only log when a size class is first used.
+        let item = da.alloc(16);
+        assert!(!item.is_null());
+        da.free(item);
+        let records = take_records();
+        assert!(records
+                    .iter()
+                    .any(|&(level, ref msg)| {
+                             level == LogLevel::Debug && msg.contains("initializing size class")
+                         }),
+                "{:?}",
+                records);
+        assert!(records.iter().all(|&(level, _)| level == LogLevel::Debug),
+                "{:?}",
+                records);
+
+        let size = usize::max_value() - 1;
+        assert!(da.alloc(size).is_null());
+        let records = take_records();
+        assert!(records
+                    .iter()
+                    .any(|&(level, ref msg)| {
+                             level == LogLevel::Warn && msg.contains(&size.to_string()) &&
+                             msg.contains("too large")
+                         }),
+                "{:?}",
+                records);
+    }
+}
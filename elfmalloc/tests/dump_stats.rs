@@ -0,0 +1,81 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// This is a separate test binary so that the logger installed below is the only one.
+#![cfg(feature = "dump_stats_on_drop")]
+
+extern crate elfmalloc;
+extern crate env_logger;
+#[macro_use]
+extern crate lazy_static;
+extern crate log;
+
+use elfmalloc::general::DynamicAllocator;
+use env_logger::LogBuilder;
+use log::{LogLevel, LogLevelFilter, LogRecord};
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref RECORDS: Mutex<Vec<(LogLevel, String)>> = Mutex::new(Vec::new());
+}
+
+#[test]
+fn dump_stats_on_drop() {
+    LogBuilder::new()
+        .filter(Some("elfmalloc"), LogLevelFilter::Info)
+        .format(|record: &LogRecord| {
+                    let msg = format!("{}", record.args());
+                    RECORDS.lock().unwrap().push((record.level(), msg.clone()));
+                    msg
+                })
+        .init()
+        .unwrap();
+
+    let histograms = || {
+        RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&(level, ref msg)| level == LogLevel::Info && msg.contains("histogram"))
+            .map(|&(_, ref msg)| msg.clone())
+            .collect::<Vec<_>>()
+    };
+
+    let mut da = DynamicAllocator::new();
+    unsafe {
+        let mut items = Vec::new();
+        for _ in 0..3 {
+            items.push(da.alloc(24));
+        }
+        for _ in 0..4 {
+            items.push(da.alloc(1000));
+        }
+        // Allocations made through clones are counted in the same histogram, which is only logged
+        // once the last clone is dropped.
+        let mut clone = da.clone();
+        items.push(clone.alloc(1000));
+        items.push(clone.alloc(4 << 20));
+        drop(clone);
+        assert!(histograms().is_empty());
+        for item in items {
+            da.free(item);
+        }
+    }
+    drop(da);
+
+    let histograms = histograms();
+    assert_eq!(histograms.len(), 1, "{:?}", histograms);
+    let hist = &histograms[0];
+    assert!(hist.contains("(9 allocations)"), "{}", hist);
+    let rows: Vec<Vec<&str>> = hist.lines()
+        .skip(2)
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(rows,
+               vec![vec!["32", "3"], vec!["1024", "5"], vec!["large", "1"]],
+               "{}",
+               hist);
+}
@@ -0,0 +1,42 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// ElfGlobal can only be the global allocator when bsalloc is not linked in.
+#![cfg(feature = "use_default_allocator")]
+#![feature(global_allocator)]
+extern crate elfmalloc;
+
+use elfmalloc::general::global::ElfGlobal;
+use std::collections::HashMap;
+use std::thread;
+
+#[global_allocator]
+static GLOBAL: ElfGlobal = ElfGlobal;
+
+#[test]
+fn global_allocator_smoke() {
+    let threads = (0..4)
+        .map(|t| {
+            thread::spawn(move || {
+                let mut v = Vec::new();
+                let mut m = HashMap::new();
+                for i in 0..100_000 {
+                    v.push(i * t);
+                    if i % 100 == 0 {
+                        m.insert(i, format!("{}-{}", t, i));
+                    }
+                }
+                let big = vec![t as u8; 4 << 20].into_boxed_slice();
+                assert!(big.iter().all(|b| *b == t as u8));
+                assert_eq!(m[&500], format!("{}-500", t));
+                v.iter().sum::<usize>()
+            })
+        })
+        .collect::<Vec<_>>();
+    for (t, h) in threads.into_iter().enumerate() {
+        assert_eq!(h.join().unwrap(), t * (100_000 * 99_999 / 2));
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// In the library configuration, bsalloc is not linked in, so the program's global allocator is
+// the system allocator, and elfmalloc's metadata is allocated from it as well.
+#![cfg(feature = "library")]
+extern crate elfmalloc;
+
+use elfmalloc::general::{DynamicAllocator, DynamicAllocatorBuilder, Strategy};
+use std::thread;
+
+#[test]
+fn library_alloc() {
+    let da = DynamicAllocator::new();
+    let threads = (0..4)
+        .map(|t| {
+            let mut da = da.clone();
+            thread::spawn(move || unsafe {
+                // Mix allocations from elfmalloc with allocations from the global heap.
+                let mut heap = Vec::new();
+                let items: Vec<_> = (0..10_000)
+                    .map(|i| {
+                             let size = 1 + (i * 7) % 4096;
+                             let item = da.alloc(size);
+                             assert!(!item.is_null());
+                             *item = (i + t) as u8;
+                             heap.push(vec![t as u8; size]);
+                             (item, i)
+                         })
+                    .collect();
+                for (item, i) in items {
+                    assert_eq!(*item, (i + t) as u8);
+                    da.free(item);
+                }
+                assert!(heap.iter().all(|v| v.iter().all(|&b| b == t as u8)));
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    // Allocators with their own size class tables work too, and release their metadata to the
+    // system allocator when dropped.
+    for _ in 0..8 {
+        let mut da = DynamicAllocatorBuilder::default()
+            .size_classes(Strategy::PowersOfTwo)
+            .build();
+        unsafe {
+            let item = da.alloc(64);
+            assert!(!item.is_null());
+            da.free(item);
+        }
+    }
+}
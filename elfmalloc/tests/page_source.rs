@@ -0,0 +1,77 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// This is a separate test binary so that no other test's MapAlloc reports to the hooks below.
+extern crate elfmalloc;
+extern crate mmap_alloc;
+
+use elfmalloc::general::DynamicAllocatorBuilder;
+use mmap_alloc::MapAllocBuilder;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+static MAPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+static MAPPED_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+static UNMAPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn on_map(ptr: *mut u8, size: usize) {
+    MAPPED.store(ptr as usize, Ordering::SeqCst);
+    MAPPED_SIZE.store(size, Ordering::SeqCst);
+}
+
+fn on_unmap(ptr: *mut u8, _size: usize) {
+    UNMAPPED.store(ptr as usize, Ordering::SeqCst);
+}
+
+#[test]
+fn map_alloc_page_source() {
+    const HEAP_SIZE: usize = 1 << 30;
+    let alloc = MapAllocBuilder::default().on_map(on_map).on_unmap(on_unmap).build();
+    let mut da = DynamicAllocatorBuilder::default().page_source(alloc, HEAP_SIZE).build();
+    let base = MAPPED.load(Ordering::SeqCst);
+    assert_ne!(base, 0);
+    assert_eq!(MAPPED_SIZE.load(Ordering::SeqCst), HEAP_SIZE);
+    unsafe {
+        // all of the size classes' objects come from the MapAlloc's region
+        let items: Vec<_> = (1..4096).map(|size| (da.alloc(size), size)).collect();
+        for &(item, size) in &items {
+            assert!(item as usize >= base && item as usize + size <= base + HEAP_SIZE);
+            *item = 1;
+        }
+        for (item, _) in items {
+            da.free(item);
+        }
+    }
+    // the region is returned to the MapAlloc once the last clone is dropped
+    let clone = da.clone();
+    mem::drop(da);
+    assert_eq!(UNMAPPED.load(Ordering::SeqCst), 0);
+    mem::drop(clone);
+    assert_eq!(UNMAPPED.load(Ordering::SeqCst), base);
+}
+
+#[test]
+fn huge_page_source_fallback() {
+    // On a host without enough reserved huge pages, allocating the region fails, and the
+    // allocator falls back to normal pages rather than failing to initialize.
+    const HEAP_SIZE: usize = 1 << 30;
+    let alloc = match MapAllocBuilder::default().huge_pagesize(1 << 21).try_build() {
+        Ok(alloc) => alloc,
+        // 2MB huge pages are not supported on this platform
+        Err(_) => return,
+    };
+    let mut da = DynamicAllocatorBuilder::default().page_source(alloc, HEAP_SIZE).build();
+    unsafe {
+        let items: Vec<_> = (1..4096).map(|size| (da.alloc(size), size)).collect();
+        for &(item, _) in &items {
+            assert!(!item.is_null());
+            *item = 1;
+        }
+        for (item, _) in items {
+            da.free(item);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// This is a separate test binary so that nothing else uses the global allocator (or starts its
+// background thread) before single-threaded mode is enabled.
+#![cfg(target_os = "linux")]
+extern crate elfmalloc;
+
+use elfmalloc::general::global;
+use std::fs;
+
+fn n_threads() -> usize {
+    fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+#[test]
+fn single_threaded_frees_synchronously() {
+    unsafe { global::set_single_threaded(true) };
+    assert!(global::is_single_threaded());
+    let before = n_threads();
+    unsafe {
+        let ptrs: Vec<_> = (0..(1 << 16)).map(|i| global::alloc(1 + i % 4096)).collect();
+        for p in ptrs {
+            global::free(p);
+        }
+        let large = global::alloc(8 << 20);
+        global::free(large);
+    }
+    global::trim();
+    // all of that work happened on this thread: the background thread was never started
+    assert_eq!(n_threads(), before);
+
+    // This thread's allocator is torn down when the test harness's thread exits; let the
+    // background thread do that, as usual.
+    unsafe { global::set_single_threaded(false) };
+}
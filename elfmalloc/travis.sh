@@ -11,6 +11,6 @@ set -e
 
 cargo build --verbose --all
 RUST_BACKTRACE=1 cargo test --verbose --all -- --ignored
-for feature in prime_schedules huge_segments no_lazy_region nightly; do
+for feature in prime_schedules huge_segments no_lazy_region nightly library; do
   RUST_BACKTRACE=1 cargo test --verbose --all --features "$feature" -- --ignored
 done
@@ -0,0 +1,160 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+//! Benchmarks comparing the cost of the system calls behind different `MapAlloc` options.
+//!
+//! These are only built with the `bench` feature:
+//!
+//! ```text
+//! cargo bench --features bench
+//! ```
+//!
+//! Benchmarks for options that are not supported on the host (for example, huge pages that have
+//! not been reserved, or `MADV_FREE` on older Linux kernels) return without measuring anything,
+//! and so report 0 ns/iter.
+
+extern crate test;
+
+use self::test::Bencher;
+use core::cmp;
+use sysconf::page::pagesize;
+use super::*;
+use super::perms::PROT_READ_WRITE;
+
+const SMALL: usize = 1;
+const LARGE: usize = 1 << 24;
+
+// touch writes to each page in the given range, committing it.
+fn touch(ptr: *mut u8, size: usize) {
+    let mut off = 0;
+    while off < size {
+        unsafe { ptr::write_volatile(ptr.offset(off as isize), 1) };
+        off += pagesize();
+    }
+}
+
+fn bench_alloc_dealloc(b: &mut Bencher, alloc: &mut MapAlloc, pages: usize, touch_pages: bool) {
+    let layout = Layout::from_size_align(pages * alloc.pagesize, 1).unwrap();
+    b.iter(|| unsafe {
+        let ptr = <MapAlloc as Alloc>::alloc(alloc, layout.clone()).unwrap();
+        if touch_pages {
+            touch(ptr, layout.size());
+        }
+        <MapAlloc as Alloc>::dealloc(alloc, ptr, layout.clone());
+    })
+}
+
+#[bench]
+fn map_unmap_small(b: &mut Bencher) {
+    bench_alloc_dealloc(b, &mut MapAlloc::default(), SMALL, false)
+}
+
+#[bench]
+fn map_unmap_large(b: &mut Bencher) {
+    bench_alloc_dealloc(b, &mut MapAlloc::default(), LARGE / pagesize(), false)
+}
+
+// huge_alloc returns an allocator using the default huge page size, or None if huge pages are not
+// available on the host.
+fn huge_alloc() -> Option<MapAlloc> {
+    #[cfg(target_os = "linux")]
+    let huge = ::sysconf::page::default_hugepage();
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    let huge = Some(SUPERPAGE_SIZE_2MB);
+    #[cfg(windows)]
+    let huge = match unsafe { ::kernel32::GetLargePageMinimum() } as usize {
+        0 => None,
+        min => Some(min),
+    };
+    let mut alloc = MapAllocBuilder::default().huge_pagesize(huge?).try_build().ok()?;
+    // Building succeeds as long as the size is supported, but mapping fails if no huge pages
+    // have been reserved.
+    let layout = Layout::from_size_align(1, 1).unwrap();
+    let ptr = unsafe { <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).ok()? };
+    unsafe { <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout) };
+    Some(alloc)
+}
+
+#[bench]
+fn map_touch_unmap_huge(b: &mut Bencher) {
+    if let Some(mut alloc) = huge_alloc() {
+        let pages = LARGE / alloc.pagesize;
+        bench_alloc_dealloc(b, &mut alloc, cmp::max(1, pages), true)
+    }
+}
+
+#[bench]
+fn map_touch_unmap_normal(b: &mut Bencher) {
+    bench_alloc_dealloc(b, &mut MapAlloc::default(), LARGE / pagesize(), true)
+}
+
+#[cfg(target_os = "linux")]
+#[bench]
+fn map_populate_unmap(b: &mut Bencher) {
+    // The same work as map_touch_unmap_normal, but the kernel commits every page up front.
+    b.iter(|| {
+               let ptr = mmap_at(ptr::null_mut(),
+                                 LARGE,
+                                 PROT_READ_WRITE,
+                                 None,
+                                 ::libc::MAP_POPULATE)
+                       .unwrap();
+               munmap(ptr, LARGE);
+           })
+}
+
+// bench_uncommit repeatedly commits a region and then releases it with the given madvise advice.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn bench_uncommit(b: &mut Bencher, advice: ::libc::c_int) {
+    let ptr = mmap(LARGE, PROT_READ_WRITE, None).unwrap();
+    let madvise = || unsafe { ::libc::madvise(ptr as *mut ::libc::c_void, LARGE, advice) };
+    if madvise() != 0 {
+        // not supported by this kernel
+        munmap(ptr, LARGE);
+        return;
+    }
+    b.iter(|| {
+               touch(ptr, LARGE);
+               assert_eq!(madvise(), 0);
+           });
+    munmap(ptr, LARGE);
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+#[bench]
+fn uncommit_dontneed(b: &mut Bencher) {
+    bench_uncommit(b, ::libc::MADV_DONTNEED)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+#[bench]
+fn uncommit_free(b: &mut Bencher) {
+    bench_uncommit(b, ::libc::MADV_FREE)
+}
+
+// The object allocation path (UntypedObjectAlloc::alloc, which calls alloc_obj) compared with the
+// general path that it used to go through (alloc_excess, which rounds the size and checks the
+// alignment again).
+
+#[bench]
+fn obj_alloc_dealloc(b: &mut Bencher) {
+    let mut alloc = MapAlloc::default();
+    b.iter(|| unsafe {
+               let ptr = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+               UntypedObjectAlloc::dealloc(&mut alloc, ptr);
+           })
+}
+
+#[bench]
+fn obj_alloc_excess_dealloc(b: &mut Bencher) {
+    let mut alloc = MapAlloc::default();
+    let layout = UntypedObjectAlloc::layout(&alloc);
+    b.iter(|| unsafe {
+               let Excess(ptr, _) = <MapAlloc as Alloc>::alloc_excess(&mut alloc, layout.clone())
+                   .unwrap();
+               UntypedObjectAlloc::dealloc(&mut alloc, ptr);
+           })
+}
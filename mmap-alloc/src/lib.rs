@@ -5,36 +5,53 @@
 // may not be copied, modified, or distributed except according to those terms.
 
 // TODO:
-// - Figure out how to panic without allocating
-// - Support all Unices, not just Linux and Mac
-// - Add tests for UntypedObjectAlloc impls
+// - Support all Unices, not just Linux and Darwin (macOS, iOS, and tvOS)
 
 #![cfg_attr(any(not(test), feature = "test-no-std"), no_std)]
 #![cfg_attr(all(test, not(feature = "test-no-std")), feature(test))]
 #![feature(alloc, allocator_api)]
+#![cfg_attr(feature = "fault_injection", feature(thread_local))]
 
 #[cfg(all(test, not(feature = "test-no-std")))]
 extern crate core;
+#[cfg(all(feature = "std", any(not(test), feature = "test-no-std")))]
+#[macro_use]
+extern crate std;
 
 extern crate alloc;
 extern crate libc;
 extern crate object_alloc;
 extern crate sysconf;
 
-#[cfg(any(target_os = "linux", target_os = "macos"))]
-extern crate errno;
-
+#[cfg(windows)]
+extern crate advapi32;
 #[cfg(windows)]
 extern crate kernel32;
 #[cfg(windows)]
 extern crate winapi;
 
-use self::alloc::allocator::{Alloc, Layout, Excess, AllocErr};
+use self::alloc::allocator::{Alloc, Layout, Excess, AllocErr, CannotReallocInPlace};
 use self::object_alloc::{Exhausted, UntypedObjectAlloc};
-use core::ptr;
+use core::{fmt, mem, ptr, slice};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-#[cfg(any(target_os = "linux", target_os = "macos"))]
-use errno::errno;
+#[cfg(feature = "alloc")]
+use self::alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::string::String;
+
+// debug_check is like debug_assert, except that the message must be a string literal. Failed
+// checks panic with that message as is, without any formatting, so that they don't allocate.
+macro_rules! debug_check {
+    ($cond:expr, $msg:expr) => {
+        if cfg!(debug_assertions) && !$cond {
+            panic!($msg);
+        }
+    };
+}
 
 /// A builder for `MapAlloc`.
 ///
@@ -62,42 +79,238 @@ use errno::errno;
 /// - Windows:
 ///   - Write permission is not supported; it is implemented as read/write.
 ///   - Write/execute permission is not supported; it is implemented as read/write/execute.
+///
+/// # Page and Object Sizes
+///
+/// All allocations are made in multiples of the page size, which is the system's default page
+/// size unless huge pages are configured (using `huge_pagesize` or `default_huge_pagesize`), in
+/// which case it is the huge page size. The object size used by the `UntypedObjectAlloc`
+/// implementation (set with `obj_size`) must be a multiple of this page size, and defaults to a
+/// single page. Objects may span many pages, but are always aligned to the page size.
+///
+/// On Windows, large pages additionally require that the user have the "Lock pages in memory"
+/// right (`SeLockMemoryPrivilege`); `try_build` enables the privilege for the process, and fails
+/// with `LockMemoryPrivilege` if it is not held. `huge_pages_optional` can be used to fall back to
+/// normal pages instead.
+///
+/// # Hooks
+///
+/// `on_map` and `on_unmap` register functions that are called with the address and size of each
+/// region of memory that is mapped or unmapped. They are plain function pointers rather than
+/// closures so that they can be used in `no_std` environments without allocating.
 pub struct MapAllocBuilder {
     read: bool,
     write: bool,
     exec: bool,
     pagesize: usize,
     huge_pagesize: Option<usize>,
+    huge_pages_optional: bool,
     obj_size: Option<usize>,
+    access_pattern: AccessPattern,
+    on_map: Option<fn(*mut u8, usize)>,
+    on_unmap: Option<fn(*mut u8, usize)>,
+    #[cfg(target_os = "linux")]
+    memfd: Option<MemfdConfig>,
+    #[cfg(target_os = "linux")]
+    dont_fork: bool,
+    #[cfg(target_os = "linux")]
+    growsdown: bool,
+    #[cfg(target_os = "linux")]
+    no_reserve: bool,
+    #[cfg(target_os = "linux")]
+    allow_uninitialized: bool,
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    reserve_only: bool,
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    zero_on_commit: bool,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    low_32bit: bool,
+}
+
+impl fmt::Debug for MapAllocBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MapAllocBuilder")
+            .field("read", &self.read)
+            .field("write", &self.write)
+            .field("exec", &self.exec)
+            .field("pagesize", &self.pagesize)
+            .field("huge_pagesize", &self.huge_pagesize)
+            .field("huge_pages_optional", &self.huge_pages_optional)
+            .field("obj_size", &self.obj_size)
+            .field("access_pattern", &self.access_pattern)
+            .finish()
+    }
+}
+
+// The name and size of a memfd to be created by MapAllocBuilder::try_build. The name is stored
+// inline (rather than as a &str or CString) so that the builder doesn't need a lifetime parameter
+// or an allocator.
+#[cfg(target_os = "linux")]
+struct MemfdConfig {
+    name: [u8; MEMFD_NAME_MAX + 1],
+    name_len: usize,
+    size: u64,
+}
+
+// The maximum length of a memfd name, not including the terminating null byte.
+#[cfg(target_os = "linux")]
+const MEMFD_NAME_MAX: usize = 249;
+
+// A memfd that a MapAlloc maps memory from. The fd is owned by the caller, and is left open when
+// the MapAlloc is dropped. Each mapping gets its own range of the memfd, starting at 'next', and
+// the memfd is extended as needed.
+#[cfg(target_os = "linux")]
+struct Memfd {
+    fd: libc::c_int,
+    // The offset of the first byte that has not been handed out to a mapping.
+    next: AtomicUsize,
+    // The size of the memfd.
+    len: AtomicUsize,
+    // Held while 'next' and 'len' are updated, so that concurrent calls to ftruncate cannot shrink
+    // the memfd.
+    lock: AtomicBool,
+}
+
+#[cfg(target_os = "linux")]
+impl Memfd {
+    fn new(fd: libc::c_int, size: u64) -> Memfd {
+        Memfd {
+            fd: fd,
+            next: AtomicUsize::new(0),
+            len: AtomicUsize::new(size as usize),
+            lock: AtomicBool::new(false),
+        }
+    }
+
+    // reserve returns the offset of a new range of 'size' bytes, extending the memfd if
+    // necessary, or None if the memfd cannot be extended.
+    fn reserve(&self, size: usize) -> Option<usize> {
+        while self.lock.compare_and_swap(false, true, Ordering::Acquire) {}
+        let offset = self.next.load(Ordering::Relaxed);
+        let len = self.len.load(Ordering::Relaxed);
+        let res = match offset.checked_add(size) {
+            Some(end) if end <= len => Some(end),
+            Some(end) if unsafe { libc::ftruncate(self.fd, end as libc::off_t) } == 0 => {
+                self.len.store(end, Ordering::Relaxed);
+                Some(end)
+            }
+            _ => None,
+        };
+        if let Some(end) = res {
+            self.next.store(end, Ordering::Relaxed);
+        }
+        self.lock.store(false, Ordering::Release);
+        res.map(|_| offset)
+    }
 }
 
 impl MapAllocBuilder {
+    /// Builds a `MapAlloc`.
+    ///
+    /// # Panics
+    ///
+    /// `build` panics if the configuration is invalid. See `try_build` for details.
     pub fn build(&self) -> MapAlloc {
-        #[cfg(target_os = "linux")]
-        {
-            if let Some(huge) = self.huge_pagesize {
-                assert!(sysconf::page::hugepage_supported(huge),
-                        "unsupported hugepage size: {}",
-                        huge);
+        match self.try_build() {
+            Ok(alloc) => alloc,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Builds a `MapAlloc`, reporting an invalid configuration as an error.
+    ///
+    /// `try_build` returns `UnsupportedHugePage` if huge pages of the configured size are not
+    /// supported on the runtime platform, `LockMemoryPrivilege` if the privilege needed to use
+    /// large pages could not be enabled (Windows only), `PageSizeNotPowerOfTwo` if the page size
+    /// (whether configured with `pagesize` or reported by the system) is not a power of two, and
+    /// `ObjSizeNotPageMultiple` if the configured object size is not a multiple of the page size.
+    /// Unlike `build`, this allows callers to, for example, fall back to normal pages if a
+    /// particular huge page size is not available. If `huge_pages_optional` was set, that fallback
+    /// happens automatically, and huge page errors are not reported.
+    pub fn try_build(&self) -> Result<MapAlloc, BuildError> {
+        let (pagesize, huge_pagesize) = match self.check_huge_pages() {
+            Ok(()) => (self.pagesize, self.huge_pagesize),
+            Err(_) if self.huge_pages_optional => (sysconf::page::pagesize(), None),
+            Err(err) => return Err(err),
+        };
+        // Falling back to normal pages is cheaper up front than after a failed huge page mapping,
+        // and it's certain to happen if no huge pages are available.
+        #[cfg(all(feature = "std", target_os = "linux"))]
+        let (pagesize, huge_pagesize) = match huge_pagesize {
+            Some(huge) if self.huge_pages_optional && available_huge_pages(huge) == Some(0) => {
+                (sysconf::page::pagesize(), None)
             }
+            _ => (pagesize, huge_pagesize),
+        };
+        // The page size is used to compute huge page flags and to check alignment, both of which
+        // assume that it is a power of two.
+        if !pagesize.is_power_of_two() {
+            return Err(BuildError::PageSizeNotPowerOfTwo(pagesize));
         }
 
+        #[cfg(target_os = "linux")]
+        let memfd = match self.memfd {
+            Some(ref cfg) => Some(Memfd::new(memfd_create(cfg, huge_pagesize)?, cfg.size)),
+            None => None,
+        };
+
         let obj_size = if let Some(obj_size) = self.obj_size {
-            assert_eq!(obj_size % self.pagesize,
-                       0,
-                       "object size ({}) is not a multiple of the page size ({})",
-                       obj_size,
-                       self.pagesize);
+            if obj_size % pagesize != 0 {
+                return Err(BuildError::ObjSizeNotPageMultiple {
+                               obj_size: obj_size,
+                               pagesize: pagesize,
+                           });
+            }
             obj_size
         } else {
-            self.pagesize
+            pagesize
+        };
+        Ok(MapAlloc {
+               pagesize: pagesize,
+               huge_pagesize: huge_pagesize,
+               huge_pages_optional: self.huge_pages_optional,
+               perms: perms::get_perm(self.read, self.write, self.exec),
+               obj_size: obj_size,
+               access_pattern: self.access_pattern,
+               on_map: self.on_map,
+               on_unmap: self.on_unmap,
+               #[cfg(target_os = "linux")]
+               memfd: memfd,
+               #[cfg(target_os = "linux")]
+               dont_fork: self.dont_fork,
+               #[cfg(target_os = "linux")]
+               growsdown: self.growsdown,
+               #[cfg(target_os = "linux")]
+               no_reserve: self.no_reserve,
+               #[cfg(target_os = "linux")]
+               uninitialized: self.allow_uninitialized && uninitialized_honored(),
+               #[cfg(any(target_os = "linux",
+                         target_os = "macos",
+                         target_os = "ios",
+                         target_os = "tvos"))]
+               reserve_only: self.reserve_only,
+               #[cfg(any(target_os = "linux",
+                         target_os = "macos",
+                         target_os = "ios",
+                         target_os = "tvos"))]
+               zero_on_commit: self.zero_on_commit,
+               #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+               low_32bit: self.low_32bit,
+           })
+    }
+
+    // check_huge_pages checks that the configured huge page size (if any) can be used.
+    fn check_huge_pages(&self) -> Result<(), BuildError> {
+        let huge = match self.huge_pagesize {
+            Some(huge) => huge,
+            None => return Ok(()),
         };
-        MapAlloc {
-            pagesize: self.pagesize,
-            huge_pagesize: self.huge_pagesize,
-            perms: perms::get_perm(self.read, self.write, self.exec),
-            obj_size: obj_size,
+        if !huge_pagesize_supported(huge) {
+            return Err(BuildError::UnsupportedHugePage(huge));
         }
+        #[cfg(windows)]
+        enable_lock_memory_privilege().map_err(BuildError::LockMemoryPrivilege)?;
+        Ok(())
     }
 
     #[cfg(target_os = "linux")]
@@ -108,11 +321,52 @@ impl MapAllocBuilder {
         self
     }
 
+    /// Configures the allocator to use huge pages of size `pagesize`.
+    ///
+    /// All allocations will be made in multiples of `pagesize`, and objects allocated by the
+    /// `UntypedObjectAlloc` implementation will be aligned to `pagesize`. For the common sizes,
+    /// `huge_pages` is less error-prone; this is meant for other sizes supported by the platform.
     pub fn huge_pagesize(mut self, pagesize: usize) -> MapAllocBuilder {
+        self.pagesize = pagesize;
         self.huge_pagesize = Some(pagesize);
         self
     }
 
+    /// Configures the allocator to use huge pages of the given size.
+    ///
+    /// This is equivalent to `huge_pagesize(size.bytes())`. See `HugePageSize` for which sizes
+    /// are available where; if `size` is not supported on the runtime platform, `try_build`
+    /// returns `UnsupportedHugePage`.
+    pub fn huge_pages(self, size: HugePageSize) -> MapAllocBuilder {
+        self.huge_pagesize(size.bytes())
+    }
+
+    /// Configures the allocator to fall back to normal pages if huge pages cannot be used.
+    ///
+    /// If the configured huge page size is not supported, or (on Windows) the privilege needed to
+    /// use large pages cannot be enabled, `try_build` uses the system's default page size rather
+    /// than returning an error. Additionally, if mapping memory with huge pages fails (for
+    /// example, because no huge pages are available), the mapping is retried with normal pages.
+    /// Memory mapped by such a retry is only aligned to the default page size. On Linux, with the
+    /// `std` feature, `try_build` also falls back to normal pages right away if
+    /// `available_huge_pages` reports that none are available.
+    pub fn huge_pages_optional(mut self) -> MapAllocBuilder {
+        self.huge_pages_optional = true;
+        self
+    }
+
+    /// Configures the allocator to use a page size of `pagesize` without using huge pages.
+    ///
+    /// All allocations will be made in multiples of `pagesize`. `pagesize` must be a power of two
+    /// and a multiple of the system's page size; otherwise, `try_build` returns
+    /// `PageSizeNotPowerOfTwo` or mappings will fail, respectively. This is mostly useful for
+    /// testing.
+    pub fn pagesize(mut self, pagesize: usize) -> MapAllocBuilder {
+        self.pagesize = pagesize;
+        self.huge_pagesize = None;
+        self
+    }
+
     /// Enables read permission for allocated memory.
     ///
     /// `read` makes it so that allocated memory will be readable. The default is readable.
@@ -185,518 +439,4871 @@ impl MapAllocBuilder {
     /// `MapAlloc` implements `UntypedObjectAlloc`. `obj_size` sets the object size that will be
     /// used by that implementation. It defaults to whatever page size is configured for the
     /// allocator.
+    ///
+    /// `obj_size` must be a multiple of the page size (the huge page size if huge pages are
+    /// configured), or else `build` will panic. Each object is mapped separately, and is aligned
+    /// to the page size.
     pub fn obj_size(mut self, obj_size: usize) -> MapAllocBuilder {
         self.obj_size = Some(obj_size);
         self
     }
-}
 
-impl Default for MapAllocBuilder {
-    fn default() -> MapAllocBuilder {
-        MapAllocBuilder {
-            read: true,
-            write: true,
-            exec: false,
-            pagesize: sysconf::page::pagesize(),
-            huge_pagesize: None,
-            obj_size: None,
-        }
+    /// Registers a function to be called whenever memory is mapped.
+    ///
+    /// `f` is called with the address and size of each newly-mapped region, after the mapping
+    /// has succeeded. See the "Hooks" section of the `MapAllocBuilder` documentation.
+    pub fn on_map(mut self, f: fn(*mut u8, usize)) -> MapAllocBuilder {
+        self.on_map = Some(f);
+        self
     }
-}
 
-pub struct MapAlloc {
-    pagesize: usize,
-    huge_pagesize: Option<usize>,
-    perms: perms::Perm,
-    obj_size: usize,
-}
+    /// Registers a function to be called whenever memory is unmapped.
+    ///
+    /// `f` is called with the address and size of each region just before it is unmapped. See
+    /// the "Hooks" section of the `MapAllocBuilder` documentation.
+    pub fn on_unmap(mut self, f: fn(*mut u8, usize)) -> MapAllocBuilder {
+        self.on_unmap = Some(f);
+        self
+    }
 
-impl Default for MapAlloc {
-    fn default() -> MapAlloc {
-        MapAllocBuilder::default().build()
+    /// Configures the allocator to advise the operating system that memory will be accessed
+    /// according to `pattern`.
+    ///
+    /// Each mapping is passed to `madvise` with the corresponding advice right after it is
+    /// created, and keeps it when it is resized. This can improve the kernel's readahead and
+    /// paging decisions, particularly for large mappings, but does not affect the contents of
+    /// memory. On Windows, this has no effect.
+    /// The default is `AccessPattern::Normal`.
+    pub fn access_pattern(mut self, pattern: AccessPattern) -> MapAllocBuilder {
+        self.access_pattern = pattern;
+        self
     }
-}
 
-impl MapAlloc {
-    // alloc_helper performs the requested allocation, properly handling the case in which mmap
-    // returns null.
-    fn alloc_helper(&self, size: usize) -> Option<*mut u8> {
-        // Since allocators in Rust are not allowed to return null pointers, but it is valid for
-        // mmap to return memory starting at null, we have to handle that case. We do this by
-        // checking for null, and if we find that mmap has returned null, we unmap all but the
-        // first page and try again. Since we leave the first page (the one starting at address 0)
-        // mapped, future calls to mmap are guaranteed to not return null. Note that this leaks
-        // memory since we never unmap that page, but this isn't a big deal - even if the page is a
-        // huge page, since we never write to it, it will remain uncommitted and will thus not
-        // consume any physical memory.
-        let f = |ptr: *mut u8| if ptr.is_null() {
-            let unmap_size = size - self.pagesize;
-            if unmap_size > 0 {
-                munmap(self.pagesize as *mut u8, unmap_size);
-            }
-            // a) Make it more likely that the kernel will not keep the page backed by physical
-            // memory and, b) make it so that an access to that range will result in a segfault to
-            // make other bugs easier to detect.
-            #[cfg(any(target_os = "linux", target_os = "macos"))]
-            mark_unused(ptr::null_mut(), self.pagesize);
-            self.alloc_helper(size)
-        } else {
-            Some(ptr)
-        };
-        mmap(size, self.perms, self.huge_pagesize).and_then(f)
+    /// Configures the allocator so that allocated memory is not inherited across `fork`.
+    ///
+    /// `dont_fork` makes it so that each mapping is marked with `MADV_DONTFORK`. A child process
+    /// created with `fork` will not have the memory mapped at all, rather than receiving a
+    /// copy-on-write copy of it, and accessing it in the child will fault. This is intended for
+    /// memory, such as allocator arenas, that the child should reinitialize rather than inherit.
+    #[cfg(target_os = "linux")]
+    pub fn dont_fork(mut self) -> MapAllocBuilder {
+        self.dont_fork = true;
+        self
     }
 
-    #[cfg(any(target_os = "linux", target_os = "macos"))]
-    pub fn commit(&self, ptr: *mut u8, layout: Layout) {
-        // TODO: What to do about sizes that are not multiples of the page size? These are legal
-        // allocations, and so they are legal to pass to uncommit.
-        let step = if let Some(huge) = self.huge_pagesize {
-            debug_assert_eq!(ptr as usize % huge,
-                             0,
-                             "ptr {:?} not aligned to huge page size {}",
-                             ptr,
-                             huge);
-            debug_assert!(layout.align() <= huge);
-            huge
-        } else {
-            debug_assert_eq!(ptr as usize % self.pagesize,
-                             0,
-                             "ptr {:?} not aligned to page size {}",
-                             ptr,
-                             self.pagesize);
-            debug_assert!(layout.align() <= self.pagesize);
-            self.pagesize
-        };
-        // TODO: More elegant way to do this?
-        // TODO: If the size isn't a multiple of the page size, this math might be wrong.
-        let steps = layout.size() / step;
-        for i in 0..steps {
-            // TODO: How to make this read not optimized out?
-            unsafe { ptr::read(((ptr as usize) + (i * step)) as *mut u8) };
-        }
+    /// Configures the allocator to map memory that grows downward, like a stack.
+    ///
+    /// `growsdown` makes it so that each mapping is created with `MAP_GROWSDOWN`. When memory just
+    /// below such a mapping is accessed, the kernel automatically extends the mapping downward to
+    /// include it, rather than delivering a segfault. This is useful for implementing growable
+    /// stacks: `MapAlloc::alloc_stack` returns the top of the initial mapping, from which the
+    /// stack grows down. (The `Alloc` methods return the lowest address of the initial mapping,
+    /// as the start of an allocation.) Deallocating the memory unmaps any pages that the mapping
+    /// has grown to include.
+    ///
+    /// This is only supported on Linux. Note that the kernel does not let a mapping grow to within
+    /// its stack guard gap (usually 1MB) of another mapping.
+    #[cfg(target_os = "linux")]
+    pub fn growsdown(mut self) -> MapAllocBuilder {
+        self.growsdown = true;
+        self
     }
 
-    #[cfg(any(target_os = "linux", target_os = "macos"))]
-    pub fn uncommit(&self, ptr: *mut u8, layout: Layout) {
-        // TODO: What to do about sizes that are not multiples of the page size? These are legal
-        // allocations, and so they are legal to pass to uncommit, but will madvise handle them
-        // properly?
-        if let Some(huge) = self.huge_pagesize {
-            debug_assert_eq!(ptr as usize % huge,
-                             0,
-                             "ptr {:?} not aligned to huge page size {}",
-                             ptr,
-                             huge);
-            debug_assert!(layout.align() <= huge);
-        } else {
-            debug_assert_eq!(ptr as usize % self.pagesize,
-                             0,
-                             "ptr {:?} not aligned to page size {}",
-                             ptr,
-                             self.pagesize);
-            debug_assert!(layout.align() <= self.pagesize);
-        }
-        uncommit(ptr, layout.size());
+    /// Configures the allocator to map memory without reserving swap space for it.
+    ///
+    /// `no_reserve` makes it so that each mapping is created with `MAP_NORESERVE`. Normally, the
+    /// kernel may refuse to create a writable private mapping if there isn't enough memory and
+    /// swap to back all of it. With this option, it doesn't check, so that large, sparsely-used
+    /// regions can be mapped cheaply. The tradeoff is that running out of memory is detected
+    /// later: rather than the mapping failing up front, writing to the memory may cause the
+    /// process to be killed or sent `SIGSEGV` under memory pressure. Unlike memory mapped without
+    /// any permissions, the memory is still readable and writable.
+    ///
+    /// This is only supported on Linux, and has no effect if the kernel is configured to always
+    /// overcommit or to never overcommit (see the `vm.overcommit_memory` sysctl), or on memory
+    /// mapped from a memfd.
+    #[cfg(target_os = "linux")]
+    pub fn no_reserve(mut self) -> MapAllocBuilder {
+        self.no_reserve = true;
+        self
     }
-}
 
-unsafe impl<'a> Alloc for &'a MapAlloc {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        self.alloc_excess(layout).map(|Excess(ptr, _)| ptr)
+    /// Configures the allocator to map memory without having the kernel zero-fill it.
+    ///
+    /// `allow_uninitialized` makes it so that each anonymous mapping is created with
+    /// `MAP_UNINITIALIZED`, which skips the kernel's zero-filling of fresh pages. Only kernels
+    /// built with `CONFIG_MMAP_ALLOW_UNINITIALIZED` honor the flag; that option is only available
+    /// without an MMU, and is meant for embedded systems on which no untrusted process can be
+    /// handed memory left over from another. Every other kernel ignores the flag. When the
+    /// allocator is built, a probe mapping is made to detect whether the flag is honored (the
+    /// result is cached for the life of the process). If it isn't, this option has no effect.
+    ///
+    /// If it is, there are several sharp edges:
+    /// - Memory returned by `alloc` may contain stale data from other processes, or from this one.
+    /// - `alloc_zeroed` can no longer rely on the kernel, and instead zeroes every byte with
+    ///   `memset`, which is slower and forces all of the memory to be backed by physical memory
+    ///   right away.
+    /// - Memory that is uncommitted and then committed again is not guaranteed to be zeroed; use
+    ///   `zero_on_commit` if that matters.
+    ///
+    /// This is only supported on Linux, and has no effect on memory mapped from a memfd.
+    #[cfg(target_os = "linux")]
+    pub fn allow_uninitialized(mut self) -> MapAllocBuilder {
+        self.allow_uninitialized = true;
+        self
     }
 
-    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        munmap(ptr, layout.size());
+    /// Configures the allocator to reserve address space without making it accessible.
+    ///
+    /// `reserve_only` makes it so that memory is mapped with no permissions, so that it occupies
+    /// address space but cannot be accessed (doing so faults) and is not backed by physical
+    /// memory. A range of the memory becomes accessible, with the configured permissions, once it
+    /// is passed to `commit`. This is useful for data structures, such as arenas, that need a
+    /// large contiguous region whose size isn't known up front: reserve the maximum size with
+    /// `MapAlloc::alloc_reserve`, and commit more of it as it grows.
+    ///
+    /// This is only supported on Linux and macOS.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub fn reserve_only(mut self) -> MapAllocBuilder {
+        self.reserve_only = true;
+        self
     }
 
-    unsafe fn alloc_zeroed(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        <&'a MapAlloc as Alloc>::alloc(self, layout)
+    /// Configures `commit` to zero the memory that it commits.
+    ///
+    /// Memory that has been uncommitted is normally zero-filled when it is next accessed, but
+    /// not always: memory released with `MADV_FREE` (as `uncommit` does on macOS) keeps its
+    /// contents until the kernel actually reclaims it, as does memory that was uncommitted by
+    /// other means or never uncommitted at all. With `zero_on_commit`, `commit` writes zeros over
+    /// the whole range after touching it, so that committed memory always reads as zeros, like a
+    /// fresh mapping. This is useful for pools that reuse uncommitted memory but promise zeroed
+    /// memory to their callers.
+    ///
+    /// The cost is a `memset` of every committed byte, which also forces all of the memory to be
+    /// backed by physical memory right away. With huge pages, `commit` operates on whole huge
+    /// pages, and so zeroes the whole of the last huge page. The memory must be writable.
+    ///
+    /// This is only supported on Linux and macOS.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub fn zero_on_commit(mut self) -> MapAllocBuilder {
+        self.zero_on_commit = true;
+        self
     }
 
-    unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
-        // alignment less than a page is fine because page-aligned objects are also aligned to
-        // any alignment less than a page
-        if layout.align() > self.pagesize {
-            return Err(AllocErr::invalid_input("cannot support alignment greater than a page"));
-        }
+    /// Configures the allocator to map memory in the low 2GB of the address space.
+    ///
+    /// `low_32bit` makes it so that each mapping is created with `MAP_32BIT`, which places it
+    /// entirely below the 2GB boundary. This is useful for code that needs to refer to the memory
+    /// with 32-bit addresses or offsets, such as JIT-compiled code or legacy FFI. The low region
+    /// is small, so allocations are much more likely to fail with `Exhausted` than usual.
+    ///
+    /// This is only supported on Linux on x86-64; `MAP_32BIT` does not exist on other
+    /// architectures. It has no effect on `alloc_at` and `alloc_at_noreplace`, which map at the
+    /// given address, or on memory mapped from a memfd.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn low_32bit(mut self) -> MapAllocBuilder {
+        self.low_32bit = true;
+        self
+    }
 
-        let size = next_multiple(layout.size(), self.pagesize);
-        match self.alloc_helper(size) {
-            Some(ptr) => Ok(Excess(ptr, size)),
-            None => Err(AllocErr::Exhausted { request: layout }),
-        }
+    /// Configures the allocator to map memory from a new memfd.
+    ///
+    /// When the `MapAlloc` is built, a memfd named `name` is created with `memfd_create` and
+    /// truncated to `size` bytes. Memory is then mapped from that memfd with `MAP_SHARED`, so it
+    /// can be shared with another process by sending the file descriptor (see
+    /// `MapAlloc::as_raw_fd`) over a Unix socket.
+    ///
+    /// Each allocation maps its own range of the memfd: ranges are handed out in order starting
+    /// at offset 0, and once the first `size` bytes are used up, the memfd is extended with
+    /// `ftruncate` as needed. Use `MapAlloc::alloc_memfd` to learn the offset of an allocation's
+    /// range, so that another process can map the same memory. Ranges are not reused, but
+    /// deallocating memory releases the part of the memfd that backed it. A freshly truncated
+    /// memfd is zero-filled, so allocations are zero-filled just like anonymous memory unless the
+    /// memfd has been written to through its file descriptor (by this process or another one).
+    /// `alloc_zeroed` explicitly zeroes memory in order to uphold its guarantee.
+    ///
+    /// Deallocating memory unmaps it, but the memfd itself is never closed by the `MapAlloc`, not
+    /// even when it is dropped: closing it is the caller's responsibility. `alloc_at` is not
+    /// supported for memfd-backed allocators.
+    ///
+    /// If the memfd cannot be created, `try_build` returns `BuildError::Memfd`.
+    #[cfg(target_os = "linux")]
+    pub fn memfd(mut self, name: &str, size: u64) -> MapAllocBuilder {
+        let mut cfg = MemfdConfig {
+            name: [0; MEMFD_NAME_MAX + 1],
+            name_len: name.len(),
+            size: size,
+        };
+        // Overly long names are rejected by try_build, so it's fine to truncate them here.
+        let len = core::cmp::min(name.len(), MEMFD_NAME_MAX);
+        cfg.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.memfd = Some(cfg);
+        self
     }
 }
 
-unsafe impl<'a> UntypedObjectAlloc for &'a MapAlloc {
-    fn layout(&self) -> Layout {
-        if cfg!(debug_assertions) {
-            Layout::from_size_align(self.obj_size, self.pagesize).unwrap()
-        } else {
-            unsafe { Layout::from_size_align_unchecked(self.obj_size, self.pagesize) }
+// The environment variables read by MapAllocBuilder::from_env, and functions that apply their
+// values to a builder, returning false if the value is invalid. They are applied in this order.
+#[cfg(feature = "std")]
+const ENV_VARS: &[(&str, fn(&mut MapAllocBuilder, &str) -> bool)] =
+    &[("MMAP_ALLOC_PAGESIZE", env_pagesize),
+      ("MMAP_ALLOC_HUGE_PAGESIZE", env_huge_pagesize),
+      ("MMAP_ALLOC_HUGE_PAGES_OPTIONAL", env_huge_pages_optional),
+      ("MMAP_ALLOC_PERMS", env_perms),
+      ("MMAP_ALLOC_ACCESS_PATTERN", env_access_pattern)];
+
+#[cfg(feature = "std")]
+impl MapAllocBuilder {
+    /// Creates a `MapAllocBuilder` configured by environment variables.
+    ///
+    /// This allows the same binary to be configured differently on different machines (for
+    /// example, depending on whether huge pages are available). Starting from the default
+    /// configuration, each of the following variables is applied if it is set:
+    ///
+    /// - `MMAP_ALLOC_PAGESIZE`: a page size in bytes, as with `pagesize`. It must be a power of
+    ///   two.
+    /// - `MMAP_ALLOC_HUGE_PAGESIZE`: a huge page size in bytes, as with `huge_pagesize`, or
+    ///   `default` for the system's default huge page size (Linux only). It must be supported on
+    ///   the runtime platform, and it takes precedence over `MMAP_ALLOC_PAGESIZE`.
+    /// - `MMAP_ALLOC_HUGE_PAGES_OPTIONAL`: `1` to fall back to normal pages if huge pages cannot
+    ///   be used (see `huge_pages_optional`), or `0` not to.
+    /// - `MMAP_ALLOC_PERMS`: the permissions of allocated memory, as any combination of `r`, `w`,
+    ///   and `x` (for example, `rw` or `rx`). An empty value means no permissions.
+    /// - `MMAP_ALLOC_ACCESS_PATTERN`: one of `normal`, `random`, `sequential`, `willneed`, or
+    ///   `dontneed` (see `access_pattern`).
+    ///
+    /// Variables with invalid values are ignored, leaving the defaults in place; use
+    /// `try_from_env` to report them instead. The returned builder can be configured further like
+    /// any other. This requires the `std` feature.
+    pub fn from_env() -> MapAllocBuilder {
+        let mut builder = MapAllocBuilder::default();
+        for &(var, apply) in ENV_VARS {
+            if let Ok(value) = std::env::var(var) {
+                apply(&mut builder, &value);
+            }
         }
+        builder
     }
 
-    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
-        // TODO: There's probably a method that does this more cleanly.
-        match self.alloc_excess(self.layout()) {
-            Ok(Excess(ptr, _)) => Ok(ptr),
-            Err(AllocErr::Exhausted { .. }) => Err(Exhausted),
-            Err(AllocErr::Unsupported { .. }) => unreachable!(),
+    /// Creates a `MapAllocBuilder` configured by environment variables, reporting invalid values.
+    ///
+    /// `try_from_env` is like `from_env`, except that if a variable is set to an invalid value,
+    /// an `EnvError` naming it is returned.
+    pub fn try_from_env() -> Result<MapAllocBuilder, EnvError> {
+        let mut builder = MapAllocBuilder::default();
+        for &(var, apply) in ENV_VARS {
+            if let Ok(value) = std::env::var(var) {
+                if !apply(&mut builder, &value) {
+                    return Err(EnvError {
+                                   var: var,
+                                   value: value,
+                               });
+                }
+            }
         }
+        Ok(builder)
     }
+}
 
-    unsafe fn dealloc(&mut self, ptr: *mut u8) {
-        munmap(ptr, self.obj_size);
+#[cfg(feature = "std")]
+fn env_pagesize(builder: &mut MapAllocBuilder, value: &str) -> bool {
+    match value.parse::<usize>() {
+        Ok(pagesize) if pagesize.is_power_of_two() => {
+            builder.pagesize = pagesize;
+            builder.huge_pagesize = None;
+            true
+        }
+        _ => false,
     }
 }
 
-unsafe impl Alloc for MapAlloc {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        <&MapAlloc as Alloc>::alloc(&mut (&*self), layout)
+#[cfg(feature = "std")]
+fn env_huge_pagesize(builder: &mut MapAllocBuilder, value: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    let huge = if value == "default" {
+        sysconf::page::default_hugepage()
+    } else {
+        value.parse::<usize>().ok()
+    };
+    #[cfg(not(target_os = "linux"))]
+    let huge = value.parse::<usize>().ok();
+    match huge {
+        Some(huge) if huge_pagesize_supported(huge) => {
+            builder.pagesize = huge;
+            builder.huge_pagesize = Some(huge);
+            true
+        }
+        _ => false,
     }
+}
 
-    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        <&MapAlloc as Alloc>::dealloc(&mut (&*self), ptr, layout)
+#[cfg(feature = "std")]
+fn env_huge_pages_optional(builder: &mut MapAllocBuilder, value: &str) -> bool {
+    match value {
+        "1" => builder.huge_pages_optional = true,
+        "0" => builder.huge_pages_optional = false,
+        _ => return false,
     }
+    true
+}
 
-    unsafe fn alloc_zeroed(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        <&MapAlloc as Alloc>::alloc_zeroed(&mut (&*self), layout)
+#[cfg(feature = "std")]
+fn env_perms(builder: &mut MapAllocBuilder, value: &str) -> bool {
+    if !value.chars().all(|c| c == 'r' || c == 'w' || c == 'x') {
+        return false;
     }
+    builder.read = value.contains('r');
+    builder.write = value.contains('w');
+    builder.exec = value.contains('x');
+    true
+}
 
-    unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
-        <&MapAlloc as Alloc>::alloc_excess(&mut (&*self), layout)
-    }
+#[cfg(feature = "std")]
+fn env_access_pattern(builder: &mut MapAllocBuilder, value: &str) -> bool {
+    builder.access_pattern = match value {
+        "normal" => AccessPattern::Normal,
+        "random" => AccessPattern::Random,
+        "sequential" => AccessPattern::Sequential,
+        "willneed" => AccessPattern::WillNeed,
+        "dontneed" => AccessPattern::DontNeed,
+        _ => return false,
+    };
+    true
 }
 
-unsafe impl UntypedObjectAlloc for MapAlloc {
-    fn layout(&self) -> Layout {
-        <&MapAlloc as UntypedObjectAlloc>::layout(&(&*self))
-    }
+/// An error returned by `MapAllocBuilder::try_from_env` for an environment variable with an
+/// invalid value.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvError {
+    /// The name of the variable.
+    pub var: &'static str,
+    /// The variable's value.
+    pub value: String,
+}
 
-    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
-        <&MapAlloc as UntypedObjectAlloc>::alloc(&mut (&*self))
+#[cfg(feature = "std")]
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid value for {}: {:?}", self.var, self.value)
     }
+}
 
-    unsafe fn dealloc(&mut self, ptr: *mut u8) {
-        <&MapAlloc as UntypedObjectAlloc>::dealloc(&mut (&*self), ptr);
+/// Returns the huge page sizes supported on the runtime platform, in increasing order.
+///
+/// Any of the returned sizes can be passed to `MapAllocBuilder::huge_pagesize`, which lets a
+/// program pick a huge page size at runtime rather than hard-coding one that may not be supported.
+///
+/// - On Linux, the sizes are read from `/sys/kernel/mm/hugepages`, which has a directory for each
+///   size that the kernel supports. If it cannot be read, no sizes are returned. Note that huge
+///   pages can only be mapped if some of that size have been reserved.
+/// - On macOS, the only supported size is 2MB.
+/// - On Windows, the only supported size is the large page size, if large pages are supported.
+///
+/// This requires the `std` feature.
+#[cfg(feature = "std")]
+pub fn supported_huge_page_sizes() -> std::vec::Vec<usize> {
+    #[allow(unused_mut)]
+    let mut sizes = std::vec::Vec::new();
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(entries) = std::fs::read_dir("/sys/kernel/mm/hugepages") {
+            sizes.extend(entries.filter_map(|entry| {
+                                                let name = entry.ok()?.file_name();
+                                                parse_hugepages_dir(name.to_str()?)
+                                            }));
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    sizes.push(SUPERPAGE_SIZE_2MB);
+    #[cfg(windows)]
+    {
+        let min = unsafe { kernel32::GetLargePageMinimum() } as usize;
+        if min != 0 {
+            sizes.push(min);
+        }
     }
+    sizes.sort();
+    sizes.dedup();
+    sizes
 }
 
-fn next_multiple(size: usize, unit: usize) -> usize {
-    if size % unit == 0 {
-        size
-    } else {
-        size + (size - (size % unit))
+/// Returns the number of huge pages of size `size` that are available to be mapped.
+///
+/// A huge page size being supported (see `supported_huge_page_sizes`) only means that the kernel
+/// can use it; mapping huge pages also requires that some have been reserved, and that not all
+/// of those are already in use. Otherwise, huge page allocations fail. On Linux, this reads the
+/// pool counts from `/sys/kernel/mm/hugepages/hugepages-<size>kB`, and returns the number of
+/// reserved pages that are neither in use nor promised to an existing mapping, plus the number of
+/// surplus pages that the kernel may still allocate on demand (up to `nr_overcommit_hugepages`,
+/// if it can find enough contiguous memory). `None` is returned
+/// if the counts cannot be read (for example, if `size` is not a supported huge page size, or if
+/// sysfs is not mounted) and on other platforms, which have no fixed pool of huge pages.
+///
+/// This requires the `std` feature.
+#[cfg(feature = "std")]
+pub fn available_huge_pages(size: usize) -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let free = read_hugepages_count(size, "free_hugepages")?;
+        // Pages reserved for existing mappings are counted as free until they are faulted in.
+        let resv = read_hugepages_count(size, "resv_hugepages").unwrap_or(0);
+        // Surplus pages are allocated beyond nr_hugepages when the pool runs out, and count
+        // against nr_overcommit_hugepages until they are freed.
+        let overcommit = read_hugepages_count(size, "nr_overcommit_hugepages").unwrap_or(0);
+        let surplus = read_hugepages_count(size, "surplus_hugepages").unwrap_or(0);
+        Some(free.saturating_sub(resv) + overcommit.saturating_sub(surplus))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = size;
+        None
     }
 }
 
-#[cfg(target_os = "linux")]
-fn mark_unused(ptr: *mut u8, size: usize) {
-    use libc::{c_void, MADV_DONTNEED, PROT_NONE};
-    unsafe {
-        // Let the kernel know we don't need this memory, so it can free physical resources for it
-        libc::madvise(ptr as *mut c_void, size, MADV_DONTNEED);
-        // Make it so that accesses to this memory result in a segfault
-        libc::mprotect(ptr as *mut c_void, size, PROT_NONE);
+// read_hugepages_count reads the count 'name' from the sysfs directory of the huge page pool of
+// the given size.
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn read_hugepages_count(size: usize, name: &str) -> Option<usize> {
+    use std::io::Read;
+    let path = format!("/sys/kernel/mm/hugepages/hugepages-{}kB/{}", size / 1024, name);
+    let mut contents = String::new();
+    std::fs::File::open(path).ok()?.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+// parse_hugepages_dir parses the name of a directory in /sys/kernel/mm/hugepages, which has the
+// form "hugepages-<size>kB", returning the size in bytes.
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn parse_hugepages_dir(name: &str) -> Option<usize> {
+    const PREFIX: &str = "hugepages-";
+    const SUFFIX: &str = "kB";
+    if !name.starts_with(PREFIX) || !name.ends_with(SUFFIX) ||
+       name.len() < PREFIX.len() + SUFFIX.len() {
+        return None;
     }
+    name[PREFIX.len()..name.len() - SUFFIX.len()]
+        .parse::<usize>()
+        .ok()?
+        .checked_mul(1024)
 }
 
-#[cfg(target_os = "macos")]
-fn mark_unused(ptr: *mut u8, size: usize) {
-    use libc::{c_void, MADV_FREE, PROT_NONE};
-    unsafe {
-        // Let the kernel know we don't need this memory, so it can free physical resources for it
-        libc::madvise(ptr as *mut c_void, size, MADV_FREE);
-        // Make it so that accesses to this memory result in a segfault
-        libc::mprotect(ptr as *mut c_void, size, PROT_NONE);
+impl Default for MapAllocBuilder {
+    fn default() -> MapAllocBuilder {
+        MapAllocBuilder {
+            read: true,
+            write: true,
+            exec: false,
+            pagesize: sysconf::page::pagesize(),
+            huge_pagesize: None,
+            huge_pages_optional: false,
+            obj_size: None,
+            access_pattern: AccessPattern::Normal,
+            on_map: None,
+            on_unmap: None,
+            #[cfg(target_os = "linux")]
+            memfd: None,
+            #[cfg(target_os = "linux")]
+            dont_fork: false,
+            #[cfg(target_os = "linux")]
+            growsdown: false,
+            #[cfg(target_os = "linux")]
+            no_reserve: false,
+            #[cfg(target_os = "linux")]
+            allow_uninitialized: false,
+            #[cfg(any(target_os = "linux",
+                      target_os = "macos",
+                      target_os = "ios",
+                      target_os = "tvos"))]
+            reserve_only: false,
+            #[cfg(any(target_os = "linux",
+                      target_os = "macos",
+                      target_os = "ios",
+                      target_os = "tvos"))]
+            zero_on_commit: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            low_32bit: false,
+        }
     }
 }
 
-#[cfg(target_os = "linux")]
-fn mmap(size: usize, perms: i32, huge_pagesize: Option<usize>) -> Option<*mut u8> {
-    use libc::{MAP_ANONYMOUS, MAP_PRIVATE, MAP_HUGETLB, MAP_FAILED, ENOMEM};
+/// An expected pattern of access to memory.
+///
+/// See `MapAllocBuilder::access_pattern`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessPattern {
+    /// No particular pattern (`MADV_NORMAL`). This is the default.
+    Normal,
+    /// Pages will be accessed in random order, so readahead is not useful (`MADV_RANDOM`).
+    Random,
+    /// Pages will be accessed in sequential order, so they can be read ahead aggressively and
+    /// freed soon after they are accessed (`MADV_SEQUENTIAL`).
+    Sequential,
+    /// Pages will be accessed soon, so they can be read in ahead of time (`MADV_WILLNEED`).
+    WillNeed,
+    /// Pages will not be accessed soon (`MADV_DONTNEED`). This does not discard any data: the
+    /// advice is only given to fresh mappings, which have not been written yet, and not to memory
+    /// that is resized with `realloc` or `grow_in_place`.
+    DontNeed,
+}
 
-    // TODO: Figure out when it's safe to pass MAP_UNINITIALIZED (it's not defined in all
-    // versions of libc). Be careful about not invalidating alloc_zeroed.
+/// A huge page size, used with `MapAllocBuilder::huge_pages`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// 2MB pages. These are available on x86-64 Linux (as huge pages), macOS (as superpages),
+    /// and Windows (as large pages). On Linux, they can only be mapped if some have been reserved
+    /// (see `/proc/sys/vm/nr_hugepages`); otherwise, allocations fail.
+    Size2MB,
+    /// 1GB ("gigantic") pages. These are only available on Linux, on processors that support
+    /// them (x86-64 processors with the `pdpe1gb` feature), and usually need to be reserved at
+    /// boot (with the `hugepagesz=1G hugepages=N` kernel parameters). Every allocation is then a
+    /// multiple of 1GB and is aligned to 1GB, so even a one-byte allocation uses a whole
+    /// gigantic page.
+    Size1GB,
+}
 
-    // MAP_HUGE_SHIFT isn't used on all kernel versions, but I assume it must be
-    // backwards-compatible. The only way for it to not be backwards-compatible would be for
-    // there to be bits in the range [26, 31] (in the 'flags' argument) that used to be
-    // meaningful. That would make old programs fail on newer kernels. In theory, old kernels
-    // could be checking to make sure that undefined flags aren't set, but that seems unlikely.
-    // See:
-    // http://elixir.free-electrons.com/linux/latest/source/arch/alpha/include/uapi/asm/mman.h
-    // http://man7.org/linux/man-pages/man2/mmap.2.html
-    const MAP_HUGE_SHIFT: usize = 26;
-    let flags = if let Some(pagesize) = huge_pagesize {
-        debug_assert!(pagesize.is_power_of_two()); // implies pagesize > 0
-        let log = pagesize.trailing_zeros();
-        MAP_HUGETLB | ((log as i32) << MAP_HUGE_SHIFT)
-    } else {
-        0
-    };
+impl HugePageSize {
+    /// Returns the page size in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::Size2MB => 2 << 20,
+            HugePageSize::Size1GB => 1 << 30,
+        }
+    }
+}
 
-    let ptr = unsafe {
-        libc::mmap(ptr::null_mut(),
-                   size,
-                   perms,
-                   MAP_ANONYMOUS | MAP_PRIVATE | flags,
-                   -1,
-                   0)
-    };
+/// An error returned by `MapAllocBuilder::try_build`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// Huge pages of the given size are not supported on this platform.
+    UnsupportedHugePage(usize),
+    /// The page size is not a power of two.
+    PageSizeNotPowerOfTwo(usize),
+    /// The object size is not a multiple of the page size.
+    ObjSizeNotPageMultiple { obj_size: usize, pagesize: usize },
+    /// A memfd could not be created or resized. The value is the `errno` from the failed call.
+    Memfd(i32),
+    /// The privilege needed to use large pages (`SeLockMemoryPrivilege`) could not be enabled.
+    /// The value is the error code from `GetLastError`. This only occurs on Windows; the usual
+    /// cause is that the user has not been granted the "Lock pages in memory" right.
+    LockMemoryPrivilege(u32),
+}
 
-    if ptr == MAP_FAILED {
-        if errno().0 == ENOMEM {
-            None
-        } else {
-            panic!("mmap failed: {}", errno())
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::UnsupportedHugePage(size) => {
+                write!(f, "unsupported hugepage size: {}", size)
+            }
+            BuildError::PageSizeNotPowerOfTwo(size) => {
+                write!(f, "page size is not a power of two: {}", size)
+            }
+            BuildError::ObjSizeNotPageMultiple { obj_size, pagesize } => {
+                write!(f,
+                       "object size ({}) is not a multiple of the page size ({})",
+                       obj_size,
+                       pagesize)
+            }
+            BuildError::Memfd(errno) => write!(f, "could not create memfd: errno {}", errno),
+            BuildError::LockMemoryPrivilege(code) => {
+                write!(f,
+                       "could not enable SeLockMemoryPrivilege (error code {}); using large \
+                        pages requires the \"Lock pages in memory\" user right",
+                       code)
+            }
         }
-    } else {
-        Some(ptr as *mut u8)
     }
 }
 
-#[cfg(target_os = "macos")]
-fn mmap(size: usize, perms: i32, huge_pagesize: Option<usize>) -> Option<*mut u8> {
-    use libc::{MAP_ANON, MAP_PRIVATE, MAP_FAILED, ENOMEM};
+pub struct MapAlloc {
+    pagesize: usize,
+    huge_pagesize: Option<usize>,
+    huge_pages_optional: bool,
+    perms: perms::Perm,
+    obj_size: usize,
+    access_pattern: AccessPattern,
+    on_map: Option<fn(*mut u8, usize)>,
+    on_unmap: Option<fn(*mut u8, usize)>,
+    // The memfd that memory is mapped from, if any.
+    #[cfg(target_os = "linux")]
+    memfd: Option<Memfd>,
+    #[cfg(target_os = "linux")]
+    dont_fork: bool,
+    #[cfg(target_os = "linux")]
+    growsdown: bool,
+    #[cfg(target_os = "linux")]
+    no_reserve: bool,
+    // Whether mappings are created with MAP_UNINITIALIZED. This is only set if it was requested
+    // and the kernel honors the flag, in which case fresh memory is not zero-filled.
+    #[cfg(target_os = "linux")]
+    uninitialized: bool,
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    reserve_only: bool,
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    zero_on_commit: bool,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    low_32bit: bool,
+}
 
-    // TODO: Support superpages (see MAP_ANON description in mmap manpage)
-    debug_assert!(huge_pagesize.is_none());
+impl fmt::Debug for MapAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Print the permissions as the read/write/exec triple that the builder was given, rather
+        // than as the platform's raw protection flags.
+        let (read, write, exec) = perms::decode(self.perms);
+        f.debug_struct("MapAlloc")
+            .field("read", &read)
+            .field("write", &write)
+            .field("exec", &exec)
+            .field("pagesize", &self.pagesize)
+            .field("huge_pagesize", &self.huge_pagesize)
+            .field("huge_pages_optional", &self.huge_pages_optional)
+            .field("obj_size", &self.obj_size)
+            .field("access_pattern", &self.access_pattern)
+            .finish()
+    }
+}
 
-    let ptr = unsafe { libc::mmap(ptr::null_mut(), size, perms, MAP_ANON | MAP_PRIVATE, -1, 0) };
+impl Default for MapAlloc {
+    fn default() -> MapAlloc {
+        MapAllocBuilder::default().build()
+    }
+}
 
-    if ptr == MAP_FAILED {
-        if errno().0 == ENOMEM {
-            None
+impl MapAlloc {
+    /// Returns the largest alignment that this allocator's `Alloc` implementation satisfies.
+    ///
+    /// All memory is mapped in multiples of the page size (the huge page size if huge pages are
+    /// configured), and so is aligned to it. Larger alignments are handled by
+    /// `alloc_aligned_excess`, which maps extra address space to find an aligned range, so any
+    /// alignment is supported (though very large ones will fail with `Exhausted`). The exception
+    /// is allocators configured with `MapAllocBuilder::memfd`, which only support alignments up to
+    /// the page size. Other methods, such as `alloc_at` and `realloc_obj`, never support
+    /// alignments greater than the page size.
+    pub fn max_align(&self) -> usize {
+        #[cfg(target_os = "linux")]
+        {
+            if self.memfd.is_some() {
+                return self.pagesize;
+            }
+        }
+        // the largest power of two
+        !(usize::max_value() >> 1)
+    }
+
+    /// Returns whether this allocator maps memory with huge pages.
+    ///
+    /// If so, `commit` and `uncommit` operate on whole huge pages. This reflects the allocator's
+    /// configuration: if `huge_pages_optional` was set and huge pages could not be used when the
+    /// allocator was built, this returns `false`. (Individual mappings that fell back to normal
+    /// pages after a failed huge page mapping are not reflected.)
+    pub fn is_huge_page_backed(&self) -> bool {
+        self.huge_pagesize.is_some()
+    }
+
+    /// Returns the number of bytes mapped for each object allocated by the `UntypedObjectAlloc`
+    /// implementation.
+    ///
+    /// This is the object size rounded up to the page size (the huge page size if huge pages are
+    /// configured), and is the amount of memory that each object may commit. `try_build` requires
+    /// the object size to be a multiple of the page size, so this is currently the same as
+    /// `layout().size()`, but callers that need the true size of each mapping should use this
+    /// method rather than relying on that.
+    pub fn obj_mapped_size(&self) -> usize {
+        next_multiple(self.obj_size, self.pagesize)
+    }
+
+    // alloc_helper performs the requested allocation, and calls the on_map hook if it succeeds.
+    fn alloc_helper(&self, size: usize) -> Option<*mut u8> {
+        #[cfg(feature = "fault_injection")]
+        {
+            if inject_failure() {
+                return None;
+            }
+        }
+        let ptr = self.map_non_null(size);
+        if let Some(ptr) = ptr {
+            self.mapped_fresh(ptr, size);
+        }
+        ptr
+    }
+
+    // alloc_obj maps a single object of size obj_mapped_size. This skips the alignment checks
+    // performed by alloc_excess, since objects are only ever aligned to the page size.
+    #[inline]
+    fn alloc_obj(&self) -> Option<*mut u8> {
+        self.alloc_helper(self.obj_mapped_size())
+    }
+
+    // check_dealloc_aligned checks, in debug builds, that 'ptr' is aligned like the memory that
+    // this allocator maps: to the system's page size, and to the huge page size if huge pages are
+    // required. Catching a misaligned pointer here gives a clearer message than munmap failing.
+    fn check_dealloc_aligned(&self, ptr: *mut u8) {
+        match self.huge_pagesize {
+            Some(huge) if !self.huge_pages_optional => {
+                debug_check!(ptr as usize % huge == 0,
+                             "dealloc: ptr not aligned to huge page size");
+            }
+            _ => {
+                debug_check!(ptr as usize % sysconf::page::pagesize() == 0,
+                             "dealloc: ptr not aligned to page size");
+            }
+        }
+    }
+
+    // munmap_helper calls the on_unmap hook, and then unmaps the given region.
+    fn munmap_helper(&self, ptr: *mut u8, size: usize) {
+        #[cfg(feature = "track_mappings")]
+        tracker::remove(ptr, size);
+        #[cfg(target_os = "linux")]
+        let (ptr, size) = if self.growsdown {
+            self.growsdown_extent(ptr, size)
         } else {
-            panic!("mmap failed: {}", errno())
+            (ptr, size)
+        };
+        if let Some(f) = self.on_unmap {
+            f(ptr, size);
         }
-    } else {
-        Some(ptr as *mut u8)
+        self.release_memfd(ptr, size);
+        munmap(ptr, size);
     }
-}
 
-// For a good overview of virtual memory handling on Windows, see
-// https://blogs.technet.microsoft.com/markrussinovich/2008/11/17/pushing-the-limits-of-windows-virtual-memory/
+    // growsdown_extent finds the full extent of a MAP_GROWSDOWN mapping that initially started at
+    // 'ptr', including any pages that it has grown to include. The kernel keeps a guard gap below
+    // such mappings, so the page below the lowest mapped page is never part of another mapping.
+    #[cfg(target_os = "linux")]
+    fn growsdown_extent(&self, ptr: *mut u8, size: usize) -> (*mut u8, usize) {
+        let mut start = ptr as usize;
+        let mut vec = 0;
+        while start >= self.pagesize &&
+              unsafe {
+                  libc::mincore((start - self.pagesize) as *mut libc::c_void,
+                                self.pagesize,
+                                &mut vec)
+              } == 0 {
+            start -= self.pagesize;
+        }
+        (start as *mut u8, size + (ptr as usize - start))
+    }
 
-#[cfg(windows)]
-fn mmap(size: usize, perms: u32, huge_pagesize: Option<usize>) -> Option<*mut u8> {
-    use kernel32::VirtualAlloc;
-    use winapi::winnt::{MEM_RESERVE, MEM_COMMIT, MEM_LARGE_PAGES};
+    // mapped marks a newly-mapped or resized region as not inherited across fork if configured,
+    // and calls the on_map hook.
+    fn mapped(&self, ptr: *mut u8, size: usize) {
+        #[cfg(target_os = "linux")]
+        {
+            if self.dont_fork {
+                self.mark_dont_fork(ptr, size);
+            }
+        }
+        #[cfg(feature = "track_mappings")]
+        tracker::insert(ptr, size);
+        if let Some(f) = self.on_map {
+            f(ptr, size);
+        }
+    }
 
-    let typ = if huge_pagesize.is_none() {
-        MEM_RESERVE | MEM_COMMIT
-    } else {
-        MEM_RESERVE | MEM_COMMIT | MEM_LARGE_PAGES
-    };
+    // mapped_fresh applies the configured access pattern to a newly-mapped region, and then calls
+    // mapped. Resized regions must not be passed here, since they may already hold data that
+    // MADV_DONTNEED would discard; mremap keeps the advice that they were given when they were
+    // first mapped.
+    fn mapped_fresh(&self, ptr: *mut u8, size: usize) {
+        #[cfg(any(target_os = "linux",
+                  target_os = "macos",
+                  target_os = "ios",
+                  target_os = "tvos"))]
+        {
+            if self.access_pattern != AccessPattern::Normal {
+                advise(ptr, size, self.access_pattern);
+            }
+        }
+        self.mapped(ptr, size);
+    }
 
-    unsafe {
-        // NOTE: While Windows makes a distinction between allocation granularity and page size
-        // (see https://msdn.microsoft.com/en-us/library/windows/desktop/ms724958(v=vs.85).aspx),
-        // VirtualAlloc only cares about allocation granularity for the pointer argument, not the
-        // size. Since we're passing null for the pointer, this doesn't affect us.
-        let ptr = VirtualAlloc(ptr::null_mut(), size as u64, typ, perms) as *mut u8;
-        // NOTE: Windows can return many different error codes in different scenarios that all
-        // relate to being out of memory. Instead of trying to list them all, we assume that any
-        // error is an out-of-memory condition. This is fine so long as our code doesn't have a bug
-        // (that would, e.g., result in VirtualAlloc being called with invalid arguments). This
-        // isn't ideal, but during debugging, error codes can be printed here, so it's not the end
-        // of the world.
-        if ptr.is_null() { None } else { Some(ptr) }
+    // map maps memory, either anonymously or from the memfd if one is configured.
+    fn map(&self, size: usize) -> Option<*mut u8> {
+        let perms = self.map_perms();
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref memfd) = self.memfd {
+                let offset = memfd.reserve(size)?;
+                return mmap_shared(size, perms, memfd.fd, offset);
+            }
+        }
+        let flags = self.map_flags();
+        let ptr = mmap_at(ptr::null_mut(), size, perms, self.huge_pagesize, flags);
+        if ptr.is_none() && self.huge_pages_optional && self.huge_pagesize.is_some() {
+            return mmap_at(ptr::null_mut(), size, perms, None, flags);
+        }
+        ptr
     }
-}
 
-#[cfg(any(target_os = "linux", target_os = "macos"))]
-fn munmap(ptr: *mut u8, size: usize) {
-    use libc::{munmap, c_void};
-    unsafe {
-        // NOTE: Don't inline the call to munmap; then errno might be called before munmap.
-        let ret = munmap(ptr as *mut c_void, size);
-        assert_eq!(ret, 0, "munmap failed: {}", errno());
+    // map_perms returns the permissions that new mappings are created with. Memory from a
+    // reserve_only allocator is inaccessible until it is committed.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    fn map_perms(&self) -> perms::Perm {
+        if self.reserve_only {
+            perms::PROT_NONE
+        } else {
+            self.perms
+        }
+    }
+
+    #[cfg(windows)]
+    fn map_perms(&self) -> perms::Perm {
+        self.perms
+    }
+
+    // map_flags returns the extra flags to pass to mmap for every anonymous mapping.
+    #[cfg(target_os = "linux")]
+    fn map_flags(&self) -> i32 {
+        let mut flags = 0;
+        if self.growsdown {
+            flags |= libc::MAP_GROWSDOWN;
+        }
+        if self.no_reserve {
+            flags |= libc::MAP_NORESERVE;
+        }
+        if self.uninitialized {
+            flags |= MAP_UNINITIALIZED;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.low_32bit {
+                flags |= libc::MAP_32BIT;
+            }
+        }
+        flags
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn map_flags(&self) -> i32 {
+        0
+    }
+
+    /// Returns the file descriptor of the memfd that memory is mapped from.
+    ///
+    /// This is `None` unless the allocator was configured with `MapAllocBuilder::memfd`. The
+    /// `MapAlloc` never closes the file descriptor; the caller must close it once it is done with
+    /// the allocator (and with any memory mapped from it). Sending it to another process over a
+    /// Unix socket gives that process its own copy.
+    #[cfg(target_os = "linux")]
+    pub fn as_raw_fd(&self) -> Option<libc::c_int> {
+        self.memfd.as_ref().map(|memfd| memfd.fd)
+    }
+
+    /// Maps `size` bytes from the memfd, and returns the mapping along with its offset in the
+    /// memfd.
+    ///
+    /// `size` is rounded up to the page size. Another process that has the file descriptor (see
+    /// `as_raw_fd`) can map the same memory by passing the offset to `mmap` with `MAP_SHARED`.
+    /// The mapping is freed like any other allocation of `size` bytes, by passing it to
+    /// `dealloc`. If the allocator was not configured with `MapAllocBuilder::memfd`, or `size` is
+    /// zero, an `Unsupported` error is returned.
+    #[cfg(target_os = "linux")]
+    pub fn alloc_memfd(&self, size: usize) -> Result<(*mut u8, u64), AllocErr> {
+        let memfd = match self.memfd {
+            Some(ref memfd) => memfd,
+            None => {
+                return Err(AllocErr::Unsupported {
+                               details: "alloc_memfd requires a memfd-backed allocator",
+                           })
+            }
+        };
+        if size == 0 {
+            return Err(AllocErr::invalid_input("cannot allocate a zero-sized mapping"));
+        }
+        let size = next_multiple(size, self.pagesize);
+        let exhausted = || {
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            AllocErr::Exhausted { request: layout }
+        };
+        let offset = memfd.reserve(size).ok_or_else(&exhausted)?;
+        let ptr = mmap_shared(size, self.map_perms(), memfd.fd, offset).ok_or_else(&exhausted)?;
+        self.mapped_fresh(ptr, size);
+        Ok((ptr, offset as u64))
+    }
+
+    // map_non_null performs the requested allocation, properly handling the case in which mmap
+    // returns null.
+    fn map_non_null(&self, size: usize) -> Option<*mut u8> {
+        // Since allocators in Rust are not allowed to return null pointers, but it is valid for
+        // mmap to return memory starting at null, we have to handle that case. We do this by
+        // checking for null, and if we find that mmap has returned null, we unmap all but the
+        // first page and try again. Since we leave the first page (the one starting at address 0)
+        // mapped, future calls to mmap should not return null. Note that this leaks memory since
+        // we never unmap that page, but this isn't a big deal - even if the page is a huge page,
+        // since we never write to it, it will remain uncommitted and will thus not consume any
+        // physical memory.
+        //
+        // In practice, mmap only returns null for very large maps (on 64-bit Linux, at least 2^30
+        // bytes), and it's not guaranteed that keeping the first page mapped is enough to stop it
+        // from doing so again. Thus, we only retry a bounded number of times, and report
+        // exhaustion if mmap keeps returning null.
+        const MAX_NULL_RETRIES: usize = 2;
+        for _ in 0..MAX_NULL_RETRIES + 1 {
+            let ptr = self.map(size)?;
+            if !ptr.is_null() {
+                return Some(ptr);
+            }
+            let unmap_size = size - self.pagesize;
+            if unmap_size > 0 {
+                munmap(self.pagesize as *mut u8, unmap_size);
+            }
+            // a) Make it more likely that the kernel will not keep the page backed by physical
+            // memory and, b) make it so that an access to that range will result in a segfault to
+            // make other bugs easier to detect.
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios",
+                      target_os = "tvos"))]
+            mark_unused(ptr::null_mut(), self.pagesize);
+        }
+        None
+    }
+
+    /// Allocates memory at a fixed address.
+    ///
+    /// `alloc_at` maps memory satisfying `layout` starting at exactly `addr`. `addr` must be
+    /// non-null and aligned to the page size, and `layout.align()` must be no greater than the
+    /// page size; otherwise, an `Unsupported` error is returned. If the memory cannot be mapped at
+    /// `addr`, an `Exhausted` error is returned.
+    ///
+    /// On Windows, `addr` must additionally be aligned to the allocation granularity (see
+    /// `GetSystemInfo`), and the call fails with an `Exhausted` error if any part of the range is
+    /// already reserved.
+    ///
+    /// # Safety
+    ///
+    /// On Linux and Mac, `alloc_at` uses `MAP_FIXED`, which silently unmaps any existing mappings
+    /// in the requested range, including memory that is in use by this or any other allocator.
+    /// The caller must ensure that no such mappings exist, or that clobbering them is acceptable.
+    /// On Linux, `alloc_at_noreplace` can be used to fail instead of clobbering existing
+    /// mappings. On Windows, `VirtualAlloc` never replaces an existing reservation, so `alloc_at`
+    /// there has the semantics of `alloc_at_noreplace` rather than of `MAP_FIXED`.
+    pub unsafe fn alloc_at(&self, addr: *mut u8, layout: Layout) -> Result<*mut u8, AllocErr> {
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios",
+                  target_os = "tvos"))]
+        let flags = libc::MAP_FIXED;
+        #[cfg(windows)]
+        let flags = 0;
+        self.alloc_at_flags(addr, layout, flags)
+    }
+
+    /// Allocates memory at a fixed address without clobbering existing mappings.
+    ///
+    /// `alloc_at_noreplace` is like `alloc_at`, except that if any part of the requested range
+    /// is already mapped, it fails with an `Exhausted` error instead of replacing the existing
+    /// mapping. It uses `MAP_FIXED_NOREPLACE` (Linux 4.17 and later). On older kernels, the
+    /// address is only treated as a hint; if the kernel chooses a different address, the mapping
+    /// is undone and an error is returned.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn alloc_at_noreplace(&self,
+                                     addr: *mut u8,
+                                     layout: Layout)
+                                     -> Result<*mut u8, AllocErr> {
+        self.alloc_at_flags(addr, layout, MAP_FIXED_NOREPLACE)
+    }
+
+    unsafe fn alloc_at_flags(&self,
+                             addr: *mut u8,
+                             layout: Layout,
+                             flags: i32)
+                             -> Result<*mut u8, AllocErr> {
+        if addr.is_null() {
+            return Err(AllocErr::invalid_input("cannot map memory at the null address"));
+        }
+        if addr as usize % self.pagesize != 0 {
+            return Err(AllocErr::invalid_input("address is not page-aligned"));
+        }
+        if layout.align() > self.pagesize {
+            return Err(AllocErr::invalid_input(OVER_ALIGNED));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if self.memfd.is_some() {
+                return Err(AllocErr::Unsupported {
+                               details: "alloc_at is not supported for memfd-backed allocators",
+                           });
+            }
+        }
+
+        let size = next_multiple(layout.size(), self.pagesize);
+        let flags = flags | self.map_flags();
+        match mmap_at(addr, size, self.perms, self.huge_pagesize, flags) {
+            Some(ptr) if ptr == addr => {
+                self.mapped_fresh(ptr, size);
+                Ok(ptr)
+            }
+            Some(ptr) => {
+                // The address was treated as a hint, and the kernel picked a different one.
+                munmap(ptr, size);
+                Err(AllocErr::Exhausted { request: layout })
+            }
+            None => Err(AllocErr::Exhausted { request: layout }),
+        }
+    }
+
+    /// Allocates memory aligned to `layout.align()`, which may be greater than the page size.
+    ///
+    /// On success, the returned `Excess` holds the aligned pointer and the number of usable bytes,
+    /// which is `layout.size()` rounded up to the page size. If `layout.align()` is no greater
+    /// than the page size, this is just `Alloc::alloc_excess`. Otherwise,
+    /// `layout.size() + layout.align() - pagesize` bytes (with the size rounded up to the page
+    /// size) are mapped, which is enough to be sure to contain an aligned range of the requested
+    /// size. The slack before and after that range is then unmapped.
+    ///
+    /// Because the slack is trimmed rather than kept around, only the aligned range is mapped
+    /// afterwards, and it is indistinguishable from any other allocation: it can be deallocated
+    /// (or resized) given only its pointer and layout, and no record of the original base address
+    /// is needed. The price is two extra `munmap` calls, and briefly using `layout.align()` more
+    /// bytes of address space than necessary.
+    ///
+    /// Windows cannot release part of a region, so there the whole oversized region is released
+    /// and the aligned range is mapped again at the same address. If another thread maps memory
+    /// at that address in between, this is retried a few times before failing with `Exhausted`.
+    ///
+    /// Over-aligned allocations are not supported for allocators configured with
+    /// `MapAllocBuilder::memfd`, since the slack trimmed from the mapping would waste part of the
+    /// memfd; an `Unsupported` error is returned.
+    ///
+    /// # Safety
+    ///
+    /// As with `Alloc::alloc_excess`, `layout.size()` must be non-zero.
+    pub unsafe fn alloc_aligned_excess(&self, layout: Layout) -> Result<Excess, AllocErr> {
+        debug_assert!(self.pagesize.is_power_of_two());
+        let size = next_multiple(layout.size(), self.pagesize);
+        // alignment less than a page is fine because page-aligned objects are also aligned to
+        // any alignment less than a page
+        if layout.align() <= self.pagesize {
+            return match self.alloc_helper(size) {
+                       Some(ptr) => Ok(Excess(ptr, size)),
+                       None => Err(AllocErr::Exhausted { request: layout }),
+                   };
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if self.memfd.is_some() {
+                return Err(AllocErr::Unsupported {
+                               details: "alignments greater than the page size are not \
+                                         supported for memfd-backed allocators",
+                           });
+            }
+        }
+
+        #[cfg(feature = "fault_injection")]
+        {
+            if inject_failure() {
+                return Err(AllocErr::Exhausted { request: layout });
+            }
+        }
+
+        let align = layout.align();
+        let total = size + align - self.pagesize;
+        #[cfg(not(windows))]
+        {
+            let ptr = match self.map_non_null(total) {
+                Some(ptr) => ptr as usize,
+                None => return Err(AllocErr::Exhausted { request: layout }),
+            };
+            let aligned = next_multiple(ptr, align);
+            let head = aligned - ptr;
+            let tail = total - head - size;
+            if head > 0 {
+                munmap(ptr as *mut u8, head);
+            }
+            if tail > 0 {
+                munmap((aligned + size) as *mut u8, tail);
+            }
+            self.mapped_fresh(aligned as *mut u8, size);
+            Ok(Excess(aligned as *mut u8, size))
+        }
+        #[cfg(windows)]
+        {
+            const MAX_RETRIES: usize = 8;
+            for _ in 0..MAX_RETRIES {
+                let ptr = match self.map_non_null(total) {
+                    Some(ptr) => ptr,
+                    None => return Err(AllocErr::Exhausted { request: layout.clone() }),
+                };
+                let aligned = next_multiple(ptr as usize, align) as *mut u8;
+                munmap(ptr, total);
+                match mmap_at(aligned, size, self.perms, self.huge_pagesize, 0) {
+                    Some(ptr) if ptr == aligned => {
+                        self.mapped_fresh(ptr, size);
+                        return Ok(Excess(ptr, size));
+                    }
+                    Some(ptr) => munmap(ptr, size),
+                    None => {}
+                }
+            }
+            Err(AllocErr::Exhausted { request: layout })
+        }
+    }
+
+    /// Allocates memory owned by a `MapBox`.
+    ///
+    /// `alloc_box` is like `alloc`, except that the returned memory is unmapped automatically
+    /// when the `MapBox` is dropped. The memory is zero-filled. If `layout.size()` is zero, an
+    /// `Unsupported` error is returned.
+    pub fn alloc_box(&self, layout: Layout) -> Result<MapBox, AllocErr> {
+        if layout.size() == 0 {
+            return Err(AllocErr::invalid_input("cannot allocate a zero-sized MapBox"));
+        }
+        let ptr = unsafe { <&MapAlloc as Alloc>::alloc(&mut &*self, layout.clone())? };
+        Ok(MapBox {
+               ptr: ptr,
+               size: next_multiple(layout.size(), self.pagesize),
+               layout: layout,
+               on_unmap: self.on_unmap,
+           })
+    }
+
+    /// Allocates a zero-filled byte slice.
+    ///
+    /// `alloc_slice` maps `len` bytes rounded up to the page size, and returns a slice of exactly
+    /// `len` bytes at the beginning of the mapping. If `len` is zero, an `Unsupported` error is
+    /// returned.
+    ///
+    /// The slice borrows from `self` only so that it has a lifetime - the memory is not unmapped
+    /// when the slice goes out of scope or when this `MapAlloc` is dropped. To free it, pass the
+    /// same slice (with its original length) to `dealloc_slice`. To have the memory unmapped
+    /// automatically, use `alloc_box` instead.
+    pub fn alloc_slice(&self, len: usize) -> Result<&mut [u8], AllocErr> {
+        if len == 0 {
+            return Err(AllocErr::invalid_input("cannot allocate a zero-length slice"));
+        }
+        let layout = Layout::from_size_align(len, 1).unwrap();
+        unsafe {
+            let ptr = <&MapAlloc as Alloc>::alloc_zeroed(&mut &*self, layout)?;
+            Ok(slice::from_raw_parts_mut(ptr, len))
+        }
+    }
+
+    /// Unmaps a slice allocated with `alloc_slice`.
+    ///
+    /// # Safety
+    ///
+    /// `slice` must have been returned by `alloc_slice` on a `MapAlloc` with the same page size,
+    /// and must have the length that was originally requested; passing a sub-slice will leave
+    /// part of the mapping in place or unmap memory that belongs to something else. The slice
+    /// (and any other references into it) must not be used afterwards.
+    pub unsafe fn dealloc_slice(&self, slice: &mut [u8]) {
+        let layout = Layout::from_size_align(slice.len(), 1).unwrap();
+        <&MapAlloc as Alloc>::dealloc(&mut &*self, slice.as_mut_ptr(), layout);
+    }
+
+    /// Maps a stack, and returns a pointer to its top.
+    ///
+    /// `alloc_stack` maps `size` bytes rounded up to the page size, and returns the address one
+    /// past the end of the mapping: stacks grow downward, so this is where a stack pointer starts
+    /// out. If the allocator was configured with `MapAllocBuilder::growsdown`, the mapping is
+    /// extended automatically when memory below it is accessed. If `size` is zero, an
+    /// `Unsupported` error is returned.
+    ///
+    /// To free the stack, pass the returned pointer and the same `size` to `dealloc_stack`.
+    pub fn alloc_stack(&self, size: usize) -> Result<*mut u8, AllocErr> {
+        if size == 0 {
+            return Err(AllocErr::invalid_input("cannot allocate a zero-sized stack"));
+        }
+        let size = next_multiple(size, self.pagesize);
+        match self.alloc_helper(size) {
+            Some(ptr) => Ok(unsafe { ptr.offset(size as isize) }),
+            None => {
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                Err(AllocErr::Exhausted { request: layout })
+            }
+        }
+    }
+
+    /// Unmaps a stack allocated with `alloc_stack`, including any pages that it has grown to
+    /// include.
+    ///
+    /// # Safety
+    ///
+    /// `top` must have been returned by `alloc_stack` on a `MapAlloc` with the same page size and
+    /// configuration, and `size` must be the size that was originally requested.
+    pub unsafe fn dealloc_stack(&self, top: *mut u8, size: usize) {
+        let size = next_multiple(size, self.pagesize);
+        self.munmap_helper(top.offset(-(size as isize)), size);
+    }
+
+    /// Splits an allocation into two that can be deallocated independently.
+    ///
+    /// `split` divides the allocation of `layout` at `ptr` at offset `at`, and returns a pointer
+    /// and layout for each half: the first covers the `at` bytes starting at `ptr`, and the
+    /// second covers the rest. Each half can then be passed to `dealloc` on its own, which unmaps
+    /// only that half. Nothing is remapped or copied, since `munmap` can unmap any page-aligned
+    /// part of a mapping; this is useful for arenas that carve up a large mapping and want to
+    /// return part of it to the kernel while keeping the rest. The second half is aligned to the
+    /// lesser of `layout.align()` and the page size.
+    ///
+    /// This is only supported on Linux and macOS. On Windows, memory reserved with `VirtualAlloc`
+    /// can only be released as a whole, so a mapping cannot be split.
+    ///
+    /// # Panics
+    ///
+    /// `split` panics if `at` is not a multiple of the page size (the huge page size, if one is
+    /// configured), or if it is not strictly between 0 and `layout.size()`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `layout` must describe a live allocation from this `MapAlloc`. Afterwards, they
+    /// must no longer be passed to `dealloc`; the two returned halves must be used instead.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub unsafe fn split(&self,
+                        ptr: *mut u8,
+                        layout: Layout,
+                        at: usize)
+                        -> (*mut u8, Layout, *mut u8, Layout) {
+        let pagesize = self.huge_pagesize.unwrap_or(self.pagesize);
+        assert!(at % pagesize == 0, "split point not aligned to page size");
+        assert!(at > 0 && at < layout.size(), "split point not within the allocation");
+        #[cfg(target_os = "linux")]
+        debug_check!(!self.growsdown, "split: cannot split a MAP_GROWSDOWN mapping");
+        #[cfg(feature = "track_mappings")]
+        {
+            tracker::remove(ptr, next_multiple(layout.size(), self.pagesize));
+            tracker::insert(ptr, at);
+            tracker::insert(ptr.offset(at as isize),
+                            next_multiple(layout.size(), self.pagesize) - at);
+        }
+        let align = layout.align();
+        (ptr,
+         Layout::from_size_align(at, align).unwrap(),
+         ptr.offset(at as isize),
+         Layout::from_size_align(layout.size() - at, core::cmp::min(align, pagesize)).unwrap())
+    }
+
+    /// Resizes an object allocated by the `UntypedObjectAlloc` implementation.
+    ///
+    /// `realloc_obj` resizes the object at `ptr`, whose current size is `old_size`, to
+    /// `new_size`, preserving its contents up to the lesser of the two sizes. On Linux, this uses
+    /// `mremap`, which may move the object without copying. Elsewhere, a new object is mapped,
+    /// the contents are copied, and the old object is unmapped. On success, the old pointer must
+    /// no longer be used. On failure, the old object is left untouched.
+    ///
+    /// This changes the effective object size for this pointer only. `MapAlloc` does not keep
+    /// track of it, so the caller is responsible for remembering the new size: the object must be
+    /// freed with `Alloc::dealloc` using a layout of that size (or resized back to the configured
+    /// object size) rather than with `UntypedObjectAlloc::dealloc`. Similarly, `old_size` must be
+    /// the object's current size - the configured object size if it has never been resized.
+    ///
+    /// # Panics
+    ///
+    /// `realloc_obj` panics if `old_size` or `new_size` is zero or is not a multiple of the page
+    /// size.
+    pub unsafe fn realloc_obj(&self,
+                              ptr: *mut u8,
+                              old_size: usize,
+                              new_size: usize)
+                              -> Result<*mut u8, Exhausted> {
+        assert!(old_size > 0 && old_size % self.pagesize == 0,
+                "old object size ({}) is not a multiple of the page size ({})",
+                old_size,
+                self.pagesize);
+        assert!(new_size > 0 && new_size % self.pagesize == 0,
+                "new object size ({}) is not a multiple of the page size ({})",
+                new_size,
+                self.pagesize);
+        if old_size == new_size {
+            return Ok(ptr);
+        }
+        self.remap(ptr, old_size, new_size).ok_or(Exhausted)
+    }
+
+    // remap resizes the mapping of 'old_size' bytes at 'ptr' to 'new_size' bytes (both non-zero
+    // multiples of the page size), moving it if necessary, and calls the hooks. On Linux, this
+    // uses mremap. Elsewhere, a new mapping is created, the contents are copied, and the old
+    // mapping is unmapped. On failure, the old mapping is left untouched.
+    unsafe fn remap(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> Option<*mut u8> {
+        #[cfg(target_os = "linux")]
+        let new = if self.memfd.is_some() && new_size > old_size {
+            // Growing the mapping with mremap would extend it into the next range of the memfd.
+            self.remap_copy(ptr, old_size, new_size)
+        } else if self.low_32bit && new_size > old_size {
+            // mremap ignores MAP_32BIT when it moves a mapping, so only let it grow the mapping in
+            // place; remap_copy maps with map_flags, keeping the new mapping below 2GB.
+            mremap(ptr, old_size, new_size, false)
+                .or_else(|| self.remap_copy(ptr, old_size, new_size))
+        } else {
+            mremap(ptr, old_size, new_size, true)
+        };
+        #[cfg(not(target_os = "linux"))]
+        let new = self.remap_copy(ptr, old_size, new_size);
+
+        if let Some(new) = new {
+            #[cfg(feature = "track_mappings")]
+            tracker::remove(ptr, old_size);
+            if let Some(f) = self.on_unmap {
+                f(ptr, old_size);
+            }
+            self.mapped(new, new_size);
+        }
+        new
+    }
+
+    // remap_copy creates a new mapping of 'new_size' bytes, copies the contents of the mapping of
+    // 'old_size' bytes at 'ptr' to it, and unmaps the old mapping. It does not call the hooks.
+    unsafe fn remap_copy(&self,
+                         ptr: *mut u8,
+                         old_size: usize,
+                         new_size: usize)
+                         -> Option<*mut u8> {
+        self.map_non_null(new_size).map(|new| {
+            ptr::copy_nonoverlapping(ptr, new, core::cmp::min(old_size, new_size));
+            self.release_memfd(ptr, old_size);
+            munmap(ptr, old_size);
+            new
+        })
+    }
+
+    // grow_helper tries to extend the mapping of 'old_size' bytes at 'ptr' to 'new_size' bytes
+    // (both multiples of the page size) without moving it, and calls the hooks if it succeeds.
+    // This is not supported on Windows, where adjacent regions cannot be released together, or
+    // for memory mapped from a memfd, where the mapping would extend into the next range of it.
+    #[cfg_attr(windows, allow(unused_variables))]
+    fn grow_helper(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            if self.memfd.is_some() {
+                return false;
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        let grown = mremap(ptr, old_size, new_size, false).is_some();
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+        let grown = {
+            // Without MAP_FIXED, the address is only a hint, so this never clobbers a mapping
+            // that is already there.
+            let tail = unsafe { ptr.offset(old_size as isize) };
+            let size = new_size - old_size;
+            match mmap_at(tail, size, self.perms, self.huge_pagesize, self.map_flags()) {
+                Some(new) if new == tail => true,
+                Some(new) => {
+                    munmap(new, size);
+                    false
+                }
+                None => false,
+            }
+        };
+        #[cfg(windows)]
+        let grown = false;
+
+        if grown {
+            #[cfg(feature = "track_mappings")]
+            tracker::remove(ptr, old_size);
+            if let Some(f) = self.on_unmap {
+                f(ptr, old_size);
+            }
+            self.mapped(ptr, new_size);
+        }
+        grown
+    }
+
+    // unmap_tail unmaps the part of the mapping of 'old_size' bytes at 'ptr' that is past its
+    // first 'new_size' bytes (both multiples of the page size), and calls the on_unmap hook for
+    // it. On Windows, a region can only be released in its entirety, so the tail stays mapped
+    // until the whole region is deallocated.
+    #[cfg_attr(windows, allow(unused_variables))]
+    fn unmap_tail(&self, ptr: *mut u8, old_size: usize, new_size: usize) {
+        #[cfg(not(windows))]
+        {
+            #[cfg(feature = "track_mappings")]
+            {
+                tracker::remove(ptr, old_size);
+                tracker::insert(ptr, new_size);
+            }
+            let tail = unsafe { ptr.offset(new_size as isize) };
+            if let Some(f) = self.on_unmap {
+                f(tail, old_size - new_size);
+            }
+            self.release_memfd(tail, old_size - new_size);
+            munmap(tail, old_size - new_size);
+        }
+    }
+
+    // release_memfd frees the part of the memfd that backs the given region, if memory is mapped
+    // from a memfd. Ranges of the memfd are never reused, so otherwise it would stay allocated
+    // until the memfd is closed.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn release_memfd(&self, ptr: *mut u8, size: usize) {
+        #[cfg(target_os = "linux")]
+        {
+            if self.memfd.is_some() {
+                unsafe {
+                    libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_REMOVE);
+                }
+            }
+        }
+    }
+
+    /// Reserves `reserve` bytes of address space, and commits the first `commit` bytes of it.
+    ///
+    /// This requires the allocator to have been configured with `MapAllocBuilder::reserve_only`;
+    /// otherwise, it fails with an `Unsupported` error. Both sizes are rounded up to the page size
+    /// (the huge page size, if one is configured), and `commit` must not be greater than
+    /// `reserve`. On success, the returned `Excess` holds a pointer to the start of the
+    /// reservation and its full size. Only the committed prefix can be accessed; the rest of the
+    /// reservation can be committed later by passing ranges of it to `commit`. The whole
+    /// reservation is freed by passing the pointer and size to `dealloc`.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub fn alloc_reserve(&self, reserve: usize, commit: usize) -> Result<Excess, AllocErr> {
+        if !self.reserve_only {
+            return Err(AllocErr::Unsupported {
+                           details: "alloc_reserve requires MapAllocBuilder::reserve_only",
+                       });
+        }
+        if reserve == 0 {
+            return Err(AllocErr::invalid_input("cannot reserve zero bytes"));
+        }
+        if commit > reserve {
+            return Err(AllocErr::invalid_input("commit size greater than reserve size"));
+        }
+        let layout = match Layout::from_size_align(reserve, 1) {
+            Some(layout) => layout,
+            None => return Err(AllocErr::invalid_input("reserve size too large")),
+        };
+        let Excess(ptr, size) =
+            unsafe { <&MapAlloc as Alloc>::alloc_excess(&mut &*self, layout.clone())? };
+        let commit = next_multiple(commit, self.pagesize);
+        if commit > 0 && protect(ptr, commit, self.perms).is_err() {
+            unsafe { <&MapAlloc as Alloc>::dealloc(&mut &*self, ptr, layout.clone()) };
+            return Err(AllocErr::Exhausted { request: layout });
+        }
+        Ok(Excess(ptr, size))
+    }
+
+    /// Commits memory by touching each of its pages.
+    ///
+    /// The memory does not need to have been allocated by this `MapAlloc` (or by any `MapAlloc`).
+    /// It only needs to be part of a live, readable mapping, and `ptr` must be aligned to this
+    /// allocator's page size (the huge page size, if one is configured). If the allocator was
+    /// configured with `MapAllocBuilder::reserve_only`, the memory is first made accessible with
+    /// the configured permissions, so it only needs to be part of a live mapping. If it was
+    /// configured with `MapAllocBuilder::zero_on_commit`, the memory is then zeroed.
+    ///
+    /// Memory is committed a page at a time, so a size that is not a multiple of the page size
+    /// commits the whole of its last page. For huge-page-backed allocators (see
+    /// `is_huge_page_backed`), that means a whole huge page.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub fn commit(&self, ptr: *mut u8, layout: Layout) {
+        let step = if let Some(huge) = self.huge_pagesize {
+            debug_check!(ptr as usize % huge == 0, "ptr not aligned to huge page size");
+            debug_check!(layout.align() <= huge, "alignment greater than huge page size");
+            huge
+        } else {
+            debug_check!(ptr as usize % self.pagesize == 0, "ptr not aligned to page size");
+            debug_check!(layout.align() <= self.pagesize, "alignment greater than page size");
+            self.pagesize
+        };
+        if self.reserve_only {
+            let res = protect(ptr, next_multiple(layout.size(), step), self.perms);
+            debug_check!(res.is_ok(), "commit: could not make memory accessible");
+        }
+        // TODO: More elegant way to do this?
+        let steps = next_multiple(layout.size(), step) / step;
+        for i in 0..steps {
+            // TODO: How to make this read not optimized out?
+            unsafe { ptr::read(((ptr as usize) + (i * step)) as *mut u8) };
+        }
+        if self.zero_on_commit {
+            unsafe { ptr::write_bytes(ptr, 0, steps * step) };
+        }
+    }
+
+    /// Reports which pages of a region of memory are resident in physical memory.
+    ///
+    /// The region starts at `ptr`, which must be aligned to the system's page size, and spans
+    /// `layout.size()` bytes. Residency is reported at the granularity of the system's page size,
+    /// even if huge pages are configured: `out[i]` is set to whether the `i`th page of the region
+    /// is resident. If `out` is shorter than the region, only its first `out.len()` pages are
+    /// reported. On success, the number of pages in the region is returned. On failure (for
+    /// example, if part of the region is not mapped), the error (`errno` on Unix, or
+    /// `GetLastError` on Windows) is returned.
+    ///
+    /// As with `commit`, the memory does not need to have been allocated by this `MapAlloc`. This
+    /// is backed by `mincore` on Unix and `QueryWorkingSetEx` on Windows, and does not allocate.
+    pub fn residency_into(&self,
+                          ptr: *mut u8,
+                          layout: Layout,
+                          out: &mut [bool])
+                          -> Result<usize, i32> {
+        let pagesize = sysconf::page::pagesize();
+        debug_check!(ptr as usize % pagesize == 0, "ptr not aligned to page size");
+        let npages = next_multiple(layout.size(), pagesize) / pagesize;
+        let len = if out.len() < npages { out.len() } else { npages };
+        residency(ptr, &mut out[..len])?;
+        Ok(npages)
+    }
+
+    /// Reports which pages of a region of memory are resident in physical memory.
+    ///
+    /// This is like `residency_into`, but returns a `Vec` with an entry for every page of the
+    /// region.
+    #[cfg(feature = "alloc")]
+    pub fn residency(&self, ptr: *mut u8, layout: Layout) -> Result<Vec<bool>, i32> {
+        let pagesize = sysconf::page::pagesize();
+        let mut out = Vec::new();
+        out.resize(next_multiple(layout.size(), pagesize) / pagesize, false);
+        self.residency_into(ptr, layout, &mut out)?;
+        Ok(out)
+    }
+
+    /// Marks memory so that it is not inherited by child processes created with `fork`.
+    ///
+    /// This is useful for memory that was not allocated by a `MapAlloc` configured with
+    /// `MapAllocBuilder::dont_fork`, including memory that was not allocated by a `MapAlloc` at
+    /// all. `ptr` must be aligned to the page size. See `dont_fork` for details.
+    #[cfg(target_os = "linux")]
+    pub fn mark_dont_fork(&self, ptr: *mut u8, size: usize) {
+        debug_check!(ptr as usize % self.pagesize == 0, "ptr not aligned to page size");
+        unsafe {
+            libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_DONTFORK);
+        }
+    }
+
+    /// Uncommits memory, releasing the physical memory backing it while keeping it mapped.
+    ///
+    /// The contents of the memory are lost: on Linux, it will be zero-filled the next time it is
+    /// accessed. As with `commit`, the memory does not need to have been allocated by this
+    /// `MapAlloc`; it only needs to be part of a live private mapping, and `ptr` must be aligned
+    /// to this allocator's page size (the huge page size, if one is configured).
+    ///
+    /// A huge page is either committed or not, so it is impossible to uncommit part of one. For
+    /// huge-page-backed allocators (see `is_huge_page_backed`), the size is rounded up to a
+    /// multiple of the huge page size, and the whole of the last huge page is uncommitted, even
+    /// if the region only covers part of it. This never reaches past the end of the mapping, since
+    /// huge page mappings are always a multiple of the huge page size.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub fn uncommit(&self, ptr: *mut u8, layout: Layout) {
+        let size = if let Some(huge) = self.huge_pagesize {
+            debug_check!(ptr as usize % huge == 0, "ptr not aligned to huge page size");
+            debug_check!(layout.align() <= huge, "alignment greater than huge page size");
+            next_multiple(layout.size(), huge)
+        } else {
+            debug_check!(ptr as usize % self.pagesize == 0, "ptr not aligned to page size");
+            debug_check!(layout.align() <= self.pagesize, "alignment greater than page size");
+            layout.size()
+        };
+        let _ = uncommit(ptr, size);
+    }
+
+    /// Hints that memory will not be used soon, without discarding its contents.
+    ///
+    /// `cool` uses `MADV_COLD` (Linux 5.4 and later) to deactivate the pages, so that they are
+    /// reclaimed before other memory when the system is under memory pressure. Unlike `uncommit`,
+    /// the contents of the memory are preserved. Returns whether the hint was applied; on older
+    /// kernels, which don't support `MADV_COLD`, it does nothing and returns `false`. As with
+    /// `commit`, the memory does not need to have been allocated by this `MapAlloc`, and `ptr`
+    /// must be aligned to the page size.
+    #[cfg(target_os = "linux")]
+    pub fn cool(&self, ptr: *mut u8, layout: Layout) -> bool {
+        debug_check!(ptr as usize % self.pagesize == 0, "ptr not aligned to page size");
+        unsafe { libc::madvise(ptr as *mut libc::c_void, layout.size(), MADV_COLD) == 0 }
+    }
+
+    /// Evicts memory from physical memory, without discarding its contents.
+    ///
+    /// `page_out` uses `MADV_PAGEOUT` (Linux 5.4 and later) to reclaim the pages right away:
+    /// their contents are written out to swap (or, for shared file mappings, to the file) and
+    /// read back in the next time they are accessed. Unlike `uncommit`, the contents of the
+    /// memory are preserved. Returns whether the hint was applied; on older kernels, which don't
+    /// support `MADV_PAGEOUT`, it does nothing and returns `false`. As with `commit`, the memory
+    /// does not need to have been allocated by this `MapAlloc`, and `ptr` must be aligned to the
+    /// page size.
+    #[cfg(target_os = "linux")]
+    pub fn page_out(&self, ptr: *mut u8, layout: Layout) -> bool {
+        debug_check!(ptr as usize % self.pagesize == 0, "ptr not aligned to page size");
+        unsafe { libc::madvise(ptr as *mut libc::c_void, layout.size(), MADV_PAGEOUT) == 0 }
+    }
+
+    /// Uncommits many ranges of memory at once.
+    ///
+    /// `uncommit_range` is equivalent to calling `uncommit` on each `(ptr, size)` pair in
+    /// `ranges`, except that adjacent ranges (where one range ends exactly where the next one in
+    /// the slice begins) are coalesced so that they are uncommitted with a single system call.
+    ///
+    /// Every range must be aligned to the page size, and its size must be a multiple of the page
+    /// size. This is checked before anything is uncommitted; if any range is not aligned, no
+    /// memory is uncommitted, and `Unaligned` is returned with the index of the first such range.
+    /// If uncommitting some of the ranges fails, the rest are still uncommitted, and `Failed` is
+    /// returned. Like `uncommit`, this works on any mapped memory, not just memory allocated by
+    /// this `MapAlloc`.
+    ///
+    /// # Safety
+    ///
+    /// As with `uncommit`, the contents of the memory are lost: on Linux, it will be zero-filled
+    /// the next time it is accessed.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub unsafe fn uncommit_range(&self, ranges: &[(*mut u8, usize)]) -> Result<(), UncommitError> {
+        for (i, &(ptr, size)) in ranges.iter().enumerate() {
+            if ptr as usize % self.pagesize != 0 || size % self.pagesize != 0 {
+                return Err(UncommitError::Unaligned(i));
+            }
+        }
+
+        let mut count = 0;
+        let mut first = None;
+        let mut i = 0;
+        while i < ranges.len() {
+            let (ptr, mut size) = ranges[i];
+            let mut j = i + 1;
+            while j < ranges.len() && ranges[j].0 as usize == ptr as usize + size {
+                size += ranges[j].1;
+                j += 1;
+            }
+            if size != 0 {
+                if let Err(errno) = uncommit(ptr, size) {
+                    count += j - i;
+                    if first.is_none() {
+                        first = Some((i, errno));
+                    }
+                }
+            }
+            i = j;
+        }
+        match first {
+            None => Ok(()),
+            Some((first, errno)) => {
+                Err(UncommitError::Failed {
+                        count: count,
+                        first: first,
+                        errno: errno,
+                    })
+            }
+        }
+    }
+}
+
+/// An error returned by `MapAlloc::uncommit_range`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UncommitError {
+    /// The range at the given index is not aligned to the page size, or its size is not a
+    /// multiple of the page size. No memory was uncommitted.
+    Unaligned(usize),
+    /// `count` ranges could not be uncommitted. `first` is the index of the first of them, and
+    /// `errno` is the error from uncommitting it.
+    Failed { count: usize, first: usize, errno: i32 },
+}
+
+unsafe impl<'a> Alloc for &'a MapAlloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        self.alloc_excess(layout).map(|Excess(ptr, _)| ptr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.check_dealloc_aligned(ptr);
+        // alloc mapped a multiple of the page size, so make sure that all of it is unmapped
+        self.munmap_helper(ptr, next_multiple(layout.size(), self.pagesize));
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        // Allocations are rounded up to the page size, so an allocation can be grown in place up
+        // to the end of its last page.
+        (layout.size(), next_multiple(layout.size(), self.pagesize))
+    }
+
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        let old_size = next_multiple(layout.size(), self.pagesize);
+        let new_size = next_multiple(new_layout.size(), self.pagesize);
+        let aligned = ptr as usize % new_layout.align() == 0;
+        if aligned && new_size == old_size {
+            Ok(ptr)
+        } else if aligned && new_size != 0 && new_size < old_size {
+            self.unmap_tail(ptr, old_size, new_size);
+            Ok(ptr)
+        } else if new_size > old_size && new_layout.align() <= self.pagesize {
+            // The memory is page-aligned wherever remap puts it.
+            self.remap(ptr, old_size, new_size)
+                .ok_or(AllocErr::Exhausted { request: new_layout })
+        } else {
+            // There is no such thing as an empty mapping, so let alloc decide what to do with a
+            // zero-sized request. Otherwise, the alignment is greater than the page size, which
+            // remap would not preserve.
+            let size = core::cmp::min(layout.size(), new_layout.size());
+            let new = <&'a MapAlloc as Alloc>::alloc(self, new_layout)?;
+            ptr::copy_nonoverlapping(ptr, new, size);
+            self.munmap_helper(ptr, old_size);
+            Ok(new)
+        }
+    }
+
+    unsafe fn grow_in_place(&mut self,
+                            ptr: *mut u8,
+                            layout: Layout,
+                            new_layout: Layout)
+                            -> Result<(), CannotReallocInPlace> {
+        if ptr as usize % new_layout.align() != 0 || new_layout.size() < layout.size() {
+            return Err(CannotReallocInPlace);
+        }
+        let old_size = next_multiple(layout.size(), self.pagesize);
+        let new_size = next_multiple(new_layout.size(), self.pagesize);
+        if new_size == old_size || self.grow_helper(ptr, old_size, new_size) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+
+    unsafe fn shrink_in_place(&mut self,
+                              ptr: *mut u8,
+                              layout: Layout,
+                              new_layout: Layout)
+                              -> Result<(), CannotReallocInPlace> {
+        if ptr as usize % new_layout.align() != 0 || new_layout.size() > layout.size() {
+            return Err(CannotReallocInPlace);
+        }
+        let old_size = next_multiple(layout.size(), self.pagesize);
+        let new_size = next_multiple(new_layout.size(), self.pagesize);
+        if new_size == 0 {
+            return Err(CannotReallocInPlace);
+        }
+        if new_size < old_size {
+            self.unmap_tail(ptr, old_size, new_size);
+        }
+        Ok(())
+    }
+
+    unsafe fn alloc_zeroed(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        // Anonymous mappings are zero-filled unless they are made with MAP_UNINITIALIZED, so only
+        // those and memory mapped from a memfd (which may have been written through an earlier
+        // mapping or by another process) need zeroing.
+        #[cfg(target_os = "linux")]
+        {
+            if self.memfd.is_some() || self.uninitialized {
+                let size = layout.size();
+                let ptr = <&'a MapAlloc as Alloc>::alloc(self, layout)?;
+                ptr::write_bytes(ptr, 0, size);
+                return Ok(ptr);
+            }
+        }
+        <&'a MapAlloc as Alloc>::alloc(self, layout)
+    }
+
+    unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
+        self.alloc_aligned_excess(layout)
+    }
+}
+
+unsafe impl<'a> UntypedObjectAlloc for &'a MapAlloc {
+    fn layout(&self) -> Layout {
+        if cfg!(debug_assertions) {
+            Layout::from_size_align(self.obj_size, self.pagesize).unwrap()
+        } else {
+            unsafe { Layout::from_size_align_unchecked(self.obj_size, self.pagesize) }
+        }
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+        self.alloc_obj().ok_or(Exhausted)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        self.check_dealloc_aligned(ptr);
+        self.munmap_helper(ptr, self.obj_mapped_size());
+    }
+}
+
+unsafe impl Alloc for MapAlloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        <&MapAlloc as Alloc>::alloc(&mut (&*self), layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        <&MapAlloc as Alloc>::dealloc(&mut (&*self), ptr, layout)
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        <&MapAlloc as Alloc>::usable_size(&(&*self), layout)
+    }
+
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        <&MapAlloc as Alloc>::realloc(&mut (&*self), ptr, layout, new_layout)
+    }
+
+    unsafe fn grow_in_place(&mut self,
+                            ptr: *mut u8,
+                            layout: Layout,
+                            new_layout: Layout)
+                            -> Result<(), CannotReallocInPlace> {
+        <&MapAlloc as Alloc>::grow_in_place(&mut (&*self), ptr, layout, new_layout)
+    }
+
+    unsafe fn shrink_in_place(&mut self,
+                              ptr: *mut u8,
+                              layout: Layout,
+                              new_layout: Layout)
+                              -> Result<(), CannotReallocInPlace> {
+        <&MapAlloc as Alloc>::shrink_in_place(&mut (&*self), ptr, layout, new_layout)
+    }
+
+    unsafe fn alloc_zeroed(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        <&MapAlloc as Alloc>::alloc_zeroed(&mut (&*self), layout)
+    }
+
+    unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
+        <&MapAlloc as Alloc>::alloc_excess(&mut (&*self), layout)
+    }
+}
+
+unsafe impl UntypedObjectAlloc for MapAlloc {
+    fn layout(&self) -> Layout {
+        <&MapAlloc as UntypedObjectAlloc>::layout(&(&*self))
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+        <&MapAlloc as UntypedObjectAlloc>::alloc(&mut (&*self))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        <&MapAlloc as UntypedObjectAlloc>::dealloc(&mut (&*self), ptr);
+    }
+}
+
+/// An owned memory mapping.
+///
+/// A `MapBox` is constructed with `MapAlloc::alloc_box`, and owns the mapped memory: it
+/// dereferences to a `[u8]` of length `layout.size()`, and the memory is unmapped when the
+/// `MapBox` is dropped.
+pub struct MapBox {
+    ptr: *mut u8,
+    // The size of the mapping: the layout's size, rounded up to the page size.
+    size: usize,
+    layout: Layout,
+    on_unmap: Option<fn(*mut u8, usize)>,
+}
+
+// A MapBox uniquely owns its mapping, just like a Box<[u8]>.
+unsafe impl Send for MapBox {}
+unsafe impl Sync for MapBox {}
+
+impl MapBox {
+    /// Returns the layout that this `MapBox` was allocated with.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Returns a raw pointer to the beginning of the mapping.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+impl Deref for MapBox {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for MapBox {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for MapBox {
+    fn drop(&mut self) {
+        #[cfg(feature = "track_mappings")]
+        tracker::remove(self.ptr, self.size);
+        if let Some(f) = self.on_unmap {
+            f(self.ptr, self.size);
+        }
+        munmap(self.ptr, self.size);
+    }
+}
+
+/// A pool of objects allocated by a `MapAlloc`.
+///
+/// `MapAlloc`'s `UntypedObjectAlloc` implementation maps and unmaps memory on every allocation
+/// and deallocation. A `MapObjectPool` instead caches deallocated objects, and hands them back out
+/// without making any system calls. Cached objects are uncommitted (on Linux and Darwin) so that
+/// they do not consume physical memory, but their address space stays reserved until the pool is
+/// dropped.
+///
+/// Objects have the same size and alignment as those allocated by the underlying `MapAlloc`. A
+/// reused object's contents are unspecified: on Linux it is zero-filled, but on other platforms it
+/// may still contain data written before it was deallocated.
+///
+/// A `MapObjectPool` can be shared between threads; the cache is protected by a spin lock.
+pub struct MapObjectPool {
+    alloc: MapAlloc,
+    lock: AtomicBool,
+    free: UnsafeCell<FreeList>,
+}
+
+// A stack of cached objects. The stack is stored in its own mapping, rather than being threaded
+// through the objects themselves, so that writing the links does not commit the objects' memory.
+struct FreeList {
+    ptrs: *mut *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+unsafe impl Send for MapObjectPool {}
+unsafe impl Sync for MapObjectPool {}
+
+impl MapObjectPool {
+    /// Creates a new, empty `MapObjectPool` allocating objects from `alloc`.
+    pub fn new(alloc: MapAlloc) -> MapObjectPool {
+        MapObjectPool {
+            alloc: alloc,
+            lock: AtomicBool::new(false),
+            free: UnsafeCell::new(FreeList {
+                                      ptrs: ptr::null_mut(),
+                                      len: 0,
+                                      cap: 0,
+                                  }),
+        }
+    }
+
+    /// Returns the number of objects currently cached by the pool.
+    pub fn cached(&self) -> usize {
+        self.with_free_list(|list| list.len)
+    }
+
+    fn with_free_list<R, F: FnOnce(&mut FreeList) -> R>(&self, f: F) -> R {
+        while self.lock.compare_and_swap(false, true, Ordering::Acquire) {}
+        let res = f(unsafe { &mut *self.free.get() });
+        self.lock.store(false, Ordering::Release);
+        res
+    }
+}
+
+impl FreeList {
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.len == 0 {
+            None
+        } else {
+            self.len -= 1;
+            Some(unsafe { *self.ptrs.offset(self.len as isize) })
+        }
+    }
+
+    // push pushes 'obj' onto the stack, returning false if the stack needed to grow, but the
+    // allocation failed.
+    fn push(&mut self, obj: *mut u8) -> bool {
+        if self.len == self.cap && !self.grow() {
+            return false;
+        }
+        unsafe { *self.ptrs.offset(self.len as isize) = obj };
+        self.len += 1;
+        true
+    }
+
+    fn grow(&mut self) -> bool {
+        let pagesize = sysconf::page::pagesize();
+        let old_bytes = self.cap * mem::size_of::<*mut u8>();
+        let new_bytes = if old_bytes == 0 { pagesize } else { 2 * old_bytes };
+        let new = match mmap(new_bytes, perms::PROT_READ_WRITE, None) {
+            Some(new) => new as *mut *mut u8,
+            None => return false,
+        };
+        if !self.ptrs.is_null() {
+            unsafe { ptr::copy_nonoverlapping(self.ptrs, new, self.len) };
+            munmap(self.ptrs as *mut u8, old_bytes);
+        }
+        self.ptrs = new;
+        self.cap = new_bytes / mem::size_of::<*mut u8>();
+        true
+    }
+}
+
+impl Drop for MapObjectPool {
+    fn drop(&mut self) {
+        let list = unsafe { &mut *self.free.get() };
+        while let Some(obj) = list.pop() {
+            self.alloc.munmap_helper(obj, self.alloc.obj_mapped_size());
+        }
+        if !list.ptrs.is_null() {
+            munmap(list.ptrs as *mut u8, list.cap * mem::size_of::<*mut u8>());
+        }
+    }
+}
+
+unsafe impl<'a> UntypedObjectAlloc for &'a MapObjectPool {
+    fn layout(&self) -> Layout {
+        <&MapAlloc as UntypedObjectAlloc>::layout(&&self.alloc)
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+        if let Some(obj) = self.with_free_list(|list| list.pop()) {
+            return Ok(obj);
+        }
+        <&MapAlloc as UntypedObjectAlloc>::alloc(&mut &self.alloc)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        // Release the physical memory before taking the lock, as it involves a system call.
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios",
+                  target_os = "tvos"))]
+        let _ = uncommit(ptr, self.alloc.obj_mapped_size());
+        if !self.with_free_list(|list| list.push(ptr)) {
+            <&MapAlloc as UntypedObjectAlloc>::dealloc(&mut &self.alloc, ptr);
+        }
+    }
+}
+
+unsafe impl UntypedObjectAlloc for MapObjectPool {
+    fn layout(&self) -> Layout {
+        <&MapObjectPool as UntypedObjectAlloc>::layout(&(&*self))
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+        <&MapObjectPool as UntypedObjectAlloc>::alloc(&mut (&*self))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        <&MapObjectPool as UntypedObjectAlloc>::dealloc(&mut (&*self), ptr);
+    }
+}
+
+/// An object allocator that packs many objects into each mapping.
+///
+/// `MapAlloc`'s `UntypedObjectAlloc` implementation maps each object separately, which costs a
+/// system call per allocation and at least a page of memory per object. A `PackedObjectAlloc`
+/// instead maps memory in chunks that each hold many objects, and carves objects out of them.
+/// Deallocated objects are kept on a free list that is threaded through the objects themselves.
+///
+/// Chunks are aligned to their size (a power of two no smaller than the page size), so an object's
+/// chunk can be found from its address. When all of the objects in a chunk have been deallocated,
+/// the chunk is unmapped, except that a single empty chunk is kept around to avoid repeatedly
+/// mapping and unmapping memory when objects are allocated and deallocated in a loop.
+///
+/// Unlike `MapObjectPool`, a `PackedObjectAlloc` cannot be shared between threads.
+pub struct PackedObjectAlloc {
+    alloc: MapAlloc,
+    layout: Layout,
+    slot_size: usize,
+    first_slot: usize,
+    objs_per_chunk: usize,
+    chunk_size: usize,
+    // Chunks with at least one free slot.
+    partial: *mut Chunk,
+    // Chunks with no free slots.
+    full: *mut Chunk,
+    // An empty chunk, if one is cached.
+    spare: *mut Chunk,
+}
+
+// The header at the beginning of each chunk. Chunks are kept in doubly-linked lists (see
+// PackedObjectAlloc). Slots below 'bump' that are not allocated are on the 'free' list; slots at
+// or above 'bump' have never been allocated.
+struct Chunk {
+    prev: *mut Chunk,
+    next: *mut Chunk,
+    free: *mut u8,
+    bump: usize,
+    live: usize,
+}
+
+// A PackedObjectAlloc uniquely owns its chunks.
+unsafe impl Send for PackedObjectAlloc {}
+
+// The maximum number of times that a PackedObjectAlloc will try to map an aligned chunk on
+// Windows before giving up (see PackedObjectAlloc::map_chunk).
+#[cfg(windows)]
+const MAX_ALIGNED_MAP_RETRIES: usize = 8;
+
+impl PackedObjectAlloc {
+    /// Creates a new `PackedObjectAlloc` that allocates objects with the given `layout` from
+    /// chunks mapped by `alloc`.
+    ///
+    /// Each chunk is large enough to hold at least `objs_per_chunk` objects; since chunk sizes are
+    /// rounded up to a power of two, chunks may hold more.
+    ///
+    /// # Panics
+    ///
+    /// `new` panics if `objs_per_chunk` is zero, or if `layout.align()` is greater than `alloc`'s
+    /// page size.
+    pub fn new(alloc: MapAlloc, layout: Layout, objs_per_chunk: usize) -> PackedObjectAlloc {
+        assert!(objs_per_chunk > 0, "objs_per_chunk must be non-zero");
+        assert!(layout.align() <= alloc.pagesize, "{}", OVER_ALIGNED);
+        // Free slots store a pointer to the next free slot.
+        let align = core::cmp::max(layout.align(), mem::align_of::<*mut u8>());
+        let slot_size = next_multiple(core::cmp::max(layout.size(), mem::size_of::<*mut u8>()),
+                                      align);
+        let first_slot = next_multiple(mem::size_of::<Chunk>(), align);
+        let chunk_size = core::cmp::max((first_slot + objs_per_chunk * slot_size)
+                                            .next_power_of_two(),
+                                        alloc.pagesize);
+        PackedObjectAlloc {
+            alloc: alloc,
+            layout: layout,
+            slot_size: slot_size,
+            first_slot: first_slot,
+            objs_per_chunk: (chunk_size - first_slot) / slot_size,
+            chunk_size: chunk_size,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            spare: ptr::null_mut(),
+        }
+    }
+
+    /// Returns the number of objects that fit in each chunk.
+    pub fn objs_per_chunk(&self) -> usize {
+        self.objs_per_chunk
+    }
+
+    // map_chunk maps a new chunk aligned to chunk_size, and initializes its header.
+    fn map_chunk(&self) -> Option<*mut Chunk> {
+        let size = self.chunk_size;
+        let chunk = if size == self.alloc.pagesize {
+            self.alloc.alloc_helper(size)?
+        } else {
+            self.map_aligned(size)?
+        };
+        let chunk = chunk as *mut Chunk;
+        unsafe {
+            ptr::write(chunk,
+                       Chunk {
+                           prev: ptr::null_mut(),
+                           next: ptr::null_mut(),
+                           free: ptr::null_mut(),
+                           bump: self.first_slot,
+                           live: 0,
+                       });
+        }
+        Some(chunk)
+    }
+
+    // map_aligned maps a region large enough to contain an aligned chunk, and unmaps the rest.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    fn map_aligned(&self, size: usize) -> Option<*mut u8> {
+        let len = 2 * size - self.alloc.pagesize;
+        let base = self.alloc.alloc_helper(len)? as usize;
+        let start = (base + size - 1) & !(size - 1);
+        // The excess before and after the chunk is unmapped separately from the chunk, so record
+        // the three pieces as separate mappings.
+        #[cfg(feature = "track_mappings")]
+        {
+            tracker::remove(base as *mut u8, len);
+            tracker::insert(start as *mut u8, size);
+            if start > base {
+                tracker::insert(base as *mut u8, start - base);
+            }
+            if base + len > start + size {
+                tracker::insert((start + size) as *mut u8, base + len - (start + size));
+            }
+        }
+        if start > base {
+            self.alloc.munmap_helper(base as *mut u8, start - base);
+        }
+        if base + len > start + size {
+            self.alloc.munmap_helper((start + size) as *mut u8, base + len - (start + size));
+        }
+        Some(start as *mut u8)
+    }
+
+    // map_aligned maps a region large enough to contain an aligned chunk, unmaps it, and then maps
+    // the chunk at the aligned address within it. VirtualFree can only release entire mappings, so
+    // the excess can't be trimmed as it is on Unix. Since another thread may map the address in
+    // the meantime, this is retried a few times.
+    #[cfg(windows)]
+    fn map_aligned(&self, size: usize) -> Option<*mut u8> {
+        let len = 2 * size - self.alloc.pagesize;
+        for _ in 0..MAX_ALIGNED_MAP_RETRIES {
+            let base = self.alloc.map_non_null(len)? as usize;
+            munmap(base as *mut u8, len);
+            let start = (base + size - 1) & !(size - 1);
+            if let Some(ptr) = mmap_at(start as *mut u8, size, self.alloc.perms, None, 0) {
+                self.alloc.mapped(ptr, size);
+                return Some(ptr);
+            }
+        }
+        None
+    }
+
+    fn unmap_chunk(&self, chunk: *mut Chunk) {
+        self.alloc.munmap_helper(chunk as *mut u8, self.chunk_size);
+    }
+}
+
+// push pushes 'chunk' onto the front of the list whose first element is '*head'.
+unsafe fn push_chunk(head: &mut *mut Chunk, chunk: *mut Chunk) {
+    (*chunk).prev = ptr::null_mut();
+    (*chunk).next = *head;
+    if !head.is_null() {
+        (**head).prev = chunk;
+    }
+    *head = chunk;
+}
+
+// unlink_chunk removes 'chunk' from the list whose first element is '*head'.
+unsafe fn unlink_chunk(head: &mut *mut Chunk, chunk: *mut Chunk) {
+    let (prev, next) = ((*chunk).prev, (*chunk).next);
+    if prev.is_null() {
+        *head = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+}
+
+impl Drop for PackedObjectAlloc {
+    fn drop(&mut self) {
+        for head in &[self.partial, self.full, self.spare] {
+            let mut chunk = *head;
+            while !chunk.is_null() {
+                let next = unsafe { (*chunk).next };
+                self.unmap_chunk(chunk);
+                chunk = next;
+            }
+        }
+    }
+}
+
+unsafe impl UntypedObjectAlloc for PackedObjectAlloc {
+    fn layout(&self) -> Layout {
+        self.layout.clone()
+    }
+
+    unsafe fn alloc(&mut self) -> Result<*mut u8, Exhausted> {
+        if self.partial.is_null() {
+            let chunk = if self.spare.is_null() {
+                self.map_chunk().ok_or(Exhausted)?
+            } else {
+                mem::replace(&mut self.spare, ptr::null_mut())
+            };
+            push_chunk(&mut self.partial, chunk);
+        }
+
+        // Every chunk on the partial list has a free slot: either one on its free list, or, if
+        // the free list is empty, one that has never been allocated.
+        let chunk = self.partial;
+        let obj = if (*chunk).free.is_null() {
+            let obj = (chunk as *mut u8).offset((*chunk).bump as isize);
+            (*chunk).bump += self.slot_size;
+            obj
+        } else {
+            let obj = (*chunk).free;
+            (*chunk).free = *(obj as *mut *mut u8);
+            obj
+        };
+        (*chunk).live += 1;
+        if (*chunk).live == self.objs_per_chunk {
+            unlink_chunk(&mut self.partial, chunk);
+            push_chunk(&mut self.full, chunk);
+        }
+        Ok(obj)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let chunk = (ptr as usize & !(self.chunk_size - 1)) as *mut Chunk;
+        if (*chunk).live == self.objs_per_chunk {
+            unlink_chunk(&mut self.full, chunk);
+            push_chunk(&mut self.partial, chunk);
+        }
+        *(ptr as *mut *mut u8) = (*chunk).free;
+        (*chunk).free = ptr;
+        (*chunk).live -= 1;
+        if (*chunk).live == 0 {
+            unlink_chunk(&mut self.partial, chunk);
+            if self.spare.is_null() {
+                self.spare = chunk;
+            } else {
+                self.unmap_chunk(chunk);
+            }
+        }
+    }
+}
+
+/// An allocator that carves memory out of a fixed buffer.
+///
+/// `StaticMapAlloc` is meant for embedded and bare-metal targets that have no `mmap` or
+/// `VirtualAlloc`. It implements `Alloc` just like `MapAlloc` does, so that code written against
+/// `Alloc` can use a `MapAlloc` on hosted platforms and a `StaticMapAlloc` elsewhere. Unlike
+/// `MapAlloc`, it never makes system calls, has no notion of pages or permissions, and its memory
+/// is not zero-filled.
+///
+/// Memory is allocated by bumping a pointer through the buffer, so any alignment can be honored
+/// (at the cost of the padding needed to reach it). Deallocating the most recent allocation
+/// returns its memory to the buffer; other deallocations are no-ops. Once the buffer is full,
+/// allocations fail with `Exhausted`.
+///
+/// A buffer with a `'static` lifetime (such as a `static mut` array) can be used to build an
+/// allocator that lives for the whole program.
+pub struct StaticMapAlloc<'a> {
+    start: *mut u8,
+    len: usize,
+    // The offset from 'start' of the first unallocated byte.
+    next: AtomicUsize,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+// A StaticMapAlloc uniquely borrows its buffer, and only hands out disjoint parts of it.
+unsafe impl<'a> Send for StaticMapAlloc<'a> {}
+unsafe impl<'a> Sync for StaticMapAlloc<'a> {}
+
+impl<'a> StaticMapAlloc<'a> {
+    /// Creates a new `StaticMapAlloc` that allocates from `buf`.
+    pub fn new(buf: &'a mut [u8]) -> StaticMapAlloc<'a> {
+        StaticMapAlloc {
+            start: buf.as_mut_ptr(),
+            len: buf.len(),
+            next: AtomicUsize::new(0),
+            _buf: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes in the buffer that have not been allocated.
+    ///
+    /// Alignment padding means that an allocation of this many bytes may still fail.
+    pub fn remaining(&self) -> usize {
+        self.len - self.next.load(Ordering::Relaxed)
+    }
+
+    fn bump(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let start = self.start as usize;
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let ptr = next_multiple(start + next, align);
+            let end = ptr.checked_add(size)?;
+            if end > start + self.len {
+                return None;
+            }
+            let new = end - start;
+            match self.next
+                      .compare_exchange_weak(next, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Some(ptr as *mut u8),
+                Err(cur) => next = cur,
+            }
+        }
+    }
+
+    fn unbump(&self, ptr: *mut u8, size: usize) {
+        let offset = ptr as usize - self.start as usize;
+        // If another allocation has been made since, this fails and the memory is leaked.
+        let _ = self.next
+            .compare_exchange(offset + size, offset, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<'a, 'b> Alloc for &'a StaticMapAlloc<'b> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        self.bump(layout.size(), layout.align()).ok_or(AllocErr::Exhausted { request: layout })
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.unbump(ptr, layout.size())
+    }
+}
+
+unsafe impl<'a> Alloc for StaticMapAlloc<'a> {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
+        <&StaticMapAlloc as Alloc>::alloc(&mut (&*self), layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        <&StaticMapAlloc as Alloc>::dealloc(&mut (&*self), ptr, layout)
+    }
+}
+
+// The number of allocations on this thread until one is made to fail, or 0 if none will be.
+#[cfg(feature = "fault_injection")]
+#[thread_local]
+static mut FAILURE_COUNTDOWN: usize = 0;
+
+/// Makes an upcoming allocation on the current thread fail.
+///
+/// After `set_alloc_failure_countdown(n)`, the `n`th allocation made by a `MapAlloc` on the
+/// calling thread fails as if the system were out of memory, and later allocations succeed as
+/// usual. `n = 1` fails the next allocation, and `n = 0` disarms a pending failure. Allocations
+/// made through the `Alloc` and `UntypedObjectAlloc` implementations (and the methods built on
+/// them, such as `alloc_box`) count; `alloc_at` and resizing existing memory do not.
+///
+/// This is meant for testing how code built on `MapAlloc` recovers from allocation failure. It
+/// is only available with the `fault_injection` feature; without it, allocations never check for
+/// injected failures.
+#[cfg(feature = "fault_injection")]
+pub fn set_alloc_failure_countdown(n: usize) {
+    unsafe { FAILURE_COUNTDOWN = n };
+}
+
+// inject_failure counts an allocation against the failure countdown, and returns whether that
+// allocation should fail.
+#[cfg(feature = "fault_injection")]
+fn inject_failure() -> bool {
+    unsafe {
+        match FAILURE_COUNTDOWN {
+            0 => false,
+            n => {
+                FAILURE_COUNTDOWN = n - 1;
+                n == 1
+            }
+        }
+    }
+}
+
+// tracker records the mappings made by all MapAllocs that have not yet been unmapped, so that
+// unmapping memory that isn't mapped (such as a double dealloc), or unmapping a mapping with the
+// wrong size, panics right away rather than silently succeeding. This is enabled by the
+// track_mappings feature. The table is mapped directly with mmap_at rather than allocated from the
+// global allocator (or through a MapAlloc), and it is only ever resized with the lock released, so
+// the tracker works even when MapAlloc is used to implement the global allocator.
+#[cfg(feature = "track_mappings")]
+mod tracker {
+    use core::{mem, ptr, slice};
+    use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+    use super::{mmap_at, munmap, perms};
+
+    // The number of entries that the table has room for when it is first mapped.
+    const INITIAL_CAPACITY: usize = 256;
+
+    // The address and size of each live mapping, sorted by address.
+    struct Table {
+        entries: *mut (usize, usize),
+        len: usize,
+        cap: usize,
+    }
+
+    static LOCKED: AtomicBool = ATOMIC_BOOL_INIT;
+    static mut TABLE: Table = Table {
+        entries: 0 as *mut (usize, usize),
+        len: 0,
+        cap: 0,
+    };
+
+    // with_table calls 'f' on the table with the lock held. 'f' must not map or unmap memory.
+    fn with_table<R, F: FnOnce(&mut Table) -> R>(f: F) -> R {
+        while LOCKED.compare_and_swap(false, true, Ordering::Acquire) {}
+        let res = f(unsafe { &mut TABLE });
+        LOCKED.store(false, Ordering::Release);
+        res
+    }
+
+    impl Table {
+        fn entries(&mut self) -> &mut [(usize, usize)] {
+            if self.entries.is_null() {
+                &mut []
+            } else {
+                unsafe { slice::from_raw_parts_mut(self.entries, self.len) }
+            }
+        }
+    }
+
+    // table_size returns the size of a table with room for 'cap' entries.
+    fn table_size(cap: usize) -> usize {
+        cap * mem::size_of::<(usize, usize)>()
+    }
+
+    // grow replaces the table with one twice as large, unless another thread has already grown
+    // it past 'cap' entries. The new table is mapped, and the old one unmapped, without the lock.
+    fn grow(cap: usize) {
+        let new_cap = if cap == 0 { INITIAL_CAPACITY } else { 2 * cap };
+        let new = match mmap_at(ptr::null_mut(),
+                                table_size(new_cap),
+                                perms::PROT_READ_WRITE,
+                                None,
+                                0) {
+            Some(new) => new as *mut (usize, usize),
+            None => panic!("could not map the table of live mappings"),
+        };
+        let (unused, unused_cap) = with_table(|table| {
+            if table.cap != cap {
+                return (new, new_cap);
+            }
+            unsafe {
+                let old = table.entries();
+                ptr::copy_nonoverlapping(old.as_ptr(), new, old.len());
+            }
+            let old = (table.entries, table.cap);
+            table.entries = new;
+            table.cap = new_cap;
+            old
+        });
+        if !unused.is_null() {
+            munmap(unused as *mut u8, table_size(unused_cap));
+        }
+    }
+
+    // insert records a mapping of 'size' bytes at 'ptr'.
+    pub fn insert(ptr: *mut u8, size: usize) {
+        let addr = ptr as usize;
+        loop {
+            // If the table is full, this is its capacity, and it needs to grow.
+            let full = with_table(|table| {
+                let found = table.entries().binary_search_by_key(&addr, |m| m.0);
+                match found {
+                    Ok(i) => table.entries()[i].1 = size,
+                    Err(_) if table.len == table.cap => return Some(table.cap),
+                    Err(i) => unsafe {
+                        let at = table.entries.offset(i as isize);
+                        ptr::copy(at, at.offset(1), table.len - i);
+                        ptr::write(at, (addr, size));
+                        table.len += 1;
+                    },
+                }
+                None
+            });
+            match full {
+                Some(cap) => grow(cap),
+                None => return,
+            }
+        }
+    }
+
+    // remove removes the mapping of 'size' bytes at 'ptr', panicking if there is no mapping at
+    // 'ptr' or if it has a different size.
+    pub fn remove(ptr: *mut u8, size: usize) {
+        // Don't panic with the lock held, so that other threads can keep using the table.
+        let found = with_table(|table| {
+            let found = table.entries().binary_search_by_key(&(ptr as usize), |m| m.0);
+            let i = match found {
+                Ok(i) => i,
+                Err(_) => return None,
+            };
+            let mapped = table.entries()[i].1;
+            if mapped == size {
+                unsafe {
+                    let at = table.entries.offset(i as isize);
+                    ptr::copy(at.offset(1), at, table.len - i - 1);
+                }
+                table.len -= 1;
+            }
+            Some(mapped)
+        });
+        match found {
+            None => panic!("unmapping {:?}, which is not mapped (double dealloc?)", ptr),
+            Some(mapped) if mapped != size => {
+                panic!("unmapping {} bytes at {:?}, which was mapped with {} bytes",
+                       size,
+                       ptr,
+                       mapped)
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+// uninitialized_honored returns whether the kernel honors MAP_UNINITIALIZED, probing it on the
+// first call. Kernels that don't honor the flag silently ignore it, so the probe dirties a page,
+// unmaps it, and maps a fresh one with the flag: freed pages are reused first, so if the kernel
+// doesn't zero-fill, the new page almost certainly holds the old contents. The probe can fail to
+// notice that the flag is honored, but not the other way around, so at worst MAP_UNINITIALIZED
+// isn't used when it could have been.
+#[cfg(target_os = "linux")]
+fn uninitialized_honored() -> bool {
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    const UNKNOWN: usize = 0;
+    const IGNORED: usize = 1;
+    const HONORED: usize = 2;
+    static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    match STATE.load(Ordering::Relaxed) {
+        UNKNOWN => {}
+        state => return state == HONORED,
+    }
+    let size = sysconf::page::pagesize();
+    let perms = perms::PROT_READ_WRITE;
+    let honored = match mmap_at(ptr::null_mut(), size, perms, None, MAP_UNINITIALIZED) {
+        Some(ptr) => unsafe {
+            ptr::write_bytes(ptr, 0xFF, size);
+            munmap(ptr, size);
+            match mmap_at(ptr::null_mut(), size, perms, None, MAP_UNINITIALIZED) {
+                Some(ptr) => {
+                    let dirty = (0..size).any(|i| *ptr.offset(i as isize) != 0);
+                    munmap(ptr, size);
+                    dirty
+                }
+                None => false,
+            }
+        },
+        None => false,
+    };
+    STATE.store(if honored { HONORED } else { IGNORED }, Ordering::Relaxed);
+    honored
+}
+
+// huge_pagesize_supported returns whether huge pages of size 'huge' are supported on the runtime
+// platform.
+fn huge_pagesize_supported(huge: usize) -> bool {
+    #[cfg(target_os = "linux")]
+    let supported = sysconf::page::hugepage_supported(huge);
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    let supported = huge == SUPERPAGE_SIZE_2MB;
+    #[cfg(windows)]
+    let supported = {
+        let min = unsafe { kernel32::GetLargePageMinimum() } as usize;
+        min != 0 && huge % min == 0
+    };
+    supported
+}
+
+// The error message for a layout whose alignment is greater than the page size, where that isn't
+// supported.
+const OVER_ALIGNED: &str = "cannot support alignment greater than the page size";
+
+fn next_multiple(size: usize, unit: usize) -> usize {
+    if size % unit == 0 {
+        size
+    } else {
+        size + (unit - (size % unit))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mark_unused(ptr: *mut u8, size: usize) {
+    use libc::{c_void, MADV_DONTNEED, PROT_NONE};
+    unsafe {
+        // Let the kernel know we don't need this memory, so it can free physical resources for it
+        libc::madvise(ptr as *mut c_void, size, MADV_DONTNEED);
+        // Make it so that accesses to this memory result in a segfault
+        libc::mprotect(ptr as *mut c_void, size, PROT_NONE);
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn mark_unused(ptr: *mut u8, size: usize) {
+    use libc::{c_void, MADV_FREE, PROT_NONE};
+    unsafe {
+        // Let the kernel know we don't need this memory, so it can free physical resources for it
+        libc::madvise(ptr as *mut c_void, size, MADV_FREE);
+        // Make it so that accesses to this memory result in a segfault
+        libc::mprotect(ptr as *mut c_void, size, PROT_NONE);
+    }
+}
+
+// Not defined by all versions of libc. This is the value used on all architectures that define it.
+#[cfg(target_os = "linux")]
+const MAP_FIXED_NOREPLACE: i32 = 0x100000;
+// Not defined by all versions of libc. This is the value used on all architectures.
+#[cfg(target_os = "linux")]
+const MAP_UNINITIALIZED: i32 = 0x4000000;
+// Not defined by all versions of libc. These are the values used on all architectures other than
+// parisc. Kernels older than 5.4 reject them with EINVAL.
+#[cfg(target_os = "linux")]
+const MADV_COLD: i32 = 20;
+#[cfg(target_os = "linux")]
+const MADV_PAGEOUT: i32 = 21;
+
+/// An error from a call to `mmap` or a related function.
+///
+/// `MmapError` carries the raw `errno` value rather than a formatted message so that failures
+/// can be handled (or reported with `panic`) without formatting or allocating.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum MmapError {
+    /// `ENOMEM`
+    OutOfMemory,
+    /// `EINVAL`
+    InvalidArgument,
+    /// Any other `errno` value
+    Other(i32),
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+impl MmapError {
+    // last_os_error constructs an MmapError from the current value of errno. It must be called
+    // immediately after the failing call.
+    fn last_os_error() -> MmapError {
+        MmapError::from_errno(errno())
+    }
+
+    fn from_errno(errno: i32) -> MmapError {
+        match errno {
+            libc::ENOMEM => MmapError::OutOfMemory,
+            libc::EINVAL => MmapError::InvalidArgument,
+            err => MmapError::Other(err),
+        }
+    }
+
+    // panic panics with a static message describing the error. Unlike formatting the errno into
+    // the message, this never allocates.
+    fn panic(self) -> ! {
+        match self {
+            MmapError::OutOfMemory => panic!("memory mapping failed: out of memory"),
+            MmapError::InvalidArgument => panic!("memory mapping failed: invalid argument"),
+            MmapError::Other(_) => panic!("memory mapping failed: unexpected error"),
+        }
+    }
+}
+
+// errno returns the calling thread's errno. It is read directly from libc's thread-local variable
+// (as std and the errno crate do) so that this works in no_std builds.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn errno() -> i32 {
+    extern "C" {
+        #[cfg_attr(target_os = "linux", link_name = "__errno_location")]
+        #[cfg_attr(any(target_os = "macos", target_os = "ios", target_os = "tvos"),
+                   link_name = "__error")]
+        fn errno_location() -> *mut libc::c_int;
+    }
+    unsafe { *errno_location() }
+}
+
+// check converts the return value of a call that returns 0 on success and sets errno on failure
+// into a Result holding errno. Since the call has to be evaluated to produce the argument, errno
+// can't be read before the call returns.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn check(ret: libc::c_int) -> Result<(), i32> {
+    if ret == 0 { Ok(()) } else { Err(errno()) }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn mmap(size: usize, perms: i32, huge_pagesize: Option<usize>) -> Option<*mut u8> {
+    mmap_at(ptr::null_mut(), size, perms, huge_pagesize, 0)
+}
+
+// mmap_at is like mmap, but passes 'addr' and any extra 'flags' through to the underlying mmap
+// call. Running out of memory is reported as None; any other error is unexpected, and causes a
+// panic.
+#[cfg(target_os = "linux")]
+fn mmap_at(addr: *mut u8,
+           size: usize,
+           perms: i32,
+           huge_pagesize: Option<usize>,
+           extra_flags: i32)
+           -> Option<*mut u8> {
+    match try_mmap_at(addr, size, perms, huge_pagesize, extra_flags) {
+        Ok(ptr) => Some(ptr),
+        Err(MmapError::OutOfMemory) => None,
+        Err(MmapError::Other(libc::EEXIST)) if extra_flags & MAP_FIXED_NOREPLACE != 0 => None,
+        Err(err) => err.panic(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn try_mmap_at(addr: *mut u8,
+               size: usize,
+               perms: i32,
+               huge_pagesize: Option<usize>,
+               extra_flags: i32)
+               -> Result<*mut u8, MmapError> {
+    use libc::{MAP_ANONYMOUS, MAP_PRIVATE, MAP_HUGETLB, MAP_FAILED};
+
+    // MAP_HUGE_SHIFT isn't used on all kernel versions, but I assume it must be
+    // backwards-compatible. The only way for it to not be backwards-compatible would be for
+    // there to be bits in the range [26, 31] (in the 'flags' argument) that used to be
+    // meaningful. That would make old programs fail on newer kernels. In theory, old kernels
+    // could be checking to make sure that undefined flags aren't set, but that seems unlikely.
+    // See:
+    // http://elixir.free-electrons.com/linux/latest/source/arch/alpha/include/uapi/asm/mman.h
+    // http://man7.org/linux/man-pages/man2/mmap.2.html
+    const MAP_HUGE_SHIFT: usize = 26;
+    let flags = if let Some(pagesize) = huge_pagesize {
+        debug_assert!(pagesize.is_power_of_two()); // implies pagesize > 0
+        let log = pagesize.trailing_zeros();
+        MAP_HUGETLB | ((log as i32) << MAP_HUGE_SHIFT)
+    } else {
+        0
+    };
+
+    let ptr = unsafe {
+        libc::mmap(addr as *mut libc::c_void,
+                   size,
+                   perms,
+                   MAP_ANONYMOUS | MAP_PRIVATE | flags | extra_flags,
+                   -1,
+                   0)
+    };
+
+    if ptr == MAP_FAILED {
+        Err(MmapError::last_os_error())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn mmap_at(addr: *mut u8,
+           size: usize,
+           perms: i32,
+           huge_pagesize: Option<usize>,
+           extra_flags: i32)
+           -> Option<*mut u8> {
+    match try_mmap_at(addr, size, perms, huge_pagesize, extra_flags) {
+        Ok(ptr) => Some(ptr),
+        Err(MmapError::OutOfMemory) => None,
+        // Superpages are not supported on all hardware, and even where they are, the kernel may
+        // be unable to find enough contiguous physical memory. In either case, treat the failure
+        // as an out-of-memory condition.
+        Err(_) if huge_pagesize.is_some() => None,
+        Err(err) => err.panic(),
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn try_mmap_at(addr: *mut u8,
+               size: usize,
+               perms: i32,
+               huge_pagesize: Option<usize>,
+               extra_flags: i32)
+               -> Result<*mut u8, MmapError> {
+    use libc::{MAP_ANON, MAP_PRIVATE, MAP_FAILED};
+
+    // Superpages are requested by passing VM_FLAGS_SUPERPAGE_SIZE_* in place of a file
+    // descriptor (see the MAP_ANON description in the mmap manpage). 2MB is the only size that
+    // macOS supports.
+    let fd = if let Some(pagesize) = huge_pagesize {
+        debug_check!(pagesize == SUPERPAGE_SIZE_2MB, "unsupported superpage size");
+        VM_FLAGS_SUPERPAGE_SIZE_2MB
+    } else {
+        -1
+    };
+
+    let ptr = unsafe {
+        libc::mmap(addr as *mut libc::c_void,
+                   size,
+                   perms,
+                   MAP_ANON | MAP_PRIVATE | extra_flags,
+                   fd,
+                   0)
+    };
+
+    if ptr == MAP_FAILED {
+        Err(MmapError::last_os_error())
+    } else {
+        Ok(ptr as *mut u8)
+    }
+}
+
+// From <mach/vm_statistics.h>; not defined by libc.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+const SUPERPAGE_SIZE_2MB: usize = 2 << 20;
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+const VM_FLAGS_SUPERPAGE_SIZE_2MB: i32 = 2 << 16;
+
+// For a good overview of virtual memory handling on Windows, see
+// https://blogs.technet.microsoft.com/markrussinovich/2008/11/17/pushing-the-limits-of-windows-virtual-memory/
+
+#[cfg(windows)]
+fn mmap(size: usize, perms: u32, huge_pagesize: Option<usize>) -> Option<*mut u8> {
+    mmap_at(ptr::null_mut(), size, perms, huge_pagesize, 0)
+}
+
+// enable_lock_memory_privilege enables SeLockMemoryPrivilege, which is required in order to map
+// memory with MEM_LARGE_PAGES, for the current process. On failure, it returns the error code
+// from GetLastError.
+#[cfg(windows)]
+fn enable_lock_memory_privilege() -> Result<(), u32> {
+    use advapi32::{AdjustTokenPrivileges, LookupPrivilegeValueW, OpenProcessToken};
+    use kernel32::{CloseHandle, GetCurrentProcess, GetLastError};
+    use winapi::{ERROR_NOT_ALL_ASSIGNED, HANDLE, LUID, LUID_AND_ATTRIBUTES, SE_PRIVILEGE_ENABLED,
+                 TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY};
+
+    // "SeLockMemoryPrivilege" as a null-terminated UTF-16 string
+    const NAME: &[u8] = b"SeLockMemoryPrivilege";
+    let mut name = [0u16; 22];
+    for (c, b) in name.iter_mut().zip(NAME) {
+        *c = *b as u16;
+    }
+
+    unsafe {
+        let mut token: HANDLE = ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(),
+                            TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY,
+                            &mut token) == 0 {
+            return Err(GetLastError());
+        }
+        let mut privileges = TOKEN_PRIVILEGES {
+            PrivilegeCount: 1,
+            Privileges: [LUID_AND_ATTRIBUTES {
+                             Luid: LUID {
+                                 LowPart: 0,
+                                 HighPart: 0,
+                             },
+                             Attributes: SE_PRIVILEGE_ENABLED,
+                         }],
+        };
+        let res = if LookupPrivilegeValueW(ptr::null(),
+                                           name.as_ptr(),
+                                           &mut privileges.Privileges[0].Luid) == 0 {
+            Err(GetLastError())
+        } else if AdjustTokenPrivileges(token,
+                                        0,
+                                        &mut privileges,
+                                        0,
+                                        ptr::null_mut(),
+                                        ptr::null_mut()) == 0 {
+            Err(GetLastError())
+        } else {
+            // AdjustTokenPrivileges succeeds even if the privilege isn't held, in which case it
+            // sets the last error to ERROR_NOT_ALL_ASSIGNED.
+            match GetLastError() {
+                ERROR_NOT_ALL_ASSIGNED => Err(ERROR_NOT_ALL_ASSIGNED),
+                _ => Ok(()),
+            }
+        };
+        CloseHandle(token);
+        res
+    }
+}
+
+// mmap_at is like mmap, but requests that the memory be mapped at 'addr'. VirtualAlloc never
+// replaces existing mappings, so there are no extra flags to pass: if any part of the range is
+// already reserved, VirtualAlloc fails and None is returned. This differs from MAP_FIXED on Unix,
+// which clobbers existing mappings. VirtualAlloc2, which can also place a mapping anywhere within
+// an address range, is not used since it requires Windows 10 version 1803 or later.
+#[cfg(windows)]
+fn mmap_at(addr: *mut u8,
+           size: usize,
+           perms: u32,
+           huge_pagesize: Option<usize>,
+           _extra_flags: i32)
+           -> Option<*mut u8> {
+    use kernel32::VirtualAlloc;
+    use winapi::winnt::{MEM_RESERVE, MEM_COMMIT, MEM_LARGE_PAGES};
+
+    let typ = if huge_pagesize.is_none() {
+        MEM_RESERVE | MEM_COMMIT
+    } else {
+        MEM_RESERVE | MEM_COMMIT | MEM_LARGE_PAGES
+    };
+
+    unsafe {
+        // NOTE: While Windows makes a distinction between allocation granularity and page size
+        // (see https://msdn.microsoft.com/en-us/library/windows/desktop/ms724958(v=vs.85).aspx),
+        // VirtualAlloc only cares about allocation granularity for the pointer argument, not the
+        // size. If the pointer is not aligned to the allocation granularity, it is rounded down;
+        // alloc_at detects this by comparing the returned pointer to the requested one.
+        let ptr = VirtualAlloc(addr as *mut winapi::c_void, size as u64, typ, perms) as *mut u8;
+        // NOTE: Windows can return many different error codes in different scenarios that all
+        // relate to being out of memory. Instead of trying to list them all, we assume that any
+        // error is an out-of-memory condition. This is fine so long as our code doesn't have a bug
+        // (that would, e.g., result in VirtualAlloc being called with invalid arguments). This
+        // isn't ideal, but during debugging, error codes can be printed here, so it's not the end
+        // of the world.
+        if ptr.is_null() { None } else { Some(ptr) }
+    }
+}
+
+// mmap_shared maps the file 'fd' starting at 'offset' with MAP_SHARED.
+#[cfg(target_os = "linux")]
+fn mmap_shared(size: usize, perms: i32, fd: libc::c_int, offset: usize) -> Option<*mut u8> {
+    use libc::{MAP_SHARED, MAP_FAILED};
+    let ptr = unsafe {
+        libc::mmap(ptr::null_mut(),
+                   size,
+                   perms,
+                   MAP_SHARED,
+                   fd,
+                   offset as libc::off_t)
+    };
+    if ptr == MAP_FAILED {
+        match MmapError::last_os_error() {
+            MmapError::OutOfMemory => None,
+            err => err.panic(),
+        }
+    } else {
+        Some(ptr as *mut u8)
+    }
+}
+
+// memfd_create creates a memfd as described by 'cfg' and truncates it to the configured size.
+#[cfg(target_os = "linux")]
+fn memfd_create(cfg: &MemfdConfig,
+                huge_pagesize: Option<usize>)
+                -> Result<libc::c_int, BuildError> {
+    // Not defined by all versions of libc.
+    const MFD_CLOEXEC: libc::c_uint = 0x1;
+    const MFD_HUGETLB: libc::c_uint = 0x4;
+    const MFD_HUGE_SHIFT: usize = 26;
+
+    if cfg.name_len > MEMFD_NAME_MAX || cfg.name[..cfg.name_len].contains(&0) {
+        return Err(BuildError::Memfd(libc::EINVAL));
+    }
+    let flags = if let Some(pagesize) = huge_pagesize {
+        MFD_CLOEXEC | MFD_HUGETLB | (pagesize.trailing_zeros() << MFD_HUGE_SHIFT)
+    } else {
+        MFD_CLOEXEC
+    };
+    unsafe {
+        let fd = libc::syscall(libc::SYS_memfd_create, cfg.name.as_ptr(), flags) as libc::c_int;
+        if fd < 0 {
+            return Err(BuildError::Memfd(errno()));
+        }
+        if libc::ftruncate(fd, cfg.size as libc::off_t) != 0 {
+            let err = errno();
+            libc::close(fd);
+            return Err(BuildError::Memfd(err));
+        }
+        Ok(fd)
+    }
+}
+
+// mremap resizes the mapping at 'ptr'. If 'may_move' is false, it fails rather than moving the
+// mapping.
+#[cfg(target_os = "linux")]
+fn mremap(ptr: *mut u8, old_size: usize, new_size: usize, may_move: bool) -> Option<*mut u8> {
+    use libc::{MREMAP_MAYMOVE, MAP_FAILED};
+    let flags = if may_move { MREMAP_MAYMOVE } else { 0 };
+    let new = unsafe { libc::mremap(ptr as *mut libc::c_void, old_size, new_size, flags) };
+    if new == MAP_FAILED {
+        match MmapError::last_os_error() {
+            MmapError::OutOfMemory => None,
+            err => err.panic(),
+        }
+    } else {
+        Some(new as *mut u8)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn munmap(ptr: *mut u8, size: usize) {
+    if let Err(errno) = check(unsafe { libc::munmap(ptr as *mut libc::c_void, size) }) {
+        MmapError::from_errno(errno).panic();
+    }
+}
+
+#[cfg(windows)]
+fn munmap(ptr: *mut u8, _size: usize) {
+    use kernel32::{VirtualFree, GetLastError};
+    use winapi::winnt::MEM_RELEASE;
+
+    unsafe {
+        // NOTE: VirtualFree, when unmapping memory (as opposed to decommitting it), can only
+        // operate on an entire region previously mapped with VirtualAlloc. As a result, 'ptr' must
+        // have been previously returned by VirtualAlloc, and no length is needed since it is known
+        // by the kernel (VirtualFree /requires/ that if the third argument is MEM_RELEASE, the
+        // second is 0).
+        let ret = VirtualFree(ptr as *mut winapi::c_void, 0, MEM_RELEASE);
+        if ret == 0 {
+            panic!("Call to VirtualFree failed with error code {}.",
+                   GetLastError());
+        }
+    }
+}
+
+// advise passes the advice corresponding to 'pattern' to madvise. It is only a hint, so failure
+// is ignored.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn advise(ptr: *mut u8, size: usize, pattern: AccessPattern) {
+    use libc::{c_void, MADV_DONTNEED, MADV_NORMAL, MADV_RANDOM, MADV_SEQUENTIAL, MADV_WILLNEED};
+    let advice = match pattern {
+        AccessPattern::Normal => MADV_NORMAL,
+        AccessPattern::Random => MADV_RANDOM,
+        AccessPattern::Sequential => MADV_SEQUENTIAL,
+        AccessPattern::WillNeed => MADV_WILLNEED,
+        AccessPattern::DontNeed => MADV_DONTNEED,
+    };
+    unsafe {
+        libc::madvise(ptr as *mut c_void, size, advice);
+    }
+}
+
+// uncommit releases the physical memory backing the given range. On failure, it returns errno.
+#[cfg(target_os = "linux")]
+fn uncommit(ptr: *mut u8, size: usize) -> Result<(), i32> {
+    use libc::{c_void, MADV_DONTNEED};
+    // TODO: Other options such as MADV_FREE are available on newer versions of Linux. Is there
+    // a way that we can use those when available? Is that even desirable?
+    check(unsafe { libc::madvise(ptr as *mut c_void, size, MADV_DONTNEED) })
+}
+
+// uncommit releases the physical memory backing the given range. On failure, it returns errno.
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn uncommit(ptr: *mut u8, size: usize) -> Result<(), i32> {
+    use libc::{c_void, MADV_FREE};
+    check(unsafe { libc::madvise(ptr as *mut c_void, size, MADV_FREE) })
+}
+
+// protect changes the permissions of the given range to 'perms'. On failure, it returns errno.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn protect(ptr: *mut u8, size: usize, perms: i32) -> Result<(), i32> {
+    check(unsafe { libc::mprotect(ptr as *mut libc::c_void, size, perms) })
+}
+
+// The number of pages whose residency is queried with a single system call.
+const RESIDENCY_CHUNK: usize = 64;
+
+// residency sets each element of out to whether the corresponding page, starting at ptr, is
+// resident. On failure, it returns errno.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+fn residency(ptr: *mut u8, out: &mut [bool]) -> Result<(), i32> {
+    let pagesize = sysconf::page::pagesize();
+    let mut vec = [0u8; RESIDENCY_CHUNK];
+    for (i, chunk) in out.chunks_mut(RESIDENCY_CHUNK).enumerate() {
+        let addr = ptr as usize + i * RESIDENCY_CHUNK * pagesize;
+        check(unsafe {
+                  libc::mincore(addr as *mut libc::c_void,
+                                chunk.len() * pagesize,
+                                vec.as_mut_ptr() as _)
+              })?;
+        for (resident, v) in chunk.iter_mut().zip(vec.iter()) {
+            // The remaining bits are reserved (or, on macOS, report other page state).
+            *resident = v & 1 != 0;
+        }
+    }
+    Ok(())
+}
+
+// residency sets each element of out to whether the corresponding page, starting at ptr, is
+// resident. On failure, it returns the error from GetLastError.
+#[cfg(windows)]
+fn residency(ptr: *mut u8, out: &mut [bool]) -> Result<(), i32> {
+    use kernel32::{GetCurrentProcess, GetLastError, K32QueryWorkingSetEx};
+
+    // PSAPI_WORKING_SET_EX_INFORMATION; the low bit of the attributes is set if the page is
+    // valid (i.e., resident in the working set).
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct WorkingSetExInformation {
+        virtual_address: *mut winapi::c_void,
+        virtual_attributes: usize,
+    }
+
+    let pagesize = sysconf::page::pagesize();
+    let mut info = [WorkingSetExInformation {
+                        virtual_address: ptr::null_mut(),
+                        virtual_attributes: 0,
+                    }; RESIDENCY_CHUNK];
+    for (i, chunk) in out.chunks_mut(RESIDENCY_CHUNK).enumerate() {
+        for (j, entry) in info.iter_mut().take(chunk.len()).enumerate() {
+            let addr = ptr as usize + (i * RESIDENCY_CHUNK + j) * pagesize;
+            entry.virtual_address = addr as *mut winapi::c_void;
+        }
+        let size = chunk.len() * mem::size_of::<WorkingSetExInformation>();
+        let ret = unsafe {
+            K32QueryWorkingSetEx(GetCurrentProcess(),
+                                 info.as_mut_ptr() as *mut winapi::c_void,
+                                 size as winapi::DWORD)
+        };
+        if ret == 0 {
+            return Err(unsafe { GetLastError() } as i32);
+        }
+        for (resident, entry) in chunk.iter_mut().zip(info.iter()) {
+            *resident = entry.virtual_attributes & 1 != 0;
+        }
+    }
+    Ok(())
+}
+
+mod perms {
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub use self::unix::*;
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    pub type Perm = i32;
+    #[cfg(windows)]
+    pub use self::windows::*;
+    #[cfg(windows)]
+    pub type Perm = u32;
+
+    pub fn get_perm(read: bool, write: bool, exec: bool) -> Perm {
+        match (read, write, exec) {
+            (false, false, false) => PROT_NONE,
+            (true, false, false) => PROT_READ,
+            (false, true, false) => PROT_WRITE,
+            (false, false, true) => PROT_EXEC,
+            (true, true, false) => PROT_READ_WRITE,
+            (true, false, true) => PROT_READ_EXEC,
+            (false, true, true) => PROT_WRITE_EXEC,
+            (true, true, true) => PROT_READ_WRITE_EXEC,
+        }
+    }
+
+    // decode is the inverse of get_perm. On platforms where one permission implies another (see
+    // the notes below), the most permissive matching combination is returned.
+    pub fn decode(perm: Perm) -> (bool, bool, bool) {
+        for i in (0..8).rev() {
+            let (read, write, exec) = (i & 4 != 0, i & 2 != 0, i & 1 != 0);
+            if get_perm(read, write, exec) == perm {
+                return (read, write, exec);
+            }
+        }
+        unreachable!()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    mod unix {
+        // NOTE: On some platforms, libc::PROT_WRITE may imply libc::PROT_READ, and libc::PROT_READ
+        // may imply libc::PROT_EXEC.
+        extern crate libc;
+        pub const PROT_NONE: i32 = libc::PROT_NONE;
+        pub const PROT_READ: i32 = libc::PROT_READ;
+        pub const PROT_WRITE: i32 = libc::PROT_WRITE;
+        pub const PROT_EXEC: i32 = libc::PROT_EXEC;
+        pub const PROT_READ_WRITE: i32 = libc::PROT_READ | libc::PROT_WRITE;
+        pub const PROT_READ_EXEC: i32 = libc::PROT_READ | libc::PROT_EXEC;
+        pub const PROT_WRITE_EXEC: i32 = libc::PROT_WRITE | libc::PROT_EXEC;
+        pub const PROT_READ_WRITE_EXEC: i32 = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    }
+
+    #[cfg(windows)]
+    mod windows {
+        extern crate winapi;
+        use self::winapi::winnt;
+        pub const PROT_NONE: u32 = winnt::PAGE_NOACCESS;
+        pub const PROT_READ: u32 = winnt::PAGE_READONLY;
+        // windows doesn't have a write-only permission, so write implies read
+        pub const PROT_WRITE: u32 = winnt::PAGE_READWRITE;
+        pub const PROT_EXEC: u32 = winnt::PAGE_EXECUTE;
+        pub const PROT_READ_WRITE: u32 = winnt::PAGE_READWRITE;
+        pub const PROT_READ_EXEC: u32 = winnt::PAGE_EXECUTE_READ;
+        // windows doesn't have a write/exec permission, so write/exec implies read/write/exec
+        pub const PROT_WRITE_EXEC: u32 = winnt::PAGE_EXECUTE_READWRITE;
+        pub const PROT_READ_WRITE_EXEC: u32 = winnt::PAGE_EXECUTE_READWRITE;
+    }
+}
+
+#[cfg(all(test, feature = "bench", not(feature = "test-no-std")))]
+mod benches;
+
+#[cfg(test)]
+mod tests {
+    extern crate sysconf;
+    use sysconf::page::pagesize;
+    use super::*;
+    use super::perms::*;
+
+
+    #[cfg(not(feature = "test-no-std"))]
+    extern crate test;
+
+    // allow(unused) because these imports aren't used on windows
+    #[allow(unused)]
+    #[cfg(not(feature = "test-no-std"))]
+    use std::time::{Instant, Duration};
+    #[allow(unused)]
+    #[cfg(not(feature = "test-no-std"))]
+    use self::test::Bencher;
+
+    // NOTE: Technically mmap is allowed to return 0, but (according to our empirical experience)
+    // it only does this for very large map sizes (on Linux, at least 2^30 bytes). We never request
+    // maps that large, so it's OK to check for null here. Even if it spuriously fails in the
+    // future, it will queue us into the fact that our assumptions about when mmap returns null are
+    // wrong.
+    fn test_valid_map_address(ptr: *mut u8) {
+        assert!(ptr as usize > 0, "ptr: {:?}", ptr);
+        assert!(ptr as usize % pagesize() == 0, "ptr: {:?}", ptr);
+    }
+
+    // Test that the given range is readable and initialized to zero.
+    fn test_zero_filled(ptr: *mut u8, size: usize) {
+        for i in 0..size {
+            unsafe {
+                assert_eq!(*ptr.offset(i as isize), 0);
+            }
+        }
+    }
+
+    // Test that the given range is writable.
+    fn test_write(ptr: *mut u8, size: usize) {
+        for i in 0..size {
+            unsafe {
+                *ptr.offset(i as isize) = 1;
+            }
+        }
+    }
+
+    // Test that the given range is readable and writable, and that writes can be read back.
+    fn test_write_read(ptr: *mut u8, size: usize) {
+        for i in 0..size {
+            unsafe {
+                *ptr.offset(i as isize) = 1;
+            }
+        }
+        for i in 0..size {
+            unsafe {
+                assert_eq!(*ptr.offset(i as isize), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_untyped_obj_multi_page() {
+        // Check that:
+        // - Objects spanning multiple pages have the expected layout
+        // - Allocating them works, and the memory is zero-filled and writable
+        // - Deallocating them works
+        let mut alloc = MapAllocBuilder::default().obj_size(4 * pagesize()).build();
+        let layout = UntypedObjectAlloc::layout(&alloc);
+        assert_eq!(layout.size(), 4 * pagesize());
+        assert_eq!(layout.align(), pagesize());
+        unsafe {
+            let ptr = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+            test_valid_map_address(ptr);
+            test_zero_filled(ptr, 4 * pagesize());
+            test_write_read(ptr, 4 * pagesize());
+            UntypedObjectAlloc::dealloc(&mut alloc, ptr);
+        }
+    }
+
+    #[test]
+    fn test_untyped_obj_realloc() {
+        // Check that:
+        // - Growing an object preserves its contents, and the new memory is zero-filled and
+        //   writable
+        // - Shrinking an object preserves the contents of the remaining pages
+        // - The resized object can be freed with Alloc::dealloc
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+            test_write(ptr, pagesize());
+            let ptr = alloc.realloc_obj(ptr, pagesize(), 4 * pagesize()).unwrap();
+            test_valid_map_address(ptr);
+            for i in 0..pagesize() {
+                assert_eq!(*ptr.offset(i as isize), 1);
+            }
+            test_zero_filled(ptr.offset(pagesize() as isize), 3 * pagesize());
+            test_write_read(ptr, 4 * pagesize());
+            let ptr = alloc.realloc_obj(ptr, 4 * pagesize(), 2 * pagesize()).unwrap();
+            test_valid_map_address(ptr);
+            for i in 0..2 * pagesize() {
+                assert_eq!(*ptr.offset(i as isize), 1);
+            }
+            let layout = Layout::from_size_align(2 * pagesize(), 1).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    // Returns a layout of 'n' pages plus 'extra' bytes.
+    fn pages(n: usize, extra: usize) -> Layout {
+        Layout::from_size_align(n * pagesize() + extra, 1).unwrap()
+    }
+
+    #[test]
+    fn test_realloc_same_pages() {
+        // Check that resizing within the same number of pages returns the same pointer and
+        // preserves the contents.
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, pages(1, 1)).unwrap();
+            test_write(ptr, pagesize() + 1);
+            let new = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, pages(1, 1), pages(2, 0))
+                .unwrap();
+            assert_eq!(new, ptr);
+            let new = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, pages(2, 0), pages(1, 17))
+                .unwrap();
+            assert_eq!(new, ptr);
+            test_write_read(ptr, 2 * pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, pages(1, 17));
+        }
+    }
+
+    #[test]
+    fn test_realloc_shrink() {
+        // Check that:
+        // - Shrinking keeps the pointer and the contents of the remaining pages
+        // - The trailing pages are unmapped
+        // - The shrunk allocation can be freed with its new layout
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, pages(4, 0)).unwrap();
+            test_write(ptr, 4 * pagesize());
+            let new = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, pages(4, 0), pages(1, 1))
+                .unwrap();
+            assert_eq!(new, ptr);
+            test_write_read(ptr, 2 * pagesize());
+            #[cfg(target_os = "linux")]
+            {
+                assert!(is_mapped(ptr.offset(pagesize() as isize)));
+                assert!(!is_mapped(ptr.offset((2 * pagesize()) as isize)));
+                assert!(!is_mapped(ptr.offset((3 * pagesize()) as isize)));
+            }
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, pages(1, 1));
+        }
+    }
+
+    #[test]
+    fn test_realloc_grow() {
+        // Check that growing preserves the contents, and that the new memory is zero-filled and
+        // writable.
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, pages(1, 0)).unwrap();
+            test_write(ptr, pagesize());
+            let ptr = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, pages(1, 0), pages(4, 0))
+                .unwrap();
+            test_valid_map_address(ptr);
+            for i in 0..pagesize() {
+                assert_eq!(*ptr.offset(i as isize), 1);
+            }
+            test_zero_filled(ptr.offset(pagesize() as isize), 3 * pagesize());
+            test_write_read(ptr, 4 * pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, pages(4, 0));
+        }
+    }
+
+    #[test]
+    fn test_shrink_in_place() {
+        // Check that:
+        // - Shrinking in place unmaps the trailing pages
+        // - Shrinking to zero bytes, or "shrinking" to a larger size, fails
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, pages(3, 0)).unwrap();
+            assert!(alloc.shrink_in_place(ptr, pages(3, 0), pages(0, 0)).is_err());
+            assert!(alloc.shrink_in_place(ptr, pages(3, 0), pages(4, 0)).is_err());
+            alloc.shrink_in_place(ptr, pages(3, 0), pages(2, 1)).unwrap();
+            alloc.shrink_in_place(ptr, pages(3, 0), pages(1, 0)).unwrap();
+            test_write_read(ptr, pagesize());
+            #[cfg(target_os = "linux")]
+            {
+                assert!(!is_mapped(ptr.offset(pagesize() as isize)));
+            }
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, pages(1, 0));
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_grow_in_place() {
+        // Check that:
+        // - Growing within the same number of pages always succeeds
+        // - Growing into unmapped address space succeeds without moving, and the new memory is
+        //   zero-filled and writable
+        // - Growing into an existing mapping fails and leaves both mappings intact
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, pages(4, 0)).unwrap();
+            alloc.grow_in_place(ptr, pages(3, 1), pages(4, 0)).unwrap();
+            test_write(ptr, pagesize());
+            alloc.shrink_in_place(ptr, pages(4, 0), pages(1, 0)).unwrap();
+            alloc.grow_in_place(ptr, pages(1, 0), pages(3, 0)).unwrap();
+            assert_eq!(*ptr, 1);
+            test_zero_filled(ptr.offset(pagesize() as isize), 2 * pagesize());
+            test_write_read(ptr, 3 * pagesize());
+
+            // The fourth page is still unmapped after the shrink above, so we can put something
+            // else there.
+            let next = ptr.offset((3 * pagesize()) as isize);
+            let other = mmap_at(next, pagesize(), PROT_READ_WRITE, None, 0).unwrap();
+            if other == next {
+                assert!(alloc.grow_in_place(ptr, pages(3, 0), pages(4, 0)).is_err());
+                test_write_read(ptr, 3 * pagesize());
+                test_write_read(next, pagesize());
+            }
+            munmap(other, pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, pages(3, 0));
+        }
+    }
+
+    #[test]
+    fn test_try_build() {
+        // Check that invalid configurations are reported as errors by try_build.
+        let res = MapAllocBuilder::default().obj_size(pagesize() + 1).try_build();
+        match res {
+            Err(BuildError::ObjSizeNotPageMultiple { obj_size, pagesize: p }) => {
+                assert_eq!(obj_size, pagesize() + 1);
+                assert_eq!(p, pagesize());
+            }
+            _ => panic!("expected ObjSizeNotPageMultiple"),
+        }
+        assert!(MapAllocBuilder::default().obj_size(2 * pagesize()).try_build().is_ok());
+
+        // no platform supports huge pages that are smaller than normal pages
+        let huge = pagesize() / 2;
+        match MapAllocBuilder::default().huge_pagesize(huge).try_build() {
+            Err(BuildError::UnsupportedHugePage(size)) => assert_eq!(size, huge),
+            _ => panic!("expected UnsupportedHugePage"),
+        }
+    }
+
+    #[test]
+    fn test_pagesize_not_power_of_two() {
+        let bad = 3 * pagesize();
+        match MapAllocBuilder::default().pagesize(bad).try_build() {
+            Err(BuildError::PageSizeNotPowerOfTwo(size)) => assert_eq!(size, bad),
+            _ => panic!("expected PageSizeNotPowerOfTwo"),
+        }
+
+        // a power-of-two multiple of the system page size is fine
+        let mut alloc = MapAllocBuilder::default().pagesize(2 * pagesize()).build();
+        assert_eq!(<MapAlloc as UntypedObjectAlloc>::layout(&alloc).size(), 2 * pagesize());
+        unsafe {
+            let layout = Layout::from_size_align(1, 1).unwrap();
+            let Excess(ptr, size) = <MapAlloc as Alloc>::alloc_excess(&mut alloc, layout).unwrap();
+            assert_eq!(size, 2 * pagesize());
+            test_valid_map_address(ptr);
+            test_write(ptr, size);
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "page size is not a power of two")]
+    fn test_pagesize_not_power_of_two_panics() {
+        MapAllocBuilder::default().pagesize(3 * pagesize()).build();
+    }
+
+    #[test]
+    fn test_huge_pages_optional() {
+        // Check that an unsupported huge page size falls back to normal pages when huge pages are
+        // optional.
+        let mut alloc = MapAllocBuilder::default()
+            .huge_pagesize(pagesize() / 2)
+            .huge_pages_optional()
+            .try_build()
+            .unwrap();
+        assert_eq!(<MapAlloc as UntypedObjectAlloc>::layout(&alloc).size(), pagesize());
+        unsafe {
+            let ptr = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+            test_valid_map_address(ptr);
+            test_write(ptr, pagesize());
+            UntypedObjectAlloc::dealloc(&mut alloc, ptr);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_supported_huge_page_sizes() {
+        let sizes = supported_huge_page_sizes();
+        for (i, &size) in sizes.iter().enumerate() {
+            assert!(huge_pagesize_supported(size), "{}", size);
+            #[cfg(target_os = "linux")]
+            assert!(sysconf::page::hugepage_supported(size), "{}", size);
+            if i > 0 {
+                assert!(sizes[i - 1] < size);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(default) = sysconf::page::default_hugepage() {
+                assert!(sizes.contains(&default), "{} not in {:?}", default, sizes);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_available_huge_pages() {
+        // This must not panic whether or not sysfs is present or any huge pages are reserved.
+        for size in supported_huge_page_sizes() {
+            let available = available_huge_pages(size);
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(available) = available {
+                    let total = read_hugepages_count(size, "nr_hugepages").unwrap() +
+                                read_hugepages_count(size, "nr_overcommit_hugepages")
+                                    .unwrap_or(0);
+                    assert!(available <= total, "{} > {}", available, total);
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            assert_eq!(available, None);
+        }
+        assert_eq!(available_huge_pages(3 * pagesize()), None);
+    }
+
+    #[cfg(not(feature = "test-no-std"))]
+    #[test]
+    fn test_debug() {
+        let builder = MapAllocBuilder::default().exec().obj_size(4 * pagesize());
+        let s = format!("{:?}", builder);
+        assert!(s.starts_with("MapAllocBuilder {"), "{}", s);
+        assert!(s.contains("read: true, write: true, exec: true"), "{}", s);
+        assert!(s.contains(&format!("pagesize: {}", pagesize())), "{}", s);
+        assert!(s.contains("huge_pagesize: None"), "{}", s);
+        assert!(s.contains(&format!("obj_size: Some({})", 4 * pagesize())), "{}", s);
+
+        let alloc = MapAllocBuilder::default().no_write().build();
+        let s = format!("{:?}", alloc);
+        assert!(s.starts_with("MapAlloc {"), "{}", s);
+        assert!(s.contains("read: true, write: false, exec: false"), "{}", s);
+        assert!(s.contains(&format!("pagesize: {}", pagesize())), "{}", s);
+        assert!(s.contains("huge_pagesize: None"), "{}", s);
+        assert!(s.contains(&format!("obj_size: {}", pagesize())), "{}", s);
+    }
+
+    #[test]
+    fn test_perms_decode() {
+        for &(read, write, exec) in &[(false, false, false),
+                                      (true, false, false),
+                                      (true, true, false),
+                                      (true, false, true),
+                                      (true, true, true)] {
+            assert_eq!(decode(get_perm(read, write, exec)), (read, write, exec));
+        }
+    }
+
+    #[cfg(all(feature = "std", target_os = "linux"))]
+    #[test]
+    fn test_parse_hugepages_dir() {
+        assert_eq!(parse_hugepages_dir("hugepages-2048kB"), Some(2 << 20));
+        assert_eq!(parse_hugepages_dir("hugepages-1048576kB"), Some(1 << 30));
+        for name in &["hugepages-kB", "hugepages-2048", "2048kB", "hugepages-xkB", "hugepageskB"] {
+            assert_eq!(parse_hugepages_dir(name), None);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_from_env() {
+        // Check that:
+        // - Valid variables are applied
+        // - Invalid variables are ignored by from_env and reported by try_from_env
+        // - Unset variables leave the defaults in place
+        // Everything is done in one test since the environment is shared by all threads.
+        use std::env;
+        let vars = ["MMAP_ALLOC_PAGESIZE",
+                    "MMAP_ALLOC_HUGE_PAGESIZE",
+                    "MMAP_ALLOC_HUGE_PAGES_OPTIONAL",
+                    "MMAP_ALLOC_PERMS",
+                    "MMAP_ALLOC_ACCESS_PATTERN"];
+        env::set_var("MMAP_ALLOC_PAGESIZE", (2 * pagesize()).to_string());
+        env::set_var("MMAP_ALLOC_HUGE_PAGES_OPTIONAL", "1");
+        env::set_var("MMAP_ALLOC_PERMS", "rx");
+        env::set_var("MMAP_ALLOC_ACCESS_PATTERN", "random");
+        let builder = MapAllocBuilder::try_from_env().unwrap();
+        assert_eq!(builder.pagesize, 2 * pagesize());
+        assert_eq!(builder.huge_pagesize, None);
+        assert!(builder.huge_pages_optional);
+        assert!(builder.read && !builder.write && builder.exec);
+        assert_eq!(builder.access_pattern, AccessPattern::Random);
+        let alloc = builder.build();
+        assert_eq!(alloc.pagesize, 2 * pagesize());
+
+        env::set_var("MMAP_ALLOC_PAGESIZE", "3");
+        env::set_var("MMAP_ALLOC_PERMS", "rwz");
+        let builder = MapAllocBuilder::from_env();
+        assert_eq!(builder.pagesize, pagesize());
+        assert!(builder.read && builder.write && !builder.exec);
+        assert_eq!(builder.access_pattern, AccessPattern::Random);
+        match MapAllocBuilder::try_from_env() {
+            Err(EnvError { var: "MMAP_ALLOC_PAGESIZE", ref value }) if value == "3" => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("invalid page size accepted"),
+        }
+
+        // no platform supports huge pages smaller than normal pages
+        env::remove_var("MMAP_ALLOC_PAGESIZE");
+        env::set_var("MMAP_ALLOC_PERMS", "rw");
+        env::set_var("MMAP_ALLOC_HUGE_PAGESIZE", (pagesize() / 2).to_string());
+        assert_eq!(MapAllocBuilder::from_env().huge_pagesize, None);
+        match MapAllocBuilder::try_from_env() {
+            Err(EnvError { var: "MMAP_ALLOC_HUGE_PAGESIZE", .. }) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("unsupported huge page size accepted"),
+        }
+
+        for var in &vars {
+            env::remove_var(var);
+        }
+        let builder = MapAllocBuilder::from_env();
+        assert_eq!(builder.pagesize, pagesize());
+        assert!(!builder.huge_pages_optional);
+        assert_eq!(builder.access_pattern, AccessPattern::Normal);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_untyped_obj_size_not_page_multiple() {
+        MapAllocBuilder::default().obj_size(pagesize() + 1).build();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_untyped_obj_huge_page() {
+        // Check that when huge pages are configured, each object is a single huge page, and the
+        // layout's alignment is the huge page size.
+        let huge = match sysconf::page::default_hugepage() {
+            Some(huge) => huge,
+            None => return,
+        };
+        let mut alloc = MapAllocBuilder::default().huge_pagesize(huge).build();
+        let layout = UntypedObjectAlloc::layout(&alloc);
+        assert_eq!(layout.size(), huge);
+        assert_eq!(layout.align(), huge);
+        unsafe {
+            // If no huge pages are reserved by the system, mapping one fails with ENOMEM.
+            if let Ok(ptr) = UntypedObjectAlloc::alloc(&mut alloc) {
+                assert_eq!(ptr as usize % huge, 0);
+                test_write_read(ptr, huge);
+                UntypedObjectAlloc::dealloc(&mut alloc, ptr);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_huge_pages_2mb() {
+        // Check that 2MB huge pages are selected with the right size (and so the right MAP_HUGE_*
+        // flag), and that allocations are aligned to them.
+        let huge = HugePageSize::Size2MB.bytes();
+        let mut alloc = match MapAllocBuilder::default()
+                  .huge_pages(HugePageSize::Size2MB)
+                  .try_build() {
+            Ok(alloc) => alloc,
+            Err(BuildError::UnsupportedHugePage(size)) => {
+                assert_eq!(size, huge);
+                assert!(!huge_pagesize_supported(huge));
+                return;
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+        };
+        assert_eq!(alloc.huge_pagesize, Some(huge));
+        let layout = Layout::from_size_align(1, 1).unwrap();
+        unsafe {
+            // If no huge pages are reserved by the system, mapping one fails with ENOMEM.
+            if let Ok(Excess(ptr, size)) = <MapAlloc as Alloc>::alloc_excess(&mut alloc,
+                                                                             layout.clone()) {
+                assert_eq!(size, huge);
+                assert_eq!(ptr as usize % huge, 0);
+                test_write_read(ptr, huge);
+                <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_huge_page_commit_uncommit() {
+        // Check that commit and uncommit operate on whole 2MB huge pages, even when given sizes
+        // that cover only part of one.
+        let huge = HugePageSize::Size2MB.bytes();
+        let alloc = match MapAllocBuilder::default()
+                  .huge_pages(HugePageSize::Size2MB)
+                  .try_build() {
+            Ok(alloc) => alloc,
+            Err(_) => return,
+        };
+        assert!(alloc.is_huge_page_backed());
+        assert!(!MapAllocBuilder::default().build().is_huge_page_backed());
+        let layout = Layout::from_size_align(2 * huge, huge).unwrap();
+        unsafe {
+            // If no huge pages are reserved by the system, mapping one fails with ENOMEM.
+            let ptr = match <&MapAlloc as Alloc>::alloc(&mut &alloc, layout.clone()) {
+                Ok(ptr) => ptr,
+                Err(_) => return,
+            };
+            assert_eq!(ptr as usize % huge, 0);
+            let second = ptr.offset(huge as isize);
+            alloc.commit(ptr, Layout::from_size_align(huge + 1, 1).unwrap());
+            ptr::write_bytes(ptr, 0xFF, 2 * huge);
+            // Uncommitting part of the first huge page uncommits all of it, but not the second.
+            alloc.uncommit(ptr, Layout::from_size_align(huge / 2, 1).unwrap());
+            test_zero_filled(ptr, huge);
+            assert_eq!(*second, 0xFF);
+            assert_eq!(*second.offset(huge as isize - 1), 0xFF);
+            // Uncommitting the rest of the region leaves the mapping intact.
+            alloc.uncommit(second, Layout::from_size_align(1, 1).unwrap());
+            test_zero_filled(ptr, 2 * huge);
+            test_write_read(ptr, 2 * huge);
+            <&MapAlloc as Alloc>::dealloc(&mut &alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_zero_on_commit() {
+        // Memory released with MADV_FREE may keep its contents until the kernel reclaims it, but
+        // committing it with zero_on_commit must make it read back as zeros regardless.
+        const MADV_FREE: libc::c_int = 8;
+        let size = 16 * pagesize();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        let alloc = MapAllocBuilder::default().zero_on_commit().build();
+        unsafe {
+            let ptr = <&MapAlloc as Alloc>::alloc(&mut &alloc, layout.clone()).unwrap();
+            ptr::write_bytes(ptr, 0xFF, size);
+            // MADV_FREE requires Linux 4.5; on older kernels, this fails and leaves the memory as
+            // it is, which zero_on_commit must handle all the same.
+            libc::madvise(ptr as *mut libc::c_void, size, MADV_FREE);
+            alloc.commit(ptr, layout.clone());
+            test_zero_filled(ptr, size);
+            <&MapAlloc as Alloc>::dealloc(&mut &alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_huge_pages_1gb() {
+        // Check that 1GB huge pages either work or fail cleanly, either when building the
+        // allocator (if the size isn't supported) or when allocating (if none are reserved).
+        let huge = HugePageSize::Size1GB.bytes();
+        let mut alloc = match MapAllocBuilder::default()
+                  .huge_pages(HugePageSize::Size1GB)
+                  .try_build() {
+            Ok(alloc) => alloc,
+            Err(BuildError::UnsupportedHugePage(size)) => {
+                assert_eq!(size, huge);
+                return;
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+        };
+        let layout = Layout::from_size_align(1, 1).unwrap();
+        unsafe {
+            match <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()) {
+                Ok(ptr) => {
+                    assert_eq!(ptr as usize % huge, 0);
+                    test_write(ptr, 1);
+                    <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+                }
+                Err(AllocErr::Exhausted { .. }) => {}
+                Err(err) => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_untyped_obj_mapped_size() {
+        // Check that an object spanning several huge pages is mapped and unmapped in full.
+        let huge = match sysconf::page::default_hugepage() {
+            Some(huge) => huge,
+            None => return,
+        };
+        let mut alloc = MapAllocBuilder::default()
+            .huge_pagesize(huge)
+            .obj_size(2 * huge)
+            .build();
+        let size = alloc.obj_mapped_size();
+        assert_eq!(size, 2 * huge);
+        assert!(size >= UntypedObjectAlloc::layout(&alloc).size());
+        unsafe {
+            // If no huge pages are reserved by the system, mapping one fails with ENOMEM.
+            if let Ok(ptr) = UntypedObjectAlloc::alloc(&mut alloc) {
+                let last = ptr.offset(size as isize - 1);
+                assert!(is_mapped(ptr));
+                assert!(is_mapped(last));
+                UntypedObjectAlloc::dealloc(&mut alloc, ptr);
+                assert!(!is_mapped(ptr));
+                assert!(!is_mapped(last));
+            }
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        // Check that:
+        // - Mapping a single page works
+        // - The returned pointer is non-null
+        // - The returned pointer is page-aligned
+        // - The page is zero-filled
+        // - Unmapping it after it's already been unmapped is OK (except on windows).
+        let mut ptr = mmap(pagesize(), PROT_READ_WRITE, None).unwrap();
+        test_valid_map_address(ptr);
+        test_zero_filled(ptr, pagesize());
+        munmap(ptr, pagesize());
+        #[cfg(not(windows))]
+        munmap(ptr, pagesize());
+
+        // Check that:
+        // - Mapping multiple pages work
+        // - The returned pointer is non-null
+        // - The returned pointer is page-aligned
+        // - The pages are zero-filled
+        // - Unmapping it after it's already been unmapped is OK (except on windows).
+        ptr = mmap(16 * pagesize(), PROT_READ_WRITE, None).unwrap();
+        test_valid_map_address(ptr);
+        test_zero_filled(ptr, 16 * pagesize());
+        munmap(ptr, 16 * pagesize());
+        #[cfg(not(windows))]
+        munmap(ptr, 16 * pagesize());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_alloc_at() {
+        // Check that:
+        // - Mapping at a previously-unmapped, page-aligned address returns that address
+        // - The memory is zero-filled and writable
+        // - Mapping over an existing mapping replaces it
+        let alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(4 * pagesize(), 1).unwrap();
+        let addr = mmap(4 * pagesize(), PROT_READ_WRITE, None).unwrap();
+        munmap(addr, 4 * pagesize());
+        unsafe {
+            let ptr = alloc.alloc_at(addr, layout.clone()).unwrap();
+            assert_eq!(ptr, addr);
+            test_zero_filled(ptr, 4 * pagesize());
+            test_write_read(ptr, 4 * pagesize());
+            // MAP_FIXED replaces the existing mapping with fresh zero-filled pages
+            let ptr = alloc.alloc_at(addr, layout.clone()).unwrap();
+            assert_eq!(ptr, addr);
+            test_zero_filled(ptr, 4 * pagesize());
+            munmap(ptr, 4 * pagesize());
+        }
+    }
+
+    #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+    #[test]
+    fn test_superpage() {
+        // Check that:
+        // - 2MB superpages can be mapped
+        // - The memory is aligned to 2MB, zero-filled, and writable
+        let size = SUPERPAGE_SIZE_2MB;
+        let alloc = MapAllocBuilder::default().huge_pagesize(size).build();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let ptr = <&MapAlloc as Alloc>::alloc(&mut &alloc, layout.clone()).unwrap();
+            assert_eq!(ptr as usize % size, 0, "ptr: {:?}", ptr);
+            test_zero_filled(ptr, size);
+            test_write_read(ptr, size);
+            <&MapAlloc as Alloc>::dealloc(&mut &alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn test_map_very_large() {
+        // Check that a very large map either succeeds with a valid (non-null) address or fails
+        // cleanly with Exhausted, rather than looping when mmap returns null.
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(1 << 40, 1).unwrap();
+        unsafe {
+            match <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()) {
+                Ok(ptr) => {
+                    test_valid_map_address(ptr);
+                    <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+                }
+                Err(AllocErr::Exhausted { .. }) => {}
+                Err(err) => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn test_obj_alloc_exhausted() {
+        // Check that a failed object allocation is reported as Exhausted by using an object size
+        // too large to map. On 64-bit Linux, overly large maps fail with ENOMEM.
+        let mut alloc = MapAllocBuilder::default().obj_size(1 << 62).build();
+        unsafe {
+            assert!(UntypedObjectAlloc::alloc(&mut alloc).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_memfd() {
+        // Check that:
+        // - Memory mapped from a memfd is zero-filled and writable
+        // - Each allocation maps its own range of the memfd, in order
+        // - The memfd is extended for allocations past its initial size
+        // - Growing an allocation moves it to a new range, keeping its contents
+        // - alloc_memfd returns the offset of its range, through which another mapping of the
+        //   memfd (as another process would make) sees the same memory
+        // - Dropping the allocator leaves the memfd open
+        let size = 2 * pagesize();
+        let mut alloc = MapAllocBuilder::default()
+            .memfd("mmap-alloc-test", size as u64)
+            .build();
+        let fd = alloc.as_raw_fd().unwrap();
+        let file_size = || unsafe {
+            let mut stat: ::libc::stat = mem::zeroed();
+            assert_eq!(::libc::fstat(fd, &mut stat), 0);
+            stat.st_size as usize
+        };
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let a = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_valid_map_address(a);
+            test_zero_filled(a, size);
+            test_write(a, size);
+            assert_eq!(file_size(), size);
+            let b = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            assert_ne!(a, b);
+            test_zero_filled(b, size);
+            assert_eq!(file_size(), 2 * size);
+            // a's writes went to the start of the memfd
+            let mut byte = 0u8;
+            let n = ::libc::pread(fd, &mut byte as *mut u8 as *mut ::libc::c_void, 1, 0);
+            assert_eq!(n, 1);
+            assert_eq!(byte, 1);
+
+            let new_layout = Layout::from_size_align(2 * size, 1).unwrap();
+            assert!(<MapAlloc as Alloc>::grow_in_place(&mut alloc,
+                                                      a,
+                                                      layout.clone(),
+                                                      new_layout.clone())
+                            .is_err());
+            let a = <MapAlloc as Alloc>::realloc(&mut alloc, a, layout.clone(), new_layout.clone())
+                .unwrap();
+            assert_eq!(file_size(), 4 * size);
+            for i in 0..size {
+                assert_eq!(*a.offset(i as isize), 1);
+            }
+            test_zero_filled(b, size);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, a, new_layout);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, b, layout.clone());
+
+            let (c, offset) = alloc.alloc_memfd(size).unwrap();
+            assert_eq!(offset, 4 * size as u64);
+            test_zero_filled(c, size);
+            test_write(c, size);
+            assert_eq!(file_size(), 5 * size);
+            let peer = ::libc::mmap(ptr::null_mut(),
+                                    size,
+                                    ::libc::PROT_READ,
+                                    ::libc::MAP_SHARED,
+                                    fd,
+                                    offset as ::libc::off_t);
+            assert_ne!(peer, ::libc::MAP_FAILED);
+            for i in 0..size {
+                assert_eq!(*(peer as *mut u8).offset(i as isize), 1);
+            }
+            assert_eq!(::libc::munmap(peer, size), 0);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, c, layout.clone());
+        }
+        assert!(MapAlloc::default().alloc_memfd(size).is_err());
+
+        drop(alloc);
+        assert_eq!(file_size(), 5 * size);
+        assert_eq!(unsafe { ::libc::close(fd) }, 0);
+
+        let name = [b'a'; MEMFD_NAME_MAX + 1];
+        let res = MapAllocBuilder::default()
+            .memfd(core::str::from_utf8(&name).unwrap(), 1)
+            .try_build();
+        assert!(res.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_alloc_zeroed_memfd() {
+        // Check that alloc_zeroed returns zeroed memory even when the memfd it maps has nonzero
+        // contents, written to the file directly rather than through a mapping.
+        let size = pagesize();
+        let mut alloc = MapAllocBuilder::default()
+            .memfd("mmap-alloc-test", 3 * size as u64)
+            .build();
+        let fd = alloc.as_raw_fd().unwrap();
+        let buf = [0xAB_u8; 256];
+        let mut off = 0;
+        while off < 3 * size {
+            let n = unsafe {
+                ::libc::pwrite(fd,
+                               buf.as_ptr() as *const ::libc::c_void,
+                               buf.len(),
+                               off as ::libc::off_t)
+            };
+            assert_eq!(n, buf.len() as isize);
+            off += buf.len();
+        }
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            assert_eq!(*ptr, 0xAB);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+
+            let ptr = <MapAlloc as Alloc>::alloc_zeroed(&mut alloc, layout.clone()).unwrap();
+            test_zero_filled(ptr, size);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+
+            let slice = alloc.alloc_slice(size).unwrap();
+            assert!(slice.iter().all(|&b| b == 0));
+            alloc.dealloc_slice(slice);
+            ::libc::close(fd);
+        }
+    }
+
+    #[cfg(all(debug_assertions, target_os = "linux"))]
+    #[test]
+    #[should_panic(expected = "ptr not aligned to page size")]
+    fn test_mark_dont_fork_unaligned() {
+        // Check that the debug alignment checks fire, with their static message.
+        MapAlloc::default().mark_dont_fork((pagesize() / 2) as *mut u8, pagesize());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "dealloc: ptr not aligned to page size")]
+    fn test_dealloc_unaligned() {
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr.offset(1), layout);
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "dealloc: ptr not aligned to page size")]
+    fn test_untyped_obj_dealloc_unaligned() {
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+            UntypedObjectAlloc::dealloc(&mut alloc, ptr.offset(8));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[should_panic(expected = "memory mapping failed: invalid argument")]
+    fn test_munmap_zero_length_panics() {
+        let ptr = mmap(pagesize(), PROT_READ_WRITE, None).unwrap();
+        munmap(ptr, 0);
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn test_mmap_oversized() {
+        // More than the 47-bit user address space, so this fails with ENOMEM.
+        assert_eq!(mmap(1 << 62, PROT_READ_WRITE, None), None);
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(1 << 62, 1).unwrap();
+        unsafe {
+            assert!(<MapAlloc as Alloc>::alloc(&mut alloc, layout).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_errno() {
+        // Check that errors report the errno of the failed call.
+        let ptr = mmap(pagesize(), PROT_READ_WRITE, None).unwrap();
+        munmap(ptr, pagesize());
+        assert_eq!(uncommit(ptr, pagesize()), Err(::libc::ENOMEM));
+        assert_eq!(protect(ptr, pagesize(), PROT_READ), Err(::libc::ENOMEM));
+        let mut out = [false; 1];
+        assert_eq!(residency(ptr, &mut out), Err(::libc::ENOMEM));
+        assert_eq!(check(0), Ok(()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dont_fork() {
+        // Check that memory from a dont_fork allocator is usable in the parent, but is not mapped
+        // in a forked child, so accessing it there faults.
+        let mut alloc = MapAllocBuilder::default().dont_fork().build();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_write_read(ptr, pagesize());
+            match ::libc::fork() {
+                -1 => panic!("fork failed"),
+                0 => {
+                    // child: this access should kill us with SIGSEGV
+                    ptr::read_volatile(ptr);
+                    ::libc::_exit(0);
+                }
+                child => {
+                    let mut status = 0;
+                    assert_eq!(::libc::waitpid(child, &mut status, 0), child);
+                    assert!(::libc::WIFSIGNALED(status), "child exited normally");
+                    assert_eq!(::libc::WTERMSIG(status), ::libc::SIGSEGV);
+                }
+            }
+            // the parent's mapping is unaffected
+            test_write_read(ptr, pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_alloc_reserve() {
+        // Check that only the committed prefix of a reservation is accessible, that more of it
+        // can be committed later, and that alloc_reserve requires reserve_only.
+        let ps = pagesize();
+        let alloc = MapAllocBuilder::default().reserve_only().build();
+        let Excess(ptr, size) = alloc.alloc_reserve(64 * ps, 4 * ps).unwrap();
+        assert_eq!(size, 64 * ps);
+        unsafe {
+            test_zero_filled(ptr, 4 * ps);
+            test_write_read(ptr, 4 * ps);
+            let beyond = ptr.offset(4 * ps as isize);
+            match ::libc::fork() {
+                -1 => panic!("fork failed"),
+                0 => {
+                    // child: this access should kill us with SIGSEGV
+                    ptr::read_volatile(beyond);
+                    ::libc::_exit(0);
+                }
+                child => {
+                    let mut status = 0;
+                    assert_eq!(::libc::waitpid(child, &mut status, 0), child);
+                    assert!(::libc::WIFSIGNALED(status), "child exited normally");
+                    assert_eq!(::libc::WTERMSIG(status), ::libc::SIGSEGV);
+                }
+            }
+            alloc.commit(beyond, Layout::from_size_align(4 * ps, 1).unwrap());
+            test_zero_filled(beyond, 4 * ps);
+            test_write_read(beyond, 4 * ps);
+            <&MapAlloc as Alloc>::dealloc(&mut &alloc,
+                                          ptr,
+                                          Layout::from_size_align(size, 1).unwrap());
+        }
+
+        assert!(alloc.alloc_reserve(ps, 2 * ps).is_err());
+        assert!(MapAlloc::default().alloc_reserve(64 * ps, 4 * ps).is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    #[test]
+    fn test_residency() {
+        // Check that:
+        // - Freshly mapped pages are not resident
+        // - Committed pages are resident, and other pages are not
+        // - A short buffer only receives the residency of the first pages
+        // - (on Linux) Uncommitted pages are no longer resident
+        let ps = pagesize();
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(8 * ps, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            let mut out = [true; 8];
+            assert_eq!(alloc.residency_into(ptr, layout.clone(), &mut out), Ok(8));
+            assert_eq!(out, [false; 8]);
+
+            let second_half = ptr.offset((4 * ps) as isize);
+            alloc.commit(second_half, Layout::from_size_align(4 * ps, 1).unwrap());
+            assert_eq!(alloc.residency_into(ptr, layout.clone(), &mut out), Ok(8));
+            assert_eq!(out, [false, false, false, false, true, true, true, true]);
+
+            #[cfg(feature = "alloc")]
+            assert_eq!(alloc.residency(ptr, layout.clone()).unwrap(), out.to_vec());
+
+            let mut short = [false; 2];
+            let half = Layout::from_size_align(4 * ps, 1).unwrap();
+            assert_eq!(alloc.residency_into(second_half, half, &mut short), Ok(4));
+            assert_eq!(short, [true; 2]);
+
+            #[cfg(target_os = "linux")]
+            {
+                alloc.uncommit(ptr, layout.clone());
+                assert_eq!(alloc.residency_into(ptr, layout.clone(), &mut out), Ok(8));
+                assert_eq!(out, [false; 8]);
+            }
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_residency_unmapped() {
+        let ps = pagesize();
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(ps, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+            let mut out = [false; 1];
+            assert_eq!(alloc.residency_into(ptr, layout, &mut out), Err(::libc::ENOMEM));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_uncommit_range() {
+        // Check that:
+        // - Unaligned ranges are rejected without uncommitting anything
+        // - Uncommitted pages (including coalesced adjacent ranges) are zero-filled on next touch
+        // - Other pages are left untouched
+        let ps = pagesize();
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(8 * ps, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_write(ptr, 8 * ps);
+            let page = |i: usize| ptr.offset((i * ps) as isize);
+
+            let unaligned = [(page(0), ps), (ptr.offset(1), ps)];
+            assert_eq!(alloc.uncommit_range(&unaligned), Err(UncommitError::Unaligned(1)));
+            assert_eq!(*page(0), 1);
+
+            let ranges = [(page(0), 2 * ps), (page(2), ps), (page(5), 2 * ps)];
+            assert_eq!(alloc.uncommit_range(&ranges), Ok(()));
+            for i in 0..8 {
+                let expected = if i < 3 || i == 5 || i == 6 { 0 } else { 1 };
+                for j in 0..ps {
+                    assert_eq!(*page(i).offset(j as isize), expected, "page {}", i);
+                }
+            }
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "tvos"))]
+    #[test]
+    fn test_external_mapping() {
+        // Check that commit and uncommit work on memory that was mapped without a MapAlloc.
+        use libc::{c_void, MAP_ANON, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+        let ps = pagesize();
+        let alloc = MapAlloc::default();
+        unsafe {
+            let ptr = ::libc::mmap(ptr::null_mut(),
+                                   4 * ps,
+                                   PROT_READ | PROT_WRITE,
+                                   MAP_ANON | MAP_PRIVATE,
+                                   -1,
+                                   0);
+            assert_ne!(ptr, MAP_FAILED);
+            let ptr = ptr as *mut u8;
+            let layout = Layout::from_size_align(4 * ps, 1).unwrap();
+            alloc.commit(ptr, layout.clone());
+            test_write(ptr, 4 * ps);
+
+            alloc.uncommit(ptr.offset(ps as isize),
+                           Layout::from_size_align(ps, 1).unwrap());
+            assert_eq!(alloc.uncommit_range(&[(ptr.offset(3 * ps as isize), ps)]), Ok(()));
+            #[cfg(target_os = "linux")]
+            {
+                // Only Linux guarantees that uncommitted memory is zero-filled.
+                test_zero_filled(ptr.offset(ps as isize), ps);
+                test_zero_filled(ptr.offset(3 * ps as isize), ps);
+            }
+            assert_eq!(*ptr, 1);
+            assert_eq!(*ptr.offset(2 * ps as isize), 1);
+            assert_eq!(::libc::munmap(ptr as *mut c_void, 4 * ps), 0);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_growsdown() {
+        // Check that:
+        // - alloc_stack returns the top of the mapping
+        // - Writing to progressively lower addresses below a growsdown mapping extends it
+        // - Deallocating the mapping unmaps the pages it grew to include
+        const GROWTH: usize = 16;
+        let alloc = MapAllocBuilder::default().growsdown().build();
+        unsafe {
+            let top = alloc.alloc_stack(pagesize()).unwrap();
+            assert_eq!(top as usize % pagesize(), 0);
+            let ptr = top.offset(-(pagesize() as isize));
+            test_valid_map_address(ptr);
+            test_write(ptr, pagesize());
+            for i in 1..GROWTH + 1 {
+                let page = ptr.offset(-((i * pagesize()) as isize));
+                test_write(page, pagesize());
+                assert!(is_mapped(page));
+            }
+            alloc.dealloc_stack(top, pagesize());
+            assert!(!is_mapped(ptr));
+            assert!(!is_mapped(ptr.offset(-((GROWTH * pagesize()) as isize))));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_access_pattern() {
+        // Check that allocations succeed and are usable with each access pattern.
+        let patterns = [AccessPattern::Normal,
+                        AccessPattern::Random,
+                        AccessPattern::Sequential,
+                        AccessPattern::WillNeed,
+                        AccessPattern::DontNeed];
+        let size = 16 * pagesize();
+        for &pattern in &patterns {
+            let mut alloc = MapAllocBuilder::default().access_pattern(pattern).build();
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            unsafe {
+                let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+                test_valid_map_address(ptr);
+                test_zero_filled(ptr, size);
+                test_write_read(ptr, size);
+                <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_access_pattern_realloc() {
+        // Check that resizing an allocation does not reapply MADV_DONTNEED to it, which would
+        // discard its contents.
+        let mut alloc = MapAllocBuilder::default().access_pattern(AccessPattern::DontNeed).build();
+        let size = 4 * pagesize();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            ptr::write_bytes(ptr, 0xAB, size);
+            let new_layout = Layout::from_size_align(4 * size, 1).unwrap();
+            let new = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, layout, new_layout.clone())
+                .unwrap();
+            for i in 0..size {
+                assert_eq!(*new.offset(i as isize), 0xAB);
+            }
+            test_zero_filled(new.offset(size as isize), 3 * size);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, new, new_layout);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn test_no_reserve() {
+        // Check that a region larger than we would want to commit can be mapped, and that the
+        // pages we actually touch are usable.
+        const SIZE: usize = 1 << 34;
+        let mut alloc = MapAllocBuilder::default().no_reserve().build();
+        let layout = Layout::from_size_align(SIZE, 1).unwrap();
+        unsafe {
+            let ptr = match <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()) {
+                Ok(ptr) => ptr,
+                // MAP_NORESERVE is ignored when overcommit is disabled (vm.overcommit_memory = 2),
+                // and the address space may be limited (RLIMIT_AS), so the map can fail anyway.
+                Err(AllocErr::Exhausted { .. }) => return,
+                Err(err) => panic!("unexpected error: {:?}", err),
+            };
+            test_valid_map_address(ptr);
+            test_zero_filled(ptr, pagesize());
+            test_write(ptr, pagesize());
+            let last = ptr.offset((SIZE - pagesize()) as isize);
+            test_write(last, pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+            assert!(!is_mapped(ptr));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_allow_uninitialized() {
+        // Most kernels ignore MAP_UNINITIALIZED, in which case the allocator behaves as usual.
+        // Either way, alloc_zeroed must return zero-filled memory.
+        let size = 16 * pagesize();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        let mut alloc = MapAllocBuilder::default().allow_uninitialized().build();
+        assert_eq!(alloc.uninitialized, uninitialized_honored());
+        // Pretend that the flag is honored, so that alloc_zeroed has to zero the memory itself.
+        alloc.uninitialized = true;
+        unsafe {
+            for _ in 0..4 {
+                let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+                ptr::write_bytes(ptr, 0xFF, size);
+                <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+                let ptr = <MapAlloc as Alloc>::alloc_zeroed(&mut alloc, layout.clone()).unwrap();
+                test_zero_filled(ptr, size);
+                <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_low_32bit() {
+        // Check that memory mapped with MAP_32BIT lies entirely below the 2GB boundary and is
+        // usable.
+        let mut alloc = MapAllocBuilder::default().low_32bit().build();
+        let size = 4 * pagesize();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_valid_map_address(ptr);
+            assert!(ptr as usize + size <= 1 << 31, "ptr: {:?}", ptr);
+            test_zero_filled(ptr, size);
+            test_write_read(ptr, size);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_low_32bit_realloc() {
+        // Check that growing a MAP_32BIT mapping keeps it below the 2GB boundary, even when it
+        // has to move (mremap ignores MAP_32BIT when it moves a mapping).
+        let mut alloc = MapAllocBuilder::default().low_32bit().build();
+        let size = 4 * pagesize();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        let new_layout = Layout::from_size_align(4 * size, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_write(ptr, size);
+            let blocker = map_blocker(ptr, size);
+            let new = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, layout, new_layout.clone())
+                .unwrap();
+            if blocker.is_some() {
+                assert_ne!(new, ptr);
+            }
+            assert!(new as usize + 4 * size <= 1 << 31, "ptr: {:?}", new);
+            for i in 0..size {
+                assert_eq!(*new.offset(i as isize), 1);
+            }
+            test_zero_filled(new.offset(size as isize), 3 * size);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, new, new_layout);
+            if let Some(blocker) = blocker {
+                munmap(blocker, pagesize());
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_low_32bit_realloc_obj() {
+        // Check that realloc_obj, which resizes through the same path as realloc, also keeps a
+        // MAP_32BIT object below the 2GB boundary when it has to move.
+        let mut alloc = MapAllocBuilder::default().low_32bit().build();
+        unsafe {
+            let ptr = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+            test_write(ptr, pagesize());
+            let blocker = map_blocker(ptr, pagesize());
+            let new = alloc.realloc_obj(ptr, pagesize(), 8 * pagesize()).unwrap();
+            if blocker.is_some() {
+                assert_ne!(new, ptr);
+            }
+            assert!(new as usize + 8 * pagesize() <= 1 << 31, "ptr: {:?}", new);
+            for i in 0..pagesize() {
+                assert_eq!(*new.offset(i as isize), 1);
+            }
+            let layout = Layout::from_size_align(8 * pagesize(), 1).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, new, layout);
+            if let Some(blocker) = blocker {
+                munmap(blocker, pagesize());
+            }
+        }
+    }
+
+    // Map a page directly after the 'size' bytes at 'ptr', so that the mapping there cannot grow
+    // in place. Returns None if the kernel does not place the page there.
+    #[cfg(target_os = "linux")]
+    fn map_blocker(ptr: *mut u8, size: usize) -> Option<*mut u8> {
+        let hint = unsafe { ptr.offset(size as isize) };
+        let page = unsafe {
+            ::libc::mmap(hint as *mut ::libc::c_void,
+                         pagesize(),
+                         ::libc::PROT_NONE,
+                         ::libc::MAP_PRIVATE | ::libc::MAP_ANONYMOUS,
+                         -1,
+                         0)
+        };
+        assert_ne!(page, ::libc::MAP_FAILED);
+        if page as *mut u8 == hint {
+            Some(hint)
+        } else {
+            munmap(page as *mut u8, pagesize());
+            None
+        }
+    }
+
+    // Test whether the page containing 'ptr' is mapped.
+    #[cfg(target_os = "linux")]
+    fn is_mapped(ptr: *mut u8) -> bool {
+        let page = (ptr as usize & !(pagesize() - 1)) as *mut ::libc::c_void;
+        let mut vec = 0;
+        // mincore fails with ENOMEM if any part of the range is unmapped
+        unsafe { ::libc::mincore(page, pagesize(), &mut vec) == 0 }
     }
-}
 
-#[cfg(windows)]
-fn munmap(ptr: *mut u8, _size: usize) {
-    use kernel32::{VirtualFree, GetLastError};
-    use winapi::winnt::MEM_RELEASE;
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_split() {
+        // Split a region in two, and check that freeing each half leaves the other usable.
+        let mut alloc = MapAlloc::default();
+        let size = 8 * pagesize();
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_write(ptr, size);
+            let (first, first_layout, second, second_layout) =
+                alloc.split(ptr, layout.clone(), 3 * pagesize());
+            assert_eq!(first, ptr);
+            assert_eq!(first_layout.size(), 3 * pagesize());
+            assert_eq!(second, ptr.offset(3 * pagesize() as isize));
+            assert_eq!(second_layout.size(), 5 * pagesize());
 
-    unsafe {
-        // NOTE: VirtualFree, when unmapping memory (as opposed to decommitting it), can only
-        // operate on an entire region previously mapped with VirtualAlloc. As a result, 'ptr' must
-        // have been previously returned by VirtualAlloc, and no length is needed since it is known
-        // by the kernel (VirtualFree /requires/ that if the third argument is MEM_RELEASE, the
-        // second is 0).
-        let ret = VirtualFree(ptr as *mut winapi::c_void, 0, MEM_RELEASE);
-        if ret == 0 {
-            panic!("Call to VirtualFree failed with error code {}.",
-                   GetLastError());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, second, second_layout);
+            assert!(!is_mapped(second));
+            assert!(!is_mapped(ptr.offset((size - 1) as isize)));
+            assert!(is_mapped(first));
+            test_write_read(first, 3 * pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, first, first_layout);
+            assert!(!is_mapped(first));
+
+            // The second half can also be freed first.
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            let (first, first_layout, second, second_layout) =
+                alloc.split(ptr, layout, pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, first, first_layout);
+            assert!(!is_mapped(first));
+            test_write_read(second, 7 * pagesize());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, second, second_layout);
+            assert!(!is_mapped(second));
         }
     }
-}
 
-#[cfg(target_os = "linux")]
-fn uncommit(ptr: *mut u8, size: usize) {
-    use libc::{c_void, MADV_DONTNEED};
-    unsafe {
-        // TODO: Other options such as MADV_FREE are available on newer versions of Linux. Is there
-        // a way that we can use those when available? Is that even desirable?
-        libc::madvise(ptr as *mut c_void, size, MADV_DONTNEED);
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[should_panic(expected = "split point not aligned to page size")]
+    fn test_split_unaligned() {
+        let alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(2 * pagesize(), 1).unwrap();
+        unsafe {
+            let ptr = <&MapAlloc as Alloc>::alloc(&mut &alloc, layout.clone()).unwrap();
+            alloc.split(ptr, layout, pagesize() / 2);
+        }
     }
-}
 
-#[cfg(target_os = "macos")]
-fn uncommit(ptr: *mut u8, size: usize) {
-    use libc::{c_void, MADV_FREE};
-    unsafe {
-        libc::madvise(ptr as *mut c_void, size, MADV_FREE);
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dealloc_unmaps_everything() {
+        // Check that deallocating an object whose size is not a multiple of the page size
+        // unmaps every page that was mapped for it.
+        let mut alloc = MapAlloc::default();
+        let size = 2 * pagesize() + 1;
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_write_read(ptr, size);
+            assert!(is_mapped(ptr.offset(size as isize - 1)));
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+            for i in 0..3 {
+                assert!(!is_mapped(ptr.offset((i * pagesize()) as isize)),
+                        "page {} still mapped",
+                        i);
+            }
+        }
     }
-}
 
-mod perms {
-    #[cfg(any(target_os = "linux", target_os = "macos"))]
-    pub use self::unix::*;
-    #[cfg(any(target_os = "linux", target_os = "macos"))]
-    pub type Perm = i32;
-    #[cfg(windows)]
-    pub use self::windows::*;
-    #[cfg(windows)]
-    pub type Perm = u32;
+    #[test]
+    fn test_hooks() {
+        // Check that:
+        // - on_map is called once per mapping, with the mapped address and size
+        // - on_unmap is called once per unmapping, including for MapBoxes
+        use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+        static MAPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+        static UNMAPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+        fn on_map(ptr: *mut u8, size: usize) {
+            test_valid_map_address(ptr);
+            assert_eq!(size, 2 * pagesize());
+            MAPPED.fetch_add(1, Ordering::SeqCst);
+        }
+        fn on_unmap(ptr: *mut u8, _size: usize) {
+            test_valid_map_address(ptr);
+            UNMAPPED.fetch_add(1, Ordering::SeqCst);
+        }
 
-    pub fn get_perm(read: bool, write: bool, exec: bool) -> Perm {
-        match (read, write, exec) {
-            (false, false, false) => PROT_NONE,
-            (true, false, false) => PROT_READ,
-            (false, true, false) => PROT_WRITE,
-            (false, false, true) => PROT_EXEC,
-            (true, true, false) => PROT_READ_WRITE,
-            (true, false, true) => PROT_READ_EXEC,
-            (false, true, true) => PROT_WRITE_EXEC,
-            (true, true, true) => PROT_READ_WRITE_EXEC,
+        let mut alloc = MapAllocBuilder::default()
+            .on_map(on_map)
+            .on_unmap(on_unmap)
+            .build();
+        let layout = Layout::from_size_align(2 * pagesize(), 1).unwrap();
+        unsafe {
+            let mut ptrs = [ptr::null_mut(); 4];
+            for p in ptrs.iter_mut() {
+                *p = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            }
+            assert_eq!(MAPPED.load(Ordering::SeqCst), 4);
+            assert_eq!(UNMAPPED.load(Ordering::SeqCst), 0);
+            for p in ptrs.iter() {
+                <MapAlloc as Alloc>::dealloc(&mut alloc, *p, layout.clone());
+            }
         }
+        assert_eq!(UNMAPPED.load(Ordering::SeqCst), 4);
+
+        drop(alloc.alloc_box(layout).unwrap());
+        assert_eq!(MAPPED.load(Ordering::SeqCst), 5);
+        assert_eq!(UNMAPPED.load(Ordering::SeqCst), 5);
     }
 
-    #[cfg(any(target_os = "linux", target_os = "macos"))]
-    mod unix {
-        // NOTE: On some platforms, libc::PROT_WRITE may imply libc::PROT_READ, and libc::PROT_READ
-        // may imply libc::PROT_EXEC.
-        extern crate libc;
-        pub const PROT_NONE: i32 = libc::PROT_NONE;
-        pub const PROT_READ: i32 = libc::PROT_READ;
-        pub const PROT_WRITE: i32 = libc::PROT_WRITE;
-        pub const PROT_EXEC: i32 = libc::PROT_EXEC;
-        pub const PROT_READ_WRITE: i32 = libc::PROT_READ | libc::PROT_WRITE;
-        pub const PROT_READ_EXEC: i32 = libc::PROT_READ | libc::PROT_EXEC;
-        pub const PROT_WRITE_EXEC: i32 = libc::PROT_WRITE | libc::PROT_EXEC;
-        pub const PROT_READ_WRITE_EXEC: i32 = libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC;
+    #[cfg(feature = "track_mappings")]
+    #[test]
+    #[should_panic(expected = "which is not mapped (double dealloc?)")]
+    fn test_track_mappings_double_dealloc() {
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
     }
 
-    #[cfg(windows)]
-    mod windows {
-        extern crate winapi;
-        use self::winapi::winnt;
-        pub const PROT_NONE: u32 = winnt::PAGE_NOACCESS;
-        pub const PROT_READ: u32 = winnt::PAGE_READONLY;
-        // windows doesn't have a write-only permission, so write implies read
-        pub const PROT_WRITE: u32 = winnt::PAGE_READWRITE;
-        pub const PROT_EXEC: u32 = winnt::PAGE_EXECUTE;
-        pub const PROT_READ_WRITE: u32 = winnt::PAGE_READWRITE;
-        pub const PROT_READ_EXEC: u32 = winnt::PAGE_EXECUTE_READ;
-        // windows doesn't have a write/exec permission, so write/exec implies read/write/exec
-        pub const PROT_WRITE_EXEC: u32 = winnt::PAGE_EXECUTE_READWRITE;
-        pub const PROT_READ_WRITE_EXEC: u32 = winnt::PAGE_EXECUTE_READWRITE;
+    #[cfg(feature = "track_mappings")]
+    #[test]
+    fn test_track_mappings_grow() {
+        // Check that the table of live mappings keeps track of more mappings than it initially
+        // has room for.
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        let mut ptrs = [ptr::null_mut(); 1024];
+        unsafe {
+            for p in ptrs.iter_mut() {
+                *p = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            }
+            for &p in ptrs.iter().rev() {
+                <MapAlloc as Alloc>::dealloc(&mut alloc, p, layout.clone());
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate sysconf;
-    use sysconf::page::pagesize;
-    use super::*;
-    use super::perms::*;
+    #[cfg(feature = "track_mappings")]
+    #[test]
+    #[should_panic(expected = "which was mapped with")]
+    fn test_track_mappings_size_mismatch() {
+        let mut alloc = MapAlloc::default();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc,
+                                                 Layout::from_size_align(2 * pagesize(), 1)
+                                                     .unwrap())
+                    .unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc,
+                                         ptr,
+                                         Layout::from_size_align(pagesize(), 1).unwrap());
+        }
+    }
 
+    #[test]
+    fn test_map_box() {
+        // Check that:
+        // - A MapBox's memory is zero-filled and can be written and read through Deref/DerefMut
+        // - The slice has the length of the requested layout, not of the whole mapping
+        // - Zero-sized MapBoxes are rejected
+        let alloc = MapAlloc::default();
+        let size = pagesize() + 1;
+        let mut mb = alloc.alloc_box(Layout::from_size_align(size, 1).unwrap()).unwrap();
+        test_valid_map_address(mb.as_ptr());
+        assert_eq!(mb.len(), size);
+        assert!(mb.iter().all(|b| *b == 0));
+        for b in mb.iter_mut() {
+            *b = 1;
+        }
+        assert!(mb.iter().all(|b| *b == 1));
+        drop(mb);
 
-    #[cfg(not(feature = "test-no-std"))]
-    extern crate test;
+        assert!(alloc.alloc_box(Layout::from_size_align(0, 1).unwrap()).is_err());
+    }
 
-    // allow(unused) because these imports aren't used on windows
-    #[allow(unused)]
-    #[cfg(not(feature = "test-no-std"))]
-    use std::time::{Instant, Duration};
-    #[allow(unused)]
-    #[cfg(not(feature = "test-no-std"))]
-    use self::test::Bencher;
+    #[test]
+    fn test_alloc_slice() {
+        // Check that:
+        // - The slice has the requested length, not that of the whole mapping
+        // - The slice is zero-filled and writes through it can be read back
+        // - dealloc_slice unmaps the entire rounded-up mapping
+        // - Zero-length slices are rejected
+        let alloc = MapAlloc::default();
+        let len = 2 * pagesize() + 17;
+        {
+            let slice = alloc.alloc_slice(len).unwrap();
+            let ptr = slice.as_mut_ptr();
+            test_valid_map_address(ptr);
+            assert_eq!(slice.len(), len);
+            assert!(slice.iter().all(|b| *b == 0));
+            for (i, b) in slice.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            assert!(slice.iter().enumerate().all(|(i, b)| *b == i as u8));
+            unsafe { alloc.dealloc_slice(slice) };
+            #[cfg(target_os = "linux")]
+            {
+                for i in 0..3 {
+                    assert!(!is_mapped(unsafe { ptr.offset((i * pagesize()) as isize) }));
+                }
+            }
+        }
 
-    // NOTE: Technically mmap is allowed to return 0, but (according to our empirical experience)
-    // it only does this for very large map sizes (on Linux, at least 2^30 bytes). We never request
-    // maps that large, so it's OK to check for null here. Even if it spuriously fails in the
-    // future, it will queue us into the fact that our assumptions about when mmap returns null are
-    // wrong.
-    fn test_valid_map_address(ptr: *mut u8) {
-        assert!(ptr as usize > 0, "ptr: {:?}", ptr);
-        assert!(ptr as usize % pagesize() == 0, "ptr: {:?}", ptr);
+        assert!(alloc.alloc_slice(0).is_err());
     }
 
-    // Test that the given range is readable and initialized to zero.
-    fn test_zero_filled(ptr: *mut u8, size: usize) {
-        for i in 0..size {
-            unsafe {
-                assert_eq!(*ptr.offset(i as isize), 0);
+    #[test]
+    fn test_alloc_at_invalid() {
+        // Check that null and unaligned addresses are rejected.
+        let alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        unsafe {
+            match alloc.alloc_at(ptr::null_mut(), layout.clone()) {
+                Err(AllocErr::Unsupported { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
+            }
+            match alloc.alloc_at((pagesize() + 1) as *mut u8, layout.clone()) {
+                Err(AllocErr::Unsupported { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
             }
         }
     }
 
-    // Test that the given range is writable.
-    fn test_write(ptr: *mut u8, size: usize) {
-        for i in 0..size {
-            unsafe {
-                *ptr.offset(i as isize) = 1;
+    #[test]
+    fn test_static_map_alloc() {
+        // Check that:
+        // - Allocations are carved out of the buffer, aligned as requested, and writable
+        // - Allocation fails with Exhausted once the buffer is full
+        // - Deallocating the most recent allocation returns its memory; others don't
+        let mut buf = [0u8; 256];
+        let end = unsafe { buf.as_mut_ptr().offset(256) };
+        let start = buf.as_mut_ptr();
+        let mut alloc = StaticMapAlloc::new(&mut buf);
+        unsafe {
+            let small = Layout::from_size_align(24, 1).unwrap();
+            let aligned = Layout::from_size_align(64, 32).unwrap();
+            let a = alloc.alloc(small.clone()).unwrap();
+            assert_eq!(a, start);
+            let b = alloc.alloc(aligned.clone()).unwrap();
+            assert_eq!(b as usize % 32, 0);
+            assert!(b >= a.offset(24) && b.offset(64) <= end);
+            test_write_read(a, 24);
+            test_write_read(b, 64);
+
+            let remaining = alloc.remaining();
+            match alloc.alloc(Layout::from_size_align(remaining + 1, 1).unwrap()) {
+                Err(AllocErr::Exhausted { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
             }
+            let c = alloc.alloc(Layout::from_size_align(remaining, 1).unwrap()).unwrap();
+            assert_eq!(alloc.remaining(), 0);
+            alloc.dealloc(c, Layout::from_size_align(remaining, 1).unwrap());
+            assert_eq!(alloc.remaining(), remaining);
+
+            // 'a' isn't the most recent allocation, so its memory isn't reclaimed.
+            alloc.dealloc(a, small);
+            assert_eq!(alloc.remaining(), remaining);
+            alloc.dealloc(b, aligned);
+            // The padding before 'b' isn't reclaimed either.
+            assert_eq!(alloc.remaining(), 256 - (b as usize - start as usize));
         }
     }
 
-    // Test that the given range is readable and writable, and that writes can be read back.
-    fn test_write_read(ptr: *mut u8, size: usize) {
-        for i in 0..size {
-            unsafe {
-                *ptr.offset(i as isize) = 1;
+    #[cfg(windows)]
+    #[test]
+    fn test_alloc_at_windows() {
+        // Check that:
+        // - Mapping at a previously-reserved, now-released address returns that address
+        // - The memory is zero-filled and writable
+        // - Mapping over an existing reservation fails and leaves it intact, unlike MAP_FIXED
+        let alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(4 * pagesize(), 1).unwrap();
+        // VirtualAlloc returns addresses aligned to the allocation granularity.
+        let addr = mmap(4 * pagesize(), PROT_READ_WRITE, None).unwrap();
+        munmap(addr, 4 * pagesize());
+        unsafe {
+            let ptr = alloc.alloc_at(addr, layout.clone()).unwrap();
+            assert_eq!(ptr, addr);
+            test_zero_filled(ptr, 4 * pagesize());
+            test_write_read(ptr, 4 * pagesize());
+            match alloc.alloc_at(addr, layout.clone()) {
+                Err(AllocErr::Exhausted { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
             }
+            // the original contents must not have been clobbered
+            assert_eq!(*ptr, 1);
+            munmap(ptr, 4 * pagesize());
         }
-        for i in 0..size {
-            unsafe {
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_cool_page_out() {
+        // Check that:
+        // - Cooling and paging out memory succeed or are ignored, depending on the kernel
+        // - Either way, the memory's contents are preserved
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(4 * pagesize(), 1).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            test_write_read(ptr, 4 * pagesize());
+            alloc.cool(ptr, layout.clone());
+            for i in 0..4 * pagesize() {
+                assert_eq!(*ptr.offset(i as isize), 1);
+            }
+            alloc.page_out(ptr, layout.clone());
+            for i in 0..4 * pagesize() {
                 assert_eq!(*ptr.offset(i as isize), 1);
             }
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
         }
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
-    fn test_map() {
-        // Check that:
-        // - Mapping a single page works
-        // - The returned pointer is non-null
-        // - The returned pointer is page-aligned
-        // - The page is zero-filled
-        // - Unmapping it after it's already been unmapped is OK (except on windows).
-        let mut ptr = mmap(pagesize(), PROT_READ_WRITE, None).unwrap();
-        test_valid_map_address(ptr);
-        test_zero_filled(ptr, pagesize());
-        munmap(ptr, pagesize());
-        #[cfg(not(windows))]
-        munmap(ptr, pagesize());
-
+    fn test_alloc_at_noreplace() {
         // Check that:
-        // - Mapping multiple pages work
-        // - The returned pointer is non-null
-        // - The returned pointer is page-aligned
-        // - The pages are zero-filled
-        // - Unmapping it after it's already been unmapped is OK (except on windows).
-        ptr = mmap(16 * pagesize(), PROT_READ_WRITE, None).unwrap();
-        test_valid_map_address(ptr);
-        test_zero_filled(ptr, 16 * pagesize());
-        munmap(ptr, 16 * pagesize());
-        #[cfg(not(windows))]
-        munmap(ptr, 16 * pagesize());
+        // - Mapping at an unmapped address works
+        // - Mapping over an existing mapping fails and leaves the existing mapping intact
+        let alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        let addr = mmap(pagesize(), PROT_READ_WRITE, None).unwrap();
+        munmap(addr, pagesize());
+        unsafe {
+            let ptr = alloc.alloc_at_noreplace(addr, layout.clone()).unwrap();
+            assert_eq!(ptr, addr);
+            test_write_read(ptr, pagesize());
+            match alloc.alloc_at_noreplace(addr, layout.clone()) {
+                Err(AllocErr::Exhausted { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
+            }
+            // the original contents must not have been clobbered
+            assert_eq!(*ptr, 1);
+            munmap(ptr, pagesize());
+        }
     }
 
     #[cfg(not(windows))]
@@ -777,6 +5384,14 @@ mod tests {
         munmap(ptr, pagesize());
     }
 
+    #[cfg(not(windows))]
+    #[test]
+    fn test_try_mmap_error() {
+        // Check that errors are reported as MmapErrors rather than causing a panic.
+        let res = try_mmap_at(ptr::null_mut(), 0, PROT_READ_WRITE, None, 0);
+        assert_eq!(res, Err(MmapError::InvalidArgument));
+    }
+
     #[cfg(not(windows))]
     #[test]
     #[should_panic]
@@ -816,6 +5431,255 @@ mod tests {
         munmap((pagesize() / 2) as *mut u8, pagesize());
     }
 
+    #[test]
+    fn test_max_align() {
+        // Check that max_align() reflects the alignments that alloc_aligned_excess supports, and
+        // that alignments both up to and past the page size are satisfied.
+        let mut alloc = MapAlloc::default();
+        assert!(alloc.max_align() > pagesize());
+        assert!(alloc.max_align().is_power_of_two());
+        let max = Layout::from_size_align(1, pagesize()).unwrap();
+        let larger = Layout::from_size_align(1, 2 * pagesize()).unwrap();
+        unsafe {
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, max.clone()).unwrap();
+            test_valid_map_address(ptr);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, max);
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, larger.clone()).unwrap();
+            assert_eq!(ptr as usize % (2 * pagesize()), 0);
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, larger);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            // memfd-backed allocators don't support alignments greater than the page size.
+            let alloc = MapAllocBuilder::default()
+                .memfd("mmap-alloc-test", pagesize() as u64)
+                .build();
+            assert_eq!(alloc.max_align(), pagesize());
+            unsafe { ::libc::close(alloc.as_raw_fd().unwrap()) };
+        }
+    }
+
+    #[test]
+    fn test_alloc_aligned_excess() {
+        // Check that:
+        // - Allocations aligned to 2MB with the default page size are aligned, zero-filled, and
+        //   have the size rounded up to the page size as their excess
+        // - dealloc with only the pointer and layout unmaps the whole allocation
+        // - Growing with realloc preserves the alignment and the contents
+        const ALIGN: usize = 1 << 21;
+        let mut alloc = MapAlloc::default();
+        for &size in &[1, pagesize(), 3 * pagesize() + 1, ALIGN, ALIGN + pagesize()] {
+            let layout = Layout::from_size_align(size, ALIGN).unwrap();
+            let rounded = next_multiple(size, pagesize());
+            unsafe {
+                let Excess(ptr, excess) = alloc.alloc_aligned_excess(layout.clone()).unwrap();
+                assert_eq!(ptr as usize % ALIGN, 0, "ptr: {:?}", ptr);
+                assert_eq!(excess, rounded);
+                test_zero_filled(ptr, rounded);
+                test_write_read(ptr, rounded);
+                <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone());
+                #[cfg(target_os = "linux")]
+                {
+                    assert!(!is_mapped(ptr));
+                    assert!(!is_mapped(ptr.offset((rounded - pagesize()) as isize)));
+                }
+
+                let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+                test_write(ptr, size);
+                let grown = Layout::from_size_align(rounded + ALIGN, ALIGN).unwrap();
+                let new = <MapAlloc as Alloc>::realloc(&mut alloc, ptr, layout, grown.clone())
+                    .unwrap();
+                assert_eq!(new as usize % ALIGN, 0, "ptr: {:?}", new);
+                for i in 0..size {
+                    assert_eq!(*new.offset(i as isize), 1);
+                }
+                test_zero_filled(new.offset(rounded as isize), ALIGN);
+                <MapAlloc as Alloc>::dealloc(&mut alloc, new, grown);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_alloc_aligned_memfd() {
+        // Check that over-aligned allocations are rejected for memfd-backed allocators, while
+        // page-aligned ones still work.
+        let alloc = MapAllocBuilder::default().memfd("mmap-alloc-test", 1 << 22).build();
+        unsafe {
+            let layout = Layout::from_size_align(pagesize(), 1 << 21).unwrap();
+            match alloc.alloc_aligned_excess(layout) {
+                Err(AllocErr::Unsupported { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
+            }
+            let layout = Layout::from_size_align(pagesize(), pagesize()).unwrap();
+            let Excess(ptr, _) = alloc.alloc_aligned_excess(layout.clone()).unwrap();
+            <&MapAlloc as Alloc>::dealloc(&mut &alloc, ptr, layout);
+            ::libc::close(alloc.as_raw_fd().unwrap());
+        }
+    }
+
+    #[cfg(feature = "fault_injection")]
+    #[test]
+    fn test_fault_injection() {
+        // Check that:
+        // - Exactly the chosen allocation fails, and later ones succeed
+        // - Injected failures are reported as exhaustion
+        // - A countdown of zero disarms the injector
+        let mut alloc = MapAlloc::default();
+        let layout = Layout::from_size_align(pagesize(), 1).unwrap();
+        unsafe {
+            set_alloc_failure_countdown(3);
+            let mut failures = 0;
+            for i in 0..5 {
+                match <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()) {
+                    Ok(ptr) => <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout.clone()),
+                    Err(AllocErr::Exhausted { .. }) => {
+                        assert_eq!(i, 2);
+                        failures += 1;
+                    }
+                    Err(err) => panic!("unexpected error: {:?}", err),
+                }
+            }
+            assert_eq!(failures, 1);
+
+            set_alloc_failure_countdown(1);
+            set_alloc_failure_countdown(0);
+            let ptr = <MapAlloc as Alloc>::alloc(&mut alloc, layout.clone()).unwrap();
+            <MapAlloc as Alloc>::dealloc(&mut alloc, ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_next_multiple() {
+        assert_eq!(next_multiple(0, 16), 0);
+        assert_eq!(next_multiple(1, 16), 16);
+        assert_eq!(next_multiple(16, 16), 16);
+        assert_eq!(next_multiple(24, 16), 32);
+        assert_eq!(next_multiple(pagesize() + 1, pagesize()), 2 * pagesize());
+    }
+
+    #[test]
+    fn test_packed_object_alloc() {
+        // Check that:
+        // - Objects are aligned, distinct, and writable
+        // - Multiple chunks are used once the first fills up
+        // - Deallocated objects are reused
+        const N: usize = 1000;
+        let layout = Layout::from_size_align(24, 16).unwrap();
+        let mut alloc = PackedObjectAlloc::new(MapAlloc::default(), layout.clone(), 64);
+        assert_eq!(UntypedObjectAlloc::layout(&alloc), layout);
+        assert!(alloc.objs_per_chunk() >= 64);
+        assert!(alloc.objs_per_chunk() < N);
+        let mut objs = [ptr::null_mut(); N];
+        unsafe {
+            for _ in 0..2 {
+                for (i, obj) in objs.iter_mut().enumerate() {
+                    *obj = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+                    assert_eq!(*obj as usize % 16, 0);
+                    ptr::write_bytes(*obj, i as u8, 24);
+                }
+                for (i, obj) in objs.iter().enumerate() {
+                    for j in 0..24 {
+                        assert_eq!(*obj.offset(j), i as u8);
+                    }
+                }
+                for obj in objs.iter() {
+                    UntypedObjectAlloc::dealloc(&mut alloc, *obj);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_object_pool() {
+        // Check that:
+        // - Objects are page-aligned and writable
+        // - Deallocated objects are cached and reused
+        // - Objects can be allocated and deallocated concurrently
+        let alloc = MapAllocBuilder::default().obj_size(2 * pagesize()).build();
+        let mut pool = MapObjectPool::new(alloc);
+        assert_eq!(UntypedObjectAlloc::layout(&pool).size(), 2 * pagesize());
+        unsafe {
+            let a = UntypedObjectAlloc::alloc(&mut pool).unwrap();
+            let b = UntypedObjectAlloc::alloc(&mut pool).unwrap();
+            test_valid_map_address(a);
+            test_valid_map_address(b);
+            test_write_read(a, 2 * pagesize());
+            UntypedObjectAlloc::dealloc(&mut pool, a);
+            UntypedObjectAlloc::dealloc(&mut pool, b);
+            assert_eq!(pool.cached(), 2);
+            assert_eq!(UntypedObjectAlloc::alloc(&mut pool).unwrap(), b);
+            assert_eq!(UntypedObjectAlloc::alloc(&mut pool).unwrap(), a);
+            assert_eq!(pool.cached(), 0);
+            // more objects than fit in a single page of the free list
+            let mut objs = [ptr::null_mut(); 1024];
+            for obj in objs.iter_mut() {
+                *obj = UntypedObjectAlloc::alloc(&mut pool).unwrap();
+            }
+            for obj in objs.iter() {
+                UntypedObjectAlloc::dealloc(&mut pool, *obj);
+            }
+            assert_eq!(pool.cached(), 1024);
+            UntypedObjectAlloc::dealloc(&mut pool, a);
+            UntypedObjectAlloc::dealloc(&mut pool, b);
+        }
+    }
+
+    #[cfg(not(feature = "test-no-std"))]
+    #[test]
+    fn test_object_pool_threads() {
+        use std::sync::Arc;
+        use std::thread;
+        let pool = Arc::new(MapObjectPool::new(MapAlloc::default()));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || for _ in 0..1000 {
+                    unsafe {
+                        let obj = UntypedObjectAlloc::alloc(&mut &*pool).unwrap();
+                        test_write_read(obj, pagesize());
+                        UntypedObjectAlloc::dealloc(&mut &*pool, obj);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(pool.cached() <= 4);
+    }
+
+    #[cfg(not(feature = "test-no-std"))]
+    #[bench]
+    fn bench_obj_alloc_dealloc_map_alloc(b: &mut Bencher) {
+        let mut alloc = MapAlloc::default();
+        b.iter(|| unsafe {
+                   let obj = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+                   UntypedObjectAlloc::dealloc(&mut alloc, obj);
+               })
+    }
+
+    #[cfg(not(feature = "test-no-std"))]
+    #[bench]
+    fn bench_obj_alloc_dealloc_packed(b: &mut Bencher) {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let mut alloc = PackedObjectAlloc::new(MapAlloc::default(), layout, 64);
+        b.iter(|| unsafe {
+                   let obj = UntypedObjectAlloc::alloc(&mut alloc).unwrap();
+                   UntypedObjectAlloc::dealloc(&mut alloc, obj);
+               })
+    }
+
+    #[cfg(not(feature = "test-no-std"))]
+    #[bench]
+    fn bench_obj_alloc_dealloc_pool(b: &mut Bencher) {
+        let mut pool = MapObjectPool::new(MapAlloc::default());
+        b.iter(|| unsafe {
+                   let obj = UntypedObjectAlloc::alloc(&mut pool).unwrap();
+                   UntypedObjectAlloc::dealloc(&mut pool, obj);
+               })
+    }
+
     #[cfg(not(windows))]
     #[cfg(not(feature = "test-no-std"))]
     #[bench]
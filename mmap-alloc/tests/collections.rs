@@ -0,0 +1,126 @@
+// Copyright 2017 the authors. See the 'Copyright and license' section of the
+// README.md file at the top-level directory of this repository.
+//
+// Licensed under the Apache License, Version 2.0 (the LICENSE file). This file
+// may not be copied, modified, or distributed except according to those terms.
+
+// Check that MapAlloc can back standard collections. Vec and Box are not yet parametric over
+// their allocator, so we use RawVec, which is what Vec is built on.
+
+#![feature(alloc, allocator_api)]
+extern crate alloc;
+extern crate mmap_alloc;
+extern crate sysconf;
+
+use alloc::allocator::{Alloc, Layout};
+use alloc::raw_vec::RawVec;
+use mmap_alloc::{MapAlloc, MapAllocBuilder};
+use sysconf::page::pagesize;
+use std::ptr;
+
+// A minimal Vec built on RawVec.
+struct MapVec<'a, T> {
+    buf: RawVec<T, &'a MapAlloc>,
+    len: usize,
+}
+
+impl<'a, T> MapVec<'a, T> {
+    fn new_in(alloc: &'a MapAlloc) -> MapVec<'a, T> {
+        MapVec {
+            buf: RawVec::new_in(alloc),
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, t: T) {
+        if self.len == self.buf.cap() {
+            self.buf.double();
+        }
+        unsafe { ptr::write(self.buf.ptr().offset(self.len as isize), t) };
+        self.len += 1;
+    }
+
+    fn get(&self, i: usize) -> &T {
+        assert!(i < self.len);
+        unsafe { &*self.buf.ptr().offset(i as isize) }
+    }
+}
+
+impl<'a, T> Drop for MapVec<'a, T> {
+    fn drop(&mut self) {
+        for i in 0..self.len {
+            unsafe { ptr::drop_in_place(self.buf.ptr().offset(i as isize)) };
+        }
+        // dropping buf deallocates the buffer
+    }
+}
+
+#[test]
+fn vec_push_and_drop() {
+    let alloc = MapAllocBuilder::default().build();
+    let mut v = MapVec::new_in(&alloc);
+    // enough to force many reallocations, including some that cross page boundaries
+    let n = 4 * pagesize();
+    for i in 0..n {
+        v.push(i as u8);
+    }
+    for i in 0..n {
+        assert_eq!(*v.get(i), i as u8);
+    }
+
+    // elements with destructors
+    let mut v = MapVec::new_in(&alloc);
+    for i in 0..1024 {
+        v.push(vec![i; 16]);
+    }
+    assert_eq!(v.get(1023)[15], 1023);
+}
+
+#[test]
+fn boxed_slice_round_trip() {
+    let alloc = MapAllocBuilder::default().build();
+    let len = 3 * pagesize() + 1;
+    let mut buf: RawVec<u8, &MapAlloc> = RawVec::with_capacity_in(len, &alloc);
+    unsafe {
+        ptr::write_bytes(buf.ptr(), 0xFF, len);
+        let slice = std::slice::from_raw_parts(buf.ptr(), len);
+        assert!(slice.iter().all(|b| *b == 0xFF));
+    }
+    buf.shrink_to_fit(1);
+    assert_eq!(buf.cap(), 1);
+    unsafe { assert_eq!(*buf.ptr(), 0xFF) };
+}
+
+#[test]
+fn realloc_within_page_in_place() {
+    let mut alloc = MapAllocBuilder::default().build();
+    let pagesize = pagesize();
+    unsafe {
+        let layout = Layout::from_size_align(1, 1).unwrap();
+        let ptr = alloc.alloc(layout.clone()).unwrap();
+        // growing within the same page doesn't need to move
+        let new_layout = Layout::from_size_align(pagesize, 1).unwrap();
+        assert_eq!(alloc.realloc(ptr, layout, new_layout.clone()).unwrap(), ptr);
+        // growing past the page might move, but must preserve contents
+        *ptr.offset(pagesize as isize - 1) = 42;
+        let bigger = Layout::from_size_align(2 * pagesize, 1).unwrap();
+        let ptr = alloc.realloc(ptr, new_layout, bigger.clone()).unwrap();
+        assert_eq!(*ptr.offset(pagesize as isize - 1), 42);
+        alloc.dealloc(ptr, bigger);
+    }
+}
+
+#[test]
+fn over_aligned_collection() {
+    // Collections of types aligned to more than a page can be backed by MapAlloc, as long as the
+    // alignment is within max_align.
+    let mut alloc = MapAllocBuilder::default().build();
+    let align = 2 * pagesize();
+    assert!(align <= alloc.max_align());
+    let layout = Layout::from_size_align(1, align).unwrap();
+    unsafe {
+        let ptr = alloc.alloc(layout.clone()).unwrap();
+        assert_eq!(ptr as usize % align, 0);
+        alloc.dealloc(ptr, layout);
+    }
+}